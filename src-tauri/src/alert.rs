@@ -0,0 +1,448 @@
+use crate::command::AppState;
+use crate::db::{unix_timestamp, AlertRule, Db, Device};
+use crate::ssh::SystemMetrics;
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+/// How often offline rules are re-evaluated in the background
+const OFFLINE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Tauri event emitted whenever an alert starts firing or resolves
+pub const ALERT_EVENT: &str = "alert";
+
+/// Metric an alert rule is evaluated against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertMetric {
+    CpuUsage,
+    MemoryPercent,
+    DiskPercent,
+    Load1,
+    Load5,
+    Load15,
+    /// Seconds since the device last reported metrics
+    Offline,
+}
+
+impl AlertMetric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertMetric::CpuUsage => "cpu_usage",
+            AlertMetric::MemoryPercent => "memory_percent",
+            AlertMetric::DiskPercent => "disk_percent",
+            AlertMetric::Load1 => "load_1",
+            AlertMetric::Load5 => "load_5",
+            AlertMetric::Load15 => "load_15",
+            AlertMetric::Offline => "offline",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "cpu_usage" => Some(AlertMetric::CpuUsage),
+            "memory_percent" => Some(AlertMetric::MemoryPercent),
+            "disk_percent" => Some(AlertMetric::DiskPercent),
+            "load_1" => Some(AlertMetric::Load1),
+            "load_5" => Some(AlertMetric::Load5),
+            "load_15" => Some(AlertMetric::Load15),
+            "offline" => Some(AlertMetric::Offline),
+            _ => None,
+        }
+    }
+
+    /// Extract this metric from a sample; `Offline` is never part of a sample
+    fn value(&self, metrics: &SystemMetrics) -> Option<f64> {
+        let load = |i: usize| {
+            metrics
+                .load_average
+                .split(',')
+                .nth(i)
+                .and_then(|s| s.trim().parse::<f64>().ok())
+        };
+        match self {
+            AlertMetric::CpuUsage => Some(metrics.cpu_usage),
+            AlertMetric::MemoryPercent => Some(metrics.memory_percent),
+            AlertMetric::DiskPercent => Some(metrics.disk_percent),
+            AlertMetric::Load1 => load(0),
+            AlertMetric::Load5 => load(1),
+            AlertMetric::Load15 => load(2),
+            AlertMetric::Offline => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparator {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl Comparator {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            ">" => Some(Comparator::Gt),
+            ">=" => Some(Comparator::Gte),
+            "<" => Some(Comparator::Lt),
+            "<=" => Some(Comparator::Lte),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparator::Gt => value > threshold,
+            Comparator::Gte => value >= threshold,
+            Comparator::Lt => value < threshold,
+            Comparator::Lte => value <= threshold,
+        }
+    }
+}
+
+/// Payload of the `alert` event and desktop notification
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertNotification {
+    pub alert_id: i32,
+    pub rule_id: i32,
+    pub rule_name: String,
+    pub device_id: i32,
+    pub device_name: String,
+    pub metric: String,
+    pub state: String,
+    pub value: f64,
+    pub threshold: f64,
+    pub timestamp: i64,
+}
+
+impl AlertNotification {
+    fn new(
+        alert_id: i32,
+        rule: &AlertRule,
+        device: &Device,
+        state: &str,
+        value: f64,
+        now: i64,
+    ) -> Self {
+        Self {
+            alert_id,
+            rule_id: rule.id,
+            rule_name: rule.name.clone(),
+            device_id: device.id,
+            device_name: device.name.clone(),
+            metric: rule.metric.clone(),
+            state: state.to_string(),
+            value,
+            threshold: rule.threshold,
+            timestamp: now,
+        }
+    }
+
+    fn title(&self) -> String {
+        match self.state.as_str() {
+            "firing" => format!("[FIRING] {} on {}", self.rule_name, self.device_name),
+            _ => format!("[RESOLVED] {} on {}", self.rule_name, self.device_name),
+        }
+    }
+
+    fn body(&self) -> String {
+        format!(
+            "{} is {:.2} (threshold {:.2})",
+            self.metric, self.value, self.threshold
+        )
+    }
+}
+
+/// Rules that are enabled and apply to the given device
+fn rules_for_device(rules: &[AlertRule], device_id: i32) -> impl Iterator<Item = &AlertRule> {
+    rules
+        .iter()
+        .filter(move |r| r.enabled && r.device_id.is_none_or(|id| id == device_id))
+}
+
+/// Evaluate every applicable rule against a fresh metrics sample
+///
+/// A sample also proves the device is online, so offline rules are evaluated
+/// with a value of zero seconds, which resolves any firing offline alert.
+pub fn evaluate_metrics(
+    db: &Db,
+    device_id: i32,
+    metrics: &SystemMetrics,
+) -> Result<Vec<AlertNotification>> {
+    let Some(device) = db.get_device(device_id)? else {
+        return Ok(Vec::new());
+    };
+    let rules = db.get_alert_rules()?;
+    let now = metrics.timestamp;
+
+    let mut notifications = Vec::new();
+    for rule in rules_for_device(&rules, device_id) {
+        let Some(metric) = AlertMetric::parse(&rule.metric) else {
+            continue;
+        };
+        let value = match metric {
+            AlertMetric::Offline => 0.0,
+            _ => match metric.value(metrics) {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        if let Some(n) = apply_rule(db, rule, &device, value, now)? {
+            notifications.push(n);
+        }
+    }
+    Ok(notifications)
+}
+
+/// Evaluate offline rules for every device that has reported at least once
+pub fn evaluate_offline(db: &Db, now: i64) -> Result<Vec<AlertNotification>> {
+    let rules = db.get_alert_rules()?;
+    let mut notifications = Vec::new();
+    for device in db.get_all_devices()? {
+        let Some(last_seen) = device.last_seen else {
+            continue;
+        };
+        let value = (now - last_seen).max(0) as f64;
+        for rule in rules_for_device(&rules, device.id) {
+            if AlertMetric::parse(&rule.metric) != Some(AlertMetric::Offline) {
+                continue;
+            }
+            if let Some(n) = apply_rule(db, rule, &device, value, now)? {
+                notifications.push(n);
+            }
+        }
+    }
+    Ok(notifications)
+}
+
+/// Advance the pending -> firing -> resolved state machine for one rule/device
+///
+/// Returns a notification only on the firing and resolved transitions, and
+/// only when no silence covers the rule/device pair.
+fn apply_rule(
+    db: &Db,
+    rule: &AlertRule,
+    device: &Device,
+    value: f64,
+    now: i64,
+) -> Result<Option<AlertNotification>> {
+    let Some(comparator) = Comparator::parse(&rule.comparator) else {
+        return Ok(None);
+    };
+    let breached = comparator.matches(value, rule.threshold);
+    let active = db.get_active_alert(rule.id, device.id)?;
+
+    let transition = match (active, breached) {
+        (None, true) if rule.duration_secs <= 0 => {
+            let id = db.insert_alert(rule.id, device.id, "firing", value, now, Some(now))?;
+            Some((id as i32, "firing"))
+        }
+        (None, true) => {
+            db.insert_alert(rule.id, device.id, "pending", value, now, None)?;
+            None
+        }
+        (Some(alert), true) if alert.state == "pending" => {
+            if now - alert.started_at >= rule.duration_secs {
+                db.fire_alert(alert.id, value, now)?;
+                Some((alert.id, "firing"))
+            } else {
+                db.update_alert_value(alert.id, value)?;
+                None
+            }
+        }
+        (Some(alert), true) => {
+            db.update_alert_value(alert.id, value)?;
+            None
+        }
+        (Some(alert), false) => {
+            db.resolve_alert(alert.id, value, now)?;
+            // A pending alert that never fired resolves quietly
+            (alert.state == "firing").then_some((alert.id, "resolved"))
+        }
+        (None, false) => None,
+    };
+
+    let Some((alert_id, state)) = transition else {
+        return Ok(None);
+    };
+    info!(
+        "Alert {} for rule '{}' on device {} is now {} (value={})",
+        alert_id, rule.name, device.name, state, value
+    );
+    if db.is_alert_silenced(rule.id, device.id, now)? {
+        info!("Alert {} is silenced, skipping notification", alert_id);
+        return Ok(None);
+    }
+    Ok(Some(AlertNotification::new(
+        alert_id, rule, device, state, value, now,
+    )))
+}
+
+/// Emit alert transitions as Tauri events and desktop notifications, and hand
+/// them to the rule's notification channels
+pub fn dispatch(app: &AppHandle, notifications: Vec<AlertNotification>) {
+    for notification in notifications {
+        if let Err(e) = app.emit(ALERT_EVENT, &notification) {
+            error!("Failed to emit alert event: {}", e);
+        }
+        if let Err(e) = app
+            .notification()
+            .builder()
+            .title(notification.title())
+            .body(notification.body())
+            .show()
+        {
+            error!("Failed to show alert notification: {}", e);
+        }
+        crate::notify::spawn_deliveries(app, &notification);
+    }
+}
+
+/// Periodically evaluate offline rules, which no metrics sample can trigger
+pub fn spawn_offline_monitor(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(OFFLINE_CHECK_INTERVAL).await;
+
+            let result = {
+                let state = app.state::<AppState>();
+                let db = match state.db.lock() {
+                    Ok(db) => db,
+                    Err(e) => {
+                        error!("Failed to lock db for offline check: {}", e);
+                        continue;
+                    }
+                };
+                evaluate_offline(&db, unix_timestamp())
+            };
+
+            match result {
+                Ok(notifications) => dispatch(&app, notifications),
+                Err(e) => error!("Failed to evaluate offline alerts: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Database with two devices and a `cpu_usage > 80` rule over `duration_secs`
+    fn setup(name: &str, duration_secs: i64) -> (Db, AlertRule, Device) {
+        let db = Db::open_temp(name);
+        let device_id = db.insert_device("pi", "192.168.1.10", Some(1000)).unwrap();
+        db.insert_device("nas", "192.168.1.20", None).unwrap();
+        db.insert_alert_rule("hot cpu", None, "cpu_usage", ">", 80.0, duration_secs)
+            .unwrap();
+        let rule = db.get_alert_rules().unwrap().remove(0);
+        let device = db.get_device(device_id as i32).unwrap().unwrap();
+        (db, rule, device)
+    }
+
+    fn state(db: &Db, rule: &AlertRule, device: &Device) -> Option<String> {
+        db.get_active_alert(rule.id, device.id)
+            .unwrap()
+            .map(|a| a.state)
+    }
+
+    #[test]
+    fn pending_fires_after_duration_then_resolves() {
+        let (db, rule, device) = setup("alert-lifecycle", 60);
+
+        assert!(apply_rule(&db, &rule, &device, 90.0, 1000)
+            .unwrap()
+            .is_none());
+        assert_eq!(state(&db, &rule, &device).as_deref(), Some("pending"));
+        assert!(apply_rule(&db, &rule, &device, 95.0, 1059)
+            .unwrap()
+            .is_none());
+        assert_eq!(state(&db, &rule, &device).as_deref(), Some("pending"));
+
+        let fired = apply_rule(&db, &rule, &device, 97.0, 1060)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fired.state, "firing");
+        assert_eq!((fired.value, fired.timestamp), (97.0, 1060));
+        assert_eq!(fired.title(), "[FIRING] hot cpu on pi");
+        // Still breached: no second notification
+        assert!(apply_rule(&db, &rule, &device, 99.0, 1075)
+            .unwrap()
+            .is_none());
+
+        let resolved = apply_rule(&db, &rule, &device, 20.0, 1090)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved.state, "resolved");
+        assert_eq!(resolved.alert_id, fired.alert_id);
+        assert_eq!(state(&db, &rule, &device), None);
+    }
+
+    #[test]
+    fn pending_alert_that_never_fires_resolves_quietly() {
+        let (db, rule, device) = setup("alert-quiet", 60);
+        assert!(apply_rule(&db, &rule, &device, 90.0, 1000)
+            .unwrap()
+            .is_none());
+        assert!(apply_rule(&db, &rule, &device, 50.0, 1030)
+            .unwrap()
+            .is_none());
+        assert_eq!(state(&db, &rule, &device), None);
+        assert!(apply_rule(&db, &rule, &device, 50.0, 1040)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn zero_duration_fires_at_once_and_silences_mute_notifications() {
+        let (db, rule, device) = setup("alert-silence", 0);
+        db.insert_alert_silence(Some(rule.id), Some(device.id), 2000, None)
+            .unwrap();
+
+        assert!(apply_rule(&db, &rule, &device, 90.0, 1000)
+            .unwrap()
+            .is_none());
+        assert_eq!(state(&db, &rule, &device).as_deref(), Some("firing"));
+        assert!(apply_rule(&db, &rule, &device, 10.0, 1500)
+            .unwrap()
+            .is_none());
+        assert_eq!(state(&db, &rule, &device), None);
+
+        // The silence has expired
+        let fired = apply_rule(&db, &rule, &device, 90.0, 2500)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fired.state, "firing");
+    }
+
+    #[test]
+    fn offline_rules_fire_for_devices_that_stopped_reporting() {
+        let (db, _, device) = setup("alert-offline", 0);
+        db.insert_alert_rule("gone", None, "offline", ">", 120.0, 0)
+            .unwrap();
+
+        // pi was last seen at 1000; nas has never reported and is skipped
+        assert!(evaluate_offline(&db, 1100).unwrap().is_empty());
+        let fired = evaluate_offline(&db, 1200).unwrap();
+        assert_eq!(fired.len(), 1);
+        assert_eq!(
+            (
+                fired[0].device_id,
+                fired[0].metric.as_str(),
+                fired[0].state.as_str()
+            ),
+            (device.id, "offline", "firing")
+        );
+        assert_eq!(fired[0].value, 200.0);
+        assert!(evaluate_offline(&db, 1300).unwrap().is_empty());
+
+        db.update_device_last_seen(device.id, 1390).unwrap();
+        let resolved = evaluate_offline(&db, 1400).unwrap();
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].state, "resolved");
+        assert_eq!(resolved[0].alert_id, fired[0].alert_id);
+    }
+}
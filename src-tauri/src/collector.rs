@@ -0,0 +1,702 @@
+//! Parsing of raw `/proc` and `df -P` output gathered over SSH.
+//!
+//! The remote side only `cat`s files, so the collector works the same on
+//! glibc distributions, BusyBox and OpenWrt; everything else happens here.
+
+use crate::ssh::SystemMetrics;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Interval between the two `/proc/stat` samples used for CPU usage
+const CPU_SAMPLE_INTERVAL: &str = "0.5";
+
+/// POSIX `sh` script printing each source under a `==name==` marker line
+pub fn collect_script() -> String {
+    format!(
+        r#"
+export LC_ALL=C
+echo "==stat1=="; grep '^cpu' /proc/stat
+echo "==net1=="; cat /proc/net/dev
+echo "==uptime1=="; cat /proc/uptime
+sleep {interval} 2>/dev/null || sleep 1
+echo "==stat2=="; grep '^cpu' /proc/stat
+echo "==net2=="; cat /proc/net/dev
+echo "==meminfo=="; cat /proc/meminfo
+echo "==df=="; df -P -k
+echo "==uptime=="; cat /proc/uptime
+echo "==loadavg=="; cat /proc/loadavg
+"#,
+        interval = CPU_SAMPLE_INTERVAL
+    )
+}
+
+/// Split script output into sections keyed by marker name
+///
+/// A marker is a line consisting only of `==name==`, so content lines that
+/// merely contain `==` are left alone.
+pub fn split_sections(output: &str) -> HashMap<&str, &str> {
+    let mut sections = HashMap::new();
+    let mut current: Option<(&str, usize)> = None;
+    let mut offset = 0;
+    for line in output.split_inclusive('\n') {
+        if let Some(name) = marker_name(line.trim_end_matches(['\r', '\n'])) {
+            if let Some((prev, start)) = current {
+                sections.insert(prev, &output[start..offset]);
+            }
+            current = Some((name, offset + line.len()));
+        }
+        offset += line.len();
+    }
+    if let Some((prev, start)) = current {
+        sections.insert(prev, &output[start..]);
+    }
+    sections
+}
+
+fn marker_name(line: &str) -> Option<&str> {
+    let name = line.strip_prefix("==")?.strip_suffix("==")?;
+    (!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .then_some(name)
+}
+
+fn section<'a>(sections: &HashMap<&str, &'a str>, name: &str) -> Result<&'a str, String> {
+    sections
+        .get(name)
+        .copied()
+        .ok_or_else(|| format!("collector output is missing the {} section", name))
+}
+
+/// Jiffies spent in each CPU state, from a `cpu` line of `/proc/stat`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub nice: u64,
+    pub system: u64,
+    pub idle: u64,
+    pub iowait: u64,
+    pub irq: u64,
+    pub softirq: u64,
+    pub steal: u64,
+}
+
+impl CpuTimes {
+    /// Guest time is already accounted in user/nice, so it is not added again
+    fn total(&self) -> u64 {
+        self.user
+            + self.nice
+            + self.system
+            + self.idle
+            + self.iowait
+            + self.irq
+            + self.softirq
+            + self.steal
+    }
+
+    fn idle_total(&self) -> u64 {
+        self.idle + self.iowait
+    }
+}
+
+/// Parse the aggregate `cpu` line of `/proc/stat`
+pub fn parse_proc_stat(stat: &str) -> Result<CpuTimes, String> {
+    let line = stat
+        .lines()
+        .find(|l| l.split_whitespace().next() == Some("cpu"))
+        .ok_or_else(|| "/proc/stat has no aggregate cpu line".to_string())?;
+    parse_cpu_line(line)
+}
+
+/// Parse the per-core `cpuN` lines of `/proc/stat`, in kernel order
+pub fn parse_proc_stat_cores(stat: &str) -> Result<Vec<(String, CpuTimes)>, String> {
+    stat.lines()
+        .filter_map(|l| {
+            let name = l.split_whitespace().next()?;
+            let index = name.strip_prefix("cpu")?;
+            (!index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+                .then(|| parse_cpu_line(l).map(|times| (name.to_string(), times)))
+        })
+        .collect()
+}
+
+fn parse_cpu_line(line: &str) -> Result<CpuTimes, String> {
+    let values = line
+        .split_whitespace()
+        .skip(1)
+        .map(|v| {
+            v.parse::<u64>()
+                .map_err(|_| format!("/proc/stat: invalid value '{}' in '{}'", v, line))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // user, nice, system and idle exist on every kernel; the rest came later
+    if values.len() < 4 {
+        return Err(format!("/proc/stat: too few fields in '{}'", line));
+    }
+    let field = |i: usize| values.get(i).copied().unwrap_or(0);
+    Ok(CpuTimes {
+        user: field(0),
+        nice: field(1),
+        system: field(2),
+        idle: field(3),
+        iowait: field(4),
+        irq: field(5),
+        softirq: field(6),
+        steal: field(7),
+    })
+}
+
+/// Busy percentage between two samples
+pub fn cpu_usage(prev: &CpuTimes, cur: &CpuTimes) -> Result<f64, String> {
+    let total = cur.total().saturating_sub(prev.total());
+    if total == 0 {
+        return Err("/proc/stat: no CPU time elapsed between samples".to_string());
+    }
+    let idle = cur.idle_total().saturating_sub(prev.idle_total());
+    Ok((total - idle.min(total)) as f64 * 100.0 / total as f64)
+}
+
+/// Share of elapsed CPU time spent in one state, in percent
+fn cpu_share(prev: &CpuTimes, cur: &CpuTimes, state: fn(&CpuTimes) -> u64) -> f64 {
+    let total = cur.total().saturating_sub(prev.total());
+    if total == 0 {
+        return 0.0;
+    }
+    state(cur).saturating_sub(state(prev)) as f64 * 100.0 / total as f64
+}
+
+/// Utilisation of a single core between the two samples
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreUsage {
+    pub core: String,
+    pub usage_percent: f64,
+}
+
+/// Cumulative counters of one interface in `/proc/net/dev`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NetDevCounters {
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+/// Parse `/proc/net/dev`, skipping its two header lines
+pub fn parse_net_dev(net_dev: &str) -> Result<Vec<(String, NetDevCounters)>, String> {
+    net_dev
+        .lines()
+        .skip(2)
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            // Old kernels glue the first counter to the colon, e.g. "eth0:1234"
+            let (name, counters) = line
+                .split_once(':')
+                .ok_or_else(|| format!("/proc/net/dev: unexpected line '{}'", line))?;
+            let values = counters
+                .split_whitespace()
+                .map(|v| {
+                    v.parse::<u64>()
+                        .map_err(|_| format!("/proc/net/dev: invalid value '{}' in '{}'", v, line))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if values.len() < 16 {
+                return Err(format!("/proc/net/dev: too few fields in '{}'", line));
+            }
+            Ok((
+                name.trim().to_string(),
+                NetDevCounters {
+                    rx_bytes: values[0],
+                    rx_errors: values[2],
+                    tx_bytes: values[8],
+                    tx_errors: values[10],
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Totals and per-second rates of one network interface
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceMetrics {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+}
+
+/// Combine two `/proc/net/dev` samples taken `elapsed` seconds apart
+///
+/// Interfaces that appeared between samples get a rate of zero, and a
+/// counter that went backwards (wrap or reset) is treated as no traffic.
+pub fn interface_rates(
+    prev: &[(String, NetDevCounters)],
+    cur: &[(String, NetDevCounters)],
+    elapsed: f64,
+) -> Vec<InterfaceMetrics> {
+    let prev: HashMap<&str, &NetDevCounters> =
+        prev.iter().map(|(name, c)| (name.as_str(), c)).collect();
+    cur.iter()
+        .map(|(name, c)| {
+            let before = prev.get(name.as_str()).copied().unwrap_or(c);
+            let rate = |now: u64, then: u64| now.saturating_sub(then) as f64 / elapsed;
+            InterfaceMetrics {
+                interface: name.clone(),
+                rx_bytes: c.rx_bytes,
+                tx_bytes: c.tx_bytes,
+                rx_errors: c.rx_errors,
+                tx_errors: c.tx_errors,
+                rx_bytes_per_sec: rate(c.rx_bytes, before.rx_bytes),
+                tx_bytes_per_sec: rate(c.tx_bytes, before.tx_bytes),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemInfo {
+    pub total_kb: u64,
+    pub available_kb: u64,
+}
+
+/// Parse `/proc/meminfo`, falling back to MemFree + Buffers + Cached on
+/// kernels older than 3.14 that lack MemAvailable
+pub fn parse_meminfo(meminfo: &str) -> Result<MemInfo, String> {
+    let mut fields = HashMap::new();
+    for line in meminfo.lines() {
+        let Some((key, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let Some(value) = rest.split_whitespace().next() else {
+            continue;
+        };
+        let value = value
+            .parse::<u64>()
+            .map_err(|_| format!("/proc/meminfo: invalid value in '{}'", line))?;
+        fields.insert(key.trim(), value);
+    }
+
+    let get = |key: &str| {
+        fields
+            .get(key)
+            .copied()
+            .ok_or_else(|| format!("/proc/meminfo: missing {}", key))
+    };
+    let total_kb = get("MemTotal")?;
+    if total_kb == 0 {
+        return Err("/proc/meminfo: MemTotal is zero".to_string());
+    }
+    let available_kb = match get("MemAvailable") {
+        Ok(available) => available,
+        Err(_) => get("MemFree")? + get("Buffers").unwrap_or(0) + get("Cached").unwrap_or(0),
+    };
+    Ok(MemInfo {
+        total_kb,
+        available_kb: available_kb.min(total_kb),
+    })
+}
+
+/// One filesystem row of `df -P -k`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiskUsage {
+    pub filesystem: String,
+    pub mount: String,
+    pub total_kb: u64,
+    pub used_kb: u64,
+    pub available_kb: u64,
+    /// Same definition df uses for its capacity column
+    pub percent: f64,
+}
+
+/// Parse `df -P -k` output; the header is skipped whatever its language
+///
+/// Rows that do not parse, such as a stale network mount printing `-` for
+/// its sizes, are skipped so one bad filesystem does not hide the others.
+pub fn parse_df(df: &str) -> Result<Vec<DiskUsage>, String> {
+    let rows: Vec<&str> = df
+        .lines()
+        .skip(1)
+        .filter(|l| !l.trim().is_empty())
+        .collect();
+    let filesystems: Vec<DiskUsage> = rows.iter().filter_map(|line| parse_df_row(line)).collect();
+    if filesystems.is_empty() && !rows.is_empty() {
+        return Err(format!("df: unexpected line '{}'", rows[0]));
+    }
+    Ok(filesystems)
+}
+
+fn parse_df_row(line: &str) -> Option<DiskUsage> {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    // The mount point is the last column and may itself contain spaces
+    if cols.len() < 6 {
+        return None;
+    }
+    let num = |i: usize| cols[i].parse::<u64>().ok();
+    let (total_kb, used_kb, available_kb) = (num(1)?, num(2)?, num(3)?);
+    let usable = used_kb + available_kb;
+    Some(DiskUsage {
+        filesystem: cols[0].to_string(),
+        mount: cols[5..].join(" "),
+        total_kb,
+        used_kb,
+        available_kb,
+        percent: if usable == 0 {
+            0.0
+        } else {
+            used_kb as f64 * 100.0 / usable as f64
+        },
+    })
+}
+
+/// Parse `/proc/uptime` into whole seconds
+pub fn parse_uptime(uptime: &str) -> Result<u64, String> {
+    parse_uptime_precise(uptime).map(|s| s as u64)
+}
+
+fn parse_uptime_precise(uptime: &str) -> Result<f64, String> {
+    let first = uptime
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| "/proc/uptime is empty".to_string())?;
+    first
+        .parse::<f64>()
+        .map_err(|_| format!("/proc/uptime: invalid value '{}'", first))
+}
+
+/// Parse the 1, 5 and 15 minute load averages from `/proc/loadavg`
+pub fn parse_loadavg(loadavg: &str) -> Result<[f64; 3], String> {
+    let values = loadavg
+        .split_whitespace()
+        .take(3)
+        .map(|v| {
+            v.parse::<f64>()
+                .map_err(|_| format!("/proc/loadavg: invalid value '{}'", v))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [one, five, fifteen] => Ok([one, five, fifteen]),
+        _ => Err(format!(
+            "/proc/loadavg: expected 3 values in '{}'",
+            loadavg.trim()
+        )),
+    }
+}
+
+/// Build a `SystemMetrics` from the output of [`collect_script`]
+pub fn parse_metrics(output: &str, timestamp: i64) -> Result<SystemMetrics, String> {
+    let sections = split_sections(output);
+
+    let stat1 = section(&sections, "stat1")?;
+    let stat2 = section(&sections, "stat2")?;
+    let prev = parse_proc_stat(stat1)?;
+    let cur = parse_proc_stat(stat2)?;
+    let cpu_usage = cpu_usage(&prev, &cur)?;
+
+    let prev_cores: HashMap<String, CpuTimes> = parse_proc_stat_cores(stat1)?.into_iter().collect();
+    let cpu_cores = parse_proc_stat_cores(stat2)?
+        .into_iter()
+        .filter_map(|(core, times)| {
+            let before = prev_cores.get(&core)?;
+            // A core that was offline for the whole interval has no elapsed time
+            let usage_percent = self::cpu_usage(before, &times).unwrap_or(0.0);
+            Some(CoreUsage {
+                core,
+                usage_percent,
+            })
+        })
+        .collect();
+
+    let mem = parse_meminfo(section(&sections, "meminfo")?)?;
+    let used_kb = mem.total_kb - mem.available_kb;
+
+    // Pseudo filesystems such as proc or sysfs report a size of zero
+    let filesystems: Vec<DiskUsage> = parse_df(section(&sections, "df")?)?
+        .into_iter()
+        .filter(|d| d.total_kb > 0)
+        .collect();
+    let root = filesystems
+        .iter()
+        .find(|d| d.mount == "/")
+        .ok_or_else(|| "df: no filesystem reported for /".to_string())?;
+    let (disk_used_kb, disk_total_kb, disk_percent) = (root.used_kb, root.total_kb, root.percent);
+
+    let uptime_start = parse_uptime_precise(section(&sections, "uptime1")?)?;
+    let uptime = parse_uptime_precise(section(&sections, "uptime")?)?;
+    let elapsed = uptime - uptime_start;
+    if elapsed <= 0.0 {
+        return Err("/proc/uptime: no time elapsed between samples".to_string());
+    }
+    let network = interface_rates(
+        &parse_net_dev(section(&sections, "net1")?)?,
+        &parse_net_dev(section(&sections, "net2")?)?,
+        elapsed,
+    );
+    let loadavg = section(&sections, "loadavg")?;
+    parse_loadavg(loadavg)?;
+    // Keep the kernel's own formatting, e.g. "0.25,0.50,1.00"
+    let load_average = loadavg
+        .split_whitespace()
+        .take(3)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    Ok(SystemMetrics {
+        cpu_usage,
+        iowait_percent: cpu_share(&prev, &cur, |t| t.iowait),
+        steal_percent: cpu_share(&prev, &cur, |t| t.steal),
+        cpu_cores,
+        memory_used_mb: used_kb as f64 / 1024.0,
+        memory_total_mb: mem.total_kb as f64 / 1024.0,
+        memory_percent: used_kb as f64 * 100.0 / mem.total_kb as f64,
+        disk_used_gb: disk_used_kb as f64 / (1024.0 * 1024.0),
+        disk_total_gb: disk_total_kb as f64 / (1024.0 * 1024.0),
+        disk_percent,
+        filesystems,
+        network,
+        uptime_seconds: uptime as u64,
+        load_average,
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAT_1: &str = "\
+cpu  4705 356 584 3699176 23060 0 277 0 0 0
+cpu0 1393 280 284 1849776 11449 0 196 0 0 0
+cpu1 3312 76 300 1849400 11611 0 81 0 0 0
+intr 2276473 0 0 0
+ctxt 5212417
+";
+    const STAT_2: &str = "\
+cpu  4805 356 634 3699326 23110 0 277 0 0 0
+cpu0 1443 280 309 1849851 11474 0 196 0 0 0
+cpu1 3362 76 325 1849475 11636 0 81 0 0 0
+";
+    /// Kernel 2.4 style line with only four fields
+    const STAT_OLD: &str = "cpu  100 0 50 850\n";
+
+    const MEMINFO: &str = "\
+MemTotal:        8048236 kB
+MemFree:          312644 kB
+MemAvailable:    4024118 kB
+Buffers:          170196 kB
+Cached:          3408276 kB
+";
+    /// OpenWrt / pre-3.14 kernel without MemAvailable
+    const MEMINFO_OLD: &str = "\
+MemTotal:          60000 kB
+MemFree:           20000 kB
+Buffers:            4000 kB
+Cached:             6000 kB
+";
+
+    const DF_GNU: &str = "\
+Filesystem     1024-blocks     Used Available Capacity Mounted on
+/dev/sda1         41152736 20576368  18463192      53% /
+";
+    /// BusyBox header differs and the mount point contains a space
+    const DF_BUSYBOX: &str = "\
+Filesystem           1K-blocks      Used Available Use% Mounted on
+/dev/root                 3072      1536      1536  50% /mnt/my disk
+";
+    /// Localised header must not matter
+    const DF_LOCALIZED: &str = "\
+Dateisystem    1024-Blöcke  Benutzt Verfügbar Kapazität Eingehängt auf
+overlay           100000    25000     75000      25% /
+";
+
+    const NET_DEV_1: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:   10000     100    0    0    0     0          0         0    10000     100    0    0    0     0       0          0
+  eth0: 5000000    4000    2    0    0     0          0         0  1000000    3000    1    0    0     0       0          0
+";
+    /// Second sample, with an interface that appeared in between
+    const NET_DEV_2: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:   10000     100    0    0    0     0          0         0    10000     100    0    0    0     0       0          0
+  eth0:5500000    4500    3    0    0     0          0         0  1250000    3200    1    0    0     0       0          0
+  wg0:     300       3    0    0    0     0          0         0      400       4    0    0    0     0       0          0
+";
+
+    const DF_ALL: &str = "\
+Filesystem     1024-blocks     Used Available Capacity Mounted on
+proc                     0        0         0       -  /proc
+/dev/sda1         41152736 20576368  18463192      53% /
+/dev/sdb1          1000000   900000    100000      90% /var/lib/data
+";
+
+    fn output(stat1: &str, stat2: &str, meminfo: &str, df: &str) -> String {
+        format!(
+            "==stat1==\n{}==net1==\n{}==uptime1==\n350735.00 234388.00\n==stat2==\n{}==net2==\n{}==meminfo==\n{}==df==\n{}==uptime==\n350735.50 234388.90\n==loadavg==\n0.25 0.50 1.00 1/123 4567\n",
+            stat1, NET_DEV_1, stat2, NET_DEV_2, meminfo, df
+        )
+    }
+
+    #[test]
+    fn splits_sections() {
+        let out = output(STAT_1, STAT_2, MEMINFO, DF_GNU);
+        let sections = split_sections(&out);
+        assert_eq!(sections["stat2"], STAT_2);
+        assert_eq!(sections["df"], DF_GNU);
+        assert_eq!(sections["uptime"], "350735.50 234388.90\n");
+    }
+
+    #[test]
+    fn cpu_usage_from_two_samples() {
+        let prev = parse_proc_stat(STAT_1).unwrap();
+        let cur = parse_proc_stat(STAT_2).unwrap();
+        // 350 jiffies elapsed, 200 of them idle or iowait
+        let usage = cpu_usage(&prev, &cur).unwrap();
+        assert!((usage - 150.0 * 100.0 / 350.0).abs() < 1e-9, "{}", usage);
+    }
+
+    #[test]
+    fn cpu_line_with_four_fields() {
+        let times = parse_proc_stat(STAT_OLD).unwrap();
+        assert_eq!(times.idle, 850);
+        assert_eq!(times.steal, 0);
+    }
+
+    #[test]
+    fn cpu_usage_without_elapsed_time_is_an_error() {
+        let times = parse_proc_stat(STAT_1).unwrap();
+        assert!(cpu_usage(&times, &times).is_err());
+    }
+
+    #[test]
+    fn meminfo_prefers_mem_available() {
+        let mem = parse_meminfo(MEMINFO).unwrap();
+        assert_eq!(mem.total_kb, 8048236);
+        assert_eq!(mem.available_kb, 4024118);
+    }
+
+    #[test]
+    fn meminfo_falls_back_without_mem_available() {
+        let mem = parse_meminfo(MEMINFO_OLD).unwrap();
+        assert_eq!(mem.available_kb, 30000);
+    }
+
+    #[test]
+    fn meminfo_missing_total_is_an_error() {
+        let err = parse_meminfo("MemFree: 100 kB\n").unwrap_err();
+        assert!(err.contains("MemTotal"), "{}", err);
+    }
+
+    #[test]
+    fn df_variants() {
+        let gnu = parse_df(DF_GNU).unwrap();
+        assert_eq!(gnu[0].used_kb, 20576368);
+        assert_eq!(gnu[0].mount, "/");
+        assert_eq!(gnu[0].percent.round(), 53.0);
+
+        let busybox = parse_df(DF_BUSYBOX).unwrap();
+        assert_eq!(busybox[0].mount, "/mnt/my disk");
+        assert_eq!(busybox[0].percent, 50.0);
+
+        let localized = parse_df(DF_LOCALIZED).unwrap();
+        assert_eq!(localized[0].filesystem, "overlay");
+        assert_eq!(localized[0].percent, 25.0);
+    }
+
+    #[test]
+    fn df_skips_unparsable_rows() {
+        let df = concat!(
+            "Filesystem     1024-blocks     Used Available Capacity Mounted on\n",
+            "nas:/export              -        -         -        - /mnt/nas\n",
+            "/dev/sda1         40000000 20000000  20000000      50% /\n",
+        );
+        let rows = parse_df(df).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].mount, "/");
+        assert_eq!(rows[0].percent, 50.0);
+    }
+
+    #[test]
+    fn df_garbage_is_an_error() {
+        assert!(parse_df("Filesystem\n/dev/sda1 lots used\n").is_err());
+    }
+
+    #[test]
+    fn uptime_and_loadavg() {
+        assert_eq!(parse_uptime("350735.47 234388.90\n").unwrap(), 350735);
+        assert_eq!(
+            parse_loadavg("0.25 0.50 1.00 1/123 4567\n").unwrap(),
+            [0.25, 0.5, 1.0]
+        );
+        assert!(parse_loadavg("0.25 0.50\n").is_err());
+        assert!(parse_uptime("").is_err());
+    }
+
+    #[test]
+    fn full_metrics() {
+        let out = output(STAT_1, STAT_2, MEMINFO_OLD, DF_GNU);
+        let metrics = parse_metrics(&out, 42).unwrap();
+        assert_eq!(metrics.memory_total_mb, 60000.0 / 1024.0);
+        assert_eq!(metrics.memory_percent, 50.0);
+        assert_eq!(metrics.uptime_seconds, 350735);
+        assert_eq!(metrics.load_average, "0.25,0.50,1.00");
+        assert_eq!(metrics.timestamp, 42);
+    }
+
+    #[test]
+    fn per_core_usage_and_cpu_states() {
+        let out = output(STAT_1, STAT_2, MEMINFO, DF_GNU);
+        let metrics = parse_metrics(&out, 0).unwrap();
+        let cores: Vec<&str> = metrics.cpu_cores.iter().map(|c| c.core.as_str()).collect();
+        assert_eq!(cores, ["cpu0", "cpu1"]);
+        // cpu0: 175 jiffies elapsed, 100 of them idle or iowait
+        assert!((metrics.cpu_cores[0].usage_percent - 75.0 * 100.0 / 175.0).abs() < 1e-9);
+        assert!((metrics.iowait_percent - 50.0 * 100.0 / 350.0).abs() < 1e-9);
+        assert_eq!(metrics.steal_percent, 0.0);
+    }
+
+    #[test]
+    fn net_dev_counters_and_rates() {
+        let counters = parse_net_dev(NET_DEV_2).unwrap();
+        assert_eq!(counters.len(), 3);
+        assert_eq!(counters[1].0, "eth0");
+        assert_eq!(counters[1].1.rx_errors, 3);
+        assert_eq!(counters[1].1.tx_bytes, 1250000);
+
+        let out = output(STAT_1, STAT_2, MEMINFO, DF_GNU);
+        let metrics = parse_metrics(&out, 0).unwrap();
+        let eth0 = &metrics.network[1];
+        // 500000 bytes received over the 0.5s between uptime samples
+        assert_eq!(eth0.rx_bytes_per_sec, 1_000_000.0);
+        assert_eq!(eth0.tx_bytes_per_sec, 500_000.0);
+        assert_eq!(metrics.network[2].interface, "wg0");
+        assert_eq!(metrics.network[2].rx_bytes_per_sec, 0.0);
+    }
+
+    #[test]
+    fn net_dev_truncated_line_is_an_error() {
+        let truncated = format!("{}  eth1: 1 2 3\n", NET_DEV_1);
+        assert!(parse_net_dev(&truncated).is_err());
+    }
+
+    #[test]
+    fn all_filesystems_are_reported() {
+        let out = output(STAT_1, STAT_2, MEMINFO, DF_ALL);
+        let metrics = parse_metrics(&out, 0).unwrap();
+        let mounts: Vec<&str> = metrics
+            .filesystems
+            .iter()
+            .map(|f| f.mount.as_str())
+            .collect();
+        assert_eq!(mounts, ["/", "/var/lib/data"]);
+        assert_eq!(metrics.disk_percent.round(), 53.0);
+        assert_eq!(metrics.filesystems[1].percent, 90.0);
+    }
+
+    #[test]
+    fn missing_section_is_an_error() {
+        let out = output(STAT_1, STAT_2, MEMINFO, DF_GNU).replace("==loadavg==", "==other==");
+        let err = parse_metrics(&out, 0).unwrap_err();
+        assert!(err.contains("loadavg"), "{}", err);
+    }
+}
@@ -1,10 +1,21 @@
-use crate::db::{Db, Device};
+use crate::db::{
+    Alert, AlertChannel, AlertDelivery, AlertRule, AlertSilence, CommandLog, CpuCoreMetric, Db,
+    Device, DeviceFactsRecord, DriftCheck, DriftSnapshot, FilesystemMetric, InterfaceMetric, Job,
+    JobRun, Metric, PackageUpdateCount, Runbook, RunbookRun, RunbookStepResult, Script,
+    ScriptVersion,
+};
+use crate::ssh::SystemMetrics;
 use log::{error, info};
+use std::collections::HashMap;
 use std::sync::Mutex;
-use tauri::State;
+use tauri::{AppHandle, State};
 
 pub struct AppState {
     pub db: Mutex<Db>,
+    /// Most recent metrics sample per device id
+    pub latest_metrics: Mutex<HashMap<i32, SystemMetrics>>,
+    /// Escalation passwords, kept in memory only
+    pub vault: crate::vault::Vault,
 }
 
 #[tauri::command]
@@ -36,9 +47,10 @@ pub async fn add_device(
 ) -> Result<(), String> {
     info!("Adding device: name={}, ip={}", name, ip);
     let db = state.db.lock().map_err(|e| e.to_string())?;
-    db.insert_device(&name, &ip, None)
+    let device = crate::validation::check_new_device(&db, &name, &ip, None)?;
+    db.insert_device(&device.name, &device.ip, None)
         .map_err(|e| e.to_string())?;
-    info!("Device added successfully: {}", name);
+    info!("Device added successfully: {}", device.name);
     Ok(())
 }
 
@@ -47,10 +59,189 @@ pub async fn delete_device(state: State<'_, AppState>, id: i32) -> Result<(), St
     info!("Deleting device with id: {}", id);
     let db = state.db.lock().map_err(|e| e.to_string())?;
     db.delete_device(id).map_err(|e| e.to_string())?;
+    state.vault.remove(id);
     info!("Device deleted successfully: {}", id);
     Ok(())
 }
 
+#[tauri::command]
+pub async fn set_device_tags(
+    state: State<'_, AppState>,
+    id: i32,
+    tags: Vec<String>,
+) -> Result<(), String> {
+    info!("Setting tags for device {}: {:?}", id, tags);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_device_tags(id, &tags).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Field-level problems with a device's name, address and port, for
+/// checking a form before submitting it; empty when the device is valid
+#[tauri::command]
+pub fn validate_device(
+    state: State<'_, AppState>,
+    name: String,
+    ip: String,
+    port: Option<u16>,
+    device_id: Option<i32>,
+) -> Result<Vec<crate::validation::FieldError>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let existing = db.get_all_devices().map_err(|e| e.to_string())?;
+    Ok(
+        crate::validation::validate_device(&name, &ip, port, &existing, device_id)
+            .err()
+            .unwrap_or_default(),
+    )
+}
+
+/// Sweep a CIDR range for SSH servers, emitting `scan-progress` as it goes
+#[tauri::command]
+pub async fn scan_network(
+    app: AppHandle,
+    options: crate::scan::ScanOptions,
+) -> Result<Vec<crate::scan::DiscoveredHost>, String> {
+    crate::scan::scan(&app, options).await
+}
+
+/// Add the chosen scan results as devices; returns the new device ids
+#[tauri::command]
+pub fn add_discovered_devices(
+    state: State<'_, AppState>,
+    devices: Vec<crate::scan::DiscoveredDevice>,
+) -> Result<Vec<i64>, String> {
+    crate::scan::add_discovered(&state, &devices)
+}
+
+/// Export all devices as a CSV, JSON or Ansible inventory
+#[tauri::command]
+pub fn export_inventory(state: State<'_, AppState>, format: String) -> Result<String, String> {
+    let format = crate::inventory::InventoryFormat::parse(&format)
+        .ok_or_else(|| format!("Unknown inventory format: {}", format))?;
+    let devices = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_all_devices().map_err(|e| e.to_string())?
+    };
+    crate::inventory::export(&devices, format)
+}
+
+/// Validate an inventory and, unless `dry_run`, add its valid devices
+#[tauri::command]
+pub fn import_inventory(
+    state: State<'_, AppState>,
+    format: String,
+    content: String,
+    dry_run: bool,
+) -> Result<crate::inventory::ImportReport, String> {
+    let format = crate::inventory::InventoryFormat::parse(&format)
+        .ok_or_else(|| format!("Unknown inventory format: {}", format))?;
+    crate::inventory::import(&state, &content, format, dry_run)
+}
+
+/// Set or clear free-form notes on a device
+#[tauri::command]
+pub fn set_device_notes(
+    state: State<'_, AppState>,
+    device_id: i32,
+    notes: Option<String>,
+) -> Result<(), String> {
+    let notes = notes.filter(|n| !n.trim().is_empty());
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_device_notes(device_id, notes.as_deref())
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Browse for `_ssh._tcp` and `_workstation._tcp` services, emitting
+/// `mdns-candidate` and `mdns-candidate-removed` until stopped
+#[tauri::command]
+pub fn start_mdns_discovery(app: AppHandle) -> Result<(), String> {
+    crate::mdns::start(&app)
+}
+
+#[tauri::command]
+pub fn stop_mdns_discovery(app: AppHandle) -> Result<(), String> {
+    crate::mdns::stop(&app)
+}
+
+/// Hosts found by mDNS discovery; add them with `add_discovered_devices`
+#[tauri::command]
+pub fn get_mdns_candidates(
+    app: AppHandle,
+    mdns: State<'_, crate::mdns::MdnsState>,
+) -> Result<Vec<crate::mdns::MdnsCandidate>, String> {
+    mdns.candidates(&app)
+}
+
+/// Escalation settings of a device, without the password itself
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeviceEscalation {
+    pub device_id: i32,
+    pub method: String,
+    /// Whether a password is in the vault for this session
+    pub password_set: bool,
+}
+
+#[tauri::command]
+pub fn get_device_escalation(
+    state: State<'_, AppState>,
+    id: i32,
+) -> Result<DeviceEscalation, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let device = db
+        .get_device(id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Device {} not found", id))?;
+    Ok(DeviceEscalation {
+        device_id: id,
+        method: device.escalation,
+        password_set: state.vault.contains(id),
+    })
+}
+
+/// Choose how privileged commands gain root on a device: `sudo`,
+/// `sudo_password`, `doas` or `su`
+#[tauri::command]
+pub fn set_device_escalation(
+    state: State<'_, AppState>,
+    id: i32,
+    method: String,
+) -> Result<(), String> {
+    let method = crate::ssh::Escalation::parse(&method)
+        .ok_or_else(|| format!("Unknown escalation method: {}", method))?;
+    info!("Setting escalation of device {} to {}", id, method.as_str());
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    if db
+        .set_device_escalation(id, method.as_str())
+        .map_err(|e| e.to_string())?
+        == 0
+    {
+        return Err(format!("Device {} not found", id));
+    }
+    Ok(())
+}
+
+/// Keep the escalation password of a device in memory until the app exits
+#[tauri::command]
+pub fn set_device_password(
+    state: State<'_, AppState>,
+    id: i32,
+    password: String,
+) -> Result<(), String> {
+    if password.contains(['\n', '\r', '\0']) {
+        return Err("The password cannot contain line breaks or NUL bytes".to_string());
+    }
+    info!("Storing escalation password for device {}", id);
+    state.vault.set(id, password);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn clear_device_password(state: State<'_, AppState>, id: i32) {
+    info!("Clearing escalation password for device {}", id);
+    state.vault.remove(id);
+}
+
 #[tauri::command]
 pub fn get_log_path() -> String {
     crate::logging::get_log_file_path_string()
@@ -66,7 +257,12 @@ pub async fn connect_and_add_device(
         "Starting connect_and_add_device for hostname={}, ip={}",
         hostname, ip
     );
-    match crate::ssh::new_connection(state, hostname.clone(), ip).await {
+    let device = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        crate::validation::check_new_device(&db, &hostname, &ip, Some(22))?
+    };
+    let hostname = device.name;
+    match crate::ssh::new_connection(state, hostname.clone(), device.ip).await {
         Ok(_) => {
             info!("Successfully connected and added device: {}", hostname);
             Ok("Connected successfully".to_string())
@@ -100,7 +296,12 @@ pub async fn connect_and_add_device_with_config(
         connect_timeout,
     };
 
-    match crate::ssh::new_connection_with_config(state, hostname.clone(), ip, config).await {
+    let device = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        crate::validation::check_new_device(&db, &hostname, &ip, config.port)?
+    };
+    let hostname = device.name;
+    match crate::ssh::new_connection_with_config(state, hostname.clone(), device.ip, config).await {
         Ok(_) => {
             info!("Successfully connected and added device: {}", hostname);
             Ok("Connected successfully".to_string())
@@ -134,13 +335,17 @@ pub async fn test_ssh_connection(
 }
 
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn get_device_metrics(
+    app: AppHandle,
+    state: State<'_, AppState>,
     ip: String,
+    device_id: Option<i32>,
     username: Option<String>,
     port: Option<u16>,
     strict_host_key_checking: Option<bool>,
     connect_timeout: Option<u64>,
-) -> Result<crate::ssh::SystemMetrics, String> {
+) -> Result<SystemMetrics, String> {
     info!("Fetching system metrics for device at ip={}", ip);
 
     let config = crate::ssh::SshConfig {
@@ -150,5 +355,1460 @@ pub async fn get_device_metrics(
         connect_timeout,
     };
 
-    crate::ssh::get_system_metrics(ip, config).await
+    let metrics = crate::ssh::get_system_metrics(ip, config).await?;
+
+    // Samples tied to a known device are recorded and fed to the alert engine
+    if let Some(device_id) = device_id {
+        let notifications = {
+            let db = state.db.lock().map_err(|e| e.to_string())?;
+            db.insert_metrics_sample(device_id, &metrics)
+                .map_err(|e| e.to_string())?;
+            db.update_device_last_seen(device_id, metrics.timestamp)
+                .map_err(|e| e.to_string())?;
+            crate::alert::evaluate_metrics(&db, device_id, &metrics).map_err(|e| {
+                let err_msg = format!("Failed to evaluate alerts: {}", e);
+                error!("{}", err_msg);
+                err_msg
+            })?
+        };
+        crate::alert::dispatch(&app, notifications);
+        state
+            .latest_metrics
+            .lock()
+            .map_err(|e| e.to_string())?
+            .insert(device_id, metrics.clone());
+    }
+
+    Ok(metrics)
+}
+
+/// Recorded summary samples for a device since the given UNIX time
+#[tauri::command]
+pub fn get_metrics_history(
+    state: State<'_, AppState>,
+    device_id: i32,
+    since: Option<i64>,
+) -> Result<Vec<Metric>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_metrics(device_id, since.unwrap_or(0))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_cpu_core_history(
+    state: State<'_, AppState>,
+    device_id: i32,
+    since: Option<i64>,
+) -> Result<Vec<CpuCoreMetric>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_cpu_core_metrics(device_id, since.unwrap_or(0))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_network_history(
+    state: State<'_, AppState>,
+    device_id: i32,
+    since: Option<i64>,
+) -> Result<Vec<InterfaceMetric>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_interface_metrics(device_id, since.unwrap_or(0))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_filesystem_history(
+    state: State<'_, AppState>,
+    device_id: i32,
+    since: Option<i64>,
+) -> Result<Vec<FilesystemMetric>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_filesystem_metrics(device_id, since.unwrap_or(0))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_alert_rules(state: State<'_, AppState>) -> Result<Vec<AlertRule>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_alert_rules().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn add_alert_rule(
+    state: State<'_, AppState>,
+    name: String,
+    metric: String,
+    comparator: String,
+    threshold: f64,
+    duration_secs: i64,
+    device_id: Option<i32>,
+) -> Result<i64, String> {
+    info!(
+        "Adding alert rule: name={}, {} {} {} for {}s, device_id={:?}",
+        name, metric, comparator, threshold, duration_secs, device_id
+    );
+    if crate::alert::AlertMetric::parse(&metric).is_none() {
+        return Err(format!("Unknown alert metric: {}", metric));
+    }
+    if crate::alert::Comparator::parse(&comparator).is_none() {
+        return Err(format!("Unknown comparator: {}", comparator));
+    }
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_alert_rule(
+        &name,
+        device_id,
+        &metric,
+        &comparator,
+        threshold,
+        duration_secs,
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_alert_rule_enabled(
+    state: State<'_, AppState>,
+    id: i32,
+    enabled: bool,
+) -> Result<(), String> {
+    info!("Setting alert rule {} enabled={}", id, enabled);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_alert_rule_enabled(id, enabled)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_alert_rule(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting alert rule with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_alert_rule(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_alerts(
+    state: State<'_, AppState>,
+    active_only: Option<bool>,
+) -> Result<Vec<Alert>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_alerts(active_only.unwrap_or(false))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn add_alert_silence(
+    state: State<'_, AppState>,
+    rule_id: Option<i32>,
+    device_id: Option<i32>,
+    duration_secs: i64,
+    comment: Option<String>,
+) -> Result<i64, String> {
+    info!(
+        "Silencing alerts for rule_id={:?}, device_id={:?} for {}s",
+        rule_id, device_id, duration_secs
+    );
+    let until = crate::db::unix_timestamp() + duration_secs;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_alert_silence(rule_id, device_id, until, comment.as_deref())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_alert_silences(state: State<'_, AppState>) -> Result<Vec<AlertSilence>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_alert_silences().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn delete_alert_silence(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting alert silence with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_alert_silence(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_alert_channels(
+    state: State<'_, AppState>,
+    rule_id: i32,
+) -> Result<Vec<AlertChannel>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_alert_channels(rule_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn add_alert_channel(
+    state: State<'_, AppState>,
+    rule_id: i32,
+    kind: String,
+    target: String,
+    max_retries: Option<i32>,
+) -> Result<i64, String> {
+    info!(
+        "Adding {} alert channel to rule {}: {}",
+        kind, rule_id, target
+    );
+    if crate::notify::ChannelKind::parse(&kind).is_none() {
+        return Err(format!("Unknown channel kind: {}", kind));
+    }
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_alert_channel(rule_id, &kind, &target, max_retries.unwrap_or(3))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_alert_channel_enabled(
+    state: State<'_, AppState>,
+    id: i32,
+    enabled: bool,
+) -> Result<(), String> {
+    info!("Setting alert channel {} enabled={}", id, enabled);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_alert_channel_enabled(id, enabled)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_alert_channel(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting alert channel with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_alert_channel(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_alert_deliveries(
+    state: State<'_, AppState>,
+    alert_id: i32,
+) -> Result<Vec<AlertDelivery>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_alert_deliveries(alert_id).map_err(|e| e.to_string())
+}
+
+/// Send a synthetic notification through a channel once, without retries or logging
+#[tauri::command]
+pub async fn test_alert_channel(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Testing alert channel {}", id);
+    let channel = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_alert_channel(id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Alert channel {} not found", id))?
+    };
+
+    let notification = crate::alert::AlertNotification {
+        alert_id: 0,
+        rule_id: channel.rule_id,
+        rule_name: "ssedge test notification".to_string(),
+        device_id: 0,
+        device_name: "test".to_string(),
+        metric: "test".to_string(),
+        state: "firing".to_string(),
+        value: 0.0,
+        threshold: 0.0,
+        timestamp: crate::db::unix_timestamp(),
+    };
+
+    crate::notify::deliver(&channel, &notification)
+        .await
+        .map_err(|e| {
+            error!("Test delivery via channel {} failed: {}", id, e);
+            e
+        })
+}
+
+#[tauri::command]
+pub async fn start_metrics_exporter(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    address: String,
+) -> Result<(), String> {
+    info!("Starting metrics exporter on {}", address);
+    crate::exporter::start(&app, &address).await?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_setting(crate::exporter::EXPORTER_ADDRESS_SETTING, &address)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn stop_metrics_exporter(
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    info!("Stopping metrics exporter");
+    crate::exporter::stop(&app);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_setting(crate::exporter::EXPORTER_ADDRESS_SETTING)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_metrics_exporter_status(
+    exporter: State<'_, crate::exporter::ExporterState>,
+) -> Result<crate::exporter::ExporterStatus, String> {
+    exporter.status()
+}
+
+#[tauri::command]
+pub async fn list_processes(
+    state: State<'_, AppState>,
+    device_id: i32,
+    filter: Option<String>,
+    sort_by: Option<String>,
+    descending: Option<bool>,
+    limit: Option<usize>,
+) -> Result<Vec<crate::process::ProcessInfo>, String> {
+    info!("Listing processes on device {}", device_id);
+    let (_, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let output = crate::ssh::exec(&session, &crate::process::list_script()).await?;
+    if !output.success() {
+        error!(
+            "Process listing failed on device {}: {}",
+            device_id, output.stderr
+        );
+        return Err(format!("Process listing failed: {}", output.stderr.trim()));
+    }
+
+    let processes = crate::process::parse_processes(&output.stdout).map_err(|e| {
+        error!(
+            "Failed to parse process list from device {}: {}",
+            device_id, e
+        );
+        format!("Failed to parse process list: {}", e)
+    })?;
+    let mut processes = crate::process::filter_and_sort(
+        processes,
+        filter.as_deref(),
+        sort_by.as_deref(),
+        descending.unwrap_or(false),
+    )?;
+    if let Some(limit) = limit {
+        processes.truncate(limit);
+    }
+    Ok(processes)
+}
+
+/// Send a signal to a remote process; `confirm` must be set by the caller
+/// after the user has approved the action
+#[tauri::command]
+pub async fn signal_process(
+    state: State<'_, AppState>,
+    device_id: i32,
+    pid: u32,
+    signal: String,
+    confirm: bool,
+) -> Result<String, String> {
+    crate::process::validate_pid(pid)?;
+    let signal = crate::process::normalize_signal(&signal)?;
+    if !confirm {
+        return Err(format!(
+            "Sending SIG{} to pid {} requires confirmation",
+            signal, pid
+        ));
+    }
+
+    info!(
+        "Sending SIG{} to pid {} on device {}",
+        signal, pid, device_id
+    );
+    let (_, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let command = crate::process::kill_command(pid, &signal);
+    let output = crate::ssh::exec(&session, &command).await?;
+    crate::ssh::log_command(&state, device_id, &command, Some(&output.log_text()));
+
+    if output.success() {
+        info!("Sent SIG{} to pid {} on device {}", signal, pid, device_id);
+        Ok(format!("Sent SIG{} to pid {}", signal, pid))
+    } else {
+        error!(
+            "Failed to signal pid {} on device {}: {}",
+            pid, device_id, output.stderr
+        );
+        Err(format!(
+            "Failed to signal process: {}",
+            output.stderr.trim()
+        ))
+    }
+}
+
+/// List systemd service units with their active/sub and unit file state
+#[tauri::command]
+pub async fn list_services(
+    state: State<'_, AppState>,
+    device_id: i32,
+) -> Result<Vec<crate::systemd::UnitInfo>, String> {
+    info!("Listing systemd services on device {}", device_id);
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    let output = crate::ssh::exec_as(&session, &privilege, &crate::systemd::list_script()).await?;
+    if !output.success() {
+        error!(
+            "Service listing failed on device {}: {}",
+            device_id, output.stderr
+        );
+        return Err(format!("Service listing failed: {}", output.stderr.trim()));
+    }
+    Ok(crate::systemd::parse_unit_list(&output.stdout))
+}
+
+/// Status of a systemd unit with its most recent journal lines
+#[tauri::command]
+pub async fn get_service_status(
+    state: State<'_, AppState>,
+    device_id: i32,
+    unit: String,
+    lines: Option<u32>,
+) -> Result<crate::systemd::UnitStatus, String> {
+    crate::systemd::validate_unit_name(&unit)?;
+    info!("Getting status of {} on device {}", unit, device_id);
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    let script = crate::systemd::status_script(&unit, lines.unwrap_or(50));
+    let output = crate::ssh::exec_as(&session, &privilege, &script).await?;
+    crate::systemd::parse_status(&unit, &output.stdout).map_err(|e| {
+        error!(
+            "Failed to read status of {} on device {}: {}",
+            unit, device_id, e
+        );
+        e
+    })
+}
+
+/// Start, stop, restart, enable or disable a systemd unit
+#[tauri::command]
+pub async fn control_service(
+    state: State<'_, AppState>,
+    device_id: i32,
+    unit: String,
+    action: String,
+) -> Result<crate::systemd::UnitActionResult, String> {
+    crate::systemd::validate_unit_name(&unit)?;
+    let action = crate::systemd::UnitAction::parse(&action)
+        .ok_or_else(|| format!("Unknown service action: {}", action))?;
+
+    info!(
+        "Running {} on {} on device {}",
+        action.as_str(),
+        unit,
+        device_id
+    );
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    let command = crate::systemd::action_command(&unit, action);
+    let output = crate::ssh::exec_as(&session, &privilege, &command).await?;
+    crate::ssh::log_command(&state, device_id, &command, Some(&output.log_text()));
+
+    let status_script = crate::systemd::status_script(&unit, 0);
+    let status = match crate::ssh::exec_as(&session, &privilege, &status_script).await {
+        Ok(status) => crate::systemd::parse_status(&unit, &status.stdout).ok(),
+        Err(e) => {
+            error!("Failed to read status of {} after action: {}", unit, e);
+            None
+        }
+    };
+
+    let result = crate::systemd::action_result(&unit, action, &output, status.as_ref());
+    if result.success {
+        info!("{}", result.message);
+    } else {
+        error!(
+            "{} {} failed on device {}: {}",
+            action.as_str(),
+            unit,
+            device_id,
+            result.message
+        );
+    }
+    Ok(result)
+}
+
+/// Start streaming a journal (`source = "journal"`) or file (`source = "file"`)
+/// tail as `log-tail-line` events; returns the tail id
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+pub async fn start_log_tail(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    device_id: i32,
+    source: String,
+    unit: Option<String>,
+    priority: Option<String>,
+    path: Option<String>,
+    backlog: Option<u32>,
+    filter: Option<String>,
+) -> Result<u32, String> {
+    let source = crate::logtail::LogSource::new(&source, unit, priority, path)?;
+    info!(
+        "Starting log tail of {} on device {}",
+        source.describe(),
+        device_id
+    );
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    crate::logtail::start(
+        &app,
+        device_id,
+        session,
+        privilege,
+        source,
+        backlog.unwrap_or(100),
+        filter,
+    )
+}
+
+#[tauri::command]
+pub fn stop_log_tail(
+    tails: State<'_, crate::logtail::LogTailState>,
+    tail_id: u32,
+) -> Result<(), String> {
+    tails.stop(tail_id)
+}
+
+#[tauri::command]
+pub fn pause_log_tail(
+    tails: State<'_, crate::logtail::LogTailState>,
+    tail_id: u32,
+) -> Result<(), String> {
+    tails.set_paused(tail_id, true)
+}
+
+#[tauri::command]
+pub fn resume_log_tail(
+    tails: State<'_, crate::logtail::LogTailState>,
+    tail_id: u32,
+) -> Result<(), String> {
+    tails.set_paused(tail_id, false)
+}
+
+/// Only lines containing `filter` (case-insensitive) are emitted and buffered
+#[tauri::command]
+pub fn set_log_tail_filter(
+    tails: State<'_, crate::logtail::LogTailState>,
+    tail_id: u32,
+    filter: Option<String>,
+) -> Result<(), String> {
+    tails.set_filter(tail_id, filter)
+}
+
+#[tauri::command]
+pub fn list_log_tails(
+    tails: State<'_, crate::logtail::LogTailState>,
+) -> Result<Vec<crate::logtail::LogTailInfo>, String> {
+    tails.list()
+}
+
+/// Store the last `lines` buffered lines of a tail (all when unset) in the DB
+#[tauri::command]
+pub fn snapshot_log_tail(
+    state: State<'_, AppState>,
+    tails: State<'_, crate::logtail::LogTailState>,
+    tail_id: u32,
+    lines: Option<usize>,
+    note: Option<String>,
+) -> Result<i64, String> {
+    let snapshot = tails.snapshot(tail_id, lines)?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let id = db
+        .insert_log_snapshot(
+            snapshot.device_id,
+            &snapshot.source,
+            snapshot.filter.as_deref(),
+            note.as_deref(),
+            &snapshot.lines,
+            crate::db::unix_timestamp(),
+        )
+        .map_err(|e| e.to_string())?;
+    info!(
+        "Saved {} lines of log tail {} as snapshot {}",
+        snapshot.lines.len(),
+        tail_id,
+        id
+    );
+    Ok(id)
+}
+
+#[tauri::command]
+pub fn get_log_snapshots(
+    state: State<'_, AppState>,
+    device_id: Option<i32>,
+) -> Result<Vec<crate::db::LogSnapshot>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_log_snapshots(device_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn delete_log_snapshot(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_log_snapshot(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_directory(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+) -> Result<Vec<crate::sftp::FileEntry>, crate::sftp::FileError> {
+    info!("Listing {} on device {}", path, device_id);
+    let fs = crate::sftp::DeviceFs::open(&state, device_id).await?;
+    let result = fs.list_dir(&path).await;
+    fs.close().await;
+    result
+}
+
+#[tauri::command]
+pub async fn stat_path(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+) -> Result<crate::sftp::FileEntry, crate::sftp::FileError> {
+    let fs = crate::sftp::DeviceFs::open(&state, device_id).await?;
+    let result = fs.stat(&path).await;
+    fs.close().await;
+    result
+}
+
+#[tauri::command]
+pub async fn make_directory(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+) -> Result<(), crate::sftp::FileError> {
+    info!("Creating directory {} on device {}", path, device_id);
+    let fs = crate::sftp::DeviceFs::open(&state, device_id).await?;
+    let result = fs.mkdir(&path).await;
+    fs.close().await;
+    result
+}
+
+#[tauri::command]
+pub async fn rename_path(
+    state: State<'_, AppState>,
+    device_id: i32,
+    from: String,
+    to: String,
+) -> Result<(), crate::sftp::FileError> {
+    info!("Renaming {} to {} on device {}", from, to, device_id);
+    let fs = crate::sftp::DeviceFs::open(&state, device_id).await?;
+    let result = fs.rename(&from, &to).await;
+    fs.close().await;
+    result
+}
+
+/// Delete a file, symlink or empty directory
+#[tauri::command]
+pub async fn delete_path(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+) -> Result<(), crate::sftp::FileError> {
+    info!("Deleting {} on device {}", path, device_id);
+    let fs = crate::sftp::DeviceFs::open(&state, device_id).await?;
+    let result = fs.delete(&path).await;
+    fs.close().await;
+    result
+}
+
+#[tauri::command]
+pub async fn read_file(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+) -> Result<crate::sftp::FileContent, crate::sftp::FileError> {
+    let fs = crate::sftp::DeviceFs::open(&state, device_id).await?;
+    let result = fs.read_file(&path).await;
+    fs.close().await;
+    result
+}
+
+#[tauri::command]
+pub fn queue_upload(
+    app: AppHandle,
+    device_id: i32,
+    local_path: String,
+    remote_path: String,
+) -> Result<i64, String> {
+    crate::transfer::enqueue(
+        &app,
+        device_id,
+        crate::transfer::Direction::Upload,
+        &local_path,
+        &remote_path,
+    )
+}
+
+#[tauri::command]
+pub fn queue_download(
+    app: AppHandle,
+    device_id: i32,
+    remote_path: String,
+    local_path: String,
+) -> Result<i64, String> {
+    crate::transfer::enqueue(
+        &app,
+        device_id,
+        crate::transfer::Direction::Download,
+        &local_path,
+        &remote_path,
+    )
+}
+
+#[tauri::command]
+pub fn get_transfers(
+    state: State<'_, AppState>,
+    device_id: Option<i32>,
+) -> Result<Vec<crate::db::Transfer>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_transfers(device_id).map_err(|e| e.to_string())
+}
+
+/// Cancel a queued or running transfer; the partial file is kept for a retry
+#[tauri::command]
+pub fn cancel_transfer(
+    state: State<'_, AppState>,
+    queue: State<'_, crate::transfer::TransferQueue>,
+    id: i32,
+) -> Result<(), String> {
+    if queue.cancel(id)? {
+        info!("Cancelling running transfer {}", id);
+        return Ok(());
+    }
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let transfer = db
+        .get_transfer(id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Transfer {} not found", id))?;
+    if transfer.state != "queued" {
+        return Err(format!("Transfer {} is already {}", id, transfer.state));
+    }
+    db.set_transfer_state(id, "cancelled", None, crate::db::unix_timestamp())
+        .map_err(|e| e.to_string())?;
+    queue.notify_finished();
+    info!("Cancelled queued transfer {}", id);
+    Ok(())
+}
+
+/// Queue a failed or cancelled transfer again; it resumes from its partial file
+#[tauri::command]
+pub fn retry_transfer(
+    state: State<'_, AppState>,
+    queue: State<'_, crate::transfer::TransferQueue>,
+    id: i32,
+) -> Result<(), String> {
+    {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        let transfer = db
+            .get_transfer(id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Transfer {} not found", id))?;
+        if transfer.state != "failed" && transfer.state != "cancelled" {
+            return Err(format!("Transfer {} is {}", id, transfer.state));
+        }
+        db.set_transfer_state(id, "queued", None, crate::db::unix_timestamp())
+            .map_err(|e| e.to_string())?;
+    }
+    info!("Requeued transfer {}", id);
+    queue.wake();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn delete_transfer(
+    state: State<'_, AppState>,
+    queue: State<'_, crate::transfer::TransferQueue>,
+    id: i32,
+) -> Result<(), String> {
+    if queue.is_running(id)? {
+        return Err(format!("Transfer {} is running; cancel it first", id));
+    }
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_transfer(id).map_err(|e| e.to_string())?;
+    queue.notify_finished();
+    Ok(())
+}
+
+/// Push a local file or directory to the selected devices; the selection is
+/// the union of `device_ids` and devices carrying any of `tags`
+#[tauri::command]
+pub async fn push_files(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    spec: crate::fleet::PushSpec,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+    concurrency: Option<usize>,
+) -> Result<Vec<crate::fleet::PushResult>, String> {
+    let devices = crate::fleet::select_devices(
+        &state,
+        &device_ids.unwrap_or_default(),
+        &tags.unwrap_or_default(),
+    )?;
+    crate::fleet::push(&app, devices, spec, crate::fleet::concurrency(concurrency)).await
+}
+
+#[tauri::command]
+pub async fn fetch_config_file(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+) -> Result<crate::config_file::ConfigFile, String> {
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    crate::config_file::fetch(&session, &privilege, &path).await
+}
+
+/// Unified diff of an edit, computed locally before anything is written
+#[tauri::command]
+pub fn preview_config_diff(path: String, original: String, updated: String) -> String {
+    crate::config_file::unified_diff(&path, &original, &updated)
+}
+
+/// Write a config file with backup, optionally validating it with
+/// `check_command` and reverting when the check fails
+#[tauri::command]
+pub async fn write_config_file(
+    state: State<'_, AppState>,
+    device_id: i32,
+    path: String,
+    content: String,
+    expected_sha256: Option<String>,
+    check_command: Option<String>,
+) -> Result<crate::config_file::ConfigWriteResult, String> {
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    crate::config_file::write(
+        &state,
+        device_id,
+        &session,
+        &privilege,
+        &path,
+        &content,
+        expected_sha256.as_deref(),
+        check_command.as_deref(),
+    )
+    .await
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_drift_check(
+    state: State<'_, AppState>,
+    name: String,
+    kind: String,
+    target: String,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+    baseline_device_id: Option<i32>,
+    interval_secs: i64,
+) -> Result<i64, String> {
+    info!(
+        "Adding drift check: name={}, {} {}, every {}s",
+        name, kind, target, interval_secs
+    );
+    let source = crate::drift::DriftSource::new(&kind, &target)?;
+    let device_ids = device_ids.unwrap_or_default();
+    let tags = tags.unwrap_or_default();
+    if device_ids.is_empty() && tags.is_empty() {
+        return Err("A drift check needs devices or tags to compare".to_string());
+    }
+    if interval_secs < crate::drift::MIN_INTERVAL_SECS {
+        return Err(format!(
+            "Interval must be at least {} seconds",
+            crate::drift::MIN_INTERVAL_SECS
+        ));
+    }
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_drift_check(
+        &name,
+        source.kind(),
+        source.target(),
+        &device_ids,
+        &tags,
+        baseline_device_id,
+        interval_secs,
+        crate::db::unix_timestamp(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_drift_checks(state: State<'_, AppState>) -> Result<Vec<DriftCheck>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_drift_checks().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_drift_check_enabled(
+    state: State<'_, AppState>,
+    id: i32,
+    enabled: bool,
+) -> Result<(), String> {
+    info!("Setting drift check {} enabled={}", id, enabled);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_drift_check_enabled(id, enabled)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_drift_check(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting drift check with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_drift_check(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn load_drift_check(state: &AppState, id: i32) -> Result<DriftCheck, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_drift_check(id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Drift check {} not found", id))
+}
+
+/// Run a drift check now instead of waiting for its interval
+#[tauri::command]
+pub async fn run_drift_check(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: i32,
+) -> Result<crate::drift::DriftReport, String> {
+    let check = load_drift_check(&state, id)?;
+    crate::drift::run_check(&app, &check).await
+}
+
+/// Drift report from the latest stored snapshots, without contacting devices
+#[tauri::command]
+pub fn get_drift_report(
+    state: State<'_, AppState>,
+    id: i32,
+) -> Result<crate::drift::DriftReport, String> {
+    let check = load_drift_check(&state, id)?;
+    let selected: Vec<i32> = crate::drift::selected_devices(&state, &check)?
+        .iter()
+        .map(|d| d.id)
+        .collect();
+    crate::drift::report(&state, &check, &selected)
+}
+
+#[tauri::command]
+pub fn get_drift_snapshots(
+    state: State<'_, AppState>,
+    check_id: i32,
+    device_id: i32,
+) -> Result<Vec<DriftSnapshot>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_drift_snapshots(check_id, device_id)
+        .map_err(|e| e.to_string())
+}
+
+/// Latest facts of every device, narrowed by `filter`
+#[tauri::command]
+pub fn get_device_facts(
+    state: State<'_, AppState>,
+    filter: Option<crate::facts::FactsFilter>,
+) -> Result<Vec<DeviceFactsRecord>, String> {
+    let filter = filter.unwrap_or_default();
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let records = db.get_all_device_facts().map_err(|e| e.to_string())?;
+    Ok(records
+        .into_iter()
+        .filter(|r| filter.matches(&r.facts))
+        .collect())
+}
+
+#[tauri::command]
+pub fn get_device_facts_history(
+    state: State<'_, AppState>,
+    device_id: i32,
+) -> Result<Vec<crate::facts::FactsChange>, String> {
+    crate::facts::history(&state, device_id)
+}
+
+/// Gather a device's facts now instead of waiting for the next refresh
+#[tauri::command]
+pub async fn refresh_device_facts(
+    state: State<'_, AppState>,
+    device_id: i32,
+) -> Result<crate::db::DeviceFacts, String> {
+    crate::facts::refresh(&state, device_id).await
+}
+
+/// Upgradable packages of a device; also updates the cached pending counts
+#[tauri::command]
+pub async fn list_upgradable_packages(
+    state: State<'_, AppState>,
+    device_id: i32,
+    refresh: Option<bool>,
+) -> Result<crate::packages::UpgradableReport, String> {
+    crate::packages::list_upgradable(&state, device_id, refresh.unwrap_or(false)).await
+}
+
+/// `action` is `install` or `remove`
+#[tauri::command]
+pub async fn manage_package(
+    state: State<'_, AppState>,
+    device_id: i32,
+    action: String,
+    package: String,
+) -> Result<crate::packages::PackageActionResult, String> {
+    let action = crate::packages::PackageAction::parse(&action)
+        .ok_or_else(|| format!("Unknown package action: {}", action))?;
+    info!(
+        "Package {} {} on device {}",
+        action.as_str(),
+        package,
+        device_id
+    );
+    crate::packages::run_action(&state, device_id, action, &package).await
+}
+
+/// Full upgrade; output lines are emitted as `package-upgrade-output` events
+#[tauri::command]
+pub async fn upgrade_packages(
+    app: AppHandle,
+    device_id: i32,
+) -> Result<crate::packages::PackageActionResult, String> {
+    crate::packages::upgrade(&app, device_id).await
+}
+
+#[tauri::command]
+pub fn get_package_update_counts(
+    state: State<'_, AppState>,
+) -> Result<Vec<PackageUpdateCount>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_package_update_counts().map_err(|e| e.to_string())
+}
+
+/// Refresh pending-update counts on the selected devices
+#[tauri::command]
+pub async fn check_fleet_updates(
+    state: State<'_, AppState>,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+) -> Result<Vec<PackageUpdateCount>, String> {
+    let devices = crate::fleet::select_devices(
+        &state,
+        &device_ids.unwrap_or_default(),
+        &tags.unwrap_or_default(),
+    )?;
+    Ok(crate::packages::check_fleet(&state, devices).await)
+}
+
+/// Reboot a device and wait until it is back and healthy; progress is
+/// emitted as `reboot-progress` events
+#[tauri::command]
+pub async fn reboot_device(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    device_id: i32,
+    options: Option<crate::reboot::RebootOptions>,
+) -> Result<crate::reboot::RebootResult, String> {
+    let device = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_device(device_id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Device {} not found", device_id))?
+    };
+    Ok(crate::reboot::reboot(&app, &device, &options.unwrap_or_default()).await)
+}
+
+/// Reboot the selected devices with at most `max_unavailable` down at once
+#[tauri::command]
+pub async fn rolling_reboot(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+    max_unavailable: Option<usize>,
+    options: Option<crate::reboot::RebootOptions>,
+) -> Result<Vec<crate::reboot::RebootResult>, String> {
+    let devices = crate::fleet::select_devices(
+        &state,
+        &device_ids.unwrap_or_default(),
+        &tags.unwrap_or_default(),
+    )?;
+    Ok(crate::reboot::rolling_reboot(
+        &app,
+        devices,
+        max_unavailable.unwrap_or(1),
+        &options.unwrap_or_default(),
+    )
+    .await)
+}
+
+#[tauri::command]
+pub fn get_scripts(state: State<'_, AppState>) -> Result<Vec<Script>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_scripts().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn create_script(
+    state: State<'_, AppState>,
+    name: String,
+    description: Option<String>,
+    body: String,
+    interpreter: String,
+    parameters: Vec<crate::db::ScriptParam>,
+) -> Result<i64, String> {
+    info!("Adding script: name={}, interpreter={}", name, interpreter);
+    crate::scripts::validate(&name, &interpreter, &parameters)?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_script(
+        name.trim(),
+        description.as_deref(),
+        &body,
+        &interpreter,
+        &parameters,
+        crate::db::unix_timestamp(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Returns the new version number
+#[tauri::command]
+pub fn update_script(
+    state: State<'_, AppState>,
+    id: i32,
+    name: String,
+    description: Option<String>,
+    body: String,
+    interpreter: String,
+    parameters: Vec<crate::db::ScriptParam>,
+) -> Result<i64, String> {
+    info!("Updating script {}", id);
+    crate::scripts::validate(&name, &interpreter, &parameters)?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.update_script(
+        id,
+        name.trim(),
+        description.as_deref(),
+        &body,
+        &interpreter,
+        &parameters,
+        crate::db::unix_timestamp(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn delete_script(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting script with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_script(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_script_versions(
+    state: State<'_, AppState>,
+    script_id: i32,
+) -> Result<Vec<ScriptVersion>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_script_versions(script_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_script_runs(
+    state: State<'_, AppState>,
+    script_id: i32,
+) -> Result<Vec<CommandLog>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_script_runs(script_id).map_err(|e| e.to_string())
+}
+
+/// Run the current version of a script; `values` are keyed by parameter name
+#[tauri::command]
+pub async fn run_script(
+    state: State<'_, AppState>,
+    script_id: i32,
+    device_id: i32,
+    values: Option<HashMap<String, String>>,
+    as_root: Option<bool>,
+) -> Result<crate::scripts::ScriptRunResult, String> {
+    let script = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_script(script_id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Script {} not found", script_id))?
+    };
+    crate::scripts::run(
+        &state,
+        &script,
+        device_id,
+        &values.unwrap_or_default(),
+        as_root.unwrap_or(false),
+    )
+    .await
+}
+
+#[tauri::command]
+pub fn get_runbooks(state: State<'_, AppState>) -> Result<Vec<Runbook>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_runbooks().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn create_runbook(
+    state: State<'_, AppState>,
+    name: String,
+    description: Option<String>,
+    steps: Vec<crate::db::RunbookStep>,
+) -> Result<i64, String> {
+    info!("Adding runbook: name={}, steps={}", name, steps.len());
+    crate::runbook::validate(&name, &steps)?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_runbook(
+        name.trim(),
+        description.as_deref(),
+        &steps,
+        crate::db::unix_timestamp(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn update_runbook(
+    state: State<'_, AppState>,
+    id: i32,
+    name: String,
+    description: Option<String>,
+    steps: Vec<crate::db::RunbookStep>,
+) -> Result<(), String> {
+    info!("Updating runbook {}", id);
+    crate::runbook::validate(&name, &steps)?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.update_runbook(
+        id,
+        name.trim(),
+        description.as_deref(),
+        &steps,
+        crate::db::unix_timestamp(),
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn delete_runbook(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting runbook with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_runbook(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Start a runbook on the given devices and every device carrying one of
+/// `tags`; returns one run id per device
+#[tauri::command]
+pub fn start_runbook(
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+    runbook_id: i32,
+    device_ids: Vec<i32>,
+    tags: Option<Vec<String>>,
+    variables: Option<std::collections::BTreeMap<String, String>>,
+    concurrency: Option<usize>,
+) -> Result<Vec<i32>, String> {
+    let runbook = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_runbook(runbook_id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Runbook {} not found", runbook_id))?
+    };
+    let devices = crate::fleet::select_devices(&state, &device_ids, &tags.unwrap_or_default())?;
+    crate::runbook::start(
+        &app,
+        &runbook,
+        devices,
+        variables.unwrap_or_default(),
+        crate::fleet::concurrency(concurrency),
+    )
+}
+
+#[tauri::command]
+pub fn get_runbook_runs(
+    state: State<'_, AppState>,
+    runbook_id: i32,
+) -> Result<Vec<RunbookRun>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_runbook_runs(runbook_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn get_runbook_run_steps(
+    state: State<'_, AppState>,
+    run_id: i32,
+) -> Result<Vec<RunbookStepResult>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_runbook_step_results(run_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn approve_runbook_step(
+    app: tauri::AppHandle,
+    run_id: i32,
+    approved: bool,
+) -> Result<RunbookRun, String> {
+    crate::runbook::approve(&app, run_id, approved).await
+}
+
+#[tauri::command]
+pub fn resume_runbook_run(app: tauri::AppHandle, run_id: i32) -> Result<RunbookRun, String> {
+    crate::runbook::resume(&app, run_id)
+}
+
+#[tauri::command]
+pub fn get_jobs(state: State<'_, AppState>) -> Result<Vec<Job>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_jobs().map_err(|e| e.to_string())
+}
+
+/// `schedule` is a five-field cron expression evaluated in UTC;
+/// `missed_policy` is `skip` (the default) or `catch_up`
+#[tauri::command]
+pub fn create_job(
+    state: State<'_, AppState>,
+    name: String,
+    schedule: String,
+    action: crate::db::JobAction,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+    missed_policy: Option<String>,
+) -> Result<i64, String> {
+    info!("Adding job: name={}, schedule={}", name, schedule);
+    let (device_ids, tags, policy) = validate_job(&name, &action, device_ids, tags, missed_policy)?;
+    let now = crate::db::unix_timestamp();
+    let next_run = crate::jobs::next_run(&schedule, now)?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.insert_job(
+        name.trim(),
+        schedule.trim(),
+        &action,
+        &device_ids,
+        &tags,
+        policy.as_str(),
+        Some(next_run),
+        now,
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub fn update_job(
+    state: State<'_, AppState>,
+    id: i32,
+    name: String,
+    schedule: String,
+    action: crate::db::JobAction,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+    missed_policy: Option<String>,
+) -> Result<(), String> {
+    info!("Updating job {}", id);
+    let (device_ids, tags, policy) = validate_job(&name, &action, device_ids, tags, missed_policy)?;
+    let next_run = crate::jobs::next_run(&schedule, crate::db::unix_timestamp())?;
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let enabled = db
+        .get_job(id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Job {} not found", id))?
+        .enabled;
+    db.update_job(
+        id,
+        name.trim(),
+        schedule.trim(),
+        &action,
+        &device_ids,
+        &tags,
+        policy.as_str(),
+        enabled.then_some(next_run),
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn validate_job(
+    name: &str,
+    action: &crate::db::JobAction,
+    device_ids: Option<Vec<i32>>,
+    tags: Option<Vec<String>>,
+    missed_policy: Option<String>,
+) -> Result<(Vec<i32>, Vec<String>, crate::jobs::MissedPolicy), String> {
+    if name.trim().is_empty() {
+        return Err("A job needs a name".to_string());
+    }
+    action.validate()?;
+    let device_ids = device_ids.unwrap_or_default();
+    let tags = tags.unwrap_or_default();
+    if device_ids.is_empty() && tags.is_empty() {
+        return Err("A job needs devices or tags to run on".to_string());
+    }
+    let policy = missed_policy.as_deref().unwrap_or("skip");
+    let policy = crate::jobs::MissedPolicy::parse(policy)
+        .ok_or_else(|| format!("Unknown missed-run policy: {}", policy))?;
+    Ok((device_ids, tags, policy))
+}
+
+/// Enabling schedules the next run from now, so runs missed while the job
+/// was disabled are never caught up
+#[tauri::command]
+pub fn set_job_enabled(state: State<'_, AppState>, id: i32, enabled: bool) -> Result<(), String> {
+    info!("Setting job {} enabled={}", id, enabled);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let job = db
+        .get_job(id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Job {} not found", id))?;
+    let next_run = if enabled {
+        Some(crate::jobs::next_run(
+            &job.schedule,
+            crate::db::unix_timestamp(),
+        )?)
+    } else {
+        None
+    };
+    db.set_job_enabled(id, enabled, next_run)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn delete_job(state: State<'_, AppState>, id: i32) -> Result<(), String> {
+    info!("Deleting job with id: {}", id);
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.delete_job(id).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Run a job immediately, outside its schedule
+#[tauri::command]
+pub async fn run_job_now(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: i32,
+) -> Result<JobRun, String> {
+    let job = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_job(id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Job {} not found", id))?
+    };
+    crate::jobs::run(&app, &job, crate::db::unix_timestamp(), "manual").await
+}
+
+#[tauri::command]
+pub fn get_job_runs(
+    state: State<'_, AppState>,
+    job_id: i32,
+    limit: Option<i64>,
+) -> Result<Vec<JobRun>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.get_job_runs(job_id, limit.unwrap_or(50))
+        .map_err(|e| e.to_string())
 }
@@ -0,0 +1,433 @@
+//! Read-modify-write editing of configuration files on a device.
+
+use crate::collector::split_sections;
+use crate::command::AppState;
+use crate::db::unix_timestamp;
+use crate::sftp::{sanitize_path, MAX_READ_BYTES};
+use crate::ssh::{exec_as, exec_with_input_as, log_command, privileged, shell_quote, Privilege};
+use log::{error, info};
+use openssh::Session;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Lines of context around each change in a diff
+const DIFF_CONTEXT: usize = 3;
+
+/// A remote file as fetched for editing
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigFile {
+    pub path: String,
+    pub content: String,
+    /// Hash of `content`; pass it back when writing to detect concurrent edits
+    pub sha256: String,
+    /// Octal permission bits, e.g. `644`
+    pub mode: String,
+    pub owner: String,
+    pub group: String,
+    pub size: u64,
+}
+
+/// Outcome of writing a file
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigWriteResult {
+    pub path: String,
+    /// False when the new contents equal the current ones and nothing was written
+    pub changed: bool,
+    pub diff: String,
+    pub backup_path: Option<String>,
+    /// Result of the check command, when one was given and the file changed
+    pub validated: Option<bool>,
+    pub check_output: Option<String>,
+    /// Set when the check failed and the backup was put back
+    pub reverted: bool,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Unified diff between two versions of `path`, empty when they are equal
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(DIFF_CONTEXT)
+        .header(&format!("a{}", path), &format!("b{}", path))
+        .to_string()
+}
+
+/// Where the backup of `path` taken at `timestamp` lives
+pub fn backup_path(path: &str, timestamp: i64) -> String {
+    format!("{}.ssedge-bak-{}", path, timestamp)
+}
+
+/// Script printing `mode owner group size` and the hash of a file
+fn meta_script(path: &str) -> String {
+    format!(
+        r#"f={path}
+[ -f "$f" ] || {{ echo "$f: not a regular file" >&2; exit 2; }}
+echo "==stat=="; stat -c '%a %U %G %s' -- "$f"
+echo "==sha256=="; sha256sum < "$f"
+"#,
+        path = shell_quote(path)
+    )
+}
+
+/// Script replacing a file with stdin after backing it up
+///
+/// The new contents go to a temporary file in the same directory, get the
+/// mode and owner of the current file, and are renamed over it, so readers
+/// never see a partial file. The write is refused if the file no longer
+/// hashes to `expected_sha256`.
+fn write_script(path: &str, backup: &str, expected_sha256: &str) -> String {
+    format!(
+        r#"set -e
+f={path} b={backup} t={path}.ssedge-new
+set -- $(sha256sum < "$f")
+[ "$1" = {expected} ] || {{ echo "$f changed on the device since it was read" >&2; exit 4; }}
+umask 077
+trap 'rm -f -- "$t"' EXIT
+cp -p -- "$f" "$b"
+cat > "$t"
+chmod "$(stat -c %a -- "$f")" "$t"
+chown "$(stat -c %u:%g -- "$f")" "$t"
+mv -f -- "$t" "$f"
+"#,
+        path = shell_quote(path),
+        backup = shell_quote(backup),
+        expected = shell_quote(expected_sha256)
+    )
+}
+
+/// Script putting a backup back in place, keeping the backup itself
+fn revert_script(path: &str, backup: &str) -> String {
+    format!(
+        r#"set -e
+f={path} b={backup} t={path}.ssedge-new
+cp -p -- "$b" "$t"
+mv -f -- "$t" "$f"
+"#,
+        path = shell_quote(path),
+        backup = shell_quote(backup)
+    )
+}
+
+fn as_root(script: &str) -> String {
+    privileged(&format!("sh -c {}", shell_quote(script)))
+}
+
+/// Fetch a text file, reading it as root so files like `/etc/sudoers` work
+pub async fn fetch(
+    session: &Session,
+    privilege: &Privilege,
+    path: &str,
+) -> Result<ConfigFile, String> {
+    let path = sanitize_path(path).map_err(|e| e.message)?;
+    let output = exec_as(session, privilege, &as_root(&meta_script(&path))).await?;
+    if !output.success() {
+        return Err(format!("Failed to stat {}: {}", path, output.stderr.trim()));
+    }
+    let sections = split_sections(&output.stdout);
+    let stat: Vec<&str> = sections
+        .get("stat")
+        .copied()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect();
+    let sha256 = sections
+        .get("sha256")
+        .and_then(|s| s.split_whitespace().next())
+        .ok_or_else(|| format!("Failed to hash {}", path))?
+        .to_string();
+    let [mode, owner, group, size] = stat[..] else {
+        return Err(format!("Unexpected stat output for {}", path));
+    };
+    let size: u64 = size
+        .parse()
+        .map_err(|_| format!("Unexpected stat output for {}", path))?;
+    if size > MAX_READ_BYTES {
+        return Err(format!(
+            "{}: {} bytes exceeds the {} byte limit",
+            path, size, MAX_READ_BYTES
+        ));
+    }
+
+    let output = exec_as(
+        session,
+        privilege,
+        &as_root(&format!("cat -- {}", shell_quote(&path))),
+    )
+    .await?;
+    if !output.success() {
+        return Err(format!("Failed to read {}: {}", path, output.stderr.trim()));
+    }
+    // Invalid UTF-8 is replaced while decoding, so it shows up as a hash mismatch
+    if sha256_hex(output.stdout.as_bytes()) != sha256 {
+        return Err(format!(
+            "{} is not a UTF-8 text file or changed while being read",
+            path
+        ));
+    }
+
+    Ok(ConfigFile {
+        path,
+        content: output.stdout,
+        sha256,
+        mode: mode.to_string(),
+        owner: owner.to_string(),
+        group: group.to_string(),
+        size,
+    })
+}
+
+/// Replace the contents of `path`, backing it up first
+///
+/// `expected_sha256` is the hash returned by [`fetch`]; the write is refused
+/// if the file has changed since. When `check_command` is given it runs as
+/// root after the write, and a non-zero exit restores the backup.
+#[allow(clippy::too_many_arguments)]
+pub async fn write(
+    state: &AppState,
+    device_id: i32,
+    session: &Session,
+    privilege: &Privilege,
+    path: &str,
+    content: &str,
+    expected_sha256: Option<&str>,
+    check_command: Option<&str>,
+) -> Result<ConfigWriteResult, String> {
+    let current = fetch(session, privilege, path).await?;
+    let path = current.path.clone();
+    if let Some(expected) = expected_sha256 {
+        if expected != current.sha256 {
+            return Err(format!("{} changed on the device since it was read", path));
+        }
+    }
+
+    let diff = unified_diff(&path, &current.content, content);
+    let mut result = ConfigWriteResult {
+        path: path.clone(),
+        changed: !diff.is_empty(),
+        diff,
+        backup_path: None,
+        validated: None,
+        check_output: None,
+        reverted: false,
+    };
+    if !result.changed {
+        info!("{} on device {} is unchanged", path, device_id);
+        return Ok(result);
+    }
+
+    let backup = backup_path(&path, unix_timestamp());
+    let output = exec_with_input_as(
+        session,
+        privilege,
+        &as_root(&write_script(&path, &backup, &current.sha256)),
+        content.as_bytes(),
+    )
+    .await?;
+    log_command(
+        state,
+        device_id,
+        &format!("write {} (backup {})", path, backup),
+        Some(&format!("{}\n{}", result.diff, output.log_text())),
+    );
+    if !output.success() {
+        return Err(format!(
+            "Failed to write {}: {}",
+            path,
+            output.stderr.trim()
+        ));
+    }
+    info!(
+        "Wrote {} on device {}, backup at {}",
+        path, device_id, backup
+    );
+    result.backup_path = Some(backup.clone());
+
+    let Some(check) = check_command.filter(|c| !c.trim().is_empty()) else {
+        return Ok(result);
+    };
+    let output = exec_as(session, privilege, &as_root(check)).await?;
+    log_command(state, device_id, check, Some(&output.log_text()));
+    result.validated = Some(output.success());
+    result.check_output = Some(output.log_text());
+    if output.success() {
+        return Ok(result);
+    }
+
+    error!(
+        "Check `{}` failed after writing {} on device {}, reverting",
+        check, path, device_id
+    );
+    let output = exec_as(session, privilege, &as_root(&revert_script(&path, &backup))).await?;
+    log_command(
+        state,
+        device_id,
+        &format!("revert {} from {}", path, backup),
+        Some(&output.log_text()),
+    );
+    if !output.success() {
+        return Err(format!(
+            "Check failed and restoring {} from {} also failed: {}",
+            path,
+            backup,
+            output.stderr.trim()
+        ));
+    }
+    result.reverted = true;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    #[cfg(unix)]
+    use std::path::PathBuf;
+
+    /// Empty scratch directory unique to this test process
+    #[cfg(unix)]
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ssedge-config-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Run a script under the local `sh` with `input` on stdin
+    #[cfg(unix)]
+    fn run_sh(script: &str, input: &str) -> std::process::Output {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    #[cfg(unix)]
+    fn write_with_mode(path: &std::path::Path, content: &str, mode: u32) {
+        std::fs::write(path, content).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn mode_of(path: &std::path::Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn unified_diff_shows_changed_lines() {
+        let diff = unified_diff("/etc/hosts", "a\nb\nc\n", "a\nB\nc\n");
+        assert!(diff.starts_with("--- a/etc/hosts\n+++ b/etc/hosts\n"));
+        assert!(diff.contains("@@ -1,3 +1,3 @@"));
+        assert!(diff.contains("\n-b\n+B\n"));
+    }
+
+    #[test]
+    fn unified_diff_is_empty_when_equal() {
+        assert_eq!(unified_diff("/etc/hosts", "a\n", "a\n"), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn meta_script_reports_mode_size_and_hash() {
+        let root = scratch("meta");
+        let file = root.join("with space.conf");
+        write_with_mode(&file, "old\n", 0o640);
+
+        let output = run_sh(&meta_script(file.to_str().unwrap()), "");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let sections = split_sections(&stdout);
+        let stat: Vec<&str> = sections["stat"].split_whitespace().collect();
+        assert_eq!(stat.len(), 4);
+        assert_eq!((stat[0], stat[3]), ("640", "4"));
+        assert!(sections["sha256"].starts_with(&sha256_hex(b"old\n")));
+
+        let output = run_sh(&meta_script(root.to_str().unwrap()), "");
+        assert_eq!(output.status.code(), Some(2));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_script_backs_up_and_keeps_mode_unless_the_file_changed() {
+        let root = scratch("write");
+        let file = root.join("app.conf");
+        let backup = root.join("app.conf.bak");
+        write_with_mode(&file, "old\n", 0o640);
+        let (path, backup_str) = (file.to_str().unwrap(), backup.to_str().unwrap());
+
+        let stale = sha256_hex(b"older\n");
+        let output = run_sh(&write_script(path, backup_str, &stale), "new\n");
+        assert_eq!(output.status.code(), Some(4));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "old\n");
+        assert!(!backup.exists());
+
+        let output = run_sh(
+            &write_script(path, backup_str, &sha256_hex(b"old\n")),
+            "new\n",
+        );
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "new\n");
+        assert_eq!(mode_of(&file), 0o640);
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "old\n");
+        assert_eq!(mode_of(&backup), 0o640);
+        assert!(!root.join("app.conf.ssedge-new").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn revert_script_restores_the_backup_and_keeps_it() {
+        let root = scratch("revert");
+        let file = root.join("app.conf");
+        let backup = root.join("app.conf.bak");
+        write_with_mode(&file, "broken\n", 0o644);
+        write_with_mode(&backup, "old\n", 0o600);
+
+        let output = run_sh(
+            &revert_script(file.to_str().unwrap(), backup.to_str().unwrap()),
+            "",
+        );
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "old\n");
+        assert_eq!(mode_of(&file), 0o600);
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "old\n");
+        assert!(!root.join("app.conf.ssedge-new").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! Five-field cron expressions (`minute hour day-of-month month day-of-week`),
+//! evaluated in UTC.
+//!
+//! Fields accept `*`, numbers, ranges `a-b`, lists `a,b` and steps `*/n` or
+//! `a-b/n`; months and weekdays also accept three-letter names. When both
+//! day fields are restricted a day matching either one matches, as in cron.
+//! `@hourly`, `@daily`, `@midnight`, `@weekly`, `@monthly`, `@yearly` and
+//! `@annually` are accepted as shorthands.
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Days searched for a match before a schedule is considered impossible,
+/// long enough to reach the next February 29th
+const SEARCH_DAYS: i64 = 366 * 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    /// Whether the day-of-month field was `*`
+    any_day: bool,
+    /// Whether the day-of-week field was `*`
+    any_weekday: bool,
+}
+
+/// Parse one field into a bit set of allowed values
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let lower = s.to_ascii_lowercase();
+        if let Some(i) = names.iter().position(|n| *n == lower) {
+            return Ok(i as u32 + min);
+        }
+        s.parse::<u32>()
+            .map_err(|_| format!("Invalid value {:?} in {:?}", s, field))
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .map_err(|_| format!("Invalid step in {:?}", field))?;
+                if step == 0 {
+                    return Err(format!("Step cannot be 0 in {:?}", field));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (value(a)?, value(b)?)
+        } else {
+            let start = value(range)?;
+            // `5/15` means every 15 starting at 5
+            (start, if step > 1 { max } else { start })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!(
+                "{:?} is outside {}-{} in {:?}",
+                range, min, max, field
+            ));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "Expected 5 fields (minute hour day month weekday), got {}",
+                fields.len()
+            ));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7, &WEEKDAYS)?;
+        // 7 is another name for Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(CronSchedule {
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])? as u32,
+            days: parse_field(day, 1, 31, &[])? as u32,
+            months: parse_field(month, 1, 12, &MONTHS)? as u16,
+            weekdays: weekdays as u8,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    fn matches_day(&self, day: u32, month: u32, weekday: u32) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let day_ok = self.days & (1 << day) != 0;
+        let weekday_ok = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day_ok || weekday_ok,
+            _ => day_ok && weekday_ok,
+        }
+    }
+
+    /// First matching minute strictly after `after` (unix seconds), if any
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let start = after.div_euclid(60) * 60 + 60;
+        let first_day = start.div_euclid(86400);
+        for day in first_day..first_day + SEARCH_DAYS {
+            let (_, month, dom) = civil_from_days(day);
+            let weekday = (day + 4).rem_euclid(7) as u32;
+            if !self.matches_day(dom, month, weekday) {
+                continue;
+            }
+            let midnight = day * 86400;
+            for hour in 0..24 {
+                if self.hours & (1 << hour) == 0 || midnight + (hour as i64 + 1) * 3600 <= start {
+                    continue;
+                }
+                for minute in 0..60 {
+                    let ts = midnight + hour as i64 * 3600 + minute * 60;
+                    if self.minutes & (1 << minute) != 0 && ts >= start {
+                        return Some(ts);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Year, month and day of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-15 10:07:30 UTC, a Friday
+    const NOW: i64 = 1_710_497_250;
+
+    fn next(expr: &str) -> i64 {
+        CronSchedule::parse(expr).unwrap().next_after(NOW).unwrap()
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(NOW / 86400), (2024, 3, 15));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn finds_next_run() {
+        assert_eq!(next("* * * * *"), 1_710_497_280); // 10:08
+        assert_eq!(next("*/15 * * * *"), 1_710_497_700); // 10:15
+        assert_eq!(next("0 9 * * *"), 1_710_579_600); // tomorrow 09:00
+        assert_eq!(next("30 2 * * mon"), 1_710_729_000); // Monday 18th 02:30
+        assert_eq!(next("@monthly"), 1_711_929_600); // 2024-04-01
+        assert_eq!(next("0 0 29 feb *"), 1_835_395_200); // 2028-02-29
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_set() {
+        // The 20th, or any Sunday: Sunday the 17th comes first
+        assert_eq!(next("0 0 20 * 0"), 1_710_633_600);
+        assert_eq!(next("0 0 20 * 7"), 1_710_633_600);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expr in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "*/0 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "* * * foo *",
+        ] {
+            assert!(CronSchedule::parse(expr).is_err(), "{}", expr);
+        }
+        assert_eq!(
+            CronSchedule::parse("0 0 31 2 *").unwrap().next_after(NOW),
+            None
+        );
+    }
+}
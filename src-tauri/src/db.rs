@@ -1,7 +1,85 @@
+use crate::ssh::{SshConfig, SystemMetrics};
 use anyhow::Result;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
+use rusqlite::{params, Connection, Row};
+use std::collections::{BTreeMap, HashMap};
+
+/// Schema changes applied on top of the base tables, in order.
+/// `PRAGMA user_version` records how many of them have been applied.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE devices ADD COLUMN tags TEXT NOT NULL DEFAULT ''",
+    "ALTER TABLE metrics ADD COLUMN iowait REAL; ALTER TABLE metrics ADD COLUMN steal REAL",
+    "ALTER TABLE devices ADD COLUMN username TEXT;
+     ALTER TABLE devices ADD COLUMN port INTEGER;
+     ALTER TABLE devices ADD COLUMN strict_host_key_checking INTEGER;
+     ALTER TABLE devices ADD COLUMN connect_timeout INTEGER",
+    "ALTER TABLE command_logs ADD COLUMN script_id INTEGER;
+     ALTER TABLE command_logs ADD COLUMN script_version INTEGER",
+    "ALTER TABLE devices ADD COLUMN escalation TEXT NOT NULL DEFAULT 'sudo'",
+    "ALTER TABLE devices ADD COLUMN host_key_fingerprint TEXT",
+    "ALTER TABLE devices ADD COLUMN notes TEXT",
+    // Backstop for crate::validation: rows written before it existed are
+    // trimmed, and later writes must be trimmed, plausible and unique by
+    // address and port. Updates of legacy duplicates that leave the address
+    // and port alone are still allowed.
+    "UPDATE devices SET name = trim(name), ip = trim(ip);
+     UPDATE devices SET name = ip WHERE name = '';
+     CREATE TRIGGER devices_validate_insert BEFORE INSERT ON devices
+     BEGIN
+         SELECT RAISE(ABORT, 'device name must be non-empty and trimmed')
+          WHERE NEW.name = '' OR NEW.name <> trim(NEW.name);
+         SELECT RAISE(ABORT, 'device address is malformed')
+          WHERE NEW.ip = '' OR NEW.ip GLOB '*[^0-9A-Za-z.:%_-]*';
+         SELECT RAISE(ABORT, 'device port must be between 1 and 65535')
+          WHERE NEW.port NOT BETWEEN 1 AND 65535;
+         SELECT RAISE(ABORT, 'a device with this address and port already exists')
+          WHERE EXISTS (SELECT 1 FROM devices WHERE lower(ip) = lower(NEW.ip)
+                        AND coalesce(port, 22) = coalesce(NEW.port, 22));
+     END;
+     CREATE TRIGGER devices_validate_update BEFORE UPDATE OF name, ip, port ON devices
+     BEGIN
+         SELECT RAISE(ABORT, 'device name must be non-empty and trimmed')
+          WHERE NEW.name = '' OR NEW.name <> trim(NEW.name);
+         SELECT RAISE(ABORT, 'device address is malformed')
+          WHERE NEW.ip IS NOT OLD.ip
+            AND (NEW.ip = '' OR NEW.ip GLOB '*[^0-9A-Za-z.:%_-]*');
+         SELECT RAISE(ABORT, 'device port must be between 1 and 65535')
+          WHERE NEW.port NOT BETWEEN 1 AND 65535;
+         SELECT RAISE(ABORT, 'a device with this address and port already exists')
+          WHERE (NEW.ip IS NOT OLD.ip OR coalesce(NEW.port, 22) <> coalesce(OLD.port, 22))
+            AND EXISTS (SELECT 1 FROM devices WHERE id <> NEW.id AND lower(ip) = lower(NEW.ip)
+                        AND coalesce(port, 22) = coalesce(NEW.port, 22));
+     END",
+];
+
+const DEVICE_COLUMNS: &str = "id, name, ip, last_seen, tags, username, port, \
+     strict_host_key_checking, connect_timeout, escalation, host_key_fingerprint, notes";
+
+const TRANSFER_COLUMNS: &str = "id, device_id, direction, local_path, remote_path, size, \
+     transferred, sha256, state, error, created_at, updated_at";
+
+const DRIFT_CHECK_COLUMNS: &str = "id, name, kind, target, device_ids, tags, baseline_device_id, \
+     interval_secs, enabled, last_run, created_at";
+
+const DRIFT_SNAPSHOT_COLUMNS: &str = "id, check_id, device_id, sha256, content, error, created_at";
+
+const SCRIPT_COLUMNS: &str =
+    "id, name, description, body, interpreter, parameters, version, created_at, updated_at";
+
+const RUNBOOK_COLUMNS: &str = "id, name, description, steps, created_at, updated_at";
+
+const RUNBOOK_RUN_COLUMNS: &str = "id, runbook_id, device_id, steps, state, current_step, \
+     variables, completed, error, created_at, updated_at";
+
+const JOB_COLUMNS: &str = "id, name, schedule, action, device_ids, tags, missed_policy, enabled, \
+     next_run, last_run, created_at";
+
+const JOB_RUN_COLUMNS: &str =
+    "id, job_id, scheduled_for, trigger, state, results, error, started_at, finished_at";
+
+const DEVICE_FACTS_COLUMNS: &str = "id, device_id, hostname, os, distro, distro_version, kernel, \
+     arch, cpu_model, cpu_count, memory_total_mb, mac_addresses, serial, collected_at, last_checked";
 
 pub struct Db {
     pool: Pool<SqliteConnectionManager>,
@@ -50,10 +128,270 @@ impl Db {
                     timestamp INTEGER,
                     FOREIGN KEY(device_id) REFERENCES devices(id)
                 );
+                CREATE TABLE IF NOT EXISTS alert_rules (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    device_id INTEGER,
+                    metric TEXT NOT NULL,
+                    comparator TEXT NOT NULL,
+                    threshold REAL NOT NULL,
+                    duration_secs INTEGER NOT NULL DEFAULT 0,
+                    enabled INTEGER NOT NULL DEFAULT 1,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS alerts (
+                    id INTEGER PRIMARY KEY,
+                    rule_id INTEGER NOT NULL,
+                    device_id INTEGER NOT NULL,
+                    state TEXT NOT NULL,
+                    value REAL,
+                    started_at INTEGER NOT NULL,
+                    fired_at INTEGER,
+                    resolved_at INTEGER,
+                    FOREIGN KEY(rule_id) REFERENCES alert_rules(id),
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS alert_silences (
+                    id INTEGER PRIMARY KEY,
+                    rule_id INTEGER,
+                    device_id INTEGER,
+                    until INTEGER NOT NULL,
+                    comment TEXT,
+                    FOREIGN KEY(rule_id) REFERENCES alert_rules(id),
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS alert_channels (
+                    id INTEGER PRIMARY KEY,
+                    rule_id INTEGER NOT NULL,
+                    kind TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    max_retries INTEGER NOT NULL DEFAULT 3,
+                    enabled INTEGER NOT NULL DEFAULT 1,
+                    FOREIGN KEY(rule_id) REFERENCES alert_rules(id)
+                );
+                CREATE TABLE IF NOT EXISTS alert_deliveries (
+                    id INTEGER PRIMARY KEY,
+                    channel_id INTEGER NOT NULL,
+                    alert_id INTEGER NOT NULL,
+                    state TEXT NOT NULL,
+                    attempt INTEGER NOT NULL,
+                    status TEXT NOT NULL,
+                    error TEXT,
+                    timestamp INTEGER NOT NULL,
+                    FOREIGN KEY(channel_id) REFERENCES alert_channels(id),
+                    FOREIGN KEY(alert_id) REFERENCES alerts(id)
+                );
                 CREATE INDEX IF NOT EXISTS idx_metrics_device_time ON metrics(device_id, timestamp);
                 CREATE INDEX IF NOT EXISTS idx_tunnels_device_status ON tunnels(device_id, status);
+                -- At most one pending/firing alert per (rule, device); this is what deduplicates alerts
+                CREATE UNIQUE INDEX IF NOT EXISTS idx_alerts_active ON alerts(rule_id, device_id) WHERE state != 'resolved';
+                CREATE TABLE IF NOT EXISTS settings (
+                    key TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS metric_cpu_cores (
+                    id INTEGER PRIMARY KEY,
+                    device_id INTEGER NOT NULL,
+                    core TEXT NOT NULL,
+                    usage REAL NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS metric_interfaces (
+                    id INTEGER PRIMARY KEY,
+                    device_id INTEGER NOT NULL,
+                    interface TEXT NOT NULL,
+                    rx_bytes INTEGER NOT NULL,
+                    tx_bytes INTEGER NOT NULL,
+                    rx_errors INTEGER NOT NULL,
+                    tx_errors INTEGER NOT NULL,
+                    rx_rate REAL NOT NULL,
+                    tx_rate REAL NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS metric_filesystems (
+                    id INTEGER PRIMARY KEY,
+                    device_id INTEGER NOT NULL,
+                    filesystem TEXT NOT NULL,
+                    mount TEXT NOT NULL,
+                    total_kb INTEGER NOT NULL,
+                    used_kb INTEGER NOT NULL,
+                    available_kb INTEGER NOT NULL,
+                    percent REAL NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_metric_cpu_cores_device_time ON metric_cpu_cores(device_id, timestamp);
+                CREATE INDEX IF NOT EXISTS idx_metric_interfaces_device_time ON metric_interfaces(device_id, timestamp);
+                CREATE INDEX IF NOT EXISTS idx_metric_filesystems_device_time ON metric_filesystems(device_id, timestamp);
+                CREATE TABLE IF NOT EXISTS log_snapshots (
+                    id INTEGER PRIMARY KEY,
+                    device_id INTEGER NOT NULL,
+                    source TEXT NOT NULL,
+                    filter TEXT,
+                    note TEXT,
+                    line_count INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS transfers (
+                    id INTEGER PRIMARY KEY,
+                    device_id INTEGER NOT NULL,
+                    direction TEXT NOT NULL,
+                    local_path TEXT NOT NULL,
+                    remote_path TEXT NOT NULL,
+                    size INTEGER,
+                    transferred INTEGER NOT NULL DEFAULT 0,
+                    sha256 TEXT,
+                    state TEXT NOT NULL,
+                    error TEXT,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_transfers_state ON transfers(state, id);
+                CREATE TABLE IF NOT EXISTS drift_checks (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    kind TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    device_ids TEXT NOT NULL DEFAULT '',
+                    tags TEXT NOT NULL DEFAULT '',
+                    baseline_device_id INTEGER,
+                    interval_secs INTEGER NOT NULL,
+                    enabled INTEGER NOT NULL DEFAULT 1,
+                    last_run INTEGER,
+                    created_at INTEGER NOT NULL,
+                    FOREIGN KEY(baseline_device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS drift_snapshots (
+                    id INTEGER PRIMARY KEY,
+                    check_id INTEGER NOT NULL,
+                    device_id INTEGER NOT NULL,
+                    sha256 TEXT,
+                    content TEXT,
+                    error TEXT,
+                    created_at INTEGER NOT NULL,
+                    FOREIGN KEY(check_id) REFERENCES drift_checks(id),
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_drift_snapshots_check_device ON drift_snapshots(check_id, device_id, id);
+                CREATE TABLE IF NOT EXISTS device_facts (
+                    id INTEGER PRIMARY KEY,
+                    device_id INTEGER NOT NULL,
+                    hostname TEXT,
+                    os TEXT,
+                    distro TEXT,
+                    distro_version TEXT,
+                    kernel TEXT,
+                    arch TEXT,
+                    cpu_model TEXT,
+                    cpu_count INTEGER,
+                    memory_total_mb INTEGER,
+                    mac_addresses TEXT NOT NULL DEFAULT '[]',
+                    serial TEXT,
+                    collected_at INTEGER NOT NULL,
+                    last_checked INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_device_facts_device ON device_facts(device_id, id);
+                CREATE TABLE IF NOT EXISTS scripts (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE,
+                    description TEXT,
+                    body TEXT NOT NULL,
+                    interpreter TEXT NOT NULL,
+                    parameters TEXT NOT NULL DEFAULT '[]',
+                    version INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS script_versions (
+                    script_id INTEGER NOT NULL,
+                    version INTEGER NOT NULL,
+                    body TEXT NOT NULL,
+                    interpreter TEXT NOT NULL,
+                    parameters TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY(script_id, version),
+                    FOREIGN KEY(script_id) REFERENCES scripts(id)
+                );
+                CREATE TABLE IF NOT EXISTS package_updates (
+                    device_id INTEGER PRIMARY KEY,
+                    manager TEXT NOT NULL,
+                    pending INTEGER NOT NULL,
+                    security INTEGER NOT NULL,
+                    checked_at INTEGER NOT NULL,
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE TABLE IF NOT EXISTS runbooks (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE,
+                    description TEXT,
+                    steps TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS runbook_runs (
+                    id INTEGER PRIMARY KEY,
+                    runbook_id INTEGER NOT NULL,
+                    device_id INTEGER NOT NULL,
+                    steps TEXT NOT NULL,
+                    state TEXT NOT NULL,
+                    current_step INTEGER NOT NULL DEFAULT 0,
+                    variables TEXT NOT NULL DEFAULT '{}',
+                    completed TEXT NOT NULL DEFAULT '[]',
+                    error TEXT,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    FOREIGN KEY(runbook_id) REFERENCES runbooks(id),
+                    FOREIGN KEY(device_id) REFERENCES devices(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_runbook_runs_runbook ON runbook_runs(runbook_id, id);
+                CREATE TABLE IF NOT EXISTS runbook_step_results (
+                    id INTEGER PRIMARY KEY,
+                    run_id INTEGER NOT NULL,
+                    step_index INTEGER NOT NULL,
+                    step_name TEXT NOT NULL,
+                    phase TEXT NOT NULL,
+                    success INTEGER NOT NULL,
+                    exit_code INTEGER,
+                    output TEXT,
+                    created_at INTEGER NOT NULL,
+                    FOREIGN KEY(run_id) REFERENCES runbook_runs(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_runbook_step_results_run ON runbook_step_results(run_id, id);
+                CREATE TABLE IF NOT EXISTS jobs (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    schedule TEXT NOT NULL,
+                    action TEXT NOT NULL,
+                    device_ids TEXT NOT NULL DEFAULT '',
+                    tags TEXT NOT NULL DEFAULT '',
+                    missed_policy TEXT NOT NULL DEFAULT 'skip',
+                    enabled INTEGER NOT NULL DEFAULT 1,
+                    next_run INTEGER,
+                    last_run INTEGER,
+                    created_at INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS job_runs (
+                    id INTEGER PRIMARY KEY,
+                    job_id INTEGER NOT NULL,
+                    scheduled_for INTEGER NOT NULL,
+                    trigger TEXT NOT NULL,
+                    state TEXT NOT NULL,
+                    results TEXT NOT NULL DEFAULT '[]',
+                    error TEXT,
+                    started_at INTEGER NOT NULL,
+                    finished_at INTEGER,
+                    FOREIGN KEY(job_id) REFERENCES jobs(id)
+                );
+                CREATE INDEX IF NOT EXISTS idx_job_runs_job ON job_runs(job_id, id);
                 ",
             )?;
+            migrate(&conn)?;
         }
 
         Ok(db)
@@ -64,25 +402,123 @@ impl Db {
         Ok(self.pool.get()?)
     }
 
-    pub fn insert_device(&self, name: &str, ip: &str, last_seen: Option<i64>) -> Result<usize> {
+    /// Returns the id of the new device
+    pub fn insert_device(&self, name: &str, ip: &str, last_seen: Option<i64>) -> Result<i64> {
         let conn = self.get_conn()?;
         conn.execute(
             "INSERT INTO devices (name, ip, last_seen) VALUES (?1, ?2, ?3)",
             params![name, ip, last_seen],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Add a device along with how to reach it; returns the id of the new device
+    pub fn insert_device_with_config(
+        &self,
+        name: &str,
+        ip: &str,
+        config: &SshConfig,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO devices (name, ip, username, port, strict_host_key_checking, connect_timeout)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name,
+                ip,
+                config.username,
+                config.port,
+                config.strict_host_key_checking,
+                config.connect_timeout
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Add several devices with their settings, tags and notes, all or none;
+    /// returns the ids of the new devices in order
+    pub fn insert_devices(&self, devices: &[NewDevice]) -> Result<Vec<i64>> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        let mut ids = Vec::with_capacity(devices.len());
+        for device in devices {
+            tx.execute(
+                "INSERT INTO devices (name, ip, username, port, strict_host_key_checking,
+                     connect_timeout, tags, notes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    device.name,
+                    device.ip,
+                    device.config.username,
+                    device.config.port,
+                    device.config.strict_host_key_checking,
+                    device.config.connect_timeout,
+                    join_tags(&device.tags),
+                    device.notes
+                ],
+            )?;
+            ids.push(tx.last_insert_rowid());
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Add a device found by a network scan along with how to reach it
+    pub fn insert_discovered_device(
+        &self,
+        name: &str,
+        ip: &str,
+        config: &SshConfig,
+        host_key_fingerprint: Option<&str>,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO devices (name, ip, username, port, host_key_fingerprint)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![name, ip, config.username, config.port, host_key_fingerprint],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Remember how to reach a device so later actions can connect by id
+    pub fn update_device_ssh_config(&self, id: i64, config: &SshConfig) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE devices SET username = ?1, port = ?2, strict_host_key_checking = ?3, connect_timeout = ?4
+             WHERE id = ?5",
+            params![
+                config.username,
+                config.port,
+                config.strict_host_key_checking,
+                config.connect_timeout,
+                id
+            ],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn set_device_notes(&self, id: i32, notes: Option<&str>) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE devices SET notes = ?1 WHERE id = ?2",
+            params![notes, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn set_device_escalation(&self, id: i32, escalation: &str) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE devices SET escalation = ?1 WHERE id = ?2",
+            params![escalation, id],
         )
         .map_err(Into::into)
     }
+
     pub fn get_all_devices(&self) -> Result<Vec<Device>> {
         let conn = self.get_conn()?;
-        let mut stmt = conn.prepare("SELECT id, name, ip, last_seen FROM devices")?;
-        let rows = stmt.query_map([], |row| {
-            Ok(Device {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                ip: row.get(2)?,
-                last_seen: row.get(3)?,
-            })
-        })?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM devices", DEVICE_COLUMNS))?;
+        let rows = stmt.query_map([], device_from_row)?;
 
         let mut devices = Vec::new();
         for device in rows {
@@ -96,17 +532,34 @@ impl Db {
             .map_err(Into::into)
     }
 
+    pub fn update_device_last_seen(&self, id: i32, last_seen: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE devices SET last_seen = ?1 WHERE id = ?2",
+            params![last_seen, id],
+        )
+        .map_err(Into::into)
+    }
+
+    /// Tags are stored comma-separated, so commas are stripped from each tag
+    pub fn set_device_tags(&self, id: i32, tags: &[String]) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE devices SET tags = ?1 WHERE id = ?2",
+            params![join_tags(tags), id],
+        )
+        .map_err(Into::into)
+    }
+
     pub fn get_device(&self, id: i32) -> Result<Option<Device>> {
         let conn = self.get_conn()?;
-        let mut stmt = conn.prepare("SELECT id, name, ip, last_seen FROM devices WHERE id = ?1")?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM devices WHERE id = ?1",
+            DEVICE_COLUMNS
+        ))?;
         let mut rows = stmt.query(params![id])?;
         if let Some(row) = rows.next()? {
-            Ok(Some(Device {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                ip: row.get(2)?,
-                last_seen: row.get(3)?,
-            }))
+            Ok(Some(device_from_row(row)?))
         } else {
             Ok(None)
         }
@@ -127,6 +580,25 @@ impl Db {
         .map_err(Into::into)
     }
 
+    pub fn get_all_tunnels(&self) -> Result<Vec<Tunnel>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare("SELECT id, device_id, status, last_checked FROM tunnels")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Tunnel {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                status: row.get(2)?,
+                last_checked: row.get(3)?,
+            })
+        })?;
+
+        let mut tunnels = Vec::new();
+        for tunnel in rows {
+            tunnels.push(tunnel?);
+        }
+        Ok(tunnels)
+    }
+
     pub fn delete_tunnel(&self, id: i32) -> Result<usize> {
         let conn = self.get_conn()?;
         conn.execute("DELETE FROM tunnels WHERE id = ?1", params![id])
@@ -174,20 +646,176 @@ impl Db {
 
     pub fn get_metric(&self, id: i32) -> Result<Option<Metric>> {
         let conn = self.get_conn()?;
-        let mut stmt =
-            conn.prepare("SELECT id, device_id, cpu, mem, timestamp FROM metrics WHERE id = ?1")?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, cpu, mem, iowait, steal, timestamp FROM metrics WHERE id = ?1",
+        )?;
         let mut rows = stmt.query(params![id])?;
         if let Some(row) = rows.next()? {
-            Ok(Some(Metric {
+            Ok(Some(metric_from_row(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Store a full sample: the summary row plus per-core, per-interface and
+    /// per-filesystem rows, all under the sample's timestamp
+    pub fn insert_metrics_sample(&self, device_id: i32, metrics: &SystemMetrics) -> Result<()> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        let ts = metrics.timestamp;
+        tx.execute(
+            "INSERT INTO metrics (device_id, cpu, mem, iowait, steal, timestamp) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                device_id,
+                metrics.cpu_usage,
+                metrics.memory_percent,
+                metrics.iowait_percent,
+                metrics.steal_percent,
+                ts
+            ],
+        )?;
+        for core in &metrics.cpu_cores {
+            tx.execute(
+                "INSERT INTO metric_cpu_cores (device_id, core, usage, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                params![device_id, core.core, core.usage_percent, ts],
+            )?;
+        }
+        for iface in &metrics.network {
+            tx.execute(
+                "INSERT INTO metric_interfaces (device_id, interface, rx_bytes, tx_bytes, rx_errors, tx_errors, rx_rate, tx_rate, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    device_id,
+                    iface.interface,
+                    iface.rx_bytes as i64,
+                    iface.tx_bytes as i64,
+                    iface.rx_errors as i64,
+                    iface.tx_errors as i64,
+                    iface.rx_bytes_per_sec,
+                    iface.tx_bytes_per_sec,
+                    ts
+                ],
+            )?;
+        }
+        for fs in &metrics.filesystems {
+            tx.execute(
+                "INSERT INTO metric_filesystems (device_id, filesystem, mount, total_kb, used_kb, available_kb, percent, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    device_id,
+                    fs.filesystem,
+                    fs.mount,
+                    fs.total_kb as i64,
+                    fs.used_kb as i64,
+                    fs.available_kb as i64,
+                    fs.percent,
+                    ts
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_metrics(&self, device_id: i32, since: i64) -> Result<Vec<Metric>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, cpu, mem, iowait, steal, timestamp FROM metrics
+             WHERE device_id = ?1 AND timestamp >= ?2 ORDER BY timestamp",
+        )?;
+        let rows = stmt.query_map(params![device_id, since], metric_from_row)?;
+
+        let mut metrics = Vec::new();
+        for metric in rows {
+            metrics.push(metric?);
+        }
+        Ok(metrics)
+    }
+
+    pub fn get_cpu_core_metrics(&self, device_id: i32, since: i64) -> Result<Vec<CpuCoreMetric>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, core, usage, timestamp FROM metric_cpu_cores
+             WHERE device_id = ?1 AND timestamp >= ?2 ORDER BY timestamp, id",
+        )?;
+        let rows = stmt.query_map(params![device_id, since], |row| {
+            Ok(CpuCoreMetric {
                 id: row.get(0)?,
                 device_id: row.get(1)?,
-                cpu: row.get(2)?,
-                mem: row.get(3)?,
+                core: row.get(2)?,
+                usage: row.get(3)?,
                 timestamp: row.get(4)?,
-            }))
-        } else {
-            Ok(None)
+            })
+        })?;
+
+        let mut metrics = Vec::new();
+        for metric in rows {
+            metrics.push(metric?);
+        }
+        Ok(metrics)
+    }
+
+    pub fn get_interface_metrics(
+        &self,
+        device_id: i32,
+        since: i64,
+    ) -> Result<Vec<InterfaceMetric>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, interface, rx_bytes, tx_bytes, rx_errors, tx_errors, rx_rate, tx_rate, timestamp
+             FROM metric_interfaces WHERE device_id = ?1 AND timestamp >= ?2 ORDER BY timestamp, id",
+        )?;
+        let rows = stmt.query_map(params![device_id, since], |row| {
+            Ok(InterfaceMetric {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                interface: row.get(2)?,
+                rx_bytes: row.get(3)?,
+                tx_bytes: row.get(4)?,
+                rx_errors: row.get(5)?,
+                tx_errors: row.get(6)?,
+                rx_rate: row.get(7)?,
+                tx_rate: row.get(8)?,
+                timestamp: row.get(9)?,
+            })
+        })?;
+
+        let mut metrics = Vec::new();
+        for metric in rows {
+            metrics.push(metric?);
+        }
+        Ok(metrics)
+    }
+
+    pub fn get_filesystem_metrics(
+        &self,
+        device_id: i32,
+        since: i64,
+    ) -> Result<Vec<FilesystemMetric>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, filesystem, mount, total_kb, used_kb, available_kb, percent, timestamp
+             FROM metric_filesystems WHERE device_id = ?1 AND timestamp >= ?2 ORDER BY timestamp, id",
+        )?;
+        let rows = stmt.query_map(params![device_id, since], |row| {
+            Ok(FilesystemMetric {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                filesystem: row.get(2)?,
+                mount: row.get(3)?,
+                total_kb: row.get(4)?,
+                used_kb: row.get(5)?,
+                available_kb: row.get(6)?,
+                percent: row.get(7)?,
+                timestamp: row.get(8)?,
+            })
+        })?;
+
+        let mut metrics = Vec::new();
+        for metric in rows {
+            metrics.push(metric?);
         }
+        Ok(metrics)
     }
 
     // Command Logs
@@ -205,6 +833,41 @@ impl Db {
         ).map_err(Into::into)
     }
 
+    /// Record a run of a library script; returns the id of the log entry
+    pub fn insert_script_command_log(
+        &self,
+        device_id: i32,
+        command: &str,
+        output: Option<&str>,
+        script_id: i32,
+        script_version: i64,
+        timestamp: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO command_logs (device_id, command, output, script_id, script_version, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![device_id, command, output, script_id, script_version, timestamp],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Runs of a library script, newest first
+    pub fn get_script_runs(&self, script_id: i32) -> Result<Vec<CommandLog>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, command, output, timestamp, script_id, script_version
+             FROM command_logs WHERE script_id = ?1 ORDER BY id DESC",
+        )?;
+        let rows = stmt.query_map(params![script_id], command_log_from_row)?;
+
+        let mut logs = Vec::new();
+        for log in rows {
+            logs.push(log?);
+        }
+        Ok(logs)
+    }
+
     pub fn delete_command_log(&self, id: i32) -> Result<usize> {
         let conn = self.get_conn()?;
         conn.execute("DELETE FROM command_logs WHERE id = ?1", params![id])
@@ -214,45 +877,1842 @@ impl Db {
     pub fn get_command_log(&self, id: i32) -> Result<Option<CommandLog>> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, device_id, command, output, timestamp FROM command_logs WHERE id = ?1",
+            "SELECT id, device_id, command, output, timestamp, script_id, script_version
+             FROM command_logs WHERE id = ?1",
         )?;
         let mut rows = stmt.query(params![id])?;
         if let Some(row) = rows.next()? {
-            Ok(Some(CommandLog {
-                id: row.get(0)?,
-                device_id: row.get(1)?,
-                command: row.get(2)?,
-                output: row.get(3)?,
-                timestamp: row.get(4)?,
-            }))
+            Ok(Some(command_log_from_row(row)?))
         } else {
             Ok(None)
         }
     }
-}
 
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct Device {
-    pub id: i32,
-    pub name: String,
-    pub ip: String,
-    pub last_seen: Option<i64>,
-}
+    // Alert rules
+    pub fn insert_alert_rule(
+        &self,
+        name: &str,
+        device_id: Option<i32>,
+        metric: &str,
+        comparator: &str,
+        threshold: f64,
+        duration_secs: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO alert_rules (name, device_id, metric, comparator, threshold, duration_secs, enabled)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1)",
+            params![name, device_id, metric, comparator, threshold, duration_secs],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
 
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct Tunnel {
-    pub id: i32,
-    pub device_id: i32,
-    pub status: String,
-    pub last_checked: Option<i64>,
-}
+    pub fn get_alert_rules(&self) -> Result<Vec<AlertRule>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, device_id, metric, comparator, threshold, duration_secs, enabled FROM alert_rules",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(AlertRule {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                device_id: row.get(2)?,
+                metric: row.get(3)?,
+                comparator: row.get(4)?,
+                threshold: row.get(5)?,
+                duration_secs: row.get(6)?,
+                enabled: row.get(7)?,
+            })
+        })?;
 
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct Metric {
-    pub id: i32,
-    pub device_id: i32,
+        let mut rules = Vec::new();
+        for rule in rows {
+            rules.push(rule?);
+        }
+        Ok(rules)
+    }
+
+    pub fn set_alert_rule_enabled(&self, id: i32, enabled: bool) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE alert_rules SET enabled = ?1 WHERE id = ?2",
+            params![enabled, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn delete_alert_rule(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "DELETE FROM alert_deliveries WHERE channel_id IN (SELECT id FROM alert_channels WHERE rule_id = ?1)",
+            params![id],
+        )?;
+        conn.execute("DELETE FROM alert_channels WHERE rule_id = ?1", params![id])?;
+        conn.execute("DELETE FROM alerts WHERE rule_id = ?1", params![id])?;
+        conn.execute("DELETE FROM alert_silences WHERE rule_id = ?1", params![id])?;
+        conn.execute("DELETE FROM alert_rules WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    // Alerts
+    pub fn insert_alert(
+        &self,
+        rule_id: i32,
+        device_id: i32,
+        state: &str,
+        value: f64,
+        started_at: i64,
+        fired_at: Option<i64>,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO alerts (rule_id, device_id, state, value, started_at, fired_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![rule_id, device_id, state, value, started_at, fired_at],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The pending or firing alert for a rule/device pair, if any
+    pub fn get_active_alert(&self, rule_id: i32, device_id: i32) -> Result<Option<Alert>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, rule_id, device_id, state, value, started_at, fired_at, resolved_at
+             FROM alerts WHERE rule_id = ?1 AND device_id = ?2 AND state != 'resolved'",
+        )?;
+        let mut rows = stmt.query(params![rule_id, device_id])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(Alert {
+                id: row.get(0)?,
+                rule_id: row.get(1)?,
+                device_id: row.get(2)?,
+                state: row.get(3)?,
+                value: row.get(4)?,
+                started_at: row.get(5)?,
+                fired_at: row.get(6)?,
+                resolved_at: row.get(7)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn get_alerts(&self, active_only: bool) -> Result<Vec<Alert>> {
+        let conn = self.get_conn()?;
+        let sql = if active_only {
+            "SELECT id, rule_id, device_id, state, value, started_at, fired_at, resolved_at
+             FROM alerts WHERE state != 'resolved' ORDER BY started_at DESC"
+        } else {
+            "SELECT id, rule_id, device_id, state, value, started_at, fired_at, resolved_at
+             FROM alerts ORDER BY started_at DESC"
+        };
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Alert {
+                id: row.get(0)?,
+                rule_id: row.get(1)?,
+                device_id: row.get(2)?,
+                state: row.get(3)?,
+                value: row.get(4)?,
+                started_at: row.get(5)?,
+                fired_at: row.get(6)?,
+                resolved_at: row.get(7)?,
+            })
+        })?;
+
+        let mut alerts = Vec::new();
+        for alert in rows {
+            alerts.push(alert?);
+        }
+        Ok(alerts)
+    }
+
+    pub fn update_alert_value(&self, id: i32, value: f64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE alerts SET value = ?1 WHERE id = ?2",
+            params![value, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn fire_alert(&self, id: i32, value: f64, fired_at: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE alerts SET state = 'firing', value = ?1, fired_at = ?2 WHERE id = ?3",
+            params![value, fired_at, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn resolve_alert(&self, id: i32, value: f64, resolved_at: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE alerts SET state = 'resolved', value = ?1, resolved_at = ?2 WHERE id = ?3",
+            params![value, resolved_at, id],
+        )
+        .map_err(Into::into)
+    }
+
+    // Alert silences
+    pub fn insert_alert_silence(
+        &self,
+        rule_id: Option<i32>,
+        device_id: Option<i32>,
+        until: i64,
+        comment: Option<&str>,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO alert_silences (rule_id, device_id, until, comment) VALUES (?1, ?2, ?3, ?4)",
+            params![rule_id, device_id, until, comment],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_alert_silences(&self) -> Result<Vec<AlertSilence>> {
+        let conn = self.get_conn()?;
+        let mut stmt =
+            conn.prepare("SELECT id, rule_id, device_id, until, comment FROM alert_silences")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(AlertSilence {
+                id: row.get(0)?,
+                rule_id: row.get(1)?,
+                device_id: row.get(2)?,
+                until: row.get(3)?,
+                comment: row.get(4)?,
+            })
+        })?;
+
+        let mut silences = Vec::new();
+        for silence in rows {
+            silences.push(silence?);
+        }
+        Ok(silences)
+    }
+
+    pub fn delete_alert_silence(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM alert_silences WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    /// A silence matches when its rule and device are either unset or equal to the given ones
+    pub fn is_alert_silenced(&self, rule_id: i32, device_id: i32, now: i64) -> Result<bool> {
+        let conn = self.get_conn()?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM alert_silences
+             WHERE until > ?1
+               AND (rule_id IS NULL OR rule_id = ?2)
+               AND (device_id IS NULL OR device_id = ?3)",
+            params![now, rule_id, device_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    // Alert notification channels
+    pub fn insert_alert_channel(
+        &self,
+        rule_id: i32,
+        kind: &str,
+        target: &str,
+        max_retries: i32,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO alert_channels (rule_id, kind, target, max_retries, enabled) VALUES (?1, ?2, ?3, ?4, 1)",
+            params![rule_id, kind, target, max_retries],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_alert_channels(&self, rule_id: i32) -> Result<Vec<AlertChannel>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, rule_id, kind, target, max_retries, enabled FROM alert_channels WHERE rule_id = ?1",
+        )?;
+        let rows = stmt.query_map(params![rule_id], |row| {
+            Ok(AlertChannel {
+                id: row.get(0)?,
+                rule_id: row.get(1)?,
+                kind: row.get(2)?,
+                target: row.get(3)?,
+                max_retries: row.get(4)?,
+                enabled: row.get(5)?,
+            })
+        })?;
+
+        let mut channels = Vec::new();
+        for channel in rows {
+            channels.push(channel?);
+        }
+        Ok(channels)
+    }
+
+    pub fn get_alert_channel(&self, id: i32) -> Result<Option<AlertChannel>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, rule_id, kind, target, max_retries, enabled FROM alert_channels WHERE id = ?1",
+        )?;
+        let mut rows = stmt.query(params![id])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(AlertChannel {
+                id: row.get(0)?,
+                rule_id: row.get(1)?,
+                kind: row.get(2)?,
+                target: row.get(3)?,
+                max_retries: row.get(4)?,
+                enabled: row.get(5)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn set_alert_channel_enabled(&self, id: i32, enabled: bool) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE alert_channels SET enabled = ?1 WHERE id = ?2",
+            params![enabled, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn delete_alert_channel(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "DELETE FROM alert_deliveries WHERE channel_id = ?1",
+            params![id],
+        )?;
+        conn.execute("DELETE FROM alert_channels WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    // Alert deliveries
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_alert_delivery(
+        &self,
+        channel_id: i32,
+        alert_id: i32,
+        state: &str,
+        attempt: i32,
+        status: &str,
+        error: Option<&str>,
+        timestamp: i64,
+    ) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO alert_deliveries (channel_id, alert_id, state, attempt, status, error, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![channel_id, alert_id, state, attempt, status, error, timestamp],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn get_alert_deliveries(&self, alert_id: i32) -> Result<Vec<AlertDelivery>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, alert_id, state, attempt, status, error, timestamp
+             FROM alert_deliveries WHERE alert_id = ?1 ORDER BY timestamp, attempt",
+        )?;
+        let rows = stmt.query_map(params![alert_id], |row| {
+            Ok(AlertDelivery {
+                id: row.get(0)?,
+                channel_id: row.get(1)?,
+                alert_id: row.get(2)?,
+                state: row.get(3)?,
+                attempt: row.get(4)?,
+                status: row.get(5)?,
+                error: row.get(6)?,
+                timestamp: row.get(7)?,
+            })
+        })?;
+
+        let mut deliveries = Vec::new();
+        for delivery in rows {
+            deliveries.push(delivery?);
+        }
+        Ok(deliveries)
+    }
+
+    // Log snapshots
+    pub fn insert_log_snapshot(
+        &self,
+        device_id: i32,
+        source: &str,
+        filter: Option<&str>,
+        note: Option<&str>,
+        lines: &[String],
+        created_at: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO log_snapshots (device_id, source, filter, note, line_count, content, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                device_id,
+                source,
+                filter,
+                note,
+                lines.len() as i64,
+                lines.join("\n"),
+                created_at
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Snapshots newest first, optionally limited to one device
+    pub fn get_log_snapshots(&self, device_id: Option<i32>) -> Result<Vec<LogSnapshot>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, device_id, source, filter, note, line_count, content, created_at
+             FROM log_snapshots
+             WHERE ?1 IS NULL OR device_id = ?1
+             ORDER BY created_at DESC, id DESC",
+        )?;
+        let rows = stmt.query_map(params![device_id], |row| {
+            Ok(LogSnapshot {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                source: row.get(2)?,
+                filter: row.get(3)?,
+                note: row.get(4)?,
+                line_count: row.get(5)?,
+                content: row.get(6)?,
+                created_at: row.get(7)?,
+            })
+        })?;
+
+        let mut snapshots = Vec::new();
+        for snapshot in rows {
+            snapshots.push(snapshot?);
+        }
+        Ok(snapshots)
+    }
+
+    pub fn delete_log_snapshot(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM log_snapshots WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    // File transfers
+    pub fn insert_transfer(
+        &self,
+        device_id: i32,
+        direction: &str,
+        local_path: &str,
+        remote_path: &str,
+        now: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO transfers (device_id, direction, local_path, remote_path, state, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, 'queued', ?5, ?5)",
+            params![device_id, direction, local_path, remote_path, now],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Transfers newest first, optionally limited to one device
+    pub fn get_transfers(&self, device_id: Option<i32>) -> Result<Vec<Transfer>> {
+        self.query_transfers(
+            &format!(
+                "SELECT {} FROM transfers WHERE ?1 IS NULL OR device_id = ?1 ORDER BY id DESC",
+                TRANSFER_COLUMNS
+            ),
+            params![device_id],
+        )
+    }
+
+    pub fn get_transfer(&self, id: i32) -> Result<Option<Transfer>> {
+        Ok(self
+            .query_transfers(
+                &format!("SELECT {} FROM transfers WHERE id = ?1", TRANSFER_COLUMNS),
+                params![id],
+            )?
+            .pop())
+    }
+
+    /// Oldest queued transfers first
+    pub fn get_queued_transfers(&self, limit: usize) -> Result<Vec<Transfer>> {
+        self.query_transfers(
+            &format!(
+                "SELECT {} FROM transfers WHERE state = 'queued' ORDER BY id LIMIT ?1",
+                TRANSFER_COLUMNS
+            ),
+            params![limit as i64],
+        )
+    }
+
+    fn query_transfers(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Transfer>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            Ok(Transfer {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                direction: row.get(2)?,
+                local_path: row.get(3)?,
+                remote_path: row.get(4)?,
+                size: row.get(5)?,
+                transferred: row.get(6)?,
+                sha256: row.get(7)?,
+                state: row.get(8)?,
+                error: row.get(9)?,
+                created_at: row.get(10)?,
+                updated_at: row.get(11)?,
+            })
+        })?;
+
+        let mut transfers = Vec::new();
+        for transfer in rows {
+            transfers.push(transfer?);
+        }
+        Ok(transfers)
+    }
+
+    pub fn set_transfer_state(
+        &self,
+        id: i32,
+        state: &str,
+        error: Option<&str>,
+        now: i64,
+    ) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE transfers SET state = ?1, error = ?2, updated_at = ?3 WHERE id = ?4",
+            params![state, error, now, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn update_transfer_progress(&self, id: i32, transferred: i64, now: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE transfers SET transferred = ?1, updated_at = ?2 WHERE id = ?3",
+            params![transferred, now, id],
+        )
+        .map_err(Into::into)
+    }
+
+    /// Record the total size and the sha256 the transfer is verified against
+    pub fn set_transfer_source(&self, id: i32, size: i64, sha256: &str) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE transfers SET size = ?1, sha256 = ?2 WHERE id = ?3",
+            params![size, sha256, id],
+        )
+        .map_err(Into::into)
+    }
+
+    /// Put transfers interrupted by an app exit back in the queue
+    pub fn requeue_running_transfers(&self) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE transfers SET state = 'queued' WHERE state = 'running'",
+            [],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn delete_transfer(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM transfers WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    // Drift checks
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_drift_check(
+        &self,
+        name: &str,
+        kind: &str,
+        target: &str,
+        device_ids: &[i32],
+        tags: &[String],
+        baseline_device_id: Option<i32>,
+        interval_secs: i64,
+        now: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO drift_checks (name, kind, target, device_ids, tags, baseline_device_id, interval_secs, enabled, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 1, ?8)",
+            params![
+                name,
+                kind,
+                target,
+                join_ids(device_ids),
+                join_tags(tags),
+                baseline_device_id,
+                interval_secs,
+                now
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_drift_checks(&self) -> Result<Vec<DriftCheck>> {
+        self.query_drift_checks(
+            &format!(
+                "SELECT {} FROM drift_checks ORDER BY id",
+                DRIFT_CHECK_COLUMNS
+            ),
+            params![],
+        )
+    }
+
+    pub fn get_drift_check(&self, id: i32) -> Result<Option<DriftCheck>> {
+        Ok(self
+            .query_drift_checks(
+                &format!(
+                    "SELECT {} FROM drift_checks WHERE id = ?1",
+                    DRIFT_CHECK_COLUMNS
+                ),
+                params![id],
+            )?
+            .pop())
+    }
+
+    /// Enabled checks whose interval has elapsed since their last run
+    pub fn get_due_drift_checks(&self, now: i64) -> Result<Vec<DriftCheck>> {
+        self.query_drift_checks(
+            &format!(
+                "SELECT {} FROM drift_checks
+                 WHERE enabled = 1 AND (last_run IS NULL OR last_run + interval_secs <= ?1)
+                 ORDER BY id",
+                DRIFT_CHECK_COLUMNS
+            ),
+            params![now],
+        )
+    }
+
+    fn query_drift_checks(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<DriftCheck>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let device_ids: String = row.get(4)?;
+            let tags: String = row.get(5)?;
+            Ok(DriftCheck {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                kind: row.get(2)?,
+                target: row.get(3)?,
+                device_ids: device_ids
+                    .split(',')
+                    .filter_map(|id| id.parse().ok())
+                    .collect(),
+                tags: split_tags(&tags),
+                baseline_device_id: row.get(6)?,
+                interval_secs: row.get(7)?,
+                enabled: row.get(8)?,
+                last_run: row.get(9)?,
+                created_at: row.get(10)?,
+            })
+        })?;
+
+        let mut checks = Vec::new();
+        for check in rows {
+            checks.push(check?);
+        }
+        Ok(checks)
+    }
+
+    pub fn set_drift_check_enabled(&self, id: i32, enabled: bool) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE drift_checks SET enabled = ?1 WHERE id = ?2",
+            params![enabled, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn set_drift_check_last_run(&self, id: i32, last_run: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE drift_checks SET last_run = ?1 WHERE id = ?2",
+            params![last_run, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn delete_drift_check(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "DELETE FROM drift_snapshots WHERE check_id = ?1",
+            params![id],
+        )?;
+        conn.execute("DELETE FROM drift_checks WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    // Drift snapshots
+    pub fn insert_drift_snapshot(
+        &self,
+        check_id: i32,
+        device_id: i32,
+        sha256: Option<&str>,
+        content: Option<&str>,
+        error: Option<&str>,
+        created_at: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO drift_snapshots (check_id, device_id, sha256, content, error, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![check_id, device_id, sha256, content, error, created_at],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The most recent snapshot of every device for a check
+    pub fn get_latest_drift_snapshots(&self, check_id: i32) -> Result<Vec<DriftSnapshot>> {
+        self.query_drift_snapshots(
+            &format!(
+                "SELECT {} FROM drift_snapshots WHERE id IN (
+                     SELECT MAX(id) FROM drift_snapshots WHERE check_id = ?1 GROUP BY device_id
+                 ) ORDER BY device_id",
+                DRIFT_SNAPSHOT_COLUMNS
+            ),
+            params![check_id],
+        )
+    }
+
+    /// Snapshot history of one device for a check, newest first
+    pub fn get_drift_snapshots(&self, check_id: i32, device_id: i32) -> Result<Vec<DriftSnapshot>> {
+        self.query_drift_snapshots(
+            &format!(
+                "SELECT {} FROM drift_snapshots WHERE check_id = ?1 AND device_id = ?2 ORDER BY id DESC",
+                DRIFT_SNAPSHOT_COLUMNS
+            ),
+            params![check_id, device_id],
+        )
+    }
+
+    fn query_drift_snapshots(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<DriftSnapshot>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            Ok(DriftSnapshot {
+                id: row.get(0)?,
+                check_id: row.get(1)?,
+                device_id: row.get(2)?,
+                sha256: row.get(3)?,
+                content: row.get(4)?,
+                error: row.get(5)?,
+                created_at: row.get(6)?,
+            })
+        })?;
+
+        let mut snapshots = Vec::new();
+        for snapshot in rows {
+            snapshots.push(snapshot?);
+        }
+        Ok(snapshots)
+    }
+
+    /// Keep only the newest `keep` snapshots of a device for a check
+    pub fn prune_drift_snapshots(
+        &self,
+        check_id: i32,
+        device_id: i32,
+        keep: usize,
+    ) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "DELETE FROM drift_snapshots WHERE check_id = ?1 AND device_id = ?2 AND id NOT IN (
+                 SELECT id FROM drift_snapshots WHERE check_id = ?1 AND device_id = ?2
+                 ORDER BY id DESC LIMIT ?3
+             )",
+            params![check_id, device_id, keep as i64],
+        )
+        .map_err(Into::into)
+    }
+
+    // Device facts
+    /// Store freshly gathered facts; returns whether they differ from the
+    /// latest record, in which case a new history row was added
+    pub fn record_device_facts(
+        &self,
+        device_id: i32,
+        facts: &DeviceFacts,
+        now: i64,
+    ) -> Result<bool> {
+        let latest = self.get_device_facts(device_id)?;
+        let conn = self.get_conn()?;
+        if let Some(latest) = latest.filter(|l| &l.facts == facts) {
+            conn.execute(
+                "UPDATE device_facts SET last_checked = ?1 WHERE id = ?2",
+                params![now, latest.id],
+            )?;
+            return Ok(false);
+        }
+        conn.execute(
+            "INSERT INTO device_facts (device_id, hostname, os, distro, distro_version, kernel, arch,
+                 cpu_model, cpu_count, memory_total_mb, mac_addresses, serial, collected_at, last_checked)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?13)",
+            params![
+                device_id,
+                facts.hostname,
+                facts.os,
+                facts.distro,
+                facts.distro_version,
+                facts.kernel,
+                facts.arch,
+                facts.cpu_model,
+                facts.cpu_count,
+                facts.memory_total_mb.map(|mb| mb as i64),
+                serde_json::to_string(&facts.mac_addresses)?,
+                facts.serial,
+                now
+            ],
+        )?;
+        Ok(true)
+    }
+
+    /// Latest facts of one device
+    pub fn get_device_facts(&self, device_id: i32) -> Result<Option<DeviceFactsRecord>> {
+        Ok(self
+            .query_device_facts(
+                &format!(
+                    "SELECT {} FROM device_facts WHERE device_id = ?1 ORDER BY id DESC LIMIT 1",
+                    DEVICE_FACTS_COLUMNS
+                ),
+                params![device_id],
+            )?
+            .pop())
+    }
+
+    /// Latest facts of every device that has any
+    pub fn get_all_device_facts(&self) -> Result<Vec<DeviceFactsRecord>> {
+        self.query_device_facts(
+            &format!(
+                "SELECT {} FROM device_facts WHERE id IN (
+                     SELECT MAX(id) FROM device_facts GROUP BY device_id
+                 ) ORDER BY device_id",
+                DEVICE_FACTS_COLUMNS
+            ),
+            params![],
+        )
+    }
+
+    /// Every recorded version of a device's facts, newest first
+    pub fn get_device_facts_history(&self, device_id: i32) -> Result<Vec<DeviceFactsRecord>> {
+        self.query_device_facts(
+            &format!(
+                "SELECT {} FROM device_facts WHERE device_id = ?1 ORDER BY id DESC",
+                DEVICE_FACTS_COLUMNS
+            ),
+            params![device_id],
+        )
+    }
+
+    fn query_device_facts(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<DeviceFactsRecord>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let macs: String = row.get(11)?;
+            let memory_total_mb: Option<i64> = row.get(10)?;
+            Ok(DeviceFactsRecord {
+                id: row.get(0)?,
+                device_id: row.get(1)?,
+                facts: DeviceFacts {
+                    hostname: row.get(2)?,
+                    os: row.get(3)?,
+                    distro: row.get(4)?,
+                    distro_version: row.get(5)?,
+                    kernel: row.get(6)?,
+                    arch: row.get(7)?,
+                    cpu_model: row.get(8)?,
+                    cpu_count: row.get(9)?,
+                    memory_total_mb: memory_total_mb.map(|mb| mb as u64),
+                    mac_addresses: serde_json::from_str(&macs).unwrap_or_default(),
+                    serial: row.get(12)?,
+                },
+                collected_at: row.get(13)?,
+                last_checked: row.get(14)?,
+            })
+        })?;
+
+        let mut records = Vec::new();
+        for record in rows {
+            records.push(record?);
+        }
+        Ok(records)
+    }
+
+    // Pending package updates
+    pub fn set_package_update_counts(
+        &self,
+        device_id: i32,
+        manager: &str,
+        pending: i64,
+        security: i64,
+        checked_at: i64,
+    ) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO package_updates (device_id, manager, pending, security, checked_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(device_id) DO UPDATE SET
+                 manager = excluded.manager, pending = excluded.pending,
+                 security = excluded.security, checked_at = excluded.checked_at",
+            params![device_id, manager, pending, security, checked_at],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn get_package_update_counts(&self) -> Result<Vec<PackageUpdateCount>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT device_id, manager, pending, security, checked_at FROM package_updates ORDER BY device_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(PackageUpdateCount {
+                device_id: row.get(0)?,
+                manager: row.get(1)?,
+                pending: row.get(2)?,
+                security: row.get(3)?,
+                checked_at: row.get(4)?,
+            })
+        })?;
+
+        let mut counts = Vec::new();
+        for count in rows {
+            counts.push(count?);
+        }
+        Ok(counts)
+    }
+
+    // Script library
+    /// Create a script at version 1; returns its id
+    pub fn insert_script(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        body: &str,
+        interpreter: &str,
+        parameters: &[ScriptParam],
+        now: i64,
+    ) -> Result<i64> {
+        let parameters = serde_json::to_string(parameters)?;
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO scripts (name, description, body, interpreter, parameters, version, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6, ?6)",
+            params![name, description, body, interpreter, parameters, now],
+        )?;
+        let id = tx.last_insert_rowid();
+        tx.execute(
+            "INSERT INTO script_versions (script_id, version, body, interpreter, parameters, created_at)
+             VALUES (?1, 1, ?2, ?3, ?4, ?5)",
+            params![id, body, interpreter, parameters, now],
+        )?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Replace a script and bump its version; returns the new version
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_script(
+        &self,
+        id: i32,
+        name: &str,
+        description: Option<&str>,
+        body: &str,
+        interpreter: &str,
+        parameters: &[ScriptParam],
+        now: i64,
+    ) -> Result<i64> {
+        let parameters = serde_json::to_string(parameters)?;
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        let version: i64 = tx.query_row(
+            "SELECT version + 1 FROM scripts WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "UPDATE scripts SET name = ?1, description = ?2, body = ?3, interpreter = ?4,
+                 parameters = ?5, version = ?6, updated_at = ?7
+             WHERE id = ?8",
+            params![
+                name,
+                description,
+                body,
+                interpreter,
+                parameters,
+                version,
+                now,
+                id
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO script_versions (script_id, version, body, interpreter, parameters, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, version, body, interpreter, parameters, now],
+        )?;
+        tx.commit()?;
+        Ok(version)
+    }
+
+    pub fn get_scripts(&self) -> Result<Vec<Script>> {
+        self.query_scripts(
+            &format!("SELECT {} FROM scripts ORDER BY name", SCRIPT_COLUMNS),
+            params![],
+        )
+    }
+
+    pub fn get_script(&self, id: i32) -> Result<Option<Script>> {
+        Ok(self
+            .query_scripts(
+                &format!("SELECT {} FROM scripts WHERE id = ?1", SCRIPT_COLUMNS),
+                params![id],
+            )?
+            .pop())
+    }
+
+    fn query_scripts(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Script>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let parameters: String = row.get(5)?;
+            Ok(Script {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                description: row.get(2)?,
+                body: row.get(3)?,
+                interpreter: row.get(4)?,
+                parameters: serde_json::from_str(&parameters).unwrap_or_default(),
+                version: row.get(6)?,
+                created_at: row.get(7)?,
+                updated_at: row.get(8)?,
+            })
+        })?;
+
+        let mut scripts = Vec::new();
+        for script in rows {
+            scripts.push(script?);
+        }
+        Ok(scripts)
+    }
+
+    /// Every version of a script, newest first
+    pub fn get_script_versions(&self, script_id: i32) -> Result<Vec<ScriptVersion>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT script_id, version, body, interpreter, parameters, created_at
+             FROM script_versions WHERE script_id = ?1 ORDER BY version DESC",
+        )?;
+        let rows = stmt.query_map(params![script_id], |row| {
+            let parameters: String = row.get(4)?;
+            Ok(ScriptVersion {
+                script_id: row.get(0)?,
+                version: row.get(1)?,
+                body: row.get(2)?,
+                interpreter: row.get(3)?,
+                parameters: serde_json::from_str(&parameters).unwrap_or_default(),
+                created_at: row.get(5)?,
+            })
+        })?;
+
+        let mut versions = Vec::new();
+        for version in rows {
+            versions.push(version?);
+        }
+        Ok(versions)
+    }
+
+    /// Delete a script and its versions; its runs stay in `command_logs`
+    pub fn delete_script(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "DELETE FROM script_versions WHERE script_id = ?1",
+            params![id],
+        )?;
+        conn.execute("DELETE FROM scripts WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    // Runbooks
+    pub fn insert_runbook(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        steps: &[RunbookStep],
+        now: i64,
+    ) -> Result<i64> {
+        let steps = serde_json::to_string(steps)?;
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO runbooks (name, description, steps, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)",
+            params![name, description, steps, now],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Runs already started keep the steps they were started with
+    pub fn update_runbook(
+        &self,
+        id: i32,
+        name: &str,
+        description: Option<&str>,
+        steps: &[RunbookStep],
+        now: i64,
+    ) -> Result<usize> {
+        let steps = serde_json::to_string(steps)?;
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE runbooks SET name = ?1, description = ?2, steps = ?3, updated_at = ?4
+             WHERE id = ?5",
+            params![name, description, steps, now, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn get_runbooks(&self) -> Result<Vec<Runbook>> {
+        self.query_runbooks(
+            &format!("SELECT {} FROM runbooks ORDER BY name", RUNBOOK_COLUMNS),
+            params![],
+        )
+    }
+
+    pub fn get_runbook(&self, id: i32) -> Result<Option<Runbook>> {
+        Ok(self
+            .query_runbooks(
+                &format!("SELECT {} FROM runbooks WHERE id = ?1", RUNBOOK_COLUMNS),
+                params![id],
+            )?
+            .pop())
+    }
+
+    fn query_runbooks(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Runbook>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let steps: String = row.get(3)?;
+            Ok(Runbook {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                description: row.get(2)?,
+                steps: serde_json::from_str(&steps).unwrap_or_default(),
+                created_at: row.get(4)?,
+                updated_at: row.get(5)?,
+            })
+        })?;
+
+        let mut runbooks = Vec::new();
+        for runbook in rows {
+            runbooks.push(runbook?);
+        }
+        Ok(runbooks)
+    }
+
+    /// Delete a runbook with its runs and their step results
+    pub fn delete_runbook(&self, id: i32) -> Result<usize> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM runbook_step_results
+             WHERE run_id IN (SELECT id FROM runbook_runs WHERE runbook_id = ?1)",
+            params![id],
+        )?;
+        tx.execute(
+            "DELETE FROM runbook_runs WHERE runbook_id = ?1",
+            params![id],
+        )?;
+        let deleted = tx.execute("DELETE FROM runbooks WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Returns the id of the new run, created in the `pending` state
+    pub fn insert_runbook_run(
+        &self,
+        runbook_id: i32,
+        device_id: i32,
+        steps: &[RunbookStep],
+        variables: &BTreeMap<String, String>,
+        now: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO runbook_runs (runbook_id, device_id, steps, state, variables, created_at, updated_at)
+             VALUES (?1, ?2, ?3, 'pending', ?4, ?5, ?5)",
+            params![
+                runbook_id,
+                device_id,
+                serde_json::to_string(steps)?,
+                serde_json::to_string(variables)?,
+                now
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get_runbook_run(&self, id: i32) -> Result<Option<RunbookRun>> {
+        Ok(self
+            .query_runbook_runs(
+                &format!(
+                    "SELECT {} FROM runbook_runs WHERE id = ?1",
+                    RUNBOOK_RUN_COLUMNS
+                ),
+                params![id],
+            )?
+            .pop())
+    }
+
+    /// Runs of a runbook, newest first
+    pub fn get_runbook_runs(&self, runbook_id: i32) -> Result<Vec<RunbookRun>> {
+        self.query_runbook_runs(
+            &format!(
+                "SELECT {} FROM runbook_runs WHERE runbook_id = ?1 ORDER BY id DESC",
+                RUNBOOK_RUN_COLUMNS
+            ),
+            params![runbook_id],
+        )
+    }
+
+    fn query_runbook_runs(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<RunbookRun>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let steps: String = row.get(3)?;
+            let variables: String = row.get(6)?;
+            let completed: String = row.get(7)?;
+            Ok(RunbookRun {
+                id: row.get(0)?,
+                runbook_id: row.get(1)?,
+                device_id: row.get(2)?,
+                steps: serde_json::from_str(&steps).unwrap_or_default(),
+                state: row.get(4)?,
+                current_step: row.get(5)?,
+                variables: serde_json::from_str(&variables).unwrap_or_default(),
+                completed: serde_json::from_str(&completed).unwrap_or_default(),
+                error: row.get(8)?,
+                created_at: row.get(9)?,
+                updated_at: row.get(10)?,
+            })
+        })?;
+
+        let mut runs = Vec::new();
+        for run in rows {
+            runs.push(run?);
+        }
+        Ok(runs)
+    }
+
+    /// Move a run to `running` if it is in one of `from`; false if it was not,
+    /// so two callers can never execute the same run at once
+    pub fn claim_runbook_run(&self, id: i32, from: &[&str], now: i64) -> Result<bool> {
+        let conn = self.get_conn()?;
+        let states = from
+            .iter()
+            .map(|s| format!("'{}'", s))
+            .collect::<Vec<_>>()
+            .join(",");
+        let updated = conn.execute(
+            &format!(
+                "UPDATE runbook_runs SET state = 'running', updated_at = ?1
+                 WHERE id = ?2 AND state IN ({})",
+                states
+            ),
+            params![now, id],
+        )?;
+        Ok(updated == 1)
+    }
+
+    /// Persist a run's progress
+    pub fn save_runbook_run(&self, run: &RunbookRun, now: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE runbook_runs SET state = ?1, current_step = ?2, variables = ?3,
+                 completed = ?4, error = ?5, updated_at = ?6
+             WHERE id = ?7",
+            params![
+                run.state,
+                run.current_step,
+                serde_json::to_string(&run.variables)?,
+                serde_json::to_string(&run.completed)?,
+                run.error,
+                now,
+                run.id
+            ],
+        )
+        .map_err(Into::into)
+    }
+
+    /// Mark runs left `running` by the last exit as `interrupted`
+    pub fn interrupt_running_runbook_runs(&self) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE runbook_runs SET state = 'interrupted' WHERE state = 'running'",
+            [],
+        )
+        .map_err(Into::into)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_runbook_step_result(
+        &self,
+        run_id: i32,
+        step_index: usize,
+        step_name: &str,
+        phase: &str,
+        success: bool,
+        exit_code: Option<i32>,
+        output: Option<&str>,
+        now: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO runbook_step_results
+                 (run_id, step_index, step_name, phase, success, exit_code, output, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                run_id,
+                step_index as i64,
+                step_name,
+                phase,
+                success,
+                exit_code,
+                output,
+                now
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Step results of a run in the order they happened
+    pub fn get_runbook_step_results(&self, run_id: i32) -> Result<Vec<RunbookStepResult>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, run_id, step_index, step_name, phase, success, exit_code, output, created_at
+             FROM runbook_step_results WHERE run_id = ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![run_id], |row| {
+            Ok(RunbookStepResult {
+                id: row.get(0)?,
+                run_id: row.get(1)?,
+                step_index: row.get(2)?,
+                step_name: row.get(3)?,
+                phase: row.get(4)?,
+                success: row.get(5)?,
+                exit_code: row.get(6)?,
+                output: row.get(7)?,
+                created_at: row.get(8)?,
+            })
+        })?;
+
+        let mut results = Vec::new();
+        for result in rows {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
+    // Scheduled jobs
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_job(
+        &self,
+        name: &str,
+        schedule: &str,
+        action: &JobAction,
+        device_ids: &[i32],
+        tags: &[String],
+        missed_policy: &str,
+        next_run: Option<i64>,
+        now: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO jobs (name, schedule, action, device_ids, tags, missed_policy, enabled, next_run, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1, ?7, ?8)",
+            params![
+                name,
+                schedule,
+                serde_json::to_string(action)?,
+                join_ids(device_ids),
+                join_tags(tags),
+                missed_policy,
+                next_run,
+                now
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_job(
+        &self,
+        id: i32,
+        name: &str,
+        schedule: &str,
+        action: &JobAction,
+        device_ids: &[i32],
+        tags: &[String],
+        missed_policy: &str,
+        next_run: Option<i64>,
+    ) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE jobs SET name = ?1, schedule = ?2, action = ?3, device_ids = ?4, tags = ?5,
+                 missed_policy = ?6, next_run = ?7
+             WHERE id = ?8",
+            params![
+                name,
+                schedule,
+                serde_json::to_string(action)?,
+                join_ids(device_ids),
+                join_tags(tags),
+                missed_policy,
+                next_run,
+                id
+            ],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn get_jobs(&self) -> Result<Vec<Job>> {
+        self.query_jobs(
+            &format!("SELECT {} FROM jobs ORDER BY name", JOB_COLUMNS),
+            params![],
+        )
+    }
+
+    pub fn get_job(&self, id: i32) -> Result<Option<Job>> {
+        Ok(self
+            .query_jobs(
+                &format!("SELECT {} FROM jobs WHERE id = ?1", JOB_COLUMNS),
+                params![id],
+            )?
+            .pop())
+    }
+
+    /// Enabled jobs whose next run is at or before `now`
+    pub fn get_due_jobs(&self, now: i64) -> Result<Vec<Job>> {
+        self.query_jobs(
+            &format!(
+                "SELECT {} FROM jobs
+                 WHERE enabled = 1 AND next_run IS NOT NULL AND next_run <= ?1
+                 ORDER BY next_run",
+                JOB_COLUMNS
+            ),
+            params![now],
+        )
+    }
+
+    fn query_jobs(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Job>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let action: String = row.get(3)?;
+            let device_ids: String = row.get(4)?;
+            let tags: String = row.get(5)?;
+            Ok(Job {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                schedule: row.get(2)?,
+                action: serde_json::from_str(&action).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        3,
+                        rusqlite::types::Type::Text,
+                        e.into(),
+                    )
+                })?,
+                device_ids: device_ids
+                    .split(',')
+                    .filter_map(|id| id.parse().ok())
+                    .collect(),
+                tags: split_tags(&tags),
+                missed_policy: row.get(6)?,
+                enabled: row.get(7)?,
+                next_run: row.get(8)?,
+                last_run: row.get(9)?,
+                created_at: row.get(10)?,
+            })
+        })?;
+
+        let mut jobs = Vec::new();
+        for job in rows {
+            jobs.push(job?);
+        }
+        Ok(jobs)
+    }
+
+    pub fn set_job_enabled(&self, id: i32, enabled: bool, next_run: Option<i64>) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE jobs SET enabled = ?1, next_run = ?2 WHERE id = ?3",
+            params![enabled, next_run, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn set_job_next_run(&self, id: i32, next_run: Option<i64>) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE jobs SET next_run = ?1 WHERE id = ?2",
+            params![next_run, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn set_job_last_run(&self, id: i32, last_run: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE jobs SET last_run = ?1 WHERE id = ?2",
+            params![last_run, id],
+        )
+        .map_err(Into::into)
+    }
+
+    /// Delete a job and its run history
+    pub fn delete_job(&self, id: i32) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM job_runs WHERE job_id = ?1", params![id])?;
+        conn.execute("DELETE FROM jobs WHERE id = ?1", params![id])
+            .map_err(Into::into)
+    }
+
+    /// Record a run; runs inserted in a final state are finished at `now`
+    pub fn insert_job_run(
+        &self,
+        job_id: i32,
+        scheduled_for: i64,
+        trigger: &str,
+        state: &str,
+        error: Option<&str>,
+        now: i64,
+    ) -> Result<i64> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO job_runs (job_id, scheduled_for, trigger, state, error, started_at, finished_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, CASE WHEN ?4 = 'running' THEN NULL ELSE ?6 END)",
+            params![job_id, scheduled_for, trigger, state, error, now],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn finish_job_run(
+        &self,
+        id: i32,
+        state: &str,
+        results: &[JobDeviceResult],
+        error: Option<&str>,
+        now: i64,
+    ) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE job_runs SET state = ?1, results = ?2, error = ?3, finished_at = ?4 WHERE id = ?5",
+            params![state, serde_json::to_string(results)?, error, now, id],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn get_job_run(&self, id: i32) -> Result<Option<JobRun>> {
+        Ok(self
+            .query_job_runs(
+                &format!("SELECT {} FROM job_runs WHERE id = ?1", JOB_RUN_COLUMNS),
+                params![id],
+            )?
+            .pop())
+    }
+
+    /// Latest runs of a job, newest first
+    pub fn get_job_runs(&self, job_id: i32, limit: i64) -> Result<Vec<JobRun>> {
+        self.query_job_runs(
+            &format!(
+                "SELECT {} FROM job_runs WHERE job_id = ?1 ORDER BY id DESC LIMIT ?2",
+                JOB_RUN_COLUMNS
+            ),
+            params![job_id, limit],
+        )
+    }
+
+    fn query_job_runs(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<JobRun>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let results: String = row.get(5)?;
+            Ok(JobRun {
+                id: row.get(0)?,
+                job_id: row.get(1)?,
+                scheduled_for: row.get(2)?,
+                trigger: row.get(3)?,
+                state: row.get(4)?,
+                results: serde_json::from_str(&results).unwrap_or_default(),
+                error: row.get(6)?,
+                started_at: row.get(7)?,
+                finished_at: row.get(8)?,
+            })
+        })?;
+
+        let mut runs = Vec::new();
+        for run in rows {
+            runs.push(run?);
+        }
+        Ok(runs)
+    }
+
+    /// Mark runs left running by the last exit as interrupted
+    pub fn interrupt_running_job_runs(&self, now: i64) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "UPDATE job_runs SET state = 'interrupted', finished_at = ?1 WHERE state = 'running'",
+            params![now],
+        )
+        .map_err(Into::into)
+    }
+
+    // Settings
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+        let mut rows = stmt.query(params![key])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(row.get(0)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )
+        .map_err(Into::into)
+    }
+
+    pub fn delete_setting(&self, key: &str) -> Result<usize> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM settings WHERE key = ?1", params![key])
+            .map_err(Into::into)
+    }
+}
+
+/// Apply every migration newer than the database's `user_version`
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "BEGIN; {}; PRAGMA user_version = {}; COMMIT;",
+            migration,
+            i + 1
+        ))?;
+    }
+    Ok(())
+}
+
+fn metric_from_row(row: &Row) -> rusqlite::Result<Metric> {
+    Ok(Metric {
+        id: row.get(0)?,
+        device_id: row.get(1)?,
+        cpu: row.get(2)?,
+        mem: row.get(3)?,
+        iowait: row.get(4)?,
+        steal: row.get(5)?,
+        timestamp: row.get(6)?,
+    })
+}
+
+/// Join tags into the comma-separated form stored in tag columns
+fn join_tags(tags: &[String]) -> String {
+    tags.iter()
+        .map(|t| t.replace(',', "").trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn join_ids(ids: &[i32]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn command_log_from_row(row: &Row) -> rusqlite::Result<CommandLog> {
+    Ok(CommandLog {
+        id: row.get(0)?,
+        device_id: row.get(1)?,
+        command: row.get(2)?,
+        output: row.get(3)?,
+        timestamp: row.get(4)?,
+        script_id: row.get(5)?,
+        script_version: row.get(6)?,
+    })
+}
+
+fn device_from_row(row: &Row) -> rusqlite::Result<Device> {
+    let tags: String = row.get(4)?;
+    Ok(Device {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        ip: row.get(2)?,
+        last_seen: row.get(3)?,
+        tags: split_tags(&tags),
+        username: row.get(5)?,
+        port: row.get(6)?,
+        strict_host_key_checking: row.get(7)?,
+        connect_timeout: row.get(8)?,
+        escalation: row.get(9)?,
+        host_key_fingerprint: row.get(10)?,
+        notes: row.get(11)?,
+    })
+}
+
+/// Current UNIX time in seconds, the unit used for every timestamp column
+pub fn unix_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Device {
+    pub id: i32,
+    pub name: String,
+    pub ip: String,
+    pub last_seen: Option<i64>,
+    pub tags: Vec<String>,
+    pub username: Option<String>,
+    pub port: Option<u16>,
+    pub strict_host_key_checking: Option<bool>,
+    pub connect_timeout: Option<u64>,
+    /// How privileged commands gain root, see [`crate::ssh::Escalation`]
+    pub escalation: String,
+    /// SHA-256 host key fingerprint recorded when the device was discovered
+    pub host_key_fingerprint: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// A device to add with [`Db::insert_devices`]
+#[derive(Debug, Clone)]
+pub struct NewDevice {
+    pub name: String,
+    pub ip: String,
+    pub config: SshConfig,
+    pub tags: Vec<String>,
+    pub notes: Option<String>,
+}
+
+impl Device {
+    pub fn ssh_config(&self) -> SshConfig {
+        SshConfig {
+            username: self.username.clone(),
+            port: self.port,
+            strict_host_key_checking: self.strict_host_key_checking,
+            connect_timeout: self.connect_timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Device {
+    /// Device with default SSH settings and no tags, for tests
+    pub(crate) fn fixture(id: i32, name: &str, ip: &str) -> Device {
+        Device {
+            id,
+            name: name.to_string(),
+            ip: ip.to_string(),
+            last_seen: None,
+            tags: Vec::new(),
+            username: None,
+            port: None,
+            strict_host_key_checking: None,
+            connect_timeout: None,
+            escalation: "sudo".to_string(),
+            host_key_fingerprint: None,
+            notes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Tunnel {
+    pub id: i32,
+    pub device_id: i32,
+    pub status: String,
+    pub last_checked: Option<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Metric {
+    pub id: i32,
+    pub device_id: i32,
     pub cpu: f64,
     pub mem: f64,
+    /// Absent for samples recorded before iowait/steal were collected
+    pub iowait: Option<f64>,
+    pub steal: Option<f64>,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CpuCoreMetric {
+    pub id: i32,
+    pub device_id: i32,
+    pub core: String,
+    pub usage: f64,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InterfaceMetric {
+    pub id: i32,
+    pub device_id: i32,
+    pub interface: String,
+    pub rx_bytes: i64,
+    pub tx_bytes: i64,
+    pub rx_errors: i64,
+    pub tx_errors: i64,
+    pub rx_rate: f64,
+    pub tx_rate: f64,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FilesystemMetric {
+    pub id: i32,
+    pub device_id: i32,
+    pub filesystem: String,
+    pub mount: String,
+    pub total_kb: i64,
+    pub used_kb: i64,
+    pub available_kb: i64,
+    pub percent: f64,
     pub timestamp: i64,
 }
 
@@ -263,4 +2723,384 @@ pub struct CommandLog {
     pub command: String,
     pub output: Option<String>,
     pub timestamp: i64,
+    /// Set when the command was a run of a library script
+    pub script_id: Option<i32>,
+    pub script_version: Option<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AlertRule {
+    pub id: i32,
+    pub name: String,
+    pub device_id: Option<i32>,
+    pub metric: String,
+    pub comparator: String,
+    pub threshold: f64,
+    pub duration_secs: i64,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Alert {
+    pub id: i32,
+    pub rule_id: i32,
+    pub device_id: i32,
+    pub state: String,
+    pub value: Option<f64>,
+    pub started_at: i64,
+    pub fired_at: Option<i64>,
+    pub resolved_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AlertSilence {
+    pub id: i32,
+    pub rule_id: Option<i32>,
+    pub device_id: Option<i32>,
+    pub until: i64,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AlertChannel {
+    pub id: i32,
+    pub rule_id: i32,
+    pub kind: String,
+    pub target: String,
+    pub max_retries: i32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AlertDelivery {
+    pub id: i32,
+    pub channel_id: i32,
+    pub alert_id: i32,
+    pub state: String,
+    pub attempt: i32,
+    pub status: String,
+    pub error: Option<String>,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LogSnapshot {
+    pub id: i32,
+    pub device_id: i32,
+    pub source: String,
+    pub filter: Option<String>,
+    pub note: Option<String>,
+    pub line_count: i64,
+    pub content: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Transfer {
+    pub id: i32,
+    pub device_id: i32,
+    pub direction: String,
+    pub local_path: String,
+    pub remote_path: String,
+    pub size: Option<i64>,
+    pub transferred: i64,
+    pub sha256: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DriftCheck {
+    pub id: i32,
+    pub name: String,
+    /// `file` or `command`
+    pub kind: String,
+    /// Path of the file or the command whose output is compared
+    pub target: String,
+    pub device_ids: Vec<i32>,
+    pub tags: Vec<String>,
+    /// Device every other device is compared with; `None` uses the most common result
+    pub baseline_device_id: Option<i32>,
+    pub interval_secs: i64,
+    pub enabled: bool,
+    pub last_run: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DriftSnapshot {
+    pub id: i32,
+    pub check_id: i32,
+    pub device_id: i32,
+    /// `None` when fetching failed, see `error`
+    pub sha256: Option<String>,
+    pub content: Option<String>,
+    pub error: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub mac: String,
+}
+
+/// Everything known about a device that is not a live metric
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DeviceFacts {
+    pub hostname: Option<String>,
+    /// Kernel name from `uname -s`, e.g. `Linux`
+    pub os: Option<String>,
+    /// `NAME` from os-release, e.g. `Debian GNU/Linux`
+    pub distro: Option<String>,
+    /// `VERSION_ID` from os-release, e.g. `12`
+    pub distro_version: Option<String>,
+    pub kernel: Option<String>,
+    pub arch: Option<String>,
+    pub cpu_model: Option<String>,
+    pub cpu_count: Option<u32>,
+    pub memory_total_mb: Option<u64>,
+    pub mac_addresses: Vec<NetworkInterface>,
+    pub serial: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeviceFactsRecord {
+    pub id: i32,
+    pub device_id: i32,
+    #[serde(flatten)]
+    pub facts: DeviceFacts,
+    /// When these facts were first seen
+    pub collected_at: i64,
+    /// When they were last confirmed unchanged
+    pub last_checked: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PackageUpdateCount {
+    pub device_id: i32,
+    pub manager: String,
+    pub pending: i64,
+    pub security: i64,
+    pub checked_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamKind {
+    String,
+    Integer,
+    Boolean,
+    /// One of `choices`
+    Choice,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ScriptParam {
+    /// Also the environment variable the value is exported as
+    pub name: String,
+    pub kind: ParamKind,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub choices: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Script {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub body: String,
+    /// One of `crate::scripts::INTERPRETERS`
+    pub interpreter: String,
+    pub parameters: Vec<ScriptParam>,
+    /// Bumped on every update; runs record the version they used
+    pub version: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ScriptVersion {
+    pub script_id: i32,
+    pub version: i64,
+    pub body: String,
+    pub interpreter: String,
+    pub parameters: Vec<ScriptParam>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StepAction {
+    /// Shell command; `{{name}}` placeholders are replaced by shell-quoted variables
+    Command {
+        command: String,
+        #[serde(default)]
+        as_root: bool,
+    },
+    /// Local file or directory installed on the device as in a file push
+    Upload {
+        local_path: String,
+        remote_path: String,
+        #[serde(default)]
+        mode: Option<String>,
+        #[serde(default)]
+        owner: Option<String>,
+    },
+    /// Pause until an operator approves or rejects the run
+    Approval { message: String },
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RunbookStep {
+    /// Unique within the runbook; branch targets refer to steps by name
+    pub name: String,
+    #[serde(flatten)]
+    pub action: StepAction,
+    /// Variable that receives the step's trimmed stdout
+    #[serde(default)]
+    pub capture: Option<String>,
+    /// Exit code to branch target; takes precedence over `on_success`/`on_failure`
+    #[serde(default)]
+    pub branches: BTreeMap<i32, String>,
+    /// Branch target when the step succeeds; the next step by default
+    #[serde(default)]
+    pub on_success: Option<String>,
+    /// Branch target when the step fails; `fail` by default
+    #[serde(default)]
+    pub on_failure: Option<String>,
+    /// Command that undoes the step, run when the run fails after it succeeded
+    #[serde(default)]
+    pub rollback: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Runbook {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub steps: Vec<RunbookStep>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RunbookRun {
+    pub id: i32,
+    pub runbook_id: i32,
+    pub device_id: i32,
+    /// The runbook's steps when the run was started
+    pub steps: Vec<RunbookStep>,
+    /// pending, running, awaiting_approval, interrupted, succeeded or failed
+    pub state: String,
+    /// Index of the step to run next
+    pub current_step: i64,
+    pub variables: BTreeMap<String, String>,
+    /// Indexes of steps that succeeded, in order; rolled back in reverse
+    pub completed: Vec<usize>,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RunbookStepResult {
+    pub id: i32,
+    pub run_id: i32,
+    pub step_index: i64,
+    pub step_name: String,
+    /// step, approval or rollback
+    pub phase: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub output: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobAction {
+    Command {
+        command: String,
+        #[serde(default)]
+        as_root: bool,
+    },
+    Script {
+        script_id: i32,
+        #[serde(default)]
+        values: HashMap<String, String>,
+        #[serde(default)]
+        as_root: bool,
+    },
+    /// Download `remote_path` from every device into `local_dir`, named
+    /// `<device id>-<scheduled time>-<file name>`; a device's step finishes
+    /// when its transfer does
+    FilePull {
+        remote_path: String,
+        local_dir: String,
+    },
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Job {
+    pub id: i32,
+    pub name: String,
+    /// Cron expression, evaluated in UTC
+    pub schedule: String,
+    pub action: JobAction,
+    pub device_ids: Vec<i32>,
+    pub tags: Vec<String>,
+    /// `skip` or `catch_up`
+    pub missed_policy: String,
+    pub enabled: bool,
+    /// `None` when disabled or the schedule never matches again
+    pub next_run: Option<i64>,
+    pub last_run: Option<i64>,
+    pub created_at: i64,
+}
+
+/// Outcome of a job run on one device
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct JobDeviceResult {
+    pub device_id: i32,
+    pub device_name: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct JobRun {
+    pub id: i32,
+    pub job_id: i32,
+    /// When the schedule called for the run; the start time for manual runs
+    pub scheduled_for: i64,
+    /// schedule, catch_up or manual
+    pub trigger: String,
+    /// running, succeeded, failed, skipped or interrupted
+    pub state: String,
+    pub results: Vec<JobDeviceResult>,
+    pub error: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[cfg(test)]
+impl Db {
+    /// Fresh database in a scratch file unique to this test process and `name`
+    pub(crate) fn open_temp(name: &str) -> Db {
+        let path = std::env::temp_dir().join(format!("ssedge-{}-{}.db", std::process::id(), name));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Db::new(&path.to_string_lossy()).unwrap()
+    }
 }
@@ -0,0 +1,418 @@
+//! Configuration drift detection: a file or command output that should be
+//! identical across devices is fetched periodically, hashed and compared.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device, DriftCheck, DriftSnapshot};
+use crate::sftp::{sanitize_path, MAX_READ_BYTES};
+use crate::ssh::{privileged, shell_quote};
+use futures_util::StreamExt;
+use log::{error, info};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tauri event emitted with a [`DriftReport`] after every run
+pub const DRIFT_EVENT: &str = "drift-report";
+
+/// Shortest interval a check can be scheduled at
+pub const MIN_INTERVAL_SECS: i64 = 60;
+
+/// How often the scheduler looks for due checks
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Snapshots kept per device and check
+const SNAPSHOT_HISTORY: usize = 20;
+
+/// What is compared across devices
+#[derive(Debug, Clone, PartialEq)]
+pub enum DriftSource {
+    /// A file, read as root
+    File(String),
+    /// Standard output of a shell command run as the login user
+    Command(String),
+}
+
+impl DriftSource {
+    pub fn new(kind: &str, target: &str) -> Result<Self, String> {
+        match kind {
+            "file" => Ok(DriftSource::File(
+                sanitize_path(target).map_err(|e| e.message)?,
+            )),
+            "command" if !target.trim().is_empty() => Ok(DriftSource::Command(target.to_string())),
+            "command" => Err("A drift check needs a command".to_string()),
+            other => Err(format!("Unknown drift check kind: {}", other)),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DriftSource::File(_) => "file",
+            DriftSource::Command(_) => "command",
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        match self {
+            DriftSource::File(path) => path,
+            DriftSource::Command(command) => command,
+        }
+    }
+
+    fn script(&self) -> String {
+        match self {
+            DriftSource::File(path) => privileged(&format!("cat -- {}", shell_quote(path))),
+            DriftSource::Command(command) => command.clone(),
+        }
+    }
+}
+
+/// How one device compares with the baseline
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceDrift {
+    pub device_id: i32,
+    pub device_name: String,
+    pub sha256: Option<String>,
+    pub drifted: bool,
+    /// Set when the file or command could not be fetched
+    pub error: Option<String>,
+    /// Unified diff from the baseline, when the device drifted and both contents are stored
+    pub diff: Option<String>,
+    pub checked_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DriftReport {
+    pub check_id: i32,
+    pub check_name: String,
+    pub baseline_device_id: Option<i32>,
+    pub baseline_sha256: Option<String>,
+    /// Number of devices that differ from the baseline
+    pub drifted: usize,
+    pub devices: Vec<DeviceDrift>,
+}
+
+/// The snapshot every other one is compared with
+///
+/// With an explicit baseline device that device's snapshot is used,
+/// otherwise the most common hash wins, ties going to the lowest device id.
+pub fn baseline(
+    snapshots: &[DriftSnapshot],
+    baseline_device_id: Option<i32>,
+) -> Option<&DriftSnapshot> {
+    if let Some(device_id) = baseline_device_id {
+        return snapshots
+            .iter()
+            .find(|s| s.device_id == device_id && s.sha256.is_some());
+    }
+
+    let mut ordered: Vec<&DriftSnapshot> =
+        snapshots.iter().filter(|s| s.sha256.is_some()).collect();
+    ordered.sort_by_key(|s| s.device_id);
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for snapshot in &ordered {
+        *counts
+            .entry(snapshot.sha256.as_deref().unwrap())
+            .or_default() += 1;
+    }
+    let best = counts.values().copied().max()?;
+    ordered
+        .into_iter()
+        .find(|s| counts[s.sha256.as_deref().unwrap()] == best)
+}
+
+fn diff(baseline: &str, baseline_name: &str, content: &str, device_name: &str) -> String {
+    similar::TextDiff::from_lines(baseline, content)
+        .unified_diff()
+        .context_radius(3)
+        .header(baseline_name, device_name)
+        .to_string()
+}
+
+/// Compare the latest snapshots of a check with its baseline
+pub fn build_report(
+    check: &DriftCheck,
+    snapshots: &[DriftSnapshot],
+    names: &HashMap<i32, String>,
+) -> DriftReport {
+    let name_of = |id: i32| {
+        names
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("device {}", id))
+    };
+    let base = baseline(snapshots, check.baseline_device_id);
+
+    let devices: Vec<DeviceDrift> = snapshots
+        .iter()
+        .map(|snapshot| {
+            let drifted = match (base, &snapshot.sha256) {
+                (Some(base), Some(sha256)) => base.sha256.as_ref() != Some(sha256),
+                _ => false,
+            };
+            let diff = match (base, drifted) {
+                (Some(base), true) => match (&base.content, &snapshot.content) {
+                    (Some(old), Some(new)) => Some(diff(
+                        old,
+                        &name_of(base.device_id),
+                        new,
+                        &name_of(snapshot.device_id),
+                    )),
+                    _ => None,
+                },
+                _ => None,
+            };
+            DeviceDrift {
+                device_id: snapshot.device_id,
+                device_name: name_of(snapshot.device_id),
+                sha256: snapshot.sha256.clone(),
+                drifted,
+                error: snapshot.error.clone(),
+                diff,
+                checked_at: snapshot.created_at,
+            }
+        })
+        .collect();
+
+    DriftReport {
+        check_id: check.id,
+        check_name: check.name.clone(),
+        baseline_device_id: base.map(|b| b.device_id),
+        baseline_sha256: base.and_then(|b| b.sha256.clone()),
+        drifted: devices.iter().filter(|d| d.drifted).count(),
+        devices,
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Fetch the source on one device and store the result as a snapshot
+async fn snapshot_device(state: &AppState, check_id: i32, source: &DriftSource, device: &Device) {
+    let result = async {
+        let (device, session) = crate::ssh::connect_device(state, device.id).await?;
+        let privilege = crate::ssh::Privilege::for_device(state, &device);
+        let output = crate::ssh::exec_as(&session, &privilege, &source.script()).await?;
+        if output.success() {
+            Ok(output.stdout)
+        } else {
+            Err(format!(
+                "{} failed: {}",
+                source.target(),
+                output.stderr.trim()
+            ))
+        }
+    }
+    .await;
+
+    let (sha256, content, error) = match result {
+        Ok(stdout) => {
+            let sha256 = sha256_hex(stdout.as_bytes());
+            // Oversized outputs are compared by hash only
+            let content = (stdout.len() as u64 <= MAX_READ_BYTES).then_some(stdout);
+            (Some(sha256), content, None)
+        }
+        Err(e) => {
+            error!("Drift check {} failed on {}: {}", check_id, device.name, e);
+            (None, None, Some(e))
+        }
+    };
+
+    let stored = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+        db.insert_drift_snapshot(
+            check_id,
+            device.id,
+            sha256.as_deref(),
+            content.as_deref(),
+            error.as_deref(),
+            unix_timestamp(),
+        )
+        .and_then(|_| db.prune_drift_snapshots(check_id, device.id, SNAPSHOT_HISTORY))
+        .map_err(|e| e.to_string())
+    });
+    if let Err(e) = stored {
+        error!(
+            "Failed to store drift snapshot for device {}: {}",
+            device.id, e
+        );
+    }
+}
+
+/// Devices a check currently selects, including its baseline device
+pub fn selected_devices(state: &AppState, check: &DriftCheck) -> Result<Vec<Device>, String> {
+    let mut ids = check.device_ids.clone();
+    ids.extend(check.baseline_device_id);
+    crate::fleet::select_devices(state, &ids, &check.tags)
+}
+
+/// Latest report of a check from stored snapshots of `device_ids`
+///
+/// Snapshots of devices the check no longer selects are left out, so they
+/// neither appear in the report nor sway the majority baseline.
+pub fn report(
+    state: &AppState,
+    check: &DriftCheck,
+    device_ids: &[i32],
+) -> Result<DriftReport, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let mut snapshots = db
+        .get_latest_drift_snapshots(check.id)
+        .map_err(|e| e.to_string())?;
+    snapshots.retain(|s| device_ids.contains(&s.device_id));
+    let names = db
+        .get_all_devices()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|d| (d.id, d.name))
+        .collect();
+    Ok(build_report(check, &snapshots, &names))
+}
+
+/// Snapshot every selected device and report drift from the baseline
+pub async fn run_check(app: &AppHandle, check: &DriftCheck) -> Result<DriftReport, String> {
+    let source = DriftSource::new(&check.kind, &check.target)?;
+    let state = app.state::<AppState>();
+    let state: &AppState = &state;
+
+    // Recorded up front so a failing check waits for its next interval
+    let now = unix_timestamp();
+    state
+        .db
+        .lock()
+        .map_err(|e| e.to_string())?
+        .set_drift_check_last_run(check.id, now)
+        .map_err(|e| e.to_string())?;
+
+    let devices = selected_devices(state, check)?;
+    let selected: Vec<i32> = devices.iter().map(|d| d.id).collect();
+    info!(
+        "Running drift check {} ({} {}) on {} device(s)",
+        check.name,
+        source.kind(),
+        source.target(),
+        devices.len()
+    );
+
+    let source = &source;
+    futures_util::stream::iter(devices)
+        .for_each_concurrent(crate::fleet::concurrency(None), |device| async move {
+            snapshot_device(state, check.id, source, &device).await;
+        })
+        .await;
+
+    let report = report(state, check, &selected)?;
+    info!(
+        "Drift check {}: {} of {} device(s) drifted",
+        check.name,
+        report.drifted,
+        report.devices.len()
+    );
+    if let Err(e) = app.emit(DRIFT_EVENT, &report) {
+        error!("Failed to emit drift report: {}", e);
+    }
+    Ok(report)
+}
+
+/// Run due drift checks in the background
+pub fn spawn_scheduler(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(SCHEDULER_INTERVAL).await;
+
+            let due = {
+                let state = app.state::<AppState>();
+                let db = match state.db.lock() {
+                    Ok(db) => db,
+                    Err(e) => {
+                        error!("Failed to lock db for drift checks: {}", e);
+                        continue;
+                    }
+                };
+                db.get_due_drift_checks(unix_timestamp())
+            };
+
+            match due {
+                Ok(checks) => {
+                    for check in checks {
+                        if let Err(e) = run_check(&app, &check).await {
+                            error!("Drift check {} failed: {}", check.name, e);
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to load due drift checks: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(device_id: i32, content: Option<&str>) -> DriftSnapshot {
+        DriftSnapshot {
+            id: device_id,
+            check_id: 1,
+            device_id,
+            sha256: content.map(|c| sha256_hex(c.as_bytes())),
+            content: content.map(str::to_string),
+            error: content.is_none().then(|| "unreachable".to_string()),
+            created_at: 0,
+        }
+    }
+
+    fn check(baseline_device_id: Option<i32>) -> DriftCheck {
+        DriftCheck {
+            id: 1,
+            name: "sshd".to_string(),
+            kind: "file".to_string(),
+            target: "/etc/ssh/sshd_config".to_string(),
+            device_ids: vec![1, 2, 3, 4],
+            tags: Vec::new(),
+            baseline_device_id,
+            interval_secs: 3600,
+            enabled: true,
+            last_run: None,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn baseline_is_most_common_hash() {
+        let snapshots = [
+            snapshot(1, Some("a\n")),
+            snapshot(2, Some("b\n")),
+            snapshot(3, Some("b\n")),
+            snapshot(4, None),
+        ];
+        assert_eq!(baseline(&snapshots, None).unwrap().device_id, 2);
+        assert_eq!(baseline(&snapshots, Some(1)).unwrap().device_id, 1);
+        assert!(baseline(&snapshots, Some(4)).is_none());
+    }
+
+    #[test]
+    fn report_flags_drifted_devices_with_diff() {
+        let snapshots = [
+            snapshot(1, Some("PermitRootLogin no\n")),
+            snapshot(2, Some("PermitRootLogin yes\n")),
+            snapshot(3, Some("PermitRootLogin no\n")),
+            snapshot(4, None),
+        ];
+        let names = HashMap::from([(1, "a".to_string()), (2, "b".to_string())]);
+        let report = build_report(&check(None), &snapshots, &names);
+
+        assert_eq!(report.baseline_device_id, Some(1));
+        assert_eq!(report.drifted, 1);
+        let drifted = &report.devices[1];
+        assert!(drifted.drifted);
+        let diff = drifted.diff.as_deref().unwrap();
+        assert!(diff.contains("-PermitRootLogin no\n+PermitRootLogin yes\n"));
+        assert!(!report.devices[3].drifted);
+        assert_eq!(report.devices[3].error.as_deref(), Some("unreachable"));
+    }
+}
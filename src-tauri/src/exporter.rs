@@ -0,0 +1,414 @@
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device, Tunnel};
+use crate::ssh::SystemMetrics;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Manager};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Settings key holding the listen address while the exporter is enabled
+pub const EXPORTER_ADDRESS_SETTING: &str = "exporter_address";
+
+/// A device counts as reachable if it reported metrics within this window
+const REACHABLE_WINDOW_SECS: i64 = 120;
+
+const MB: f64 = 1024.0 * 1024.0;
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Handle of the running exporter listener, managed as Tauri state
+#[derive(Default)]
+pub struct ExporterState {
+    running: Mutex<Option<(String, JoinHandle<()>)>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ExporterStatus {
+    pub running: bool,
+    pub address: Option<String>,
+}
+
+impl ExporterState {
+    pub fn status(&self) -> Result<ExporterStatus, String> {
+        let running = self.running.lock().map_err(|e| e.to_string())?;
+        Ok(ExporterStatus {
+            running: running.is_some(),
+            address: running.as_ref().map(|(addr, _)| addr.clone()),
+        })
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn device_labels(device: &Device) -> String {
+    format!(
+        "device=\"{}\",device_id=\"{}\",tags=\"{}\"",
+        escape_label(&device.name),
+        device.id,
+        escape_label(&device.tags.join(","))
+    )
+}
+
+/// Render the latest metrics in Prometheus text exposition format
+pub fn render(
+    devices: &[Device],
+    latest: &HashMap<i32, SystemMetrics>,
+    tunnels: &[Tunnel],
+    now: i64,
+) -> String {
+    let mut out = String::new();
+
+    let mut family = |name: &str, help: &str, samples: Vec<(String, f64)>| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    };
+
+    family(
+        "ssedge_device_up",
+        "Whether the device reported metrics recently (1) or not (0).",
+        devices
+            .iter()
+            .map(|d| {
+                let up = d
+                    .last_seen
+                    .is_some_and(|seen| now - seen <= REACHABLE_WINDOW_SECS);
+                (device_labels(d), if up { 1.0 } else { 0.0 })
+            })
+            .collect(),
+    );
+    family(
+        "ssedge_device_last_seen_timestamp_seconds",
+        "UNIX time the device last reported metrics.",
+        devices
+            .iter()
+            .filter_map(|d| d.last_seen.map(|seen| (device_labels(d), seen as f64)))
+            .collect(),
+    );
+
+    let with_metrics: Vec<(&Device, &SystemMetrics)> = devices
+        .iter()
+        .filter_map(|d| latest.get(&d.id).map(|m| (d, m)))
+        .collect();
+    let per_device = |f: &dyn Fn(&SystemMetrics) -> Option<f64>| {
+        with_metrics
+            .iter()
+            .filter_map(|(d, m)| f(m).map(|v| (device_labels(d), v)))
+            .collect::<Vec<_>>()
+    };
+    let load = |m: &SystemMetrics, i: usize| {
+        m.load_average
+            .split(',')
+            .nth(i)
+            .and_then(|s| s.trim().parse::<f64>().ok())
+    };
+
+    family(
+        "ssedge_cpu_usage_percent",
+        "CPU utilisation in percent.",
+        per_device(&|m| Some(m.cpu_usage)),
+    );
+    family(
+        "ssedge_memory_used_bytes",
+        "Used memory in bytes.",
+        per_device(&|m| Some(m.memory_used_mb * MB)),
+    );
+    family(
+        "ssedge_memory_total_bytes",
+        "Total memory in bytes.",
+        per_device(&|m| Some(m.memory_total_mb * MB)),
+    );
+    family(
+        "ssedge_memory_usage_percent",
+        "Used memory in percent.",
+        per_device(&|m| Some(m.memory_percent)),
+    );
+    family(
+        "ssedge_disk_used_bytes",
+        "Used space on the root filesystem in bytes.",
+        per_device(&|m| Some(m.disk_used_gb * GB)),
+    );
+    family(
+        "ssedge_disk_total_bytes",
+        "Size of the root filesystem in bytes.",
+        per_device(&|m| Some(m.disk_total_gb * GB)),
+    );
+    family(
+        "ssedge_disk_usage_percent",
+        "Used space on the root filesystem in percent.",
+        per_device(&|m| Some(m.disk_percent)),
+    );
+    family(
+        "ssedge_uptime_seconds",
+        "Device uptime in seconds.",
+        per_device(&|m| Some(m.uptime_seconds as f64)),
+    );
+    family(
+        "ssedge_load1",
+        "1-minute load average.",
+        per_device(&|m| load(m, 0)),
+    );
+    family(
+        "ssedge_load5",
+        "5-minute load average.",
+        per_device(&|m| load(m, 1)),
+    );
+    family(
+        "ssedge_load15",
+        "15-minute load average.",
+        per_device(&|m| load(m, 2)),
+    );
+    family(
+        "ssedge_metrics_timestamp_seconds",
+        "UNIX time the latest metrics sample was taken.",
+        per_device(&|m| Some(m.timestamp as f64)),
+    );
+
+    let devices_by_id: HashMap<i32, &Device> = devices.iter().map(|d| (d.id, d)).collect();
+    let tunnel_labels = |t: &Tunnel| {
+        let device = match devices_by_id.get(&t.device_id) {
+            Some(d) => device_labels(d),
+            None => format!("device_id=\"{}\"", t.device_id),
+        };
+        format!(
+            "{},tunnel_id=\"{}\",status=\"{}\"",
+            device,
+            t.id,
+            escape_label(&t.status)
+        )
+    };
+    family(
+        "ssedge_tunnel_status",
+        "Tunnel status; always 1, the status is carried in the label.",
+        tunnels.iter().map(|t| (tunnel_labels(t), 1.0)).collect(),
+    );
+    family(
+        "ssedge_tunnel_last_checked_timestamp_seconds",
+        "UNIX time the tunnel status was last checked.",
+        tunnels
+            .iter()
+            .filter_map(|t| t.last_checked.map(|c| (tunnel_labels(t), c as f64)))
+            .collect(),
+    );
+
+    out
+}
+
+fn scrape(app: &AppHandle) -> Result<String, String> {
+    let state = app.state::<AppState>();
+    let latest = state
+        .latest_metrics
+        .lock()
+        .map_err(|e| e.to_string())?
+        .clone();
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let devices = db.get_all_devices().map_err(|e| e.to_string())?;
+    let tunnels = db.get_all_tunnels().map_err(|e| e.to_string())?;
+    Ok(render(&devices, &latest, &tunnels, unix_timestamp()))
+}
+
+/// Answer a single HTTP request; only `GET /metrics` is served
+async fn handle_connection(app: AppHandle, mut socket: tokio::net::TcpStream) {
+    let mut buf = [0u8; 4096];
+    let n = match socket.read(&mut buf).await {
+        Ok(n) => n,
+        Err(e) => {
+            warn!("Exporter failed to read request: {}", e);
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    let (status, content_type, body) = if method != "GET" {
+        ("405 Method Not Allowed", "text/plain", String::new())
+    } else if path != "/metrics" && !path.starts_with("/metrics?") {
+        ("404 Not Found", "text/plain", String::new())
+    } else {
+        match scrape(&app) {
+            Ok(body) => ("200 OK", "text/plain; version=0.0.4", body),
+            Err(e) => {
+                error!("Exporter failed to collect metrics: {}", e);
+                ("500 Internal Server Error", "text/plain", e)
+            }
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        warn!("Exporter failed to write response: {}", e);
+    }
+}
+
+/// Bind the listener and serve scrapes in the background, replacing any running exporter
+pub async fn start(app: &AppHandle, address: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| format!("Failed to bind exporter to {}: {}", address, e))?;
+    let bound = listener
+        .local_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| address.to_string());
+    info!("Metrics exporter listening on {}", bound);
+
+    let app_handle = app.clone();
+    let handle = tauri::async_runtime::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    tauri::async_runtime::spawn(handle_connection(app_handle.clone(), socket));
+                }
+                Err(e) => error!("Exporter failed to accept connection: {}", e),
+            }
+        }
+    });
+
+    let exporter = app.state::<ExporterState>();
+    let mut running = match exporter.running.lock() {
+        Ok(running) => running,
+        Err(e) => {
+            handle.abort();
+            return Err(e.to_string());
+        }
+    };
+    if let Some((_, previous)) = running.replace((bound, handle)) {
+        previous.abort();
+    }
+    Ok(())
+}
+
+pub fn stop(app: &AppHandle) {
+    let exporter = app.state::<ExporterState>();
+    let running = match exporter.running.lock() {
+        Ok(mut running) => running.take(),
+        Err(e) => {
+            error!("Failed to lock exporter state to stop it: {}", e);
+            return;
+        }
+    };
+    if let Some((address, handle)) = running {
+        handle.abort();
+        info!("Metrics exporter on {} stopped", address);
+    }
+}
+
+/// Start the exporter on launch if it was enabled in a previous session
+pub fn spawn_from_settings(app: AppHandle) {
+    let address = {
+        let state = app.state::<AppState>();
+        let db = match state.db.lock() {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Failed to lock db to read exporter settings: {}", e);
+                return;
+            }
+        };
+        match db.get_setting(EXPORTER_ADDRESS_SETTING) {
+            Ok(Some(address)) => address,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to read exporter settings: {}", e);
+                return;
+            }
+        }
+    };
+
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = start(&app, &address).await {
+            error!("{}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> SystemMetrics {
+        SystemMetrics {
+            cpu_usage: 12.5,
+            iowait_percent: 0.0,
+            steal_percent: 0.0,
+            cpu_cores: Vec::new(),
+            memory_used_mb: 512.0,
+            memory_total_mb: 2048.0,
+            memory_percent: 25.0,
+            disk_used_gb: 4.0,
+            disk_total_gb: 16.0,
+            disk_percent: 25.0,
+            filesystems: Vec::new(),
+            network: Vec::new(),
+            uptime_seconds: 3600,
+            load_average: "0.50, 0.25, oops".to_string(),
+            timestamp: 990,
+        }
+    }
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut quoted = Device::fixture(1, "lab \"pi\"\\1\nnew", "10.0.0.1");
+        quoted.tags = vec!["edge".to_string(), "a\"b".to_string()];
+        quoted.last_seen = Some(990);
+        let mut stale = Device::fixture(2, "nas", "10.0.0.2");
+        stale.last_seen = Some(100);
+        let never = Device::fixture(3, "new", "10.0.0.3");
+        let latest = HashMap::from([(1, metrics())]);
+        let tunnels = [Tunnel {
+            id: 7,
+            device_id: 2,
+            status: "up".to_string(),
+            last_checked: None,
+        }];
+
+        let out = render(&[quoted, stale, never], &latest, &tunnels, 1000);
+        let lines: Vec<&str> = out.lines().collect();
+
+        let labels = r#"device="lab \"pi\"\\1\nnew",device_id="1",tags="edge,a\"b""#;
+        for expected in [
+            "# HELP ssedge_device_up Whether the device reported metrics recently (1) or not (0).".to_string(),
+            "# TYPE ssedge_device_up gauge".to_string(),
+            format!("ssedge_device_up{{{}}} 1", labels),
+            r#"ssedge_device_up{device="nas",device_id="2",tags=""} 0"#.to_string(),
+            r#"ssedge_device_up{device="new",device_id="3",tags=""} 0"#.to_string(),
+            format!("ssedge_memory_total_bytes{{{}}} 2147483648", labels),
+            format!("ssedge_load5{{{}}} 0.25", labels),
+            r#"ssedge_tunnel_status{device="nas",device_id="2",tags="",tunnel_id="7",status="up"} 1"#.to_string(),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "missing {:?} in\n{}", expected, out);
+        }
+
+        // Every family is introduced by HELP then TYPE, even with no samples
+        let helps = lines.iter().filter(|l| l.starts_with("# HELP ")).count();
+        let types = lines.iter().filter(|l| l.starts_with("# TYPE ")).count();
+        assert_eq!((helps, types), (16, 16));
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                let name = rest.split(' ').next().unwrap();
+                assert_eq!(lines[i + 1], format!("# TYPE {} gauge", name));
+            }
+        }
+        // A device never seen has no last-seen sample, and an unparsable
+        // load average is left out
+        assert!(!out.contains(r#"ssedge_device_last_seen_timestamp_seconds{device="new""#));
+        assert!(!out.contains("ssedge_load15{"));
+        assert!(!out.contains("ssedge_tunnel_last_checked_timestamp_seconds{"));
+    }
+}
@@ -0,0 +1,429 @@
+//! Inventory facts about a device: OS, hardware and identity.
+//!
+//! Facts are gathered when a device is added and on a schedule. A new
+//! `device_facts` row is written only when something changed, so the table
+//! doubles as the change history.
+
+use crate::collector::split_sections;
+use crate::command::AppState;
+use crate::db::{unix_timestamp, DeviceFacts, DeviceFactsRecord, NetworkInterface};
+use crate::ssh::privileged;
+use futures_util::StreamExt;
+use log::{error, info};
+use openssh::Session;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// How often facts are refreshed for every device
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Serial numbers firmware ships when the vendor did not set one
+const PLACEHOLDER_SERIALS: &[&str] = &[
+    "0",
+    "default string",
+    "none",
+    "not applicable",
+    "not specified",
+    "system serial number",
+    "to be filled by o.e.m.",
+];
+
+/// Criteria for narrowing the facts list; every set field must match
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FactsFilter {
+    pub distro: Option<String>,
+    pub arch: Option<String>,
+    /// Case-insensitive substring matched against every text fact
+    pub search: Option<String>,
+}
+
+impl FactsFilter {
+    pub fn matches(&self, facts: &DeviceFacts) -> bool {
+        let eq = |want: &Option<String>, have: &Option<String>| match want {
+            Some(want) => have
+                .as_deref()
+                .is_some_and(|have| have.eq_ignore_ascii_case(want)),
+            None => true,
+        };
+        let search = match &self.search {
+            Some(needle) => {
+                let needle = needle.to_lowercase();
+                [
+                    &facts.hostname,
+                    &facts.distro,
+                    &facts.distro_version,
+                    &facts.kernel,
+                    &facts.arch,
+                    &facts.cpu_model,
+                    &facts.serial,
+                ]
+                .iter()
+                .filter_map(|f| f.as_deref())
+                .chain(facts.mac_addresses.iter().map(|i| i.mac.as_str()))
+                .any(|f| f.to_lowercase().contains(&needle))
+            }
+            None => true,
+        };
+        eq(&self.distro, &facts.distro) && eq(&self.arch, &facts.arch) && search
+    }
+}
+
+/// POSIX `sh` script printing each fact source under a `==name==` marker line
+///
+/// The DMI serial is only readable by root on most machines, so it is read
+/// as root when `as_root` is set and the device-tree serial is tried after.
+pub fn facts_script(as_root: bool) -> String {
+    let root_serial = if as_root {
+        format!(" || {}", privileged("cat /sys/class/dmi/id/product_serial"))
+    } else {
+        String::new()
+    };
+    format!(
+        r#"
+export LC_ALL=C
+echo "==uname=="; uname -s; uname -r; uname -m
+echo "==hostname=="; hostname 2>/dev/null || cat /proc/sys/kernel/hostname
+echo "==os_release=="; cat /etc/os-release 2>/dev/null || cat /usr/lib/os-release 2>/dev/null
+echo "==cpuinfo=="; cat /proc/cpuinfo
+echo "==nproc=="; getconf _NPROCESSORS_ONLN 2>/dev/null || nproc 2>/dev/null
+echo "==meminfo=="; grep '^MemTotal:' /proc/meminfo
+echo "==macs=="; for i in /sys/class/net/*; do [ -r "$i/address" ] && echo "${{i##*/}} $(cat "$i/address")"; done
+echo "==serial=="; {{ cat /sys/class/dmi/id/product_serial{root_serial} || tr -d '\000' < /proc/device-tree/serial-number; }} 2>/dev/null
+true
+"#
+    )
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_string())
+}
+
+/// `KEY=value` pairs of os-release, with shell quoting removed
+fn parse_os_release(section: &str) -> HashMap<&str, String> {
+    section
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            (key.trim(), value.to_string())
+        })
+        .collect()
+}
+
+/// First `/proc/cpuinfo` value among `keys`, in order of preference
+fn cpuinfo_value(section: &str, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| {
+        section.lines().find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            (k.trim().eq_ignore_ascii_case(key))
+                .then(|| non_empty(v))
+                .flatten()
+        })
+    })
+}
+
+fn valid_serial(serial: String) -> Option<String> {
+    let lower = serial.to_lowercase();
+    (!PLACEHOLDER_SERIALS.contains(&lower.as_str()) && !serial.chars().all(|c| c == '0'))
+        .then_some(serial)
+}
+
+/// Build facts from the output of [`facts_script`]
+pub fn parse_facts(output: &str) -> DeviceFacts {
+    let sections = split_sections(output);
+    let get = |name: &str| sections.get(name).copied().unwrap_or("");
+
+    let mut uname = get("uname").lines().map(non_empty);
+    let os_release = parse_os_release(get("os_release"));
+    let cpuinfo = get("cpuinfo");
+
+    let cpu_count = get("nproc").trim().parse().ok().or_else(|| {
+        let count = cpuinfo
+            .lines()
+            .filter(|l| l.starts_with("processor"))
+            .count();
+        (count > 0).then_some(count as u32)
+    });
+    let memory_total_mb = get("meminfo")
+        .split_whitespace()
+        .nth(1)
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb / 1024);
+    let mac_addresses = get("macs")
+        .lines()
+        .filter_map(|line| {
+            let (name, mac) = line.trim().split_once(' ')?;
+            let mac = mac.trim().to_lowercase();
+            (name != "lo" && !mac.is_empty() && mac != "00:00:00:00:00:00").then(|| {
+                NetworkInterface {
+                    name: name.to_string(),
+                    mac,
+                }
+            })
+        })
+        .collect();
+    let serial = non_empty(get("serial"))
+        .and_then(valid_serial)
+        .or_else(|| cpuinfo_value(cpuinfo, &["Serial"]).and_then(valid_serial));
+
+    DeviceFacts {
+        os: uname.next().flatten(),
+        kernel: uname.next().flatten(),
+        arch: uname.next().flatten(),
+        hostname: non_empty(get("hostname")),
+        distro: os_release.get("NAME").and_then(|v| non_empty(v)),
+        distro_version: os_release.get("VERSION_ID").and_then(|v| non_empty(v)),
+        cpu_model: cpuinfo_value(
+            cpuinfo,
+            &["model name", "cpu model", "Model", "Hardware", "Processor"],
+        ),
+        cpu_count,
+        memory_total_mb,
+        mac_addresses,
+        serial,
+    }
+}
+
+/// Names of the facts that differ between two collections
+pub fn changed_fields(old: &DeviceFacts, new: &DeviceFacts) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    let mut check = |name, differs: bool| {
+        if differs {
+            changed.push(name);
+        }
+    };
+    check("hostname", old.hostname != new.hostname);
+    check("os", old.os != new.os);
+    check("distro", old.distro != new.distro);
+    check("distro_version", old.distro_version != new.distro_version);
+    check("kernel", old.kernel != new.kernel);
+    check("arch", old.arch != new.arch);
+    check("cpu_model", old.cpu_model != new.cpu_model);
+    check("cpu_count", old.cpu_count != new.cpu_count);
+    check(
+        "memory_total_mb",
+        old.memory_total_mb != new.memory_total_mb,
+    );
+    check("mac_addresses", old.mac_addresses != new.mac_addresses);
+    check("serial", old.serial != new.serial);
+    changed
+}
+
+/// Gather facts over an open session and record them for `device_id`
+pub async fn collect(
+    state: &AppState,
+    device_id: i32,
+    session: &Session,
+) -> Result<DeviceFacts, String> {
+    let device = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_device(device_id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Device {} not found", device_id))?
+    };
+    // Facts are gathered unattended, so the serial is skipped rather than
+    // failing everything when the escalation password is not set
+    let privilege = crate::ssh::Privilege::for_device(state, &device);
+    let script = facts_script(privilege.is_usable());
+    let output = crate::ssh::exec_as(session, &privilege, &script).await?;
+    let facts = parse_facts(&output.stdout);
+    if facts.os.is_none() {
+        return Err(format!("Failed to gather facts: {}", output.stderr.trim()));
+    }
+
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let changed = db
+        .record_device_facts(device_id, &facts, unix_timestamp())
+        .map_err(|e| e.to_string())?;
+    if changed {
+        info!("Facts of device {} changed", device_id);
+    }
+    Ok(facts)
+}
+
+/// Connect to a stored device and refresh its facts
+pub async fn refresh(state: &AppState, device_id: i32) -> Result<DeviceFacts, String> {
+    let (_device, session) = crate::ssh::connect_device(state, device_id).await?;
+    collect(state, device_id, &session).await
+}
+
+/// History of a device's facts, newest first, with the fields that changed
+/// compared with the previous record
+pub fn history(state: &AppState, device_id: i32) -> Result<Vec<FactsChange>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let records = db
+        .get_device_facts_history(device_id)
+        .map_err(|e| e.to_string())?;
+    let changes = records
+        .iter()
+        .enumerate()
+        .map(|(i, record)| FactsChange {
+            changed: records
+                .get(i + 1)
+                .map(|prev| changed_fields(&prev.facts, &record.facts))
+                .unwrap_or_default()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            record: record.clone(),
+        })
+        .collect();
+    Ok(changes)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FactsChange {
+    #[serde(flatten)]
+    pub record: DeviceFactsRecord,
+    /// Empty for the first record of a device
+    pub changed: Vec<String>,
+}
+
+/// Refresh the facts of every device in the background
+pub fn spawn_scheduler(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+
+            let state = app.state::<AppState>();
+            let state: &AppState = &state;
+            let devices = match state
+                .db
+                .lock()
+                .map_err(|e| e.to_string())
+                .and_then(|db| db.get_all_devices().map_err(|e| e.to_string()))
+            {
+                Ok(devices) => devices,
+                Err(e) => {
+                    error!("Failed to load devices for fact gathering: {}", e);
+                    continue;
+                }
+            };
+
+            futures_util::stream::iter(devices)
+                .for_each_concurrent(crate::fleet::concurrency(None), |device| async move {
+                    if let Err(e) = refresh(state, device.id).await {
+                        error!("Failed to gather facts for {}: {}", device.name, e);
+                    }
+                })
+                .await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBIAN: &str = r#"
+==uname==
+Linux
+6.1.0-18-amd64
+x86_64
+==hostname==
+web01
+==os_release==
+PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+ID=debian
+==cpuinfo==
+processor	: 0
+model name	: Intel(R) Xeon(R) CPU E5-2680 v4 @ 2.40GHz
+processor	: 1
+model name	: Intel(R) Xeon(R) CPU E5-2680 v4 @ 2.40GHz
+==nproc==
+2
+==meminfo==
+MemTotal:        4028724 kB
+==macs==
+eth0 52:54:00:AB:CD:EF
+lo 00:00:00:00:00:00
+==serial==
+To Be Filled By O.E.M.
+"#;
+
+    const RASPBERRY_PI: &str = r#"
+==uname==
+Linux
+6.6.20+rpt-rpi-v8
+aarch64
+==hostname==
+pi
+==os_release==
+NAME='Raspbian GNU/Linux'
+VERSION_ID='12'
+==cpuinfo==
+processor	: 0
+BogoMIPS	: 108.00
+processor	: 1
+BogoMIPS	: 108.00
+Hardware	: BCM2835
+Serial		: 10000000a3b4c5d6
+Model		: Raspberry Pi 4 Model B Rev 1.4
+==nproc==
+==meminfo==
+MemTotal:        3882924 kB
+==macs==
+eth0 dc:a6:32:00:11:22
+==serial==
+"#;
+
+    #[test]
+    fn parses_debian_facts() {
+        let facts = parse_facts(DEBIAN);
+        assert_eq!(facts.hostname.as_deref(), Some("web01"));
+        assert_eq!(facts.os.as_deref(), Some("Linux"));
+        assert_eq!(facts.kernel.as_deref(), Some("6.1.0-18-amd64"));
+        assert_eq!(facts.arch.as_deref(), Some("x86_64"));
+        assert_eq!(facts.distro.as_deref(), Some("Debian GNU/Linux"));
+        assert_eq!(facts.distro_version.as_deref(), Some("12"));
+        assert_eq!(
+            facts.cpu_model.as_deref(),
+            Some("Intel(R) Xeon(R) CPU E5-2680 v4 @ 2.40GHz")
+        );
+        assert_eq!(facts.cpu_count, Some(2));
+        assert_eq!(facts.memory_total_mb, Some(3934));
+        assert_eq!(
+            facts.mac_addresses,
+            vec![NetworkInterface {
+                name: "eth0".to_string(),
+                mac: "52:54:00:ab:cd:ef".to_string()
+            }]
+        );
+        assert_eq!(facts.serial, None);
+    }
+
+    #[test]
+    fn parses_raspberry_pi_facts() {
+        let facts = parse_facts(RASPBERRY_PI);
+        assert_eq!(facts.distro.as_deref(), Some("Raspbian GNU/Linux"));
+        assert_eq!(
+            facts.cpu_model.as_deref(),
+            Some("Raspberry Pi 4 Model B Rev 1.4")
+        );
+        assert_eq!(facts.cpu_count, Some(2));
+        assert_eq!(facts.serial.as_deref(), Some("10000000a3b4c5d6"));
+    }
+
+    #[test]
+    fn changed_fields_lists_differences() {
+        let old = parse_facts(DEBIAN);
+        let mut new = old.clone();
+        new.kernel = Some("6.1.0-21-amd64".to_string());
+        new.memory_total_mb = Some(8000);
+        assert_eq!(
+            changed_fields(&old, &new),
+            vec!["kernel", "memory_total_mb"]
+        );
+        assert!(changed_fields(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,623 @@
+//! Jobs that act on many devices at once, starting with file distribution.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device};
+use crate::sftp::{sanitize_path, DeviceFs};
+use crate::ssh::{privileged, shell_quote};
+use futures_util::StreamExt;
+use log::{error, info};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::io::AsyncReadExt;
+
+/// Tauri event emitted with a [`PushResult`] as each device finishes
+pub const FILE_PUSH_EVENT: &str = "file-push-result";
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const MAX_CONCURRENCY: usize = 32;
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Devices whose id is listed or that carry any of the tags
+pub fn select_devices(
+    state: &AppState,
+    device_ids: &[i32],
+    tags: &[String],
+) -> Result<Vec<Device>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let devices = db.get_all_devices().map_err(|e| e.to_string())?;
+    let selected: Vec<Device> = devices
+        .into_iter()
+        .filter(|d| device_ids.contains(&d.id) || d.tags.iter().any(|t| tags.contains(t)))
+        .collect();
+    if selected.is_empty() {
+        return Err("No devices match the selection".to_string());
+    }
+    Ok(selected)
+}
+
+/// Clamp a requested concurrency to a sane range
+pub fn concurrency(requested: Option<usize>) -> usize {
+    requested
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .clamp(1, MAX_CONCURRENCY)
+}
+
+/// What to push and how to install it
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PushSpec {
+    /// Local file or directory
+    pub local_path: String,
+    /// Destination file, or destination directory when pushing a directory
+    pub remote_path: String,
+    /// Octal mode applied to every installed file, e.g. `0644`
+    pub mode: Option<String>,
+    /// `user` or `user:group` applied to every installed file
+    pub owner: Option<String>,
+    /// Run after a successful install, as root
+    pub post_command: Option<String>,
+}
+
+/// Outcome of a push on one device
+#[derive(Debug, Clone, Serialize)]
+pub struct PushResult {
+    pub device_id: i32,
+    pub device_name: String,
+    pub success: bool,
+    pub files: usize,
+    pub bytes: u64,
+    /// Failed step and its error
+    pub error: Option<String>,
+    pub post_command_output: Option<String>,
+    pub post_command_exit_code: Option<i32>,
+}
+
+/// A local file and where it goes on the device
+#[derive(Debug, Clone)]
+pub struct PushFile {
+    pub local: PathBuf,
+    pub remote: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+fn validate_mode(mode: &str) -> Result<(), String> {
+    let valid = (3..=4).contains(&mode.len()) && mode.chars().all(|c| ('0'..='7').contains(&c));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid mode: {}", mode))
+    }
+}
+
+fn validate_owner(owner: &str) -> Result<(), String> {
+    let valid_name = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    };
+    let valid = match owner.split_once(':') {
+        Some((user, group)) => valid_name(user) && valid_name(group),
+        None => valid_name(owner),
+    };
+    if valid && !owner.starts_with('-') {
+        Ok(())
+    } else {
+        Err(format!("Invalid owner: {}", owner))
+    }
+}
+
+/// Regular files under `dir`, as paths relative to it; symlinks are skipped
+fn walk(dir: &Path, relative: &Path, out: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        let file_type = entry
+            .file_type()
+            .map_err(|e| format!("Failed to stat {}: {}", entry.path().display(), e))?;
+        let rel = relative.join(entry.file_name());
+        if file_type.is_dir() {
+            walk(&entry.path(), &rel, out)?;
+        } else if file_type.is_file() {
+            out.push(rel);
+        }
+    }
+    Ok(())
+}
+
+/// Validate the spec and hash every file to push
+pub async fn prepare(spec: &PushSpec) -> Result<Vec<PushFile>, String> {
+    if let Some(mode) = &spec.mode {
+        validate_mode(mode)?;
+    }
+    if let Some(owner) = &spec.owner {
+        validate_owner(owner)?;
+    }
+    let remote_root = sanitize_path(&spec.remote_path).map_err(|e| e.message)?;
+    if remote_root == "/" {
+        return Err("Refusing to push to /".to_string());
+    }
+
+    let local_root = PathBuf::from(&spec.local_path);
+    if !local_root.is_absolute() {
+        return Err(format!("Local path must be absolute: {}", spec.local_path));
+    }
+    let pairs: Vec<(PathBuf, String)> = if local_root.is_dir() {
+        let mut relative = Vec::new();
+        walk(&local_root, Path::new(""), &mut relative)?;
+        relative.sort();
+        relative
+            .into_iter()
+            .map(|rel| {
+                let remote = format!("{}/{}", remote_root, rel.to_string_lossy());
+                (local_root.join(rel), remote)
+            })
+            .collect()
+    } else if local_root.is_file() {
+        vec![(local_root, remote_root)]
+    } else {
+        return Err(format!("No such file or directory: {}", spec.local_path));
+    };
+    if pairs.is_empty() {
+        return Err(format!("Nothing to push in {}", spec.local_path));
+    }
+
+    let mut files = Vec::with_capacity(pairs.len());
+    for (local, remote) in pairs {
+        let local_str = local.to_string_lossy().to_string();
+        let size = std::fs::metadata(&local)
+            .map_err(|e| format!("Failed to stat {}: {}", local_str, e))?
+            .len();
+        let sha256 = crate::transfer::local_sha256(&local_str).await?;
+        files.push(PushFile {
+            local,
+            remote,
+            size,
+            sha256,
+        });
+    }
+    Ok(files)
+}
+
+/// Script that verifies staged files, then installs them atomically
+///
+/// Every checksum is checked before anything is installed, so a corrupt
+/// upload leaves the device untouched. Each file is copied next to its
+/// destination, given its mode and owner, and renamed over it.
+pub fn install_script(staging: &str, files: &[PushFile], spec: &PushSpec) -> String {
+    let mut script = String::from(
+        r#"set -e
+verify() {
+    f=$1 want=$2 dest=$3
+    set -- $(sha256sum -- "$f")
+    [ "$1" = "$want" ] || { echo "checksum mismatch for $dest" >&2; exit 3; }
+}
+install_file() {
+    src=$1 dest=$2
+    mkdir -p -- "$(dirname -- "$dest")"
+    cp -- "$src" "$dest.ssedge-new"
+"#,
+    );
+    if let Some(mode) = &spec.mode {
+        script.push_str(&format!("    chmod {} \"$dest.ssedge-new\"\n", mode));
+    }
+    if let Some(owner) = &spec.owner {
+        script.push_str(&format!("    chown {} \"$dest.ssedge-new\"\n", owner));
+    }
+    script.push_str("    mv -f -- \"$dest.ssedge-new\" \"$dest\"\n}\n");
+
+    let staged = |i: usize| shell_quote(&format!("{}/{}", staging, i));
+    for (i, file) in files.iter().enumerate() {
+        script.push_str(&format!(
+            "verify {} {} {}\n",
+            staged(i),
+            file.sha256,
+            shell_quote(&file.remote)
+        ));
+    }
+    for (i, file) in files.iter().enumerate() {
+        script.push_str(&format!(
+            "install_file {} {}\n",
+            staged(i),
+            shell_quote(&file.remote)
+        ));
+    }
+    script.push_str(&format!("rm -rf -- {}\n", shell_quote(staging)));
+    script
+}
+
+/// Upload every file into `staging` as `staging/<index>`
+async fn stage(fs: &DeviceFs, staging: &str, files: &[PushFile]) -> Result<u64, String> {
+    fs.sftp()
+        .fs()
+        .create_dir(staging)
+        .await
+        .map_err(|e| crate::sftp::FileError::from_sftp(e, staging).message)?;
+
+    let mut bytes = 0;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    for (i, file) in files.iter().enumerate() {
+        let path = format!("{}/{}", staging, i);
+        let mut remote = fs
+            .sftp()
+            .create(&path)
+            .await
+            .map_err(|e| crate::sftp::FileError::from_sftp(e, &path).message)?;
+        let mut local = tokio::fs::File::open(&file.local)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", file.local.display(), e))?;
+        loop {
+            let n = local
+                .read(&mut buf)
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", file.local.display(), e))?;
+            if n == 0 {
+                break;
+            }
+            remote
+                .write_all(&buf[..n])
+                .await
+                .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            bytes += n as u64;
+        }
+        remote
+            .close()
+            .await
+            .map_err(|e| format!("Failed to close {}: {}", path, e))?;
+    }
+    Ok(bytes)
+}
+
+pub(crate) async fn push_to_device(
+    state: &AppState,
+    device: &Device,
+    files: &[PushFile],
+    spec: &PushSpec,
+) -> PushResult {
+    let mut result = PushResult {
+        device_id: device.id,
+        device_name: device.name.clone(),
+        success: false,
+        files: files.len(),
+        bytes: 0,
+        error: None,
+        post_command_output: None,
+        post_command_exit_code: None,
+    };
+
+    let fs = match DeviceFs::open(state, device.id).await {
+        Ok(fs) => fs,
+        Err(e) => {
+            result.error = Some(format!("connect: {}", e.message));
+            return result;
+        }
+    };
+    let outcome = install(state, &fs, device, files, spec, &mut result).await;
+    fs.close().await;
+    match outcome {
+        Ok(()) => result.success = true,
+        Err(e) => result.error = Some(e),
+    }
+    result
+}
+
+async fn install(
+    state: &AppState,
+    fs: &DeviceFs,
+    device: &Device,
+    files: &[PushFile],
+    spec: &PushSpec,
+    result: &mut PushResult,
+) -> Result<(), String> {
+    let privilege = crate::ssh::Privilege::for_device(state, device);
+    let staging = format!("/tmp/ssedge-push-{}-{}", unix_timestamp(), device.id);
+    result.bytes = match stage(fs, &staging, files).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            // Staged files belong to the login user, so no escalation is needed
+            let _ = crate::ssh::exec(
+                fs.session(),
+                &format!("rm -rf -- {}", shell_quote(&staging)),
+            )
+            .await;
+            return Err(format!("upload: {}", e));
+        }
+    };
+
+    let script = install_script(&staging, files, spec);
+    let command = privileged(&format!("sh -c {}", shell_quote(&script)));
+    let output = crate::ssh::exec_as(fs.session(), &privilege, &command).await?;
+    crate::ssh::log_command(
+        state,
+        device.id,
+        &format!("push {} -> {}", spec.local_path, spec.remote_path),
+        Some(&output.log_text()),
+    );
+    if !output.success() {
+        // Leave nothing behind if the install script bailed out early
+        let _ = crate::ssh::exec_as(
+            fs.session(),
+            &privilege,
+            &privileged(&format!("rm -rf -- {}", shell_quote(&staging))),
+        )
+        .await;
+        return Err(format!("install: {}", output.stderr.trim()));
+    }
+
+    if let Some(post) = spec
+        .post_command
+        .as_deref()
+        .filter(|c| !c.trim().is_empty())
+    {
+        let command = privileged(&format!("sh -c {}", shell_quote(post)));
+        let output = crate::ssh::exec_as(fs.session(), &privilege, &command).await?;
+        crate::ssh::log_command(state, device.id, post, Some(&output.log_text()));
+        result.post_command_output = Some(output.log_text());
+        result.post_command_exit_code = output.exit_code;
+        if !output.success() {
+            return Err(format!("post-install command: {}", output.stderr.trim()));
+        }
+    }
+    Ok(())
+}
+
+/// Push to every device with at most `concurrency` in flight, emitting each result as it lands
+pub async fn push(
+    app: &AppHandle,
+    devices: Vec<Device>,
+    spec: PushSpec,
+    concurrency: usize,
+) -> Result<Vec<PushResult>, String> {
+    let files = prepare(&spec).await?;
+    info!(
+        "Pushing {} file(s) from {} to {} on {} device(s)",
+        files.len(),
+        spec.local_path,
+        spec.remote_path,
+        devices.len()
+    );
+
+    let state = app.state::<AppState>();
+    let state: &AppState = &state;
+    let files = &files;
+    let spec = &spec;
+    let results: Vec<PushResult> = futures_util::stream::iter(devices)
+        .map(|device| async move {
+            let result = push_to_device(state, &device, files, spec).await;
+            match &result.error {
+                None => info!("Pushed {} to {}", spec.remote_path, device.name),
+                Some(e) => error!(
+                    "Push of {} to {} failed: {}",
+                    spec.remote_path, device.name, e
+                ),
+            }
+            if let Err(e) = app.emit(FILE_PUSH_EVENT, &result) {
+                error!("Failed to emit push result: {}", e);
+            }
+            result
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut results = results;
+    results.sort_by_key(|r| r.device_id);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn spec(mode: Option<&str>, owner: Option<&str>) -> PushSpec {
+        PushSpec {
+            local_path: String::new(),
+            remote_path: String::new(),
+            mode: mode.map(str::to_string),
+            owner: owner.map(str::to_string),
+            post_command: None,
+        }
+    }
+
+    fn push_file(remote: &str, sha256: &str) -> PushFile {
+        PushFile {
+            local: PathBuf::new(),
+            remote: remote.to_string(),
+            size: 5,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    /// Empty scratch directory unique to this test process
+    #[cfg(unix)]
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ssedge-fleet-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    fn run_sh(script: &str) -> std::process::Output {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap()
+    }
+
+    #[test]
+    fn validates_modes() {
+        for mode in ["644", "0644", "0755", "1777"] {
+            assert!(validate_mode(mode).is_ok(), "{}", mode);
+        }
+        for mode in ["", "64", "00644", "0888", "u+x", "-644", "0644 "] {
+            assert!(validate_mode(mode).is_err(), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn validates_owners() {
+        for owner in ["pi", "www-data:www-data", "svc.user:staff", "1000:1000"] {
+            assert!(validate_owner(owner).is_ok(), "{}", owner);
+        }
+        for owner in [
+            "",
+            ":",
+            "pi:",
+            ":staff",
+            "-R",
+            "pi:staff:x",
+            "pi staff",
+            "pi;id",
+            "root$(id)",
+        ] {
+            assert!(validate_owner(owner).is_err(), "{:?}", owner);
+        }
+    }
+
+    #[test]
+    fn install_script_verifies_everything_before_installing() {
+        let files = [
+            push_file("/etc/app/a b.conf", HELLO_SHA256),
+            push_file("/etc/app/it's", HELLO_SHA256),
+        ];
+        let script = install_script("/tmp/stage", &files, &spec(Some("0640"), Some("pi:pi")));
+        assert!(script.contains("    chmod 0640 \"$dest.ssedge-new\"\n"));
+        assert!(script.contains("    chown pi:pi \"$dest.ssedge-new\"\n"));
+        assert!(script.contains(&format!(
+            "verify '/tmp/stage/0' {} '/etc/app/a b.conf'\n",
+            HELLO_SHA256
+        )));
+        assert!(script.contains(r#"install_file '/tmp/stage/1' '/etc/app/it'\''s'"#));
+        let last_verify = script.rfind("\nverify ").unwrap();
+        let first_install = script.find("\ninstall_file ").unwrap();
+        assert!(last_verify < first_install);
+        assert!(script.ends_with("rm -rf -- '/tmp/stage'\n"));
+
+        let plain = install_script("/tmp/stage", &files, &spec(None, None));
+        assert!(!plain.contains("chmod") && !plain.contains("chown"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn install_script_installs_staged_files() {
+        let root = scratch("install");
+        let staging = root.join("stage");
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::write(staging.join("0"), "hello").unwrap();
+        std::fs::write(staging.join("1"), "hello").unwrap();
+        let first = root.join("etc/app.conf");
+        let second = root.join("srv/with space/data");
+        let files = [
+            push_file(first.to_str().unwrap(), HELLO_SHA256),
+            push_file(second.to_str().unwrap(), HELLO_SHA256),
+        ];
+
+        let script = install_script(staging.to_str().unwrap(), &files, &spec(Some("0640"), None));
+        let output = run_sh(&script);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        for dest in [&first, &second] {
+            assert_eq!(std::fs::read_to_string(dest).unwrap(), "hello");
+            let mode = std::fs::metadata(dest).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+        assert!(!staging.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checksum_mismatch_names_the_destination_and_installs_nothing() {
+        let root = scratch("mismatch");
+        let staging = root.join("stage");
+        std::fs::create_dir(&staging).unwrap();
+        std::fs::write(staging.join("0"), "hello").unwrap();
+        std::fs::write(staging.join("1"), "corrupt").unwrap();
+        let first = root.join("a.conf");
+        let second = root.join("b dir/b.conf");
+        let files = [
+            push_file(first.to_str().unwrap(), HELLO_SHA256),
+            push_file(second.to_str().unwrap(), HELLO_SHA256),
+        ];
+
+        let output = run_sh(&install_script(
+            staging.to_str().unwrap(),
+            &files,
+            &spec(None, None),
+        ));
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr).trim(),
+            format!("checksum mismatch for {}", second.display())
+        );
+        assert!(!first.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn prepare_maps_a_directory_and_rejects_bad_specs() {
+        let root = scratch("prepare");
+        std::fs::create_dir_all(root.join("conf.d")).unwrap();
+        std::fs::write(root.join("main.conf"), "hello").unwrap();
+        std::fs::write(root.join("conf.d/extra.conf"), "hello").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join("link")).unwrap();
+
+        let mut good = spec(Some("0644"), Some("root:root"));
+        good.local_path = root.to_string_lossy().to_string();
+        good.remote_path = "/etc/app/./sub/../".to_string();
+        let files = prepare(&good).await.unwrap();
+        let remotes: Vec<&str> = files.iter().map(|f| f.remote.as_str()).collect();
+        assert_eq!(
+            remotes,
+            vec!["/etc/app/conf.d/extra.conf", "/etc/app/main.conf"]
+        );
+        assert!(files
+            .iter()
+            .all(|f| f.size == 5 && f.sha256 == HELLO_SHA256));
+
+        let mut single = good.clone();
+        single.local_path = root.join("main.conf").to_string_lossy().to_string();
+        single.remote_path = "/etc/app.conf".to_string();
+        let files = prepare(&single).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].remote, "/etc/app.conf");
+
+        let empty = root.join("empty");
+        std::fs::create_dir(&empty).unwrap();
+        for (change, expected) in [
+            (("mode", "0999"), "Invalid mode"),
+            (("owner", "-R"), "Invalid owner"),
+            (("remote", "/.."), "Refusing to push to /"),
+            (("remote", "etc/app"), "must be absolute"),
+            (("local", "relative/dir"), "Local path must be absolute"),
+            (
+                ("local", "/nonexistent/ssedge"),
+                "No such file or directory",
+            ),
+            (("local", empty.to_str().unwrap()), "Nothing to push"),
+        ] {
+            let mut bad = good.clone();
+            match change.0 {
+                "mode" => bad.mode = Some(change.1.to_string()),
+                "owner" => bad.owner = Some(change.1.to_string()),
+                "remote" => bad.remote_path = change.1.to_string(),
+                _ => bad.local_path = change.1.to_string(),
+            }
+            let error = prepare(&bad).await.unwrap_err();
+            assert!(error.contains(expected), "{:?}: {}", change, error);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,929 @@
+//! Import and export of the device table as CSV, JSON or an Ansible
+//! inventory in INI or YAML form.
+//!
+//! An import is validated as a whole before anything is written: malformed
+//! addresses, entries repeated in the file and devices already stored with
+//! the same address and port are reported and skipped. A dry run stops after
+//! validation so the report can be reviewed first.
+//!
+//! Ansible inventories map groups to tags and host variables to SSH settings
+//! (`ansible_host`, `ansible_user`, `ansible_port`), with `ssedge_*` variables
+//! for the rest. Group names only allow letters, digits and underscores, so
+//! other characters in tags are exported as `_`. Host aliases must be unique,
+//! so devices sharing a name are exported as `name-<id>` with the name kept
+//! in `ssedge_name`.
+
+use crate::command::AppState;
+use crate::db::{Device, NewDevice};
+use crate::ssh::SshConfig;
+use crate::validation;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Groups every Ansible host belongs to, which are not turned into tags
+const IMPLICIT_GROUPS: &[&str] = &["all", "ungrouped"];
+/// Depth of `children` nesting followed, which also stops cycles
+const MAX_GROUP_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InventoryFormat {
+    Csv,
+    Json,
+    AnsibleIni,
+    AnsibleYaml,
+}
+
+impl InventoryFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InventoryFormat::Csv => "csv",
+            InventoryFormat::Json => "json",
+            InventoryFormat::AnsibleIni => "ansible_ini",
+            InventoryFormat::AnsibleYaml => "ansible_yaml",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(InventoryFormat::Csv),
+            "json" => Some(InventoryFormat::Json),
+            "ansible_ini" => Some(InventoryFormat::AnsibleIni),
+            "ansible_yaml" => Some(InventoryFormat::AnsibleYaml),
+            _ => None,
+        }
+    }
+}
+
+/// One device as it appears in an inventory
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InventoryDevice {
+    pub name: String,
+    pub ip: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub strict_host_key_checking: Option<bool>,
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+impl From<&Device> for InventoryDevice {
+    fn from(device: &Device) -> Self {
+        InventoryDevice {
+            name: device.name.clone(),
+            ip: device.ip.clone(),
+            username: device.username.clone(),
+            port: device.port,
+            strict_host_key_checking: device.strict_host_key_checking,
+            connect_timeout: device.connect_timeout,
+            tags: device.tags.clone(),
+            notes: device.notes.clone(),
+        }
+    }
+}
+
+/// CSV has no lists, so tags are joined with `;`
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRow {
+    name: String,
+    ip: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    strict_host_key_checking: Option<bool>,
+    #[serde(default)]
+    connect_timeout: Option<u64>,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportIssue {
+    /// 1-based position of the entry in the file; in Ansible inventories
+    /// hosts count in order of first appearance
+    pub entry: usize,
+    pub name: String,
+    pub ip: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub entries: usize,
+    /// Devices added, or that would be added by a dry run
+    pub devices: Vec<InventoryDevice>,
+    /// Ids of the added devices, empty for a dry run
+    pub device_ids: Vec<i64>,
+    pub issues: Vec<ImportIssue>,
+}
+
+/// An entry read from a file: a device, or why it could not be read
+type Entry = Result<InventoryDevice, String>;
+
+pub fn export(devices: &[Device], format: InventoryFormat) -> Result<String, String> {
+    let entries: Vec<InventoryDevice> = devices.iter().map(InventoryDevice::from).collect();
+    match format {
+        InventoryFormat::Csv => export_csv(&entries),
+        InventoryFormat::Json => serde_json::to_string_pretty(&entries).map_err(|e| e.to_string()),
+        InventoryFormat::AnsibleIni => Ok(export_ini(&ansible_hosts(devices, entries))),
+        InventoryFormat::AnsibleYaml => export_yaml(&ansible_hosts(devices, entries)),
+    }
+}
+
+/// Pair devices with unique Ansible host aliases. Device names need not be
+/// unique, so a name shared by several devices gets `-<id>` appended, and
+/// `ssedge_name` keeps the real name for import.
+fn ansible_hosts(
+    devices: &[Device],
+    entries: Vec<InventoryDevice>,
+) -> Vec<(String, InventoryDevice)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for device in devices {
+        *counts.entry(device.name.as_str()).or_default() += 1;
+    }
+    let mut used: HashSet<String> = devices
+        .iter()
+        .filter(|d| counts[d.name.as_str()] == 1)
+        .map(|d| d.name.clone())
+        .collect();
+    devices
+        .iter()
+        .zip(entries)
+        .map(|(device, entry)| {
+            let mut alias = device.name.clone();
+            if counts[device.name.as_str()] > 1 {
+                alias = format!("{}-{}", device.name, device.id);
+                while !used.insert(alias.clone()) {
+                    alias.push_str(&format!("-{}", device.id));
+                }
+            }
+            (alias, entry)
+        })
+        .collect()
+}
+
+fn export_csv(devices: &[InventoryDevice]) -> Result<String, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for device in devices {
+        writer
+            .serialize(CsvRow {
+                name: device.name.clone(),
+                ip: device.ip.clone(),
+                username: device.username.clone(),
+                port: device.port,
+                strict_host_key_checking: device.strict_host_key_checking,
+                connect_timeout: device.connect_timeout,
+                tags: device.tags.join(";"),
+                notes: device.notes.clone(),
+            })
+            .map_err(|e| e.to_string())?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+/// Ansible group name for a tag
+fn group_name(tag: &str) -> String {
+    tag.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Host variables of a device exported as `alias`, in inventory order
+fn host_vars(alias: &str, device: &InventoryDevice) -> Vec<(&'static str, Value)> {
+    let mut vars = vec![("ansible_host", Value::from(device.ip.clone()))];
+    if alias != device.name {
+        vars.push(("ssedge_name", Value::from(device.name.clone())));
+    }
+    if let Some(username) = &device.username {
+        vars.push(("ansible_user", Value::from(username.clone())));
+    }
+    if let Some(port) = device.port {
+        vars.push(("ansible_port", Value::from(port)));
+    }
+    if let Some(strict) = device.strict_host_key_checking {
+        vars.push(("ssedge_strict_host_key_checking", Value::from(strict)));
+    }
+    if let Some(timeout) = device.connect_timeout {
+        vars.push(("ssedge_connect_timeout", Value::from(timeout)));
+    }
+    if let Some(notes) = &device.notes {
+        vars.push(("ssedge_notes", Value::from(notes.clone())));
+    }
+    vars
+}
+
+/// Host aliases grouped by tag, for the group sections of an inventory
+fn groups(hosts: &[(String, InventoryDevice)]) -> BTreeMap<String, Vec<&str>> {
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (alias, device) in hosts {
+        for tag in &device.tags {
+            let group = group_name(tag);
+            if !group.is_empty() && !IMPLICIT_GROUPS.contains(&group.as_str()) {
+                groups.entry(group).or_default().push(alias);
+            }
+        }
+    }
+    groups
+}
+
+/// Quote an INI token the way Ansible splits host lines, like a shell would
+fn ini_quote(s: &str) -> String {
+    if !s.is_empty()
+        && !s
+            .chars()
+            .any(|c| c.is_whitespace() || "\"'\\#=;".contains(c))
+    {
+        return s.to_string();
+    }
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn export_ini(hosts: &[(String, InventoryDevice)]) -> String {
+    let mut out = String::new();
+    for (alias, device) in hosts {
+        out.push_str(&ini_quote(alias));
+        for (key, value) in host_vars(alias, device) {
+            let value = match value {
+                Value::String(s) => s.replace(['\n', '\r'], " "),
+                Value::Bool(b) => b.to_string(),
+                Value::Number(n) => n.to_string(),
+                _ => continue,
+            };
+            out.push_str(&format!(" {}={}", key, ini_quote(&value)));
+        }
+        out.push('\n');
+    }
+    for (group, members) in groups(hosts) {
+        out.push_str(&format!("\n[{}]\n", group));
+        for host in members {
+            out.push_str(&ini_quote(host));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn export_yaml(hosts: &[(String, InventoryDevice)]) -> Result<String, String> {
+    let mut host_map = Mapping::new();
+    for (alias, device) in hosts {
+        let vars: Mapping = host_vars(alias, device)
+            .into_iter()
+            .map(|(key, value)| (Value::from(key), value))
+            .collect();
+        host_map.insert(Value::from(alias.clone()), Value::Mapping(vars));
+    }
+    let mut children = Mapping::new();
+    for (group, names) in groups(hosts) {
+        let members: Mapping = names
+            .into_iter()
+            .map(|name| (Value::from(name), Value::Null))
+            .collect();
+        let mut group_value = Mapping::new();
+        group_value.insert(Value::from("hosts"), Value::Mapping(members));
+        children.insert(Value::from(group), Value::Mapping(group_value));
+    }
+
+    let mut all = Mapping::new();
+    all.insert(Value::from("hosts"), Value::Mapping(host_map));
+    if !children.is_empty() {
+        all.insert(Value::from("children"), Value::Mapping(children));
+    }
+    let mut root = Mapping::new();
+    root.insert(Value::from("all"), Value::Mapping(all));
+    serde_yaml::to_string(&root).map_err(|e| e.to_string())
+}
+
+/// Read the entries of a file; fails only when the file as a whole is unreadable
+pub fn parse(text: &str, format: InventoryFormat) -> Result<Vec<Entry>, String> {
+    match format {
+        InventoryFormat::Csv => Ok(parse_csv(text)),
+        InventoryFormat::Json => parse_json(text),
+        InventoryFormat::AnsibleIni => parse_ini(text),
+        InventoryFormat::AnsibleYaml => parse_yaml(text),
+    }
+}
+
+fn parse_csv(text: &str) -> Vec<Entry> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    reader
+        .deserialize::<CsvRow>()
+        .map(|row| {
+            let row = row.map_err(|e| e.to_string())?;
+            Ok(InventoryDevice {
+                name: row.name,
+                ip: row.ip,
+                username: row.username,
+                port: row.port,
+                strict_host_key_checking: row.strict_host_key_checking,
+                connect_timeout: row.connect_timeout,
+                tags: row
+                    .tags
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(String::from)
+                    .collect(),
+                notes: row.notes,
+            })
+        })
+        .collect()
+}
+
+fn parse_json(text: &str) -> Result<Vec<Entry>, String> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_str(text).map_err(|e| format!("Invalid JSON inventory: {}", e))?;
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+        .collect())
+}
+
+/// A host collected from an Ansible inventory, before its variables are read
+#[derive(Debug, Default)]
+struct AnsibleHost {
+    name: String,
+    vars: BTreeMap<String, String>,
+    /// Variables from the host's groups, closest group first
+    group_vars: BTreeMap<String, String>,
+    groups: Vec<String>,
+}
+
+impl AnsibleHost {
+    fn var(&self, key: &str) -> Option<&str> {
+        self.vars
+            .get(key)
+            .or_else(|| self.group_vars.get(key))
+            .map(String::as_str)
+            .filter(|v| !v.is_empty())
+    }
+
+    fn into_device(self) -> Entry {
+        fn parsed<T: std::str::FromStr>(
+            host: &AnsibleHost,
+            key: &str,
+        ) -> Result<Option<T>, String> {
+            host.var(key)
+                .map(|v| {
+                    v.to_ascii_lowercase()
+                        .parse()
+                        .map_err(|_| format!("{}: invalid {} {:?}", host.name, key, v))
+                })
+                .transpose()
+        }
+        Ok(InventoryDevice {
+            ip: self.var("ansible_host").unwrap_or(&self.name).to_string(),
+            username: self.var("ansible_user").map(String::from),
+            port: parsed(&self, "ansible_port")?,
+            strict_host_key_checking: parsed(&self, "ssedge_strict_host_key_checking")?,
+            connect_timeout: parsed(&self, "ssedge_connect_timeout")?,
+            notes: self.var("ssedge_notes").map(String::from),
+            tags: self
+                .groups
+                .iter()
+                .filter(|g| !IMPLICIT_GROUPS.contains(&g.as_str()))
+                .cloned()
+                .collect(),
+            name: self
+                .var("ssedge_name")
+                .map(String::from)
+                .unwrap_or(self.name),
+        })
+    }
+}
+
+/// Hosts in order of first appearance
+#[derive(Default)]
+struct AnsibleHosts {
+    hosts: Vec<AnsibleHost>,
+    index: HashMap<String, usize>,
+}
+
+impl AnsibleHosts {
+    fn get(&mut self, name: &str) -> &mut AnsibleHost {
+        let i = *self.index.entry(name.to_string()).or_insert_with(|| {
+            self.hosts.push(AnsibleHost {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            self.hosts.len() - 1
+        });
+        &mut self.hosts[i]
+    }
+
+    fn add_to_group(&mut self, name: &str, group: &str) {
+        let host = self.get(name);
+        if !host.groups.iter().any(|g| g == group) {
+            host.groups.push(group.to_string());
+        }
+    }
+}
+
+/// Split a line into words, honouring quotes and backslash escapes
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '#') if !in_word => break,
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None | Some('"'), '\\') => {
+                word.push(chars.next().ok_or("Line ends with a backslash")?);
+                in_word = true;
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("Unterminated quote in {:?}", line));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+enum IniSection {
+    Hosts(String),
+    Vars(String),
+    Children(String),
+}
+
+fn parse_ini(text: &str) -> Result<Vec<Entry>, String> {
+    let mut hosts = AnsibleHosts::default();
+    let mut group_vars: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+    let mut parents: HashMap<String, Vec<String>> = HashMap::new();
+    // Each error with the number of hosts seen before it, to keep file order
+    let mut errors: Vec<(usize, String)> = Vec::new();
+    let mut section = IniSection::Hosts("ungrouped".to_string());
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = match header.split_once(':') {
+                Some((group, "vars")) => IniSection::Vars(group.to_string()),
+                Some((group, "children")) => IniSection::Children(group.to_string()),
+                Some(_) => return Err(format!("Line {}: unknown section [{}]", n + 1, header)),
+                None => IniSection::Hosts(header.to_string()),
+            };
+            continue;
+        }
+        let words = split_words(line).map_err(|e| format!("Line {}: {}", n + 1, e))?;
+        match &section {
+            IniSection::Hosts(group) => {
+                let (name, vars) = words.split_first().ok_or("Empty host line")?;
+                if name.contains('[') {
+                    errors.push((
+                        hosts.hosts.len(),
+                        format!("Line {}: host ranges are not supported", n + 1),
+                    ));
+                    continue;
+                }
+                // `host:2222`, but not an IPv6 address
+                let (name, port) = match name.split_once(':') {
+                    Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                    _ => (name.as_str(), None),
+                };
+                hosts.add_to_group(name, group);
+                let host = hosts.get(name);
+                if let Some(port) = port {
+                    host.vars
+                        .insert("ansible_port".to_string(), port.to_string());
+                }
+                for var in vars {
+                    let (key, value) = var.split_once('=').ok_or_else(|| {
+                        format!("Line {}: expected key=value, got {:?}", n + 1, var)
+                    })?;
+                    host.vars.insert(key.to_string(), value.to_string());
+                }
+            }
+            IniSection::Vars(group) => {
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| format!("Line {}: expected key=value", n + 1))?;
+                let value = split_words(value.trim())
+                    .map_err(|e| format!("Line {}: {}", n + 1, e))?
+                    .join(" ");
+                group_vars
+                    .entry(group.clone())
+                    .or_default()
+                    .insert(key.trim().to_string(), value);
+            }
+            IniSection::Children(group) => {
+                for child in words {
+                    parents.entry(child).or_default().push(group.clone());
+                }
+            }
+        }
+    }
+
+    /// Add a group's variables and then its ancestors', keeping the closest
+    fn inherit(
+        group: &str,
+        depth: usize,
+        group_vars: &HashMap<String, BTreeMap<String, String>>,
+        parents: &HashMap<String, Vec<String>>,
+        into: &mut BTreeMap<String, String>,
+    ) {
+        if depth > MAX_GROUP_DEPTH {
+            return;
+        }
+        for (key, value) in group_vars.get(group).into_iter().flatten() {
+            into.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        for parent in parents.get(group).into_iter().flatten() {
+            inherit(parent, depth + 1, group_vars, parents, into);
+        }
+    }
+    for host in &mut hosts.hosts {
+        for group in host.groups.iter().map(String::as_str).chain(["all"]) {
+            inherit(group, 0, &group_vars, &parents, &mut host.group_vars);
+        }
+    }
+
+    let mut errors = errors.into_iter().peekable();
+    let mut entries = Vec::new();
+    for (i, host) in hosts.hosts.into_iter().enumerate() {
+        while let Some((_, error)) = errors.next_if(|(before, _)| *before == i) {
+            entries.push(Err(error));
+        }
+        entries.push(host.into_device());
+    }
+    entries.extend(errors.map(|(_, error)| Err(error)));
+    Ok(entries)
+}
+
+/// Scalar YAML value as the string Ansible would template it to
+fn yaml_scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn yaml_vars(value: Option<&Value>) -> BTreeMap<String, String> {
+    value
+        .and_then(Value::as_mapping)
+        .into_iter()
+        .flatten()
+        .filter_map(|(k, v)| Some((yaml_scalar(k)?, yaml_scalar(v)?)))
+        .collect()
+}
+
+fn walk_yaml_group(
+    name: &str,
+    group: &Value,
+    inherited: &BTreeMap<String, String>,
+    depth: usize,
+    hosts: &mut AnsibleHosts,
+) {
+    if depth > MAX_GROUP_DEPTH {
+        return;
+    }
+    let mut vars = yaml_vars(group.get("vars"));
+    for (key, value) in inherited {
+        vars.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+    for (host, host_vars) in group
+        .get("hosts")
+        .and_then(Value::as_mapping)
+        .into_iter()
+        .flatten()
+    {
+        let Some(host) = yaml_scalar(host) else {
+            continue;
+        };
+        hosts.add_to_group(&host, name);
+        let entry = hosts.get(&host);
+        entry.vars.extend(yaml_vars(Some(host_vars)));
+        for (key, value) in &vars {
+            entry
+                .group_vars
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+    for (child, child_group) in group
+        .get("children")
+        .and_then(Value::as_mapping)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(child) = yaml_scalar(child) {
+            walk_yaml_group(&child, child_group, &vars, depth + 1, hosts);
+        }
+    }
+}
+
+fn parse_yaml(text: &str) -> Result<Vec<Entry>, String> {
+    let root: Value =
+        serde_yaml::from_str(text).map_err(|e| format!("Invalid YAML inventory: {}", e))?;
+    let groups = root
+        .as_mapping()
+        .ok_or("A YAML inventory maps group names to groups")?;
+    let mut hosts = AnsibleHosts::default();
+    for (name, group) in groups {
+        if let Some(name) = yaml_scalar(name) {
+            walk_yaml_group(&name, group, &BTreeMap::new(), 0, &mut hosts);
+        }
+    }
+    Ok(hosts
+        .hosts
+        .into_iter()
+        .map(AnsibleHost::into_device)
+        .collect())
+}
+
+/// Check a device read from a file with [`crate::validation`] and clean up
+/// its optional fields; an empty name falls back to the address
+fn normalize(mut device: InventoryDevice, existing: &[Device]) -> Result<InventoryDevice, String> {
+    if device.name.trim().is_empty() {
+        device.name = device.ip.trim().to_string();
+    }
+    let valid = validation::validate_device(&device.name, &device.ip, device.port, existing, None)
+        .map_err(|errors| validation::describe(&errors))?;
+    device.name = valid.name;
+    device.ip = valid.ip;
+    device.username = device
+        .username
+        .map(|u| u.trim().to_string())
+        .filter(|u| !u.is_empty());
+    device.notes = device.notes.filter(|n| !n.trim().is_empty());
+    Ok(device)
+}
+
+/// Split entries into importable devices and issues, given the stored devices
+pub fn validate(
+    entries: Vec<Entry>,
+    existing: &[Device],
+) -> (Vec<InventoryDevice>, Vec<ImportIssue>) {
+    let mut seen: HashSet<(String, u16)> = HashSet::new();
+    let mut devices = Vec::new();
+    let mut issues = Vec::new();
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        let (name, ip) = match &entry {
+            Ok(device) => (device.name.clone(), device.ip.clone()),
+            Err(_) => (String::new(), String::new()),
+        };
+        let result = entry
+            .and_then(|device| normalize(device, existing))
+            .and_then(|device| {
+                if !seen.insert(validation::endpoint(&device.ip, device.port)) {
+                    return Err("Duplicate of an earlier entry".to_string());
+                }
+                Ok(device)
+            });
+        match result {
+            Ok(device) => devices.push(device),
+            Err(error) => issues.push(ImportIssue {
+                entry: i + 1,
+                name,
+                ip,
+                error,
+            }),
+        }
+    }
+    (devices, issues)
+}
+
+/// Validate an inventory and, unless `dry_run`, add its valid devices
+pub fn import(
+    state: &AppState,
+    text: &str,
+    format: InventoryFormat,
+    dry_run: bool,
+) -> Result<ImportReport, String> {
+    let entries = parse(text, format)?;
+    let total = entries.len();
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let existing = db.get_all_devices().map_err(|e| e.to_string())?;
+    let (devices, issues) = validate(entries, &existing);
+
+    let device_ids = if dry_run {
+        Vec::new()
+    } else {
+        let rows: Vec<NewDevice> = devices
+            .iter()
+            .map(|device| NewDevice {
+                name: device.name.clone(),
+                ip: device.ip.clone(),
+                config: SshConfig {
+                    username: device.username.clone(),
+                    port: device.port,
+                    strict_host_key_checking: device.strict_host_key_checking,
+                    connect_timeout: device.connect_timeout,
+                },
+                tags: device.tags.clone(),
+                notes: device.notes.clone(),
+            })
+            .collect();
+        db.insert_devices(&rows).map_err(|e| e.to_string())?
+    };
+    info!(
+        "{} {} inventory: {} of {} entries valid, {} issue(s)",
+        if dry_run { "Checked" } else { "Imported" },
+        format.as_str(),
+        devices.len(),
+        total,
+        issues.len()
+    );
+    Ok(ImportReport {
+        dry_run,
+        entries: total,
+        devices,
+        device_ids,
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: i32, name: &str, ip: &str, tags: &[&str]) -> Device {
+        Device {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Device::fixture(id, name, ip)
+        }
+    }
+
+    fn sample() -> Vec<Device> {
+        let mut gateway = device(1, "gateway", "192.168.1.1", &["edge", "lab"]);
+        gateway.username = Some("admin".to_string());
+        gateway.port = Some(2222);
+        gateway.strict_host_key_checking = Some(false);
+        gateway.connect_timeout = Some(10);
+        gateway.notes = Some("rack 2, \"top\" shelf".to_string());
+        vec![gateway, device(2, "nas box", "fd00::20", &["lab"])]
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        let devices = sample();
+        let expected: Vec<InventoryDevice> = devices.iter().map(InventoryDevice::from).collect();
+        for format in [
+            InventoryFormat::Csv,
+            InventoryFormat::Json,
+            InventoryFormat::AnsibleIni,
+            InventoryFormat::AnsibleYaml,
+        ] {
+            let text = export(&devices, format).unwrap();
+            let parsed: Vec<InventoryDevice> = parse(&text, format)
+                .unwrap()
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(parsed, expected, "{}:\n{}", format.as_str(), text);
+        }
+    }
+
+    #[test]
+    fn devices_sharing_a_name_keep_distinct_aliases() {
+        let mut devices = vec![
+            device(3, "pi", "10.0.0.3", &["lab"]),
+            device(4, "pi", "10.0.0.4", &["lab"]),
+            device(5, "pi-4", "10.0.0.5", &[]),
+        ];
+        devices[1].port = Some(2222);
+        let expected: Vec<InventoryDevice> = devices.iter().map(InventoryDevice::from).collect();
+        for format in [InventoryFormat::AnsibleIni, InventoryFormat::AnsibleYaml] {
+            let text = export(&devices, format).unwrap();
+            assert!(text.contains("pi-3"), "{}", text);
+            assert!(text.contains("pi-4-4"), "{}", text);
+            let parsed: Vec<InventoryDevice> = parse(&text, format)
+                .unwrap()
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(parsed, expected, "{}:\n{}", format.as_str(), text);
+        }
+    }
+
+    #[test]
+    fn reads_ansible_ini_groups_and_vars() {
+        let text = r#"
+# lab inventory
+bastion ansible_host=10.0.0.1
+
+[web]
+web1 ansible_host=10.0.0.11 ansible_user=deploy
+db[01:03]
+web2:2200 ansible_host=10.0.0.12
+
+[web:vars]
+ansible_user=www
+
+[prod:children]
+web
+
+[prod:vars]
+ssedge_connect_timeout=5
+"#;
+        let entries = parse_ini(text).unwrap();
+        assert_eq!(entries.len(), 4);
+        let bastion = entries[0].as_ref().unwrap();
+        assert_eq!((bastion.ip.as_str(), bastion.tags.len()), ("10.0.0.1", 0));
+        let web1 = entries[1].as_ref().unwrap();
+        assert_eq!(web1.username.as_deref(), Some("deploy"));
+        assert_eq!(web1.tags, vec!["web"]);
+        assert_eq!(web1.connect_timeout, Some(5));
+        assert!(entries[2]
+            .as_ref()
+            .unwrap_err()
+            .contains("Line 7: host ranges"));
+        let web2 = entries[3].as_ref().unwrap();
+        assert_eq!(web2.port, Some(2200));
+        assert_eq!(web2.username.as_deref(), Some("www"));
+    }
+
+    #[test]
+    fn reads_nested_ansible_yaml() {
+        let text = r#"
+all:
+  vars:
+    ansible_user: ops
+  children:
+    sensors:
+      hosts:
+        probe-1:
+          ansible_host: 10.1.0.5
+          ansible_port: 2022
+        probe-2:
+"#;
+        let entries: Vec<InventoryDevice> = parse_yaml(text)
+            .unwrap()
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries[0].ip, "10.1.0.5");
+        assert_eq!(entries[0].port, Some(2022));
+        assert_eq!(entries[0].username.as_deref(), Some("ops"));
+        assert_eq!(entries[0].tags, vec!["sensors"]);
+        assert_eq!(entries[1].ip, "probe-2");
+    }
+
+    #[test]
+    fn validation_reports_duplicates_and_malformed_addresses() {
+        let existing = vec![device(1, "gateway", "192.168.1.1", &[])];
+        let text = "name,ip,port\n\
+            gw,192.168.1.1,\n\
+            gw-alt,192.168.1.1,2222\n\
+            twin,192.168.1.1,2222\n\
+            bad,300.1.1.1,\n\
+            ,  fileserver.lan ,\n\
+            worse,host_name,\n\
+            port,10.0.0.1,http\n";
+        let (devices, issues) = validate(parse_csv(text), &existing);
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["gw-alt", "fileserver.lan"]);
+        let entries: Vec<usize> = issues.iter().map(|i| i.entry).collect();
+        assert_eq!(entries, vec![1, 3, 4, 6, 7]);
+        assert_eq!(
+            issues[0].error,
+            "ip: 192.168.1.1 port 22 is already used by gateway"
+        );
+        assert_eq!(issues[1].error, "Duplicate of an earlier entry");
+        assert!(issues[2].error.contains("not a valid IPv4 address"));
+        assert!(issues[3]
+            .error
+            .contains("not a valid IP address or hostname"));
+        assert_eq!(issues[3].ip, "host_name");
+    }
+}
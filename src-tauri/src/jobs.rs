@@ -0,0 +1,517 @@
+//! Jobs run against devices on a cron schedule: a command, a saved script or
+//! a file pulled from every device into a local directory.
+//!
+//! The next run time is stored with each job, so runs that fell due while
+//! the app was closed are noticed at startup and either skipped or caught up
+//! with a single run, as the job's missed-run policy says. A job never runs
+//! twice at once; a run that comes due while the last is still going is
+//! recorded as skipped.
+
+use crate::command::AppState;
+use crate::cron::CronSchedule;
+use crate::db::{
+    unix_timestamp, Device, Job, JobAction, JobDeviceResult, JobRun, Script, Transfer,
+};
+use crate::ssh::{privileged, shell_quote};
+use crate::transfer::Direction;
+use futures_util::StreamExt;
+use log::{error, info};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tauri event emitted with the [`JobRun`] when a run finishes or is skipped
+pub const JOB_RUN_EVENT: &str = "job-run";
+
+/// How often the scheduler looks for due jobs
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(20);
+
+/// A run that fell due longer ago than this was missed rather than late
+const MISSED_GRACE_SECS: i64 = 120;
+
+impl JobAction {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            JobAction::Command { command, .. } if command.trim().is_empty() => {
+                Err("A command job needs a command".to_string())
+            }
+            JobAction::FilePull {
+                remote_path,
+                local_dir,
+            } => {
+                crate::sftp::sanitize_path(remote_path).map_err(|e| e.message)?;
+                if !Path::new(local_dir).is_absolute() {
+                    return Err(format!("Local directory must be absolute: {}", local_dir));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What to do with runs that fell due while the app was not running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissedPolicy {
+    Skip,
+    /// Run once, however many runs were missed
+    CatchUp,
+}
+
+impl MissedPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MissedPolicy::Skip => "skip",
+            MissedPolicy::CatchUp => "catch_up",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "skip" => Some(MissedPolicy::Skip),
+            "catch_up" => Some(MissedPolicy::CatchUp),
+            _ => None,
+        }
+    }
+}
+
+/// Ids of jobs with a run in progress, managed as Tauri state
+#[derive(Default)]
+pub struct JobRunner {
+    running: Mutex<HashSet<i32>>,
+}
+
+/// Removes a job from the running set when its run ends, however it ends
+struct RunningGuard<'a> {
+    runner: &'a JobRunner,
+    job_id: i32,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.runner.running.lock() {
+            running.remove(&self.job_id);
+        }
+    }
+}
+
+impl JobRunner {
+    fn start(&self, job_id: i32) -> Option<RunningGuard<'_>> {
+        let mut running = self.running.lock().ok()?;
+        running.insert(job_id).then(|| RunningGuard {
+            runner: self,
+            job_id,
+        })
+    }
+}
+
+/// Check a schedule and return its next run after `now`
+pub fn next_run(schedule: &str, now: i64) -> Result<i64, String> {
+    CronSchedule::parse(schedule)?
+        .next_after(now)
+        .ok_or_else(|| format!("Schedule {:?} never matches", schedule))
+}
+
+fn emit(app: &AppHandle, run_id: i64) {
+    let state = app.state::<AppState>();
+    let run = state
+        .db
+        .lock()
+        .map_err(|e| e.to_string())
+        .and_then(|db| db.get_job_run(run_id as i32).map_err(|e| e.to_string()));
+    match run {
+        Ok(Some(run)) => {
+            if let Err(e) = app.emit(JOB_RUN_EVENT, &run) {
+                error!("Failed to emit job run: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to load job run {}: {}", run_id, e),
+    }
+}
+
+/// Record a run that did not happen
+fn record_skipped(app: &AppHandle, job: &Job, scheduled_for: i64, trigger: &str, reason: &str) {
+    info!("Skipping run of job {}: {}", job.name, reason);
+    let state = app.state::<AppState>();
+    let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+        db.insert_job_run(
+            job.id,
+            scheduled_for,
+            trigger,
+            "skipped",
+            Some(reason),
+            unix_timestamp(),
+        )
+        .map_err(|e| e.to_string())
+    });
+    match result {
+        Ok(id) => emit(app, id),
+        Err(e) => error!("Failed to record skipped run of job {}: {}", job.name, e),
+    }
+}
+
+async fn run_on_device(
+    app: &AppHandle,
+    action: &JobAction,
+    script: Option<&Script>,
+    device: &Device,
+    scheduled_for: i64,
+) -> JobDeviceResult {
+    let state = app.state::<AppState>();
+    let mut result = JobDeviceResult {
+        device_id: device.id,
+        device_name: device.name.clone(),
+        success: false,
+        exit_code: None,
+        output: String::new(),
+    };
+    let outcome = match action {
+        JobAction::Command { command, as_root } => {
+            match crate::ssh::connect_device(&state, device.id).await {
+                Ok((device, session)) => {
+                    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+                    let script = if *as_root {
+                        privileged(&format!("sh -c {}", shell_quote(command)))
+                    } else {
+                        command.clone()
+                    };
+                    crate::ssh::exec_as(&session, &privilege, &script)
+                        .await
+                        .map(|output| {
+                            crate::ssh::log_command(
+                                &state,
+                                device.id,
+                                command,
+                                Some(&output.log_text()),
+                            );
+                            (output.success(), output.exit_code, output.log_text())
+                        })
+                }
+                Err(e) => Err(e),
+            }
+        }
+        JobAction::Script {
+            values, as_root, ..
+        } => match script {
+            Some(script) => crate::scripts::run(&state, script, device.id, values, *as_root)
+                .await
+                .map(|run| {
+                    let output = format!("{}{}", run.stdout, run.stderr);
+                    (run.success, run.exit_code, output)
+                }),
+            None => Err("Script not found".to_string()),
+        },
+        JobAction::FilePull {
+            remote_path,
+            local_dir,
+        } => {
+            let file_name = Path::new(remote_path)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "file".to_string());
+            let local =
+                Path::new(local_dir).join(format!("{}-{}-{}", device.id, scheduled_for, file_name));
+            let local = local.to_string_lossy();
+            match crate::transfer::enqueue(app, device.id, Direction::Download, &local, remote_path)
+            {
+                Ok(id) => crate::transfer::wait(app, id as i32)
+                    .await
+                    .map(|transfer| pull_outcome(&transfer)),
+                Err(e) => Err(e),
+            }
+        }
+    };
+    match outcome {
+        Ok((success, exit_code, output)) => {
+            result.success = success;
+            result.exit_code = exit_code;
+            result.output = output;
+        }
+        Err(e) => result.output = e,
+    }
+    result
+}
+
+/// Result of a file pull step from its transfer's final state
+fn pull_outcome(transfer: &Transfer) -> (bool, Option<i32>, String) {
+    match transfer.state.as_str() {
+        "completed" => (
+            true,
+            None,
+            format!(
+                "Pulled {} to {} ({} bytes, transfer {})",
+                transfer.remote_path, transfer.local_path, transfer.transferred, transfer.id
+            ),
+        ),
+        state => (
+            false,
+            None,
+            format!(
+                "Transfer {} of {} {}{}",
+                transfer.id,
+                transfer.remote_path,
+                state,
+                transfer
+                    .error
+                    .as_deref()
+                    .map(|e| format!(": {}", e))
+                    .unwrap_or_default()
+            ),
+        ),
+    }
+}
+
+/// Load the job's script, if it runs one, and select its devices
+fn prepare(state: &AppState, job: &Job) -> Result<(Option<Script>, Vec<Device>), String> {
+    let script = match &job.action {
+        JobAction::Script { script_id, .. } => {
+            let db = state.db.lock().map_err(|e| e.to_string())?;
+            Some(
+                db.get_script(*script_id)
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("Script {} not found", script_id))?,
+            )
+        }
+        _ => None,
+    };
+    let devices = crate::fleet::select_devices(state, &job.device_ids, &job.tags)?;
+    Ok((script, devices))
+}
+
+/// Run a job now on all its devices and record the run
+///
+/// Returns an error without running if the job already has a run in progress;
+/// that attempt is recorded as skipped.
+pub async fn run(
+    app: &AppHandle,
+    job: &Job,
+    scheduled_for: i64,
+    trigger: &str,
+) -> Result<JobRun, String> {
+    let runner = app.state::<JobRunner>();
+    let Some(_guard) = runner.start(job.id) else {
+        let reason = "Previous run still in progress";
+        record_skipped(app, job, scheduled_for, trigger, reason);
+        return Err(reason.to_string());
+    };
+
+    let state = app.state::<AppState>();
+    let now = unix_timestamp();
+    let run_id = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.set_job_last_run(job.id, now)
+            .map_err(|e| e.to_string())?;
+        db.insert_job_run(job.id, scheduled_for, trigger, "running", None, now)
+            .map_err(|e| e.to_string())?
+    };
+    info!("Running job {} ({})", job.name, trigger);
+
+    let (results, error) = match prepare(&state, job) {
+        Ok((script, devices)) => {
+            let script = script.as_ref();
+            let mut results: Vec<JobDeviceResult> = futures_util::stream::iter(devices)
+                .map(|device| async move {
+                    run_on_device(app, &job.action, script, &device, scheduled_for).await
+                })
+                .buffer_unordered(crate::fleet::concurrency(None))
+                .collect()
+                .await;
+            results.sort_by_key(|r| r.device_id);
+            let failed = results.iter().filter(|r| !r.success).count();
+            let error = (failed > 0).then(|| format!("Failed on {} device(s)", failed));
+            (results, error)
+        }
+        Err(e) => (Vec::new(), Some(e)),
+    };
+
+    let run_state = if error.is_none() {
+        "succeeded"
+    } else {
+        "failed"
+    };
+    match &error {
+        Some(e) => error!("Job {} failed: {}", job.name, e),
+        None => info!("Job {} succeeded on {} device(s)", job.name, results.len()),
+    }
+    let run = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.finish_job_run(
+            run_id as i32,
+            run_state,
+            &results,
+            error.as_deref(),
+            unix_timestamp(),
+        )
+        .map_err(|e| e.to_string())?;
+        db.get_job_run(run_id as i32)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Job run {} not found", run_id))?
+    };
+    if let Err(e) = app.emit(JOB_RUN_EVENT, &run) {
+        error!("Failed to emit job run: {}", e);
+    }
+    Ok(run)
+}
+
+/// Advance a due job's schedule and start its run in the background
+fn dispatch(app: &AppHandle, job: Job, now: i64) {
+    let Some(scheduled_for) = job.next_run else {
+        return;
+    };
+    // Advanced before running, so a slow run can never be started twice
+    let next = match next_run(&job.schedule, now) {
+        Ok(next) => Some(next),
+        Err(e) => {
+            error!("Job {} has an invalid schedule: {}", job.name, e);
+            None
+        }
+    };
+    {
+        let state = app.state::<AppState>();
+        let result = state
+            .db
+            .lock()
+            .map_err(|e| e.to_string())
+            .and_then(|db| db.set_job_next_run(job.id, next).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Failed to schedule job {}: {}", job.name, e);
+            return;
+        }
+    }
+
+    let missed = now - scheduled_for > MISSED_GRACE_SECS;
+    let trigger = if missed { "catch_up" } else { "schedule" };
+    if missed && MissedPolicy::parse(&job.missed_policy) != Some(MissedPolicy::CatchUp) {
+        record_skipped(
+            app,
+            &job,
+            scheduled_for,
+            "schedule",
+            "Missed while the app was not running",
+        );
+        return;
+    }
+
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = run(&app, &job, scheduled_for, trigger).await {
+            error!("Job {} did not run: {}", job.name, e);
+        }
+    });
+}
+
+/// Mark runs cut short by the last exit and start the scheduler
+pub fn spawn_scheduler(app: AppHandle) {
+    {
+        let state = app.state::<AppState>();
+        let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+            db.interrupt_running_job_runs(unix_timestamp())
+                .map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(0) => {}
+            Ok(n) => info!("{} job run(s) were interrupted by the last exit", n),
+            Err(e) => error!("Failed to mark interrupted job runs: {}", e),
+        }
+    }
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let now = unix_timestamp();
+            let due = {
+                let state = app.state::<AppState>();
+                let db = state.db.lock();
+                db.map_err(|e| e.to_string())
+                    .and_then(|db| db.get_due_jobs(now).map_err(|e| e.to_string()))
+            };
+            match due {
+                Ok(jobs) => {
+                    for job in jobs {
+                        dispatch(&app, job, now);
+                    }
+                }
+                Err(e) => error!("Failed to load due jobs: {}", e),
+            }
+            tokio::time::sleep(SCHEDULER_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_parse_from_json() {
+        let action: JobAction = serde_json::from_str(
+            r#"{"type": "file_pull", "remote_path": "/var/log/syslog", "local_dir": "/tmp/logs"}"#,
+        )
+        .unwrap();
+        assert!(action.validate().is_ok());
+
+        let action: JobAction = serde_json::from_str(
+            r#"{"type": "file_pull", "remote_path": "/var/log/syslog", "local_dir": "logs"}"#,
+        )
+        .unwrap();
+        assert!(action.validate().is_err());
+
+        let action: JobAction =
+            serde_json::from_str(r#"{"type": "command", "command": " "}"#).unwrap();
+        assert!(action.validate().is_err());
+    }
+
+    #[test]
+    fn runner_prevents_overlap() {
+        let runner = JobRunner::default();
+        let guard = runner.start(1);
+        assert!(guard.is_some());
+        assert!(runner.start(1).is_none());
+        assert!(runner.start(2).is_some());
+        drop(guard);
+        assert!(runner.start(1).is_some());
+    }
+
+    #[test]
+    fn file_pull_reports_the_transfer_outcome() {
+        let mut transfer = Transfer {
+            id: 9,
+            device_id: 1,
+            direction: "download".to_string(),
+            local_path: "/tmp/logs/1-1700000000-syslog".to_string(),
+            remote_path: "/var/log/syslog".to_string(),
+            size: Some(2048),
+            transferred: 2048,
+            sha256: None,
+            state: "completed".to_string(),
+            error: None,
+            created_at: 1_700_000_000,
+            updated_at: 1_700_000_005,
+        };
+        assert_eq!(
+            pull_outcome(&transfer),
+            (
+                true,
+                None,
+                "Pulled /var/log/syslog to /tmp/logs/1-1700000000-syslog (2048 bytes, transfer 9)"
+                    .to_string()
+            )
+        );
+
+        transfer.state = "failed".to_string();
+        transfer.error = Some("Permission denied".to_string());
+        assert_eq!(
+            pull_outcome(&transfer),
+            (
+                false,
+                None,
+                "Transfer 9 of /var/log/syslog failed: Permission denied".to_string()
+            )
+        );
+        transfer.state = "cancelled".to_string();
+        transfer.error = None;
+        assert!(!pull_outcome(&transfer).0);
+    }
+}
@@ -1,10 +1,35 @@
+pub mod alert;
+pub mod collector;
 pub mod command;
+pub mod config_file;
+pub mod cron;
 pub mod db;
+pub mod drift;
+pub mod exporter;
+pub mod facts;
+pub mod fleet;
+pub mod inventory;
+pub mod jobs;
 pub mod logging;
+pub mod logtail;
+pub mod mdns;
+pub mod notify;
+pub mod packages;
+pub mod process;
+pub mod reboot;
+pub mod runbook;
+pub mod scan;
+pub mod scripts;
+pub mod sftp;
 pub mod ssh;
+pub mod systemd;
+pub mod transfer;
+pub mod validation;
+pub mod vault;
 
 use command::AppState;
 use db::Db;
+use std::collections::HashMap;
 use std::sync::Mutex;
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
@@ -23,16 +48,141 @@ pub fn run() {
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .manage(AppState { db: Mutex::new(db) })
+        .plugin(tauri_plugin_notification::init())
+        .manage(AppState {
+            db: Mutex::new(db),
+            latest_metrics: Mutex::new(HashMap::new()),
+            vault: vault::Vault::default(),
+        })
+        .manage(exporter::ExporterState::default())
+        .manage(logtail::LogTailState::default())
+        .manage(mdns::MdnsState::default())
+        .manage(transfer::TransferQueue::default())
+        .manage(jobs::JobRunner::default())
+        .setup(|app| {
+            alert::spawn_offline_monitor(app.handle().clone());
+            exporter::spawn_from_settings(app.handle().clone());
+            transfer::spawn_worker(app.handle().clone());
+            drift::spawn_scheduler(app.handle().clone());
+            facts::spawn_scheduler(app.handle().clone());
+            runbook::mark_interrupted(app.handle());
+            jobs::spawn_scheduler(app.handle().clone());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             command::get_devices,
             command::add_device,
             command::delete_device,
+            command::set_device_tags,
+            command::validate_device,
+            command::get_device_escalation,
+            command::set_device_escalation,
+            command::set_device_password,
+            command::clear_device_password,
+            command::scan_network,
+            command::add_discovered_devices,
+            command::export_inventory,
+            command::import_inventory,
+            command::set_device_notes,
+            command::start_mdns_discovery,
+            command::stop_mdns_discovery,
+            command::get_mdns_candidates,
             command::get_log_path,
             command::connect_and_add_device,
             command::connect_and_add_device_with_config,
             command::test_ssh_connection,
             command::get_device_metrics,
+            command::get_metrics_history,
+            command::get_cpu_core_history,
+            command::get_network_history,
+            command::get_filesystem_history,
+            command::get_alert_rules,
+            command::add_alert_rule,
+            command::set_alert_rule_enabled,
+            command::delete_alert_rule,
+            command::get_alerts,
+            command::add_alert_silence,
+            command::get_alert_silences,
+            command::delete_alert_silence,
+            command::get_alert_channels,
+            command::add_alert_channel,
+            command::set_alert_channel_enabled,
+            command::delete_alert_channel,
+            command::get_alert_deliveries,
+            command::test_alert_channel,
+            command::start_metrics_exporter,
+            command::stop_metrics_exporter,
+            command::get_metrics_exporter_status,
+            command::list_processes,
+            command::signal_process,
+            command::list_services,
+            command::get_service_status,
+            command::control_service,
+            command::start_log_tail,
+            command::stop_log_tail,
+            command::pause_log_tail,
+            command::resume_log_tail,
+            command::set_log_tail_filter,
+            command::list_log_tails,
+            command::snapshot_log_tail,
+            command::get_log_snapshots,
+            command::delete_log_snapshot,
+            command::list_directory,
+            command::stat_path,
+            command::make_directory,
+            command::rename_path,
+            command::delete_path,
+            command::read_file,
+            command::queue_upload,
+            command::queue_download,
+            command::get_transfers,
+            command::cancel_transfer,
+            command::retry_transfer,
+            command::delete_transfer,
+            command::push_files,
+            command::fetch_config_file,
+            command::preview_config_diff,
+            command::write_config_file,
+            command::create_drift_check,
+            command::get_drift_checks,
+            command::set_drift_check_enabled,
+            command::delete_drift_check,
+            command::run_drift_check,
+            command::get_drift_report,
+            command::get_drift_snapshots,
+            command::get_device_facts,
+            command::get_device_facts_history,
+            command::refresh_device_facts,
+            command::list_upgradable_packages,
+            command::manage_package,
+            command::upgrade_packages,
+            command::get_package_update_counts,
+            command::check_fleet_updates,
+            command::reboot_device,
+            command::rolling_reboot,
+            command::get_scripts,
+            command::create_script,
+            command::update_script,
+            command::delete_script,
+            command::get_script_versions,
+            command::get_script_runs,
+            command::run_script,
+            command::get_runbooks,
+            command::create_runbook,
+            command::update_runbook,
+            command::delete_runbook,
+            command::start_runbook,
+            command::get_runbook_runs,
+            command::get_runbook_run_steps,
+            command::approve_runbook_step,
+            command::resume_runbook_run,
+            command::get_jobs,
+            command::create_job,
+            command::update_job,
+            command::set_job_enabled,
+            command::delete_job,
+            command::run_job_now,
+            command::get_job_runs,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
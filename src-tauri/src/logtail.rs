@@ -0,0 +1,459 @@
+//! Live tails of journald or log files on a device, streamed as Tauri events.
+
+use crate::db::unix_timestamp;
+use crate::ssh::{privileged, shell_quote, Privilege};
+use log::{error, info};
+use openssh::Session;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tauri event carrying one line of a tail
+pub const LOG_LINE_EVENT: &str = "log-tail-line";
+/// Tauri event emitted once when a tail's remote command exits
+pub const LOG_END_EVENT: &str = "log-tail-ended";
+
+/// Lines kept per tail for snapshots
+const BUFFER_LINES: usize = 2000;
+/// Upper bound on the history printed before following
+const MAX_BACKLOG: u32 = 1000;
+
+const JOURNAL_PRIORITIES: &[&str] = &[
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+/// What to follow on the device
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogSource {
+    Journal {
+        unit: Option<String>,
+        /// journald priority name; entries at this level or more severe are shown
+        priority: Option<String>,
+    },
+    File {
+        path: String,
+    },
+}
+
+impl LogSource {
+    /// Build and validate a source from command arguments
+    pub fn new(
+        kind: &str,
+        unit: Option<String>,
+        priority: Option<String>,
+        path: Option<String>,
+    ) -> Result<Self, String> {
+        match kind {
+            "journal" => {
+                let unit = unit.filter(|u| !u.is_empty());
+                if let Some(unit) = &unit {
+                    crate::systemd::validate_unit_name(unit)?;
+                }
+                let priority = priority
+                    .filter(|p| !p.is_empty())
+                    .map(|p| normalize_priority(&p))
+                    .transpose()?;
+                Ok(LogSource::Journal { unit, priority })
+            }
+            "file" => {
+                let path = path.ok_or("A file tail needs a path")?;
+                if !path.starts_with('/') || path.contains(['\0', '\n']) {
+                    return Err(format!("Invalid log file path: {}", path));
+                }
+                Ok(LogSource::File { path })
+            }
+            other => Err(format!("Unknown log source: {}", other)),
+        }
+    }
+
+    /// Short description stored with snapshots, e.g. `journal:nginx.service:warning`
+    pub fn describe(&self) -> String {
+        match self {
+            LogSource::Journal { unit, priority } => format!(
+                "journal:{}:{}",
+                unit.as_deref().unwrap_or("*"),
+                priority.as_deref().unwrap_or("*")
+            ),
+            LogSource::File { path } => format!("file:{}", path),
+        }
+    }
+
+    /// Script following the source, printing the last `backlog` lines first
+    pub fn script(&self, backlog: u32) -> String {
+        let backlog = backlog.min(MAX_BACKLOG);
+        match self {
+            LogSource::Journal { unit, priority } => {
+                let mut cmd = format!(
+                    "journalctl --no-pager --output=short-iso --follow -n {}",
+                    backlog
+                );
+                if let Some(unit) = unit {
+                    cmd.push_str(&format!(" -u {}", shell_quote(unit)));
+                }
+                if let Some(priority) = priority {
+                    cmd.push_str(&format!(" -p {}", priority));
+                }
+                format!(
+                    "export LC_ALL=C SYSTEMD_COLORS=0; {} 2>&1",
+                    privileged(&cmd)
+                )
+            }
+            LogSource::File { path } => {
+                let path = shell_quote(path);
+                let cmd = format!("tail -n {} -F {}", backlog, path);
+                format!(
+                    "if [ -r {path} ]; then {cmd}; else {root}; fi 2>&1",
+                    path = path,
+                    root = privileged(&cmd),
+                    cmd = cmd
+                )
+            }
+        }
+    }
+}
+
+/// Accept a journald priority as a name or 0-7 and return its name
+fn normalize_priority(priority: &str) -> Result<String, String> {
+    let lower = priority.trim().to_lowercase();
+    if let Ok(level) = lower.parse::<usize>() {
+        if let Some(name) = JOURNAL_PRIORITIES.get(level) {
+            return Ok(name.to_string());
+        }
+    }
+    if JOURNAL_PRIORITIES.contains(&lower.as_str()) {
+        Ok(lower)
+    } else {
+        Err(format!("Invalid journal priority: {}", priority))
+    }
+}
+
+/// Whether a line passes a case-insensitive substring filter
+fn matches_filter(line: &str, filter: Option<&str>) -> bool {
+    match filter {
+        Some(filter) => line.to_lowercase().contains(filter),
+        None => true,
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLineEvent {
+    pub tail_id: u32,
+    pub line: String,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogTailEnded {
+    pub tail_id: u32,
+    /// Set when the remote command failed rather than being stopped
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogTailInfo {
+    pub id: u32,
+    pub device_id: i32,
+    pub source: String,
+    pub filter: Option<String>,
+    pub paused: bool,
+    pub ended: bool,
+    pub buffered_lines: usize,
+    pub started_at: i64,
+}
+
+/// State shared between a tail's task and the commands controlling it
+#[derive(Default)]
+struct TailShared {
+    paused: AtomicBool,
+    ended: AtomicBool,
+    /// Lower-cased substring lines must contain
+    filter: Mutex<Option<String>>,
+    /// Most recent lines that passed the filter, including while paused
+    buffer: Mutex<VecDeque<String>>,
+}
+
+impl TailShared {
+    fn filter(&self) -> Result<Option<String>, String> {
+        Ok(self.filter.lock().map_err(|e| e.to_string())?.clone())
+    }
+
+    /// Buffer a line and return whether it should be emitted
+    fn accept(&self, line: &str) -> Result<bool, String> {
+        if !matches_filter(line, self.filter()?.as_deref()) {
+            return Ok(false);
+        }
+        let mut buffer = self.buffer.lock().map_err(|e| e.to_string())?;
+        if buffer.len() == BUFFER_LINES {
+            buffer.pop_front();
+        }
+        buffer.push_back(line.to_string());
+        Ok(!self.paused.load(Ordering::Relaxed))
+    }
+}
+
+struct Tail {
+    device_id: i32,
+    source: LogSource,
+    started_at: i64,
+    shared: Arc<TailShared>,
+    task: JoinHandle<()>,
+}
+
+impl Tail {
+    fn info(&self, id: u32) -> Result<LogTailInfo, String> {
+        Ok(LogTailInfo {
+            id,
+            device_id: self.device_id,
+            source: self.source.describe(),
+            filter: self.shared.filter()?,
+            paused: self.shared.paused.load(Ordering::Relaxed),
+            ended: self.shared.ended.load(Ordering::Relaxed),
+            buffered_lines: self.shared.buffer.lock().map_err(|e| e.to_string())?.len(),
+            started_at: self.started_at,
+        })
+    }
+}
+
+/// Running tails, managed as Tauri state
+#[derive(Default)]
+pub struct LogTailState {
+    next_id: AtomicU32,
+    tails: Mutex<HashMap<u32, Tail>>,
+}
+
+/// Lines and metadata needed to store a snapshot
+pub struct TailSnapshot {
+    pub device_id: i32,
+    pub source: String,
+    pub filter: Option<String>,
+    pub lines: Vec<String>,
+}
+
+impl LogTailState {
+    pub fn list(&self) -> Result<Vec<LogTailInfo>, String> {
+        let tails = self.tails.lock().map_err(|e| e.to_string())?;
+        let mut list = tails
+            .iter()
+            .map(|(id, t)| t.info(*id))
+            .collect::<Result<Vec<_>, _>>()?;
+        list.sort_by_key(|t| t.id);
+        Ok(list)
+    }
+
+    fn with_tail<T>(
+        &self,
+        id: u32,
+        f: impl FnOnce(&Tail) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let tails = self.tails.lock().map_err(|e| e.to_string())?;
+        let tail = tails
+            .get(&id)
+            .ok_or_else(|| format!("Log tail {} not found", id))?;
+        f(tail)
+    }
+
+    pub fn set_paused(&self, id: u32, paused: bool) -> Result<(), String> {
+        self.with_tail(id, |t| {
+            t.shared.paused.store(paused, Ordering::Relaxed);
+            Ok(())
+        })
+    }
+
+    /// Replace the filter; only lines received from now on are affected
+    pub fn set_filter(&self, id: u32, filter: Option<String>) -> Result<(), String> {
+        let filter = filter.map(|f| f.to_lowercase()).filter(|f| !f.is_empty());
+        self.with_tail(id, |t| {
+            *t.shared.filter.lock().map_err(|e| e.to_string())? = filter;
+            Ok(())
+        })
+    }
+
+    /// The last `lines` buffered lines, or all of them
+    pub fn snapshot(&self, id: u32, lines: Option<usize>) -> Result<TailSnapshot, String> {
+        self.with_tail(id, |t| {
+            let filter = t.shared.filter()?;
+            let buffer = t.shared.buffer.lock().map_err(|e| e.to_string())?;
+            let skip = lines.map_or(0, |n| buffer.len().saturating_sub(n));
+            Ok(TailSnapshot {
+                device_id: t.device_id,
+                source: t.source.describe(),
+                filter,
+                lines: buffer.iter().skip(skip).cloned().collect(),
+            })
+        })
+    }
+
+    /// Stop a tail and forget it; dropping the SSH session ends the remote command
+    pub fn stop(&self, id: u32) -> Result<(), String> {
+        let tail = self
+            .tails
+            .lock()
+            .map_err(|e| e.to_string())?
+            .remove(&id)
+            .ok_or_else(|| format!("Log tail {} not found", id))?;
+        tail.task.abort();
+        info!("Stopped log tail {} ({})", id, tail.source.describe());
+        Ok(())
+    }
+}
+
+/// Run the tail command and hand every output line to `on_line`
+async fn stream(
+    session: &Session,
+    privilege: &Privilege,
+    script: &str,
+    on_line: impl FnMut(String),
+) -> Result<(), String> {
+    match crate::ssh::exec_streaming_as(session, privilege, script, on_line).await? {
+        Some(0) => Ok(()),
+        Some(code) => Err(format!("Tail command exited with status {}", code)),
+        None => Err("Tail command was killed by a signal".to_string()),
+    }
+}
+
+/// Start following `source` over an established session and return the tail id
+pub fn start(
+    app: &AppHandle,
+    device_id: i32,
+    session: Session,
+    privilege: Privilege,
+    source: LogSource,
+    backlog: u32,
+    filter: Option<String>,
+) -> Result<u32, String> {
+    let tails = app.state::<LogTailState>();
+    // Locked before spawning so a poisoned map cannot leave an untracked task
+    let mut running = tails.tails.lock().map_err(|e| e.to_string())?;
+    let id = tails.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let shared = Arc::new(TailShared {
+        filter: Mutex::new(filter.map(|f| f.to_lowercase()).filter(|f| !f.is_empty())),
+        ..Default::default()
+    });
+
+    let script = source.script(backlog);
+    let task_shared = shared.clone();
+    let app_handle = app.clone();
+    let task = tauri::async_runtime::spawn(async move {
+        let result = stream(&session, &privilege, &script, |line| {
+            match task_shared.accept(&line) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    error!("Failed to buffer line of log tail {}: {}", id, e);
+                    return;
+                }
+            }
+            let event = LogLineEvent {
+                tail_id: id,
+                line,
+                timestamp: unix_timestamp(),
+            };
+            if let Err(e) = app_handle.emit(LOG_LINE_EVENT, &event) {
+                error!("Failed to emit log line: {}", e);
+            }
+        })
+        .await;
+
+        task_shared.ended.store(true, Ordering::Relaxed);
+        if let Err(e) = &result {
+            error!("Log tail {} on device {} failed: {}", id, device_id, e);
+        } else {
+            info!("Log tail {} on device {} ended", id, device_id);
+        }
+        let ended = LogTailEnded {
+            tail_id: id,
+            error: result.err(),
+        };
+        if let Err(e) = app_handle.emit(LOG_END_EVENT, &ended) {
+            error!("Failed to emit log tail end: {}", e);
+        }
+    });
+
+    info!(
+        "Started log tail {} on device {} ({})",
+        id,
+        device_id,
+        source.describe()
+    );
+    running.insert(
+        id,
+        Tail {
+            device_id,
+            source,
+            started_at: unix_timestamp(),
+            shared,
+            task,
+        },
+    );
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(filter: Option<&str>) -> TailShared {
+        let shared = TailShared::default();
+        *shared.filter.lock().unwrap() = filter.map(str::to_string);
+        shared
+    }
+
+    fn buffered(shared: &TailShared) -> Vec<String> {
+        shared.buffer.lock().unwrap().iter().cloned().collect()
+    }
+
+    #[test]
+    fn normalizes_priorities() {
+        assert_eq!(normalize_priority("0").unwrap(), "emerg");
+        assert_eq!(normalize_priority("4").unwrap(), "warning");
+        assert_eq!(normalize_priority(" 7 ").unwrap(), "debug");
+        assert_eq!(normalize_priority("ERR").unwrap(), "err");
+        for bad in ["8", "-1", "warn", "error", "", "info; id"] {
+            assert!(normalize_priority(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn filters_case_insensitively() {
+        let line = "2024-05-06T09:14:02+0000 pi sshd[812]: Failed password for ROOT";
+        assert!(matches_filter(line, None));
+        assert!(matches_filter(line, Some("root")));
+        assert!(matches_filter(line, Some("failed password")));
+        assert!(!matches_filter(line, Some("accepted")));
+    }
+
+    #[test]
+    fn accept_buffers_filtered_lines_and_holds_them_while_paused() {
+        let tail = shared(Some("error"));
+        assert!(tail.accept("kernel: I/O Error on sda").unwrap());
+        assert!(!tail.accept("systemd: Started session 4").unwrap());
+        tail.paused.store(true, Ordering::Relaxed);
+        assert!(!tail.accept("nginx: ERROR upstream timed out").unwrap());
+        tail.paused.store(false, Ordering::Relaxed);
+        assert_eq!(
+            buffered(&tail),
+            vec![
+                "kernel: I/O Error on sda",
+                "nginx: ERROR upstream timed out"
+            ]
+        );
+    }
+
+    #[test]
+    fn accept_keeps_only_the_latest_lines() {
+        let tail = shared(None);
+        for i in 0..BUFFER_LINES + 5 {
+            assert!(tail.accept(&format!("line {}", i)).unwrap());
+        }
+        let lines = buffered(&tail);
+        assert_eq!(lines.len(), BUFFER_LINES);
+        assert_eq!(lines[0], "line 5");
+        assert_eq!(
+            lines[BUFFER_LINES - 1],
+            format!("line {}", BUFFER_LINES + 4)
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! mDNS / DNS-SD discovery of devices advertising `_ssh._tcp` or
+//! `_workstation._tcp`, as Avahi does out of the box.
+//!
+//! While discovery runs, every resolved or removed service updates a set of
+//! candidates keyed by hostname and is emitted to the frontend, so the view
+//! stays current until discovery is stopped. Candidates are added as devices
+//! through [`crate::scan::add_discovered`], like scan results.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device};
+use log::{error, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter, Manager};
+
+pub const SERVICE_TYPES: &[&str] = &["_ssh._tcp.local.", "_workstation._tcp.local."];
+const SSH_SERVICE: &str = "_ssh._tcp.local.";
+
+pub const MDNS_CANDIDATE_EVENT: &str = "mdns-candidate";
+pub const MDNS_REMOVED_EVENT: &str = "mdns-candidate-removed";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MdnsCandidate {
+    /// e.g. `raspberrypi.local.`
+    pub hostname: String,
+    /// Instance name of the first service seen, used as the device name
+    pub name: String,
+    /// IPv4 addresses first
+    pub addresses: Vec<String>,
+    /// Port of the `_ssh._tcp` service, if the host advertises one
+    pub ssh_port: Option<u16>,
+    /// Full names of the services seen for this host
+    pub services: Vec<String>,
+    /// Stored device with one of the addresses or the hostname
+    pub known_device_id: Option<i32>,
+    pub last_seen: i64,
+}
+
+/// The parts of a resolved service that discovery uses
+#[derive(Debug, Clone)]
+pub struct ResolvedService {
+    pub fullname: String,
+    pub service_type: String,
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl From<&ServiceInfo> for ResolvedService {
+    fn from(info: &ServiceInfo) -> Self {
+        ResolvedService {
+            fullname: info.get_fullname().to_string(),
+            service_type: info.get_type().to_string(),
+            hostname: info.get_hostname().to_string(),
+            addresses: info.get_addresses().iter().copied().collect(),
+            port: info.get_port(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Updated(MdnsCandidate),
+    /// Hostname of a candidate with no services left
+    Removed(String),
+}
+
+/// Instance label of a service, without the MAC address Avahi appends to
+/// workstation names: `pi [b8:27:eb:01:02:03]._workstation._tcp.local.` is `pi`
+fn instance_name(fullname: &str, service_type: &str) -> String {
+    let instance = fullname
+        .strip_suffix(service_type)
+        .and_then(|s| s.strip_suffix('.'))
+        .unwrap_or(fullname);
+    let instance = match instance.rfind(" [") {
+        Some(i) if instance.ends_with(']') => &instance[..i],
+        _ => instance,
+    };
+    instance.replace("\\032", " ")
+}
+
+/// Candidates seen so far, keyed by hostname
+#[derive(Default)]
+pub struct Candidates {
+    by_host: BTreeMap<String, MdnsCandidate>,
+}
+
+impl Candidates {
+    /// Record a resolved service and return the host's updated candidate
+    pub fn resolved(&mut self, service: ResolvedService, now: i64) -> MdnsCandidate {
+        let name = instance_name(&service.fullname, &service.service_type);
+        let candidate = self
+            .by_host
+            .entry(service.hostname.clone())
+            .or_insert_with(|| MdnsCandidate {
+                hostname: service.hostname.clone(),
+                name,
+                addresses: Vec::new(),
+                ssh_port: None,
+                services: Vec::new(),
+                known_device_id: None,
+                last_seen: now,
+            });
+        let mut addresses = service.addresses;
+        addresses.sort_by_key(|a| (a.is_ipv6(), *a));
+        for address in addresses {
+            let address = address.to_string();
+            if !candidate.addresses.contains(&address) {
+                candidate.addresses.push(address);
+            }
+        }
+        if service.service_type == SSH_SERVICE {
+            candidate.ssh_port = Some(service.port);
+        }
+        if !candidate.services.contains(&service.fullname) {
+            candidate.services.push(service.fullname);
+        }
+        candidate.last_seen = now;
+        candidate.clone()
+    }
+
+    /// Forget a service; `None` when it was never resolved
+    pub fn removed(&mut self, fullname: &str) -> Option<Change> {
+        let (hostname, candidate) = self
+            .by_host
+            .iter_mut()
+            .find(|(_, c)| c.services.iter().any(|s| s == fullname))?;
+        candidate.services.retain(|s| s != fullname);
+        if fullname.ends_with(SSH_SERVICE) {
+            candidate.ssh_port = None;
+        }
+        if !candidate.services.is_empty() {
+            return Some(Change::Updated(candidate.clone()));
+        }
+        let hostname = hostname.clone();
+        self.by_host.remove(&hostname);
+        Some(Change::Removed(hostname))
+    }
+
+    pub fn list(&self) -> Vec<MdnsCandidate> {
+        self.by_host.values().cloned().collect()
+    }
+}
+
+/// Set `known_device_id` from the stored devices
+fn mark_known(candidate: &mut MdnsCandidate, devices: &[Device]) {
+    let hostname = candidate.hostname.trim_end_matches('.');
+    candidate.known_device_id = devices
+        .iter()
+        .find(|d| d.ip == hostname || candidate.addresses.contains(&d.ip))
+        .map(|d| d.id);
+}
+
+fn stored_devices(app: &AppHandle) -> Vec<Device> {
+    let state = app.state::<AppState>();
+    let devices = state
+        .db
+        .lock()
+        .map_err(|e| e.to_string())
+        .and_then(|db| db.get_all_devices().map_err(|e| e.to_string()));
+    devices.unwrap_or_else(|e| {
+        error!("Failed to load devices for mDNS discovery: {}", e);
+        Vec::new()
+    })
+}
+
+#[derive(Default)]
+pub struct MdnsState {
+    daemon: Mutex<Option<ServiceDaemon>>,
+    candidates: Arc<Mutex<Candidates>>,
+}
+
+impl MdnsState {
+    /// Current candidates, flagged against the stored devices
+    pub fn candidates(&self, app: &AppHandle) -> Result<Vec<MdnsCandidate>, String> {
+        let mut candidates = self.candidates.lock().map_err(|e| e.to_string())?.list();
+        let devices = stored_devices(app);
+        for candidate in &mut candidates {
+            mark_known(candidate, &devices);
+        }
+        Ok(candidates)
+    }
+}
+
+fn handle_event(app: &AppHandle, candidates: &Mutex<Candidates>, event: ServiceEvent) {
+    let mut candidates = match candidates.lock() {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            error!("Failed to lock mDNS candidates: {}", e);
+            return;
+        }
+    };
+    let change = match event {
+        ServiceEvent::ServiceResolved(info) => {
+            let service = ResolvedService::from(&info);
+            Some(Change::Updated(
+                candidates.resolved(service, unix_timestamp()),
+            ))
+        }
+        ServiceEvent::ServiceRemoved(_, fullname) => candidates.removed(&fullname),
+        _ => None,
+    };
+    drop(candidates);
+    let result = match change {
+        Some(Change::Updated(mut candidate)) => {
+            mark_known(&mut candidate, &stored_devices(app));
+            app.emit(MDNS_CANDIDATE_EVENT, &candidate)
+        }
+        Some(Change::Removed(hostname)) => app.emit(MDNS_REMOVED_EVENT, &hostname),
+        None => Ok(()),
+    };
+    if let Err(e) = result {
+        error!("Failed to emit mDNS discovery update: {}", e);
+    }
+}
+
+/// Start browsing; a no-op when discovery is already running
+pub fn start(app: &AppHandle) -> Result<(), String> {
+    let state = app.state::<MdnsState>();
+    let mut running = state.daemon.lock().map_err(|e| e.to_string())?;
+    if running.is_some() {
+        return Ok(());
+    }
+    let daemon = ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {}", e))?;
+    state
+        .candidates
+        .lock()
+        .map_err(|e| e.to_string())?
+        .by_host
+        .clear();
+
+    for service_type in SERVICE_TYPES {
+        let receiver = match daemon.browse(service_type) {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                let _ = daemon.shutdown();
+                return Err(format!("Failed to browse {}: {}", service_type, e));
+            }
+        };
+        let app = app.clone();
+        let candidates = state.candidates.clone();
+        // Ends when the daemon shuts down and drops the sender
+        tauri::async_runtime::spawn(async move {
+            while let Ok(event) = receiver.recv_async().await {
+                handle_event(&app, &candidates, event);
+            }
+        });
+    }
+    info!("Started mDNS discovery of {}", SERVICE_TYPES.join(", "));
+    *running = Some(daemon);
+    Ok(())
+}
+
+/// Stop browsing; the candidates found stay available
+pub fn stop(app: &AppHandle) -> Result<(), String> {
+    let state = app.state::<MdnsState>();
+    let Some(daemon) = state.daemon.lock().map_err(|e| e.to_string())?.take() else {
+        return Ok(());
+    };
+    if let Err(e) = daemon.shutdown() {
+        error!("Failed to stop mDNS discovery: {}", e);
+    }
+    info!("Stopped mDNS discovery");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(
+        fullname: &str,
+        service_type: &str,
+        addresses: &[&str],
+        port: u16,
+    ) -> ResolvedService {
+        ResolvedService {
+            fullname: fullname.to_string(),
+            service_type: service_type.to_string(),
+            hostname: "pi.local.".to_string(),
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            port,
+        }
+    }
+
+    #[test]
+    fn strips_service_type_and_mac_from_names() {
+        assert_eq!(
+            instance_name(
+                "pi [b8:27:eb:01:02:03]._workstation._tcp.local.",
+                "_workstation._tcp.local."
+            ),
+            "pi"
+        );
+        assert_eq!(
+            instance_name("Lab\\032Pi._ssh._tcp.local.", "_ssh._tcp.local."),
+            "Lab Pi"
+        );
+    }
+
+    #[test]
+    fn merges_services_of_one_host() {
+        let mut candidates = Candidates::default();
+        candidates.resolved(
+            service(
+                "pi [b8:27:eb:01:02:03]._workstation._tcp.local.",
+                "_workstation._tcp.local.",
+                &["fe80::1", "192.168.1.20"],
+                9,
+            ),
+            100,
+        );
+        let candidate = candidates.resolved(
+            service("pi._ssh._tcp.local.", SSH_SERVICE, &["192.168.1.20"], 2222),
+            110,
+        );
+        assert_eq!(candidate.name, "pi");
+        assert_eq!(candidate.addresses, vec!["192.168.1.20", "fe80::1"]);
+        assert_eq!(candidate.ssh_port, Some(2222));
+        assert_eq!(candidate.services.len(), 2);
+        assert_eq!(candidate.last_seen, 110);
+        assert_eq!(candidates.list().len(), 1);
+    }
+
+    #[test]
+    fn removes_host_with_its_last_service() {
+        let mut candidates = Candidates::default();
+        candidates.resolved(
+            service("pi._ssh._tcp.local.", SSH_SERVICE, &["192.168.1.20"], 22),
+            100,
+        );
+        candidates.resolved(
+            service(
+                "pi._workstation._tcp.local.",
+                "_workstation._tcp.local.",
+                &["192.168.1.20"],
+                9,
+            ),
+            100,
+        );
+
+        let Some(Change::Updated(candidate)) = candidates.removed("pi._ssh._tcp.local.") else {
+            panic!("expected an update");
+        };
+        assert_eq!(candidate.ssh_port, None);
+        assert_eq!(
+            candidates.removed("pi._workstation._tcp.local."),
+            Some(Change::Removed("pi.local.".to_string()))
+        );
+        assert_eq!(candidates.removed("pi._workstation._tcp.local."), None);
+        assert!(candidates.list().is_empty());
+    }
+}
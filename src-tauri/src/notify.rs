@@ -0,0 +1,306 @@
+use crate::alert::AlertNotification;
+use crate::command::AppState;
+use crate::db::{unix_timestamp, AlertChannel};
+use log::{error, info, warn};
+use std::process::Stdio;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use tokio::io::AsyncWriteExt;
+
+/// Delay before the first retry; doubled on every further attempt
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where an alert notification is delivered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelKind {
+    /// HTTP POST of the notification as JSON to `target`
+    Webhook,
+    /// Local shell command; the notification JSON is written to its stdin
+    Command,
+}
+
+impl ChannelKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "webhook" => Some(ChannelKind::Webhook),
+            "command" => Some(ChannelKind::Command),
+            _ => None,
+        }
+    }
+}
+
+/// POST the notification to a webhook, treating any non-2xx status as failure
+pub async fn send_webhook(url: &str, notification: &AlertNotification) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+
+    let response = client
+        .post(url)
+        .json(notification)
+        .send()
+        .await
+        .map_err(|e| format!("Webhook request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!("Webhook returned HTTP {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// Run a local command with the notification as JSON on stdin and as env vars
+pub async fn run_command(command: &str, notification: &AlertNotification) -> Result<(), String> {
+    let payload = serde_json::to_vec(notification)
+        .map_err(|e| format!("Failed to serialize notification: {}", e))?;
+
+    let mut child = shell_command(command)
+        .env("SSEDGE_ALERT_STATE", &notification.state)
+        .env("SSEDGE_ALERT_RULE", &notification.rule_name)
+        .env("SSEDGE_ALERT_DEVICE", &notification.device_name)
+        .env("SSEDGE_ALERT_METRIC", &notification.metric)
+        .env("SSEDGE_ALERT_VALUE", notification.value.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to spawn command: {}", e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may not read stdin at all, so a broken pipe is not an error
+        let _ = stdin.write_all(&payload).await;
+    }
+
+    let output = tokio::time::timeout(COMMAND_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| format!("Command timed out after {}s", COMMAND_TIMEOUT.as_secs()))?
+        .map_err(|e| format!("Failed to wait for command: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "Command exited with {}: {}",
+            output.status,
+            stderr.trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Deliver a notification through a channel once, without retrying
+pub async fn deliver(
+    channel: &AlertChannel,
+    notification: &AlertNotification,
+) -> Result<(), String> {
+    match ChannelKind::parse(&channel.kind) {
+        Some(ChannelKind::Webhook) => send_webhook(&channel.target, notification).await,
+        Some(ChannelKind::Command) => run_command(&channel.target, notification).await,
+        None => Err(format!("Unknown channel kind: {}", channel.kind)),
+    }
+}
+
+fn record_attempt(
+    app: &AppHandle,
+    channel: &AlertChannel,
+    notification: &AlertNotification,
+    attempt: i32,
+    result: &Result<(), String>,
+) {
+    let state = app.state::<AppState>();
+    let db = match state.db.lock() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to lock db to record delivery: {}", e);
+            return;
+        }
+    };
+    let (status, err) = match result {
+        Ok(()) => ("delivered", None),
+        Err(e) => ("failed", Some(e.as_str())),
+    };
+    if let Err(e) = db.insert_alert_delivery(
+        channel.id,
+        notification.alert_id,
+        &notification.state,
+        attempt,
+        status,
+        err,
+        unix_timestamp(),
+    ) {
+        error!("Failed to record alert delivery: {}", e);
+    }
+}
+
+/// Deliver with exponential backoff, logging every attempt to `alert_deliveries`
+async fn deliver_with_retry(
+    app: AppHandle,
+    channel: AlertChannel,
+    notification: AlertNotification,
+) {
+    let attempts = channel.max_retries.max(0) + 1;
+    let mut delay = BASE_RETRY_DELAY;
+
+    for attempt in 1..=attempts {
+        let result = deliver(&channel, &notification).await;
+        record_attempt(&app, &channel, &notification, attempt, &result);
+
+        match result {
+            Ok(()) => {
+                info!(
+                    "Delivered alert {} via channel {} on attempt {}",
+                    notification.alert_id, channel.id, attempt
+                );
+                return;
+            }
+            Err(e) => warn!(
+                "Delivery of alert {} via channel {} failed on attempt {}/{}: {}",
+                notification.alert_id, channel.id, attempt, attempts, e
+            ),
+        }
+
+        if attempt < attempts {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    error!(
+        "Giving up on alert {} via channel {} after {} attempts",
+        notification.alert_id, channel.id, attempts
+    );
+}
+
+/// Fan a notification out to every enabled channel of its rule in the background
+pub fn spawn_deliveries(app: &AppHandle, notification: &AlertNotification) {
+    let channels = {
+        let state = app.state::<AppState>();
+        let db = match state.db.lock() {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Failed to lock db to load alert channels: {}", e);
+                return;
+            }
+        };
+        match db.get_alert_channels(notification.rule_id) {
+            Ok(channels) => channels,
+            Err(e) => {
+                error!("Failed to load alert channels: {}", e);
+                return;
+            }
+        }
+    };
+
+    for channel in channels.into_iter().filter(|c| c.enabled) {
+        tauri::async_runtime::spawn(deliver_with_retry(
+            app.clone(),
+            channel,
+            notification.clone(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn notification() -> AlertNotification {
+        AlertNotification {
+            alert_id: 7,
+            rule_id: 1,
+            rule_name: "disk full".to_string(),
+            device_id: 2,
+            device_name: "edge-01".to_string(),
+            metric: "disk_percent".to_string(),
+            state: "firing".to_string(),
+            value: 93.5,
+            threshold: 90.0,
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    /// Accept one HTTP request, answer with `status` and return the request body
+    async fn serve_once(listener: TcpListener, status: &'static str) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        let body_start = loop {
+            let n = socket.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let headers = String::from_utf8_lossy(&buf[..body_start]).to_lowercase();
+        let content_length: usize = headers
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .map(|v| v.trim().parse().unwrap())
+            .unwrap_or(0);
+        while buf.len() < body_start + content_length {
+            let n = socket.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&buf[body_start..]).to_string()
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_notification_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "200 OK"));
+
+        send_webhook(&url, &notification()).await.unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert_eq!(body["alert_id"], 7);
+        assert_eq!(body["rule_name"], "disk full");
+        assert_eq!(body["state"], "firing");
+    }
+
+    #[tokio::test]
+    async fn webhook_error_status_is_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "500 Internal Server Error"));
+
+        let err = send_webhook(&url, &notification()).await.unwrap_err();
+        server.await.unwrap();
+        assert!(err.contains("500"), "unexpected error: {}", err);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_receives_notification() {
+        run_command(
+            r#"grep -q '"rule_name":"disk full"' && test "$SSEDGE_ALERT_STATE" = firing"#,
+            &notification(),
+        )
+        .await
+        .unwrap();
+
+        let err = run_command("exit 3", &notification()).await.unwrap_err();
+        assert!(err.contains("exit"), "unexpected error: {}", err);
+    }
+}
@@ -0,0 +1,623 @@
+//! Package manager detection and typed package operations for apt, dnf/yum,
+//! apk and opkg.
+
+use crate::collector::split_sections;
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device, PackageUpdateCount};
+use crate::ssh::{privileged, shell_quote, CommandOutput, Privilege};
+use futures_util::StreamExt;
+use log::{error, info};
+use openssh::Session;
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tauri event carrying one line of full-upgrade output
+pub const UPGRADE_OUTPUT_EVENT: &str = "package-upgrade-output";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Yum,
+    Apk,
+    Opkg,
+}
+
+/// Binaries probed in order; dnf wins over yum where both exist
+const DETECT_ORDER: &[(&str, PackageManager)] = &[
+    ("apt-get", PackageManager::Apt),
+    ("dnf", PackageManager::Dnf),
+    ("yum", PackageManager::Yum),
+    ("apk", PackageManager::Apk),
+    ("opkg", PackageManager::Opkg),
+];
+
+/// apt must never stop to ask about changed config files
+const APT: &str = "env DEBIAN_FRONTEND=noninteractive apt-get -y \
+-o Dpkg::Options::=--force-confdef -o Dpkg::Options::=--force-confold";
+
+impl PackageManager {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PackageManager::Apt => "apt",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Yum => "yum",
+            PackageManager::Apk => "apk",
+            PackageManager::Opkg => "opkg",
+        }
+    }
+
+    /// Script printing the binary of the first package manager found
+    pub fn detect_script() -> String {
+        let binaries: Vec<&str> = DETECT_ORDER.iter().map(|(bin, _)| *bin).collect();
+        format!(
+            "for pm in {}; do command -v $pm >/dev/null 2>&1 && {{ echo $pm; exit 0; }}; done; exit 1",
+            binaries.join(" ")
+        )
+    }
+
+    /// Map the output of [`Self::detect_script`] to a package manager
+    pub fn from_detected(output: &str) -> Option<Self> {
+        let binary = output.trim();
+        DETECT_ORDER
+            .iter()
+            .find(|(bin, _)| *bin == binary)
+            .map(|(_, pm)| *pm)
+    }
+
+    /// Script listing upgradable packages, refreshing the index first when asked
+    pub fn upgradable_script(&self, refresh: bool) -> String {
+        let refresh_cmd = match self {
+            PackageManager::Apt => Some("apt-get update -qq"),
+            PackageManager::Apk => Some("apk update -q"),
+            PackageManager::Opkg => Some("opkg update"),
+            // dnf and yum refresh expired metadata on their own
+            PackageManager::Dnf | PackageManager::Yum => None,
+        };
+        let mut script = String::from("export LC_ALL=C\n");
+        if let Some(cmd) = refresh_cmd.filter(|_| refresh) {
+            script.push_str(&format!("{} >/dev/null 2>&1\n", privileged(cmd)));
+        }
+        script.push_str(match self {
+            PackageManager::Apt => {
+                r#"echo "==upgradable=="; apt-get -s -o Debug::NoLocking=1 dist-upgrade"#
+            }
+            PackageManager::Dnf => {
+                r#"echo "==upgradable=="; dnf -q check-update
+echo "==security=="; dnf -q updateinfo list --security --available 2>/dev/null"#
+            }
+            PackageManager::Yum => {
+                r#"echo "==upgradable=="; yum -q check-update
+echo "==security=="; yum -q updateinfo list security 2>/dev/null"#
+            }
+            PackageManager::Apk => r#"echo "==upgradable=="; apk version -l '<'"#,
+            PackageManager::Opkg => r#"echo "==upgradable=="; opkg list-upgradable"#,
+        });
+        // check-update exits 100 when updates exist
+        script.push_str("\ntrue\n");
+        script
+    }
+
+    /// Command installing or removing a package as root
+    pub fn action_command(&self, action: PackageAction, package: &str) -> String {
+        let package = shell_quote(package);
+        let cmd = match (self, action) {
+            (PackageManager::Apt, PackageAction::Install) => {
+                format!("{} install -- {}", APT, package)
+            }
+            (PackageManager::Apt, PackageAction::Remove) => {
+                format!("{} remove -- {}", APT, package)
+            }
+            (PackageManager::Dnf | PackageManager::Yum, action) => {
+                format!("{} -y {} {}", self.as_str(), action.as_str(), package)
+            }
+            (PackageManager::Apk, PackageAction::Install) => format!("apk add {}", package),
+            (PackageManager::Apk, PackageAction::Remove) => format!("apk del {}", package),
+            (PackageManager::Opkg, action) => format!("opkg {} {}", action.as_str(), package),
+        };
+        format!("{} 2>&1", privileged(&cmd))
+    }
+
+    /// Script upgrading every package, with stderr merged into stdout
+    pub fn upgrade_script(&self) -> String {
+        let cmd = match self {
+            PackageManager::Apt => format!("apt-get update && {} dist-upgrade", APT),
+            PackageManager::Dnf | PackageManager::Yum => format!("{} -y upgrade", self.as_str()),
+            PackageManager::Apk => "apk update && apk upgrade".to_string(),
+            // opkg has no full upgrade, so upgrade whatever is listed
+            PackageManager::Opkg => {
+                "opkg update && opkg list-upgradable | cut -d ' ' -f 1 | xargs -r opkg upgrade"
+                    .to_string()
+            }
+        };
+        format!(
+            "export LC_ALL=C; {} 2>&1",
+            privileged(&format!("sh -c {}", shell_quote(&cmd)))
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageAction {
+    Install,
+    Remove,
+}
+
+impl PackageAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PackageAction::Install => "install",
+            PackageAction::Remove => "remove",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "install" => Some(PackageAction::Install),
+            "remove" => Some(PackageAction::Remove),
+            _ => None,
+        }
+    }
+}
+
+/// Check a package name before it is put on a command line
+pub fn validate_package_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 256
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".+_:@=~-".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid package name: {}", name))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct UpgradablePackage {
+    pub name: String,
+    pub current_version: Option<String>,
+    pub new_version: String,
+    /// Repository or suite the update comes from, when the manager reports it
+    pub repository: Option<String>,
+    pub security: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpgradableReport {
+    pub manager: String,
+    pub packages: Vec<UpgradablePackage>,
+    pub pending: usize,
+    pub security: usize,
+}
+
+/// Outcome of an install, remove or full upgrade
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageActionResult {
+    pub manager: String,
+    pub action: String,
+    pub package: Option<String>,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    /// Set when the action failed because escalating to root wanted a password
+    pub sudo_password_required: bool,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpgradeOutputLine {
+    pub device_id: i32,
+    pub line: String,
+}
+
+/// `Inst name [current] (new origin [arch])` lines of `apt-get -s`
+fn parse_apt(section: &str) -> Vec<UpgradablePackage> {
+    section
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Inst ")?;
+            let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (current, rest) = match rest.strip_prefix('[') {
+                Some(r) => {
+                    let (current, rest) = r.split_once(']')?;
+                    (Some(current.to_string()), rest.trim_start())
+                }
+                None => (None, rest),
+            };
+            let inner = rest.strip_prefix('(')?.split(')').next()?;
+            let (new_version, origins) = inner.trim().split_once(' ').unwrap_or((inner, ""));
+            // Every origin offering the version, comma separated, then ` [arch]`
+            let origins: Vec<&str> = origins
+                .split(" [")
+                .next()
+                .unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|o| !o.is_empty())
+                .collect();
+            Some(UpgradablePackage {
+                name: name.to_string(),
+                current_version: current,
+                new_version: new_version.trim().to_string(),
+                repository: origins.first().map(|o| o.to_string()),
+                security: origins
+                    .iter()
+                    .any(|o| o.to_lowercase().contains("security")),
+            })
+        })
+        .collect()
+}
+
+/// `name.arch  version  repo` rows of `check-update`
+///
+/// A name too long for its column pushes the rest of the row onto the next
+/// line, which is indented, so indented lines continue the row before them.
+fn parse_rpm(section: &str, security: &str) -> Vec<UpgradablePackage> {
+    // updateinfo lines are `ADVISORY  SEVERITY  name-version.arch`
+    let advisories: Vec<&str> = security
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .collect();
+    let mut rows: Vec<Vec<&str>> = Vec::new();
+    for line in section
+        .lines()
+        .take_while(|line| !line.starts_with("Obsoleting"))
+    {
+        match rows.last_mut() {
+            Some(row) if line.starts_with(char::is_whitespace) => {
+                row.extend(line.split_whitespace())
+            }
+            _ => rows.push(line.split_whitespace().collect()),
+        }
+    }
+    rows.into_iter()
+        .filter_map(|row| {
+            let [name_arch, version, repo] = row[..] else {
+                return None;
+            };
+            let name = name_arch
+                .rsplit_once('.')
+                .map_or(name_arch, |(name, _)| name);
+            let security = advisories
+                .iter()
+                .any(|nevra| nevra.starts_with(&format!("{}-{}", name, version)));
+            Some(UpgradablePackage {
+                name: name.to_string(),
+                current_version: None,
+                new_version: version.to_string(),
+                repository: Some(repo.to_string()),
+                security,
+            })
+        })
+        .collect()
+}
+
+/// Split an apk `name-version-rN` at the first `-` followed by a digit
+fn split_apk_name(package: &str) -> Option<(&str, &str)> {
+    let bytes = package.as_bytes();
+    (1..bytes.len())
+        .find(|&i| bytes[i - 1] == b'-' && bytes[i].is_ascii_digit())
+        .map(|i| (&package[..i - 1], &package[i..]))
+}
+
+/// `name-current < new` lines of `apk version -l '<'`
+fn parse_apk(section: &str) -> Vec<UpgradablePackage> {
+    section
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (package, op, new_version) = (fields.next()?, fields.next()?, fields.next()?);
+            if op != "<" {
+                return None;
+            }
+            let (name, current) = split_apk_name(package)?;
+            Some(UpgradablePackage {
+                name: name.to_string(),
+                current_version: Some(current.to_string()),
+                new_version: new_version.to_string(),
+                repository: None,
+                security: false,
+            })
+        })
+        .collect()
+}
+
+/// `name - current - new` lines of `opkg list-upgradable`
+fn parse_opkg(section: &str) -> Vec<UpgradablePackage> {
+    section
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(" - ");
+            let (name, current, new_version) = (fields.next()?, fields.next()?, fields.next()?);
+            Some(UpgradablePackage {
+                name: name.trim().to_string(),
+                current_version: Some(current.trim().to_string()),
+                new_version: new_version.trim().to_string(),
+                repository: None,
+                security: false,
+            })
+        })
+        .collect()
+}
+
+/// Build the report from the output of [`PackageManager::upgradable_script`]
+pub fn parse_upgradable(manager: PackageManager, output: &str) -> UpgradableReport {
+    let sections = split_sections(output);
+    let section = |name: &str| sections.get(name).copied().unwrap_or("");
+    let packages = match manager {
+        PackageManager::Apt => parse_apt(section("upgradable")),
+        PackageManager::Dnf | PackageManager::Yum => {
+            parse_rpm(section("upgradable"), section("security"))
+        }
+        PackageManager::Apk => parse_apk(section("upgradable")),
+        PackageManager::Opkg => parse_opkg(section("upgradable")),
+    };
+    UpgradableReport {
+        manager: manager.as_str().to_string(),
+        pending: packages.len(),
+        security: packages.iter().filter(|p| p.security).count(),
+        packages,
+    }
+}
+
+/// Turn the output of an install, remove or upgrade into a typed result
+pub fn action_result(
+    manager: PackageManager,
+    action: &str,
+    package: Option<&str>,
+    output: &CommandOutput,
+) -> PackageActionResult {
+    PackageActionResult {
+        manager: manager.as_str().to_string(),
+        action: action.to_string(),
+        package: package.map(str::to_string),
+        success: output.success(),
+        exit_code: output.exit_code,
+        sudo_password_required: !output.success()
+            && crate::ssh::sudo_password_required(&output.log_text()),
+        output: output.log_text(),
+    }
+}
+
+/// Find the package manager of the device behind `session`
+pub async fn detect(session: &Session) -> Result<PackageManager, String> {
+    let output = crate::ssh::exec(session, &PackageManager::detect_script()).await?;
+    PackageManager::from_detected(&output.stdout)
+        .ok_or_else(|| "No supported package manager found (apt, dnf, yum, apk, opkg)".to_string())
+}
+
+/// List upgradable packages and cache the counts for the fleet overview
+pub async fn list_upgradable(
+    state: &AppState,
+    device_id: i32,
+    refresh: bool,
+) -> Result<UpgradableReport, String> {
+    let (device, session) = crate::ssh::connect_device(state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(state, &device);
+    list_upgradable_on(state, device_id, &session, &privilege, refresh).await
+}
+
+async fn list_upgradable_on(
+    state: &AppState,
+    device_id: i32,
+    session: &Session,
+    privilege: &Privilege,
+    refresh: bool,
+) -> Result<UpgradableReport, String> {
+    let manager = detect(session).await?;
+    let script = manager.upgradable_script(refresh);
+    let output = crate::ssh::exec_as(session, privilege, &script).await?;
+    let report = parse_upgradable(manager, &output.stdout);
+
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_package_update_counts(
+        device_id,
+        manager.as_str(),
+        report.pending as i64,
+        report.security as i64,
+        unix_timestamp(),
+    )
+    .map_err(|e| e.to_string())?;
+    info!(
+        "Device {} has {} pending update(s), {} security",
+        device_id, report.pending, report.security
+    );
+    Ok(report)
+}
+
+/// Install or remove one package
+pub async fn run_action(
+    state: &AppState,
+    device_id: i32,
+    action: PackageAction,
+    package: &str,
+) -> Result<PackageActionResult, String> {
+    validate_package_name(package)?;
+    let (device, session) = crate::ssh::connect_device(state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(state, &device);
+    let manager = detect(&session).await?;
+    let command = manager.action_command(action, package);
+    let output = crate::ssh::exec_as(&session, &privilege, &command).await?;
+    crate::ssh::log_command(state, device_id, &command, Some(&output.log_text()));
+    let result = action_result(manager, action.as_str(), Some(package), &output);
+
+    // Installing or removing can change what is upgradable
+    if let Err(e) = list_upgradable_on(state, device_id, &session, &privilege, false).await {
+        error!(
+            "Failed to refresh pending updates of device {}: {}",
+            device_id, e
+        );
+    }
+    Ok(result)
+}
+
+/// Upgrade every package, emitting each output line as it arrives
+pub async fn upgrade(app: &AppHandle, device_id: i32) -> Result<PackageActionResult, String> {
+    let state = app.state::<AppState>();
+    let (device, session) = crate::ssh::connect_device(&state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &device);
+    let manager = detect(&session).await?;
+    let script = manager.upgrade_script();
+    info!(
+        "Upgrading packages on device {} with {}",
+        device_id,
+        manager.as_str()
+    );
+
+    let mut lines = Vec::new();
+    let exit_code = crate::ssh::exec_streaming_as(&session, &privilege, &script, |line| {
+        let event = UpgradeOutputLine {
+            device_id,
+            line: line.clone(),
+        };
+        if let Err(e) = app.emit(UPGRADE_OUTPUT_EVENT, &event) {
+            error!("Failed to emit upgrade output: {}", e);
+        }
+        lines.push(line);
+    })
+    .await?;
+
+    let output = CommandOutput {
+        stdout: lines.join("\n"),
+        stderr: String::new(),
+        exit_code,
+    };
+    crate::ssh::log_command(&state, device_id, &script, Some(&output.log_text()));
+    let result = action_result(manager, "upgrade", None, &output);
+
+    if let Err(e) = list_upgradable_on(&state, device_id, &session, &privilege, false).await {
+        error!(
+            "Failed to refresh pending updates of device {}: {}",
+            device_id, e
+        );
+    }
+    Ok(result)
+}
+
+/// Refresh the cached pending-update counts of many devices
+pub async fn check_fleet(state: &AppState, devices: Vec<Device>) -> Vec<PackageUpdateCount> {
+    futures_util::stream::iter(devices)
+        .for_each_concurrent(crate::fleet::concurrency(None), |device| async move {
+            if let Err(e) = list_upgradable(state, device.id, true).await {
+                error!("Failed to check updates on {}: {}", device.name, e);
+            }
+        })
+        .await;
+    state
+        .db
+        .lock()
+        .map_err(|e| e.to_string())
+        .and_then(|db| db.get_package_update_counts().map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            error!("Failed to load pending update counts: {}", e);
+            Vec::new()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_apt_simulation() {
+        let output = "==upgradable==\n\
+NOTE: This is only a simulation!\n\
+Inst openssl [3.0.11-1~deb12u1] (3.0.11-1~deb12u2 Debian-Security:12/stable-security [amd64])\n\
+Inst tzdata [2024a-0+deb12u1] (2024b-0+deb12u1 Debian:12.7/stable [all])\n\
+Inst linux-image-6.1.0-25-amd64 (6.1.106-3 Debian:12.7/stable [amd64])\n\
+Inst libssl1.1 [1.1.1f-1ubuntu2.19] (1.1.1f-1ubuntu2.20 Ubuntu:20.04/focal-updates, Ubuntu:20.04/focal-security [amd64])\n\
+Conf openssl (3.0.11-1~deb12u2 Debian-Security:12/stable-security [amd64])\n";
+        let report = parse_upgradable(PackageManager::Apt, output);
+        assert_eq!(report.pending, 4);
+        assert_eq!(report.security, 2);
+        assert_eq!(
+            report.packages[0],
+            UpgradablePackage {
+                name: "openssl".to_string(),
+                current_version: Some("3.0.11-1~deb12u1".to_string()),
+                new_version: "3.0.11-1~deb12u2".to_string(),
+                repository: Some("Debian-Security:12/stable-security".to_string()),
+                security: true,
+            }
+        );
+        assert_eq!(report.packages[2].current_version, None);
+        // Offered by -updates and -security; any security origin counts
+        let libssl = report
+            .packages
+            .iter()
+            .find(|p| p.name == "libssl1.1")
+            .unwrap();
+        assert!(libssl.security);
+        assert_eq!(libssl.new_version, "1.1.1f-1ubuntu2.20");
+        assert_eq!(
+            libssl.repository.as_deref(),
+            Some("Ubuntu:20.04/focal-updates")
+        );
+    }
+
+    #[test]
+    fn parses_dnf_check_update_with_advisories() {
+        let output = concat!(
+            "==upgradable==\n",
+            "\n",
+            "openssl-libs.x86_64          1:3.0.7-27.el9          baseos\n",
+            "vim-minimal.x86_64           2:8.2.2637-21.el9       baseos\n",
+            "python3-pip-wheel-with-a-long-name.noarch\n",
+            "                             21.3.1-1.el9            appstream\n",
+            "NetworkManager-libnm-with-a-long-name.x86_64         1:1.46.0-1.el9\n",
+            "                                                     baseos\n",
+            "Obsoleting Packages\n",
+            "grub2-tools.x86_64           1:2.06-80.el9           baseos\n",
+            "==security==\n",
+            "RLSA-2024:1234 Important/Sec. openssl-libs-1:3.0.7-27.el9.x86_64\n",
+        );
+        let report = parse_upgradable(PackageManager::Dnf, output);
+        assert_eq!(report.pending, 4);
+        assert_eq!(report.security, 1);
+        assert_eq!(report.packages[0].name, "openssl-libs");
+        assert!(report.packages[0].security);
+        assert!(!report.packages[1].security);
+        // Rows wrapped after a long name
+        assert_eq!(
+            report.packages[2].name,
+            "python3-pip-wheel-with-a-long-name"
+        );
+        assert_eq!(report.packages[2].new_version, "21.3.1-1.el9");
+        assert_eq!(report.packages[2].repository.as_deref(), Some("appstream"));
+        assert_eq!(
+            report.packages[3].name,
+            "NetworkManager-libnm-with-a-long-name"
+        );
+        assert_eq!(report.packages[3].new_version, "1:1.46.0-1.el9");
+        assert_eq!(report.packages[3].repository.as_deref(), Some("baseos"));
+    }
+
+    #[test]
+    fn parses_apk_and_opkg() {
+        let apk = "==upgradable==\nInstalled:                                Available:\n\
+busybox-1.36.1-r2         < 1.36.1-r5\npy3-six-1.16.0-r3 < 1.16.0-r4\n";
+        let report = parse_upgradable(PackageManager::Apk, apk);
+        assert_eq!(report.pending, 2);
+        assert_eq!(report.packages[1].name, "py3-six");
+        assert_eq!(
+            report.packages[1].current_version.as_deref(),
+            Some("1.16.0-r3")
+        );
+
+        let opkg = "==upgradable==\nluci-base - git-23.1 - git-24.2\n";
+        let report = parse_upgradable(PackageManager::Opkg, opkg);
+        assert_eq!(report.packages[0].name, "luci-base");
+        assert_eq!(report.packages[0].new_version, "git-24.2");
+    }
+
+    #[test]
+    fn package_names_are_validated() {
+        assert!(validate_package_name("libssl3:amd64").is_ok());
+        assert!(validate_package_name("g++").is_ok());
+        assert!(validate_package_name("-y").is_err());
+        assert!(validate_package_name("a b").is_err());
+        assert!(validate_package_name("a;rm").is_err());
+    }
+}
@@ -0,0 +1,447 @@
+//! Remote process listing built from `/proc`, and signalling via `kill`.
+
+use crate::collector::split_sections;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Interval between the two `/proc/<pid>/stat` samples used for CPU usage
+const CPU_SAMPLE_INTERVAL: &str = "0.5";
+
+/// Signals that may be sent from the app; anything else is rejected
+const ALLOWED_SIGNALS: &[&str] = &[
+    "HUP", "INT", "QUIT", "KILL", "USR1", "USR2", "TERM", "CONT", "STOP",
+];
+
+/// POSIX `sh` script dumping everything needed to build the process list
+pub fn list_script() -> String {
+    format!(
+        r#"
+export LC_ALL=C
+echo "==clk=="; getconf CLK_TCK 2>/dev/null || echo 100
+echo "==pagesize=="; getconf PAGESIZE 2>/dev/null || echo 4096
+echo "==btime=="; grep '^btime' /proc/stat
+echo "==passwd=="; cat /etc/passwd
+echo "==uptime1=="; cat /proc/uptime
+echo "==stat1=="; cat /proc/[0-9]*/stat 2>/dev/null
+sleep {interval} 2>/dev/null || sleep 1
+echo "==uptime2=="; cat /proc/uptime
+echo "==stat2=="; cat /proc/[0-9]*/stat 2>/dev/null
+echo "==uid=="; grep -H '^Uid:' /proc/[0-9]*/status 2>/dev/null
+echo "==cmdline=="
+for p in /proc/[0-9]*; do
+    printf '%s ' "${{p#/proc/}}"; od -An -v -tx1 < "$p/cmdline" 2>/dev/null | tr -d ' \n'; echo
+done
+"#,
+        interval = CPU_SAMPLE_INTERVAL
+    )
+}
+
+/// One process on the device
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    pub user: String,
+    pub state: String,
+    pub cpu_percent: f64,
+    pub rss_kb: u64,
+    /// Full command line, or `[comm]` for kernel threads
+    pub command: String,
+    /// UNIX time the process started
+    pub start_time: i64,
+}
+
+/// Fields of `/proc/<pid>/stat` the process list needs
+#[derive(Debug, Clone)]
+struct PidStat {
+    comm: String,
+    state: String,
+    ppid: u32,
+    cpu_ticks: u64,
+    start_ticks: u64,
+    rss_pages: u64,
+}
+
+/// Parse one `/proc/<pid>/stat` line; `comm` may contain spaces and parens,
+/// so fields are counted from the last `)`
+fn parse_pid_stat(line: &str) -> Result<(u32, PidStat), String> {
+    let open = line.find('(');
+    let close = line.rfind(')');
+    let (Some(open), Some(close)) = (open, close) else {
+        return Err(format!("/proc/<pid>/stat: unexpected line '{}'", line));
+    };
+    let pid = line[..open]
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| format!("/proc/<pid>/stat: invalid pid in '{}'", line))?;
+    let fields: Vec<&str> = line[close + 1..].split_whitespace().collect();
+    // fields[0] is field 3 (state) of proc(5)
+    if fields.len() < 22 {
+        return Err(format!("/proc/<pid>/stat: too few fields for pid {}", pid));
+    }
+    let num = |i: usize| {
+        fields[i]
+            .parse::<u64>()
+            .map_err(|_| format!("/proc/<pid>/stat: invalid field for pid {}", pid))
+    };
+    Ok((
+        pid,
+        PidStat {
+            comm: line[open + 1..close].to_string(),
+            state: fields[0].to_string(),
+            ppid: num(1)? as u32,
+            cpu_ticks: num(11)? + num(12)?,
+            start_ticks: num(19)?,
+            rss_pages: num(21)?,
+        },
+    ))
+}
+
+/// Parse a dump of `/proc/*/stat`, skipping lines that do not parse, such
+/// as a process that exited while it was being read
+fn parse_pid_stats(section: &str) -> HashMap<u32, PidStat> {
+    section
+        .lines()
+        .filter_map(|l| parse_pid_stat(l).ok())
+        .collect()
+}
+
+/// Map uid to user name from `/etc/passwd`; also maps gid to name for `/etc/group`
+pub(crate) fn parse_passwd(passwd: &str) -> HashMap<u32, String> {
+    passwd
+        .lines()
+        .filter_map(|l| {
+            let mut parts = l.split(':');
+            let name = parts.next()?;
+            let uid = parts.nth(1)?.parse().ok()?;
+            Some((uid, name.to_string()))
+        })
+        .collect()
+}
+
+/// Parse `grep -H '^Uid:' /proc/*/status` into pid -> real uid
+fn parse_uids(uids: &str) -> HashMap<u32, u32> {
+    uids.lines()
+        .filter_map(|l| {
+            let (path, rest) = l.split_once(":Uid:")?;
+            let pid = path.strip_prefix("/proc/")?.strip_suffix("/status")?;
+            let uid = rest.split_whitespace().next()?;
+            Some((pid.parse().ok()?, uid.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Parse `<pid> <hex>` lines, where the hex is the raw `/proc/<pid>/cmdline`.
+/// Arguments are hex encoded on the device so that one containing a newline
+/// cannot end its line early and pose as another pid.
+fn parse_cmdlines(cmdlines: &str) -> HashMap<u32, String> {
+    cmdlines
+        .lines()
+        .filter_map(|l| {
+            let (pid, hex) = l.split_once(' ').unwrap_or((l, ""));
+            let bytes = decode_hex(hex.trim())?;
+            let args: Vec<String> = bytes
+                .strip_suffix(&[0])
+                .unwrap_or(&bytes)
+                .split(|b| *b == 0)
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+            Some((pid.parse().ok()?, args.join(" ").trim().to_string()))
+        })
+        .collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parse the `field`-th whitespace separated token of a one-line section
+fn parse_single<T: std::str::FromStr>(
+    sections: &HashMap<&str, &str>,
+    name: &str,
+    field: usize,
+) -> Result<T, String> {
+    sections
+        .get(name)
+        .and_then(|raw| raw.split_whitespace().nth(field))
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| format!("process output has a missing or invalid {} section", name))
+}
+
+/// Build the process list from the output of [`list_script`]
+///
+/// Processes that exited between the two samples are dropped; processes
+/// that started in between get their CPU usage from the second sample only.
+pub fn parse_processes(output: &str) -> Result<Vec<ProcessInfo>, String> {
+    let sections = split_sections(output);
+    let clk_tck: u64 = parse_single(&sections, "clk", 0)?;
+    let page_size: u64 = parse_single(&sections, "pagesize", 0)?;
+    // "btime 1700000000"
+    let btime: i64 = parse_single(&sections, "btime", 1)?;
+    let uptime1: f64 = parse_single(&sections, "uptime1", 0)?;
+    let uptime2: f64 = parse_single(&sections, "uptime2", 0)?;
+    let elapsed = uptime2 - uptime1;
+    if clk_tck == 0 || elapsed <= 0.0 {
+        return Err("process output has no elapsed time between samples".to_string());
+    }
+
+    let section = |name: &str| sections.get(name).copied().unwrap_or("");
+    let before = parse_pid_stats(section("stat1"));
+    let after = parse_pid_stats(section("stat2"));
+    let users = parse_passwd(section("passwd"));
+    let uids = parse_uids(section("uid"));
+    let cmdlines = parse_cmdlines(section("cmdline"));
+
+    let mut processes: Vec<ProcessInfo> = after
+        .into_iter()
+        .map(|(pid, stat)| {
+            let prev_ticks = before.get(&pid).map_or(0, |p| p.cpu_ticks);
+            let cpu_seconds = stat.cpu_ticks.saturating_sub(prev_ticks) as f64 / clk_tck as f64;
+            let user = match uids.get(&pid) {
+                Some(uid) => users.get(uid).cloned().unwrap_or_else(|| uid.to_string()),
+                None => String::new(),
+            };
+            let command = match cmdlines.get(&pid) {
+                Some(cmd) if !cmd.is_empty() => cmd.clone(),
+                _ => format!("[{}]", stat.comm),
+            };
+            ProcessInfo {
+                pid,
+                ppid: stat.ppid,
+                user,
+                state: stat.state,
+                cpu_percent: cpu_seconds * 100.0 / elapsed,
+                rss_kb: stat.rss_pages * page_size / 1024,
+                command,
+                start_time: btime + (stat.start_ticks / clk_tck) as i64,
+            }
+        })
+        .collect();
+    processes.sort_by_key(|p| p.pid);
+    Ok(processes)
+}
+
+/// Filter by a case-insensitive substring of user or command, then sort
+pub fn filter_and_sort(
+    mut processes: Vec<ProcessInfo>,
+    filter: Option<&str>,
+    sort_by: Option<&str>,
+    descending: bool,
+) -> Result<Vec<ProcessInfo>, String> {
+    if let Some(filter) = filter.map(str::to_lowercase).filter(|f| !f.is_empty()) {
+        processes.retain(|p| {
+            p.command.to_lowercase().contains(&filter) || p.user.to_lowercase().contains(&filter)
+        });
+    }
+
+    match sort_by.unwrap_or("pid") {
+        "pid" => processes.sort_by_key(|p| p.pid),
+        "cpu" => processes.sort_by(|a, b| a.cpu_percent.total_cmp(&b.cpu_percent)),
+        "rss" => processes.sort_by_key(|p| p.rss_kb),
+        "start_time" => processes.sort_by_key(|p| p.start_time),
+        "user" => processes.sort_by(|a, b| a.user.cmp(&b.user)),
+        "command" => processes.sort_by(|a, b| a.command.cmp(&b.command)),
+        other => return Err(format!("Unknown sort field: {}", other)),
+    }
+    if descending {
+        processes.reverse();
+    }
+    Ok(processes)
+}
+
+/// Normalise a signal name such as `SIGTERM`, `term` or `15` to `TERM`
+pub fn normalize_signal(signal: &str) -> Result<String, String> {
+    let upper = signal.trim().to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    let name = match name {
+        "1" => "HUP",
+        "2" => "INT",
+        "3" => "QUIT",
+        "9" => "KILL",
+        "15" => "TERM",
+        other => other,
+    };
+    if ALLOWED_SIGNALS.contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("Unsupported signal: {}", signal))
+    }
+}
+
+/// Check a pid before it is signalled: `kill 0` would signal the SSH
+/// session's own process group, and `pid_t` is signed
+pub fn validate_pid(pid: u32) -> Result<(), String> {
+    if pid == 0 || pid > i32::MAX as u32 {
+        Err(format!("Invalid pid: {}", pid))
+    } else {
+        Ok(())
+    }
+}
+
+/// `kill` invocation for an already validated pid and signal
+pub fn kill_command(pid: u32, signal: &str) -> String {
+    format!("kill -s {} {}", signal, pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/proc/<pid>/stat` line with the given comm, ppid, utime+stime,
+    /// starttime and rss
+    fn stat_line(pid: u32, comm: &str, ppid: u32, ticks: u64, start: u64, rss: u64) -> String {
+        format!(
+            "{} ({}) S {} {} {} 0 -1 4194560 100 0 0 0 {} 0 0 0 20 0 1 0 {} 1000000 {} 0",
+            pid, comm, ppid, pid, pid, ticks, start, rss
+        )
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn process(pid: u32, user: &str, cpu: f64, rss_kb: u64, command: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid: 1,
+            user: user.to_string(),
+            state: "S".to_string(),
+            cpu_percent: cpu,
+            rss_kb,
+            command: command.to_string(),
+            start_time: 1_700_000_000 + pid as i64,
+        }
+    }
+
+    #[test]
+    fn parses_stat_with_spaces_and_parens_in_comm() {
+        let (pid, stat) =
+            parse_pid_stat(&stat_line(42, "tmux: (server) x", 1, 30, 500, 256)).unwrap();
+        assert_eq!(pid, 42);
+        assert_eq!(stat.comm, "tmux: (server) x");
+        assert_eq!(stat.state, "S");
+        assert_eq!(stat.ppid, 1);
+        assert_eq!(stat.cpu_ticks, 30);
+        assert_eq!(stat.start_ticks, 500);
+        assert_eq!(stat.rss_pages, 256);
+
+        assert!(parse_pid_stat("42 (short) S 1 2 3").is_err());
+        assert!(parse_pid_stat("no parens here").is_err());
+    }
+
+    #[test]
+    fn skips_unparsable_stat_lines() {
+        let section = format!(
+            "{}\n42 (trunc\n\n{}\n",
+            stat_line(1, "init", 0, 10, 1, 100),
+            stat_line(7, "sshd", 1, 5, 2, 200)
+        );
+        let stats = parse_pid_stats(&section);
+        let mut pids: Vec<u32> = stats.keys().copied().collect();
+        pids.sort();
+        assert_eq!(pids, vec![1, 7]);
+    }
+
+    #[test]
+    fn newline_in_argv_cannot_pose_as_another_pid() {
+        let section = format!(
+            "10 {}\n11 {}\n12 \n",
+            hex(b"sh\0-c\0x\n11 fake\0"),
+            hex(b"nginx: worker\0")
+        );
+        let cmdlines = parse_cmdlines(&section);
+        assert_eq!(cmdlines[&10], "sh -c x\n11 fake");
+        assert_eq!(cmdlines[&11], "nginx: worker");
+        assert_eq!(cmdlines[&12], "");
+        assert_eq!(cmdlines.len(), 3);
+    }
+
+    #[test]
+    fn builds_process_list_from_two_samples() {
+        let output = format!(
+            "==clk==\n100\n==pagesize==\n4096\n==btime==\nbtime 1700000000\n\
+             ==passwd==\nroot:x:0:0:root:/root:/bin/sh\npi:x:1000:1000::/home/pi:/bin/sh\n\
+             ==uptime1==\n100.00 50.00\n==stat1==\n{}\n{}\n\
+             ==uptime2==\n100.50 50.20\n==stat2==\n{}\n{}\n{}\n\
+             ==uid==\n/proc/1/status:Uid:\t0\t0\t0\t0\n/proc/300/status:Uid:\t1000\t1000\t1000\t1000\n\
+             ==cmdline==\n1 {}\n2 \n300 {}\n",
+            stat_line(1, "init", 0, 100, 1, 1000),
+            stat_line(2, "kthreadd", 0, 0, 2, 0),
+            stat_line(1, "init", 0, 110, 1, 1000),
+            stat_line(2, "kthreadd", 0, 0, 2, 0),
+            stat_line(300, "python3", 1, 25, 9000, 2048),
+            hex(b"/sbin/init\0splash\0"),
+            hex(b"python3\0app.py\0"),
+        );
+        let processes = parse_processes(&output).unwrap();
+        let pids: Vec<u32> = processes.iter().map(|p| p.pid).collect();
+        assert_eq!(pids, vec![1, 2, 300]);
+
+        let init = &processes[0];
+        assert_eq!(init.user, "root");
+        assert_eq!(init.command, "/sbin/init splash");
+        // 10 ticks at 100/s over 0.5 s
+        assert!((init.cpu_percent - 20.0).abs() < 1e-9);
+        assert_eq!(init.rss_kb, 4000);
+
+        let kthread = &processes[1];
+        assert_eq!(kthread.command, "[kthreadd]");
+        assert_eq!(kthread.user, "");
+
+        // Started between the samples, so all of its ticks count
+        let app = &processes[2];
+        assert_eq!(app.user, "pi");
+        assert_eq!(app.command, "python3 app.py");
+        assert!((app.cpu_percent - 50.0).abs() < 1e-9);
+        assert_eq!(app.start_time, 1_700_000_090);
+
+        assert!(parse_processes("==clk==\n100\n").is_err());
+    }
+
+    #[test]
+    fn rejects_pids_kill_would_misread() {
+        assert!(validate_pid(1).is_ok());
+        assert!(validate_pid(i32::MAX as u32).is_ok());
+        assert!(validate_pid(0).is_err());
+        assert!(validate_pid(i32::MAX as u32 + 1).is_err());
+        assert!(validate_pid(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn filters_and_sorts() {
+        let processes = vec![
+            process(3, "root", 1.5, 900, "/usr/sbin/sshd -D"),
+            process(1, "root", 0.0, 4000, "/sbin/init"),
+            process(20, "pi", 12.0, 300, "python3 app.py"),
+        ];
+        let pids = |list: Vec<ProcessInfo>| list.iter().map(|p| p.pid).collect::<Vec<_>>();
+
+        assert_eq!(
+            pids(filter_and_sort(processes.clone(), None, None, false).unwrap()),
+            vec![1, 3, 20]
+        );
+        assert_eq!(
+            pids(filter_and_sort(processes.clone(), Some("ROOT"), Some("rss"), true).unwrap()),
+            vec![1, 3]
+        );
+        assert_eq!(
+            pids(filter_and_sort(processes.clone(), Some("sshd"), None, false).unwrap()),
+            vec![3]
+        );
+        assert_eq!(
+            pids(filter_and_sort(processes.clone(), Some(""), Some("cpu"), true).unwrap()),
+            vec![20, 3, 1]
+        );
+        assert_eq!(
+            pids(filter_and_sort(processes.clone(), None, Some("user"), false).unwrap()),
+            vec![20, 3, 1]
+        );
+        assert!(filter_and_sort(processes, None, Some("memory"), false).is_err());
+    }
+}
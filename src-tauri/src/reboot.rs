@@ -0,0 +1,328 @@
+//! Reboots that are verified: the device must drop off, come back with a
+//! reset uptime and pass its health checks.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device};
+use crate::ssh::{privileged, shell_quote, Destination};
+use futures_util::StreamExt;
+use log::{error, info};
+use openssh::Session;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tauri event emitted with a [`RebootProgress`] at every phase change
+pub const REBOOT_PROGRESS_EVENT: &str = "reboot-progress";
+
+const DEFAULT_OFFLINE_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_ONLINE_TIMEOUT_SECS: u64 = 600;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const PORT_TIMEOUT: Duration = Duration::from_secs(3);
+/// sshd may accept connections a little before it can open sessions
+const SSH_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RebootOptions {
+    /// Commands run after the device is back; every one must exit 0
+    #[serde(default)]
+    pub health_checks: Vec<String>,
+    pub offline_timeout_secs: Option<u64>,
+    pub online_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RebootProgress {
+    pub device_id: i32,
+    /// issued, offline, online, checking, succeeded, failed or skipped
+    pub phase: String,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthCheckResult {
+    pub command: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RebootResult {
+    pub device_id: i32,
+    pub device_name: String,
+    pub success: bool,
+    /// Not rebooted because an earlier device in a rolling reboot failed
+    pub skipped: bool,
+    pub error: Option<String>,
+    pub uptime_before_secs: Option<f64>,
+    pub uptime_after_secs: Option<f64>,
+    /// Seconds from issuing the reboot until the SSH port stopped answering
+    pub offline_after_secs: Option<f64>,
+    /// Seconds from issuing the reboot until the SSH port answered again
+    pub online_after_secs: Option<f64>,
+    pub total_secs: f64,
+    pub health_checks: Vec<HealthCheckResult>,
+}
+
+impl RebootResult {
+    fn new(device: &Device) -> Self {
+        RebootResult {
+            device_id: device.id,
+            device_name: device.name.clone(),
+            success: false,
+            skipped: false,
+            error: None,
+            uptime_before_secs: None,
+            uptime_after_secs: None,
+            offline_after_secs: None,
+            online_after_secs: None,
+            total_secs: 0.0,
+            health_checks: Vec::new(),
+        }
+    }
+}
+
+/// First field of `/proc/uptime`
+pub fn parse_uptime(output: &str) -> Option<f64> {
+    output.split_whitespace().next()?.parse().ok()
+}
+
+/// Whether an uptime read after the reboot proves the device restarted
+///
+/// The device cannot have been up longer than it has been since the reboot
+/// was issued, so an uptime below that elapsed time means the kernel restarted.
+pub fn uptime_reset(before: f64, after: f64, since_issued: f64) -> bool {
+    after < since_issued || after < before
+}
+
+/// Command that reboots in the background so the SSH command can return first
+pub fn reboot_command() -> String {
+    let detached = "nohup sh -c 'sleep 2; reboot || /sbin/reboot' >/dev/null 2>&1 </dev/null &";
+    privileged(&format!("sh -c {}", shell_quote(detached)))
+}
+
+async fn read_uptime(session: &Session) -> Result<f64, String> {
+    let output = crate::ssh::exec(session, "cat /proc/uptime").await?;
+    parse_uptime(&output.stdout).ok_or_else(|| "Failed to read /proc/uptime".to_string())
+}
+
+async fn port_open(device: &Device) -> bool {
+    let Ok(destination) = Destination::new(&device.ip, &device.ssh_config()) else {
+        return false;
+    };
+    let connect = async {
+        let addrs = destination.socket_addrs().await?;
+        tokio::net::TcpStream::connect(&addrs[..])
+            .await
+            .map_err(|e| e.to_string())
+    };
+    matches!(tokio::time::timeout(PORT_TIMEOUT, connect).await, Ok(Ok(_)))
+}
+
+/// Poll the SSH port until it is `open` or `timeout` passes
+async fn wait_for_port(device: &Device, open: bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if port_open(device).await == open {
+            return true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    false
+}
+
+async fn reconnect(state: &AppState, device_id: i32) -> Result<Session, String> {
+    let mut last_error = String::new();
+    for _ in 0..SSH_ATTEMPTS {
+        match crate::ssh::connect_device(state, device_id).await {
+            Ok((_device, session)) => return Ok(session),
+            Err(e) => last_error = e,
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    Err(last_error)
+}
+
+fn emit_progress(app: &AppHandle, device_id: i32, phase: &str, started: Instant) {
+    let progress = RebootProgress {
+        device_id,
+        phase: phase.to_string(),
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    };
+    if let Err(e) = app.emit(REBOOT_PROGRESS_EVENT, &progress) {
+        error!("Failed to emit reboot progress: {}", e);
+    }
+}
+
+async fn run(
+    app: &AppHandle,
+    device: &Device,
+    options: &RebootOptions,
+    result: &mut RebootResult,
+) -> Result<(), String> {
+    let state = app.state::<AppState>();
+    let (connected, session) = crate::ssh::connect_device(&state, device.id).await?;
+    let privilege = crate::ssh::Privilege::for_device(&state, &connected);
+    let before = read_uptime(&session).await?;
+    result.uptime_before_secs = Some(before);
+
+    let command = reboot_command();
+    let output = crate::ssh::exec_as(&session, &privilege, &command).await?;
+    crate::ssh::log_command(&state, device.id, "reboot", Some(&output.log_text()));
+    if !output.success() {
+        return Err(format!(
+            "Reboot command failed: {}",
+            output.log_text().trim()
+        ));
+    }
+    drop(session);
+    let issued = Instant::now();
+    emit_progress(app, device.id, "issued", issued);
+
+    // A fast device may be back before a poll sees it down; the uptime check
+    // below is what proves the reboot happened
+    let offline_timeout = Duration::from_secs(
+        options
+            .offline_timeout_secs
+            .unwrap_or(DEFAULT_OFFLINE_TIMEOUT_SECS),
+    );
+    if wait_for_port(device, false, offline_timeout).await {
+        result.offline_after_secs = Some(issued.elapsed().as_secs_f64());
+        emit_progress(app, device.id, "offline", issued);
+    }
+
+    let online_timeout = Duration::from_secs(
+        options
+            .online_timeout_secs
+            .unwrap_or(DEFAULT_ONLINE_TIMEOUT_SECS),
+    );
+    if !wait_for_port(device, true, online_timeout).await {
+        return Err(format!(
+            "SSH port did not come back within {}s",
+            online_timeout.as_secs()
+        ));
+    }
+    result.online_after_secs = Some(issued.elapsed().as_secs_f64());
+    emit_progress(app, device.id, "online", issued);
+
+    let session = reconnect(&state, device.id).await?;
+    let after = read_uptime(&session).await?;
+    result.uptime_after_secs = Some(after);
+    if !uptime_reset(before, after, issued.elapsed().as_secs_f64()) {
+        return Err(format!(
+            "Uptime did not reset ({:.0}s before, {:.0}s after); the device did not reboot",
+            before, after
+        ));
+    }
+    if let Ok(db) = state.db.lock() {
+        if let Err(e) = db.update_device_last_seen(device.id, unix_timestamp()) {
+            error!("Failed to update last seen of {}: {}", device.name, e);
+        }
+    }
+
+    emit_progress(app, device.id, "checking", issued);
+    for command in options
+        .health_checks
+        .iter()
+        .filter(|c| !c.trim().is_empty())
+    {
+        let output = crate::ssh::exec(&session, command).await?;
+        crate::ssh::log_command(&state, device.id, command, Some(&output.log_text()));
+        result.health_checks.push(HealthCheckResult {
+            command: command.clone(),
+            success: output.success(),
+            exit_code: output.exit_code,
+            output: output.log_text(),
+        });
+    }
+    if let Some(failed) = result.health_checks.iter().find(|c| !c.success) {
+        return Err(format!("Health check failed: {}", failed.command));
+    }
+    Ok(())
+}
+
+/// Reboot one device and wait until it is verified back
+pub async fn reboot(app: &AppHandle, device: &Device, options: &RebootOptions) -> RebootResult {
+    let started = Instant::now();
+    let mut result = RebootResult::new(device);
+    info!("Rebooting {}", device.name);
+    let outcome = run(app, device, options, &mut result).await;
+    result.total_secs = started.elapsed().as_secs_f64();
+
+    match outcome {
+        Ok(()) => {
+            result.success = true;
+            info!(
+                "{} rebooted and verified in {:.0}s",
+                device.name, result.total_secs
+            );
+            emit_progress(app, device.id, "succeeded", started);
+        }
+        Err(e) => {
+            error!("Reboot of {} failed: {}", device.name, e);
+            result.error = Some(e);
+            emit_progress(app, device.id, "failed", started);
+        }
+    }
+    result
+}
+
+/// Reboot a group with at most `max_unavailable` devices down at a time
+///
+/// Once any device fails, devices not yet started are skipped so a bad
+/// change cannot take down the whole group.
+pub async fn rolling_reboot(
+    app: &AppHandle,
+    devices: Vec<Device>,
+    max_unavailable: usize,
+    options: &RebootOptions,
+) -> Vec<RebootResult> {
+    let halted = AtomicBool::new(false);
+    let halted = &halted;
+    info!(
+        "Rolling reboot of {} device(s), at most {} at a time",
+        devices.len(),
+        max_unavailable
+    );
+
+    let mut results: Vec<RebootResult> = futures_util::stream::iter(devices)
+        .map(|device| async move {
+            if halted.load(Ordering::Relaxed) {
+                let mut result = RebootResult::new(&device);
+                result.skipped = true;
+                result.error = Some("Skipped after an earlier reboot failed".to_string());
+                emit_progress(app, device.id, "skipped", Instant::now());
+                return result;
+            }
+            let result = reboot(app, &device, options).await;
+            if !result.success {
+                halted.store(true, Ordering::Relaxed);
+            }
+            result
+        })
+        .buffer_unordered(max_unavailable.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|r| r.device_id);
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_proc_uptime() {
+        assert_eq!(parse_uptime("350735.47 234388.90\n"), Some(350735.47));
+        assert_eq!(parse_uptime(""), None);
+    }
+
+    #[test]
+    fn uptime_reset_requires_a_fresh_kernel() {
+        // Back after 90s with 40s of uptime: rebooted
+        assert!(uptime_reset(86400.0, 40.0, 90.0));
+        // Uptime kept growing: the reboot never happened
+        assert!(!uptime_reset(86400.0, 86490.0, 90.0));
+    }
+}
@@ -0,0 +1,711 @@
+//! Runbooks: ordered steps run on a device, with captured variables,
+//! branching on exit codes, operator approvals and rollback on failure.
+//!
+//! Every run stores its steps, position, variables and step results in the
+//! database as it goes, so a run waiting for approval survives a restart and
+//! a run cut short by one can be resumed.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Device, Runbook, RunbookRun, RunbookStep, StepAction};
+use crate::fleet::PushSpec;
+use crate::ssh::{privileged, shell_quote, Privilege};
+use futures_util::StreamExt;
+use log::{error, info};
+use openssh::Session;
+use std::collections::{BTreeMap, HashSet};
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tauri event emitted with the [`RunbookRun`] whenever a run's state is saved
+pub const RUNBOOK_RUN_EVENT: &str = "runbook-run";
+
+/// Branch target that finishes the run successfully
+pub const END: &str = "end";
+/// Branch target that fails the run and rolls it back
+pub const FAIL: &str = "fail";
+
+/// Steps executed by one call before the run is failed, so a branch that
+/// loops back on itself cannot run forever
+const MAX_STEP_EXECUTIONS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunState {
+    Pending,
+    Running,
+    AwaitingApproval,
+    Interrupted,
+    Succeeded,
+    Failed,
+}
+
+impl RunState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunState::Pending => "pending",
+            RunState::Running => "running",
+            RunState::AwaitingApproval => "awaiting_approval",
+            RunState::Interrupted => "interrupted",
+            RunState::Succeeded => "succeeded",
+            RunState::Failed => "failed",
+        }
+    }
+}
+
+impl RunbookStep {
+    /// Rollbacks run with the privileges of the step they undo
+    fn as_root(&self) -> bool {
+        match &self.action {
+            StepAction::Command { as_root, .. } => *as_root,
+            StepAction::Upload { .. } => true,
+            StepAction::Approval { .. } => false,
+        }
+    }
+
+    fn targets(&self) -> impl Iterator<Item = &String> {
+        self.branches
+            .values()
+            .chain(self.on_success.iter())
+            .chain(self.on_failure.iter())
+    }
+}
+
+/// Where a run goes after a step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Next {
+    Step(usize),
+    End,
+    Fail,
+}
+
+fn target(steps: &[RunbookStep], name: &str) -> Option<Next> {
+    match name {
+        END => Some(Next::End),
+        FAIL => Some(Next::Fail),
+        _ => steps.iter().position(|s| s.name == name).map(Next::Step),
+    }
+}
+
+/// Pick the step after `index` from its exit code and success
+pub fn next_step(
+    steps: &[RunbookStep],
+    index: usize,
+    exit_code: Option<i32>,
+    success: bool,
+) -> Next {
+    let step = &steps[index];
+    let chosen = exit_code
+        .and_then(|code| step.branches.get(&code))
+        .or(if success {
+            step.on_success.as_ref()
+        } else {
+            step.on_failure.as_ref()
+        });
+    match chosen {
+        Some(name) => target(steps, name).unwrap_or(Next::Fail),
+        None if !success => Next::Fail,
+        None if index + 1 < steps.len() => Next::Step(index + 1),
+        None => Next::End,
+    }
+}
+
+/// Check a runbook definition before it is saved
+pub fn validate(name: &str, steps: &[RunbookStep]) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("A runbook needs a name".to_string());
+    }
+    if steps.is_empty() {
+        return Err("A runbook needs at least one step".to_string());
+    }
+    let mut names = HashSet::new();
+    for step in steps {
+        let step_name = step.name.trim();
+        if step_name.is_empty() || step_name == END || step_name == FAIL {
+            return Err(format!("Invalid step name: {:?}", step.name));
+        }
+        if !names.insert(step_name) {
+            return Err(format!("Duplicate step: {}", step_name));
+        }
+        if let Some(var) = &step.capture {
+            if !crate::scripts::valid_identifier(var) {
+                return Err(format!("Invalid variable name in {}: {}", step_name, var));
+            }
+        }
+        match &step.action {
+            StepAction::Command { command, .. } if command.trim().is_empty() => {
+                return Err(format!("Step {} has no command", step_name));
+            }
+            StepAction::Upload {
+                local_path,
+                remote_path,
+                ..
+            } if local_path.trim().is_empty() || remote_path.trim().is_empty() => {
+                return Err(format!("Step {} needs a local and remote path", step_name));
+            }
+            _ => {}
+        }
+    }
+    for step in steps {
+        if let Some(unknown) = step.targets().find(|t| target(steps, t).is_none()) {
+            return Err(format!(
+                "Step {} branches to unknown step {}",
+                step.name, unknown
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Replace `{{name}}` placeholders with shell-quoted variables
+pub fn render(command: &str, variables: &BTreeMap<String, String>) -> String {
+    let values: Vec<(String, String)> = variables
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    crate::scripts::render(command, "sh", &values)
+}
+
+/// What a command or upload step produced
+struct StepOutcome {
+    success: bool,
+    exit_code: Option<i32>,
+    stdout: String,
+    output: String,
+}
+
+impl StepOutcome {
+    fn error(message: String) -> Self {
+        StepOutcome {
+            success: false,
+            exit_code: None,
+            stdout: String::new(),
+            output: message,
+        }
+    }
+}
+
+/// Run a command, connecting on first use and reusing the session after
+async fn run_command(
+    state: &AppState,
+    device_id: i32,
+    session: &mut Option<(Session, Privilege)>,
+    command: &str,
+    as_root: bool,
+) -> StepOutcome {
+    if session.is_none() {
+        match crate::ssh::connect_device(state, device_id).await {
+            Ok((device, connected)) => {
+                let privilege = Privilege::for_device(state, &device);
+                *session = Some((connected, privilege));
+            }
+            Err(e) => return StepOutcome::error(e),
+        }
+    }
+    let Some((connected, privilege)) = session.as_ref() else {
+        return StepOutcome::error("Not connected".to_string());
+    };
+    let script = if as_root {
+        privileged(&format!("sh -c {}", shell_quote(command)))
+    } else {
+        command.to_string()
+    };
+    match crate::ssh::exec_as(connected, privilege, &script).await {
+        Ok(output) => {
+            crate::ssh::log_command(state, device_id, command, Some(&output.log_text()));
+            StepOutcome {
+                success: output.success(),
+                exit_code: output.exit_code,
+                output: output.log_text(),
+                stdout: output.stdout,
+            }
+        }
+        Err(e) => {
+            // The session is likely dead; reconnect for the next command
+            *session = None;
+            StepOutcome::error(e)
+        }
+    }
+}
+
+async fn upload(state: &AppState, device: &Device, spec: &PushSpec) -> StepOutcome {
+    let files = match crate::fleet::prepare(spec).await {
+        Ok(files) => files,
+        Err(e) => return StepOutcome::error(e),
+    };
+    let result = crate::fleet::push_to_device(state, device, &files, spec).await;
+    let output = result
+        .error
+        .clone()
+        .unwrap_or_else(|| format!("Installed {} file(s), {} bytes", result.files, result.bytes));
+    crate::ssh::log_command(
+        state,
+        device.id,
+        &format!("upload {} -> {}", spec.local_path, spec.remote_path),
+        Some(&output),
+    );
+    StepOutcome {
+        success: result.success,
+        exit_code: None,
+        stdout: String::new(),
+        output,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn record(
+    state: &AppState,
+    run_id: i32,
+    index: usize,
+    step_name: &str,
+    phase: &str,
+    success: bool,
+    exit_code: Option<i32>,
+    output: &str,
+) {
+    let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+        db.insert_runbook_step_result(
+            run_id,
+            index,
+            step_name,
+            phase,
+            success,
+            exit_code,
+            Some(output),
+            unix_timestamp(),
+        )
+        .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        error!(
+            "Failed to record step {} of run {}: {}",
+            step_name, run_id, e
+        );
+    }
+}
+
+/// Persist a run and tell the frontend
+fn save(app: &AppHandle, run: &RunbookRun) {
+    let state = app.state::<AppState>();
+    let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+        db.save_runbook_run(run, unix_timestamp())
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        error!("Failed to save runbook run {}: {}", run.id, e);
+    }
+    if let Err(e) = app.emit(RUNBOOK_RUN_EVENT, run) {
+        error!("Failed to emit runbook run: {}", e);
+    }
+}
+
+/// Undo the run's successful steps in reverse order; returns the steps whose
+/// rollback failed
+async fn rollback(
+    state: &AppState,
+    run: &RunbookRun,
+    session: &mut Option<(Session, Privilege)>,
+) -> Vec<String> {
+    let mut failed = Vec::new();
+    for &index in run.completed.iter().rev() {
+        let step = &run.steps[index];
+        let Some(command) = step.rollback.as_deref().filter(|c| !c.trim().is_empty()) else {
+            continue;
+        };
+        let command = render(command, &run.variables);
+        let outcome = run_command(state, run.device_id, session, &command, step.as_root()).await;
+        record(
+            state,
+            run.id,
+            index,
+            &step.name,
+            "rollback",
+            outcome.success,
+            outcome.exit_code,
+            &outcome.output,
+        );
+        if !outcome.success {
+            failed.push(step.name.clone());
+        }
+    }
+    failed
+}
+
+/// Apply a branch decision; returns whether the run should keep executing
+async fn advance(
+    state: &AppState,
+    run: &mut RunbookRun,
+    next: Next,
+    session: &mut Option<(Session, Privilege)>,
+) -> bool {
+    let step_name = run.steps[run.current_step as usize].name.clone();
+    match next {
+        Next::Step(index) => {
+            run.current_step = index as i64;
+            true
+        }
+        Next::End => {
+            run.current_step = run.steps.len() as i64;
+            run.state = RunState::Succeeded.as_str().to_string();
+            false
+        }
+        Next::Fail => {
+            let failed = rollback(state, run, session).await;
+            run.error = Some(if failed.is_empty() {
+                format!("Step {} failed", step_name)
+            } else {
+                format!(
+                    "Step {} failed; rollback failed for: {}",
+                    step_name,
+                    failed.join(", ")
+                )
+            });
+            run.state = RunState::Failed.as_str().to_string();
+            false
+        }
+    }
+}
+
+/// Execute a run that has been claimed until it finishes, fails or reaches an approval
+async fn execute(app: &AppHandle, mut run: RunbookRun) -> RunbookRun {
+    let state = app.state::<AppState>();
+    let device = {
+        let db = state.db.lock().map_err(|e| e.to_string());
+        db.and_then(|db| db.get_device(run.device_id).map_err(|e| e.to_string()))
+    };
+    let device = match device {
+        Ok(Some(device)) => device,
+        Ok(None) | Err(_) => {
+            run.state = RunState::Failed.as_str().to_string();
+            run.error = Some(format!("Device {} not found", run.device_id));
+            save(app, &run);
+            return run;
+        }
+    };
+
+    let mut session = None;
+    let mut executed = 0;
+    loop {
+        let index = run.current_step as usize;
+        if index >= run.steps.len() {
+            run.state = RunState::Succeeded.as_str().to_string();
+            break;
+        }
+        executed += 1;
+        if executed > MAX_STEP_EXECUTIONS {
+            run.state = RunState::Failed.as_str().to_string();
+            run.error = Some(format!(
+                "Stopped after {} steps; check the branches for a loop",
+                MAX_STEP_EXECUTIONS
+            ));
+            break;
+        }
+
+        let step = run.steps[index].clone();
+        let outcome = match &step.action {
+            StepAction::Approval { message } => {
+                info!(
+                    "Runbook run {} on {} waiting for approval: {}",
+                    run.id, device.name, message
+                );
+                run.state = RunState::AwaitingApproval.as_str().to_string();
+                break;
+            }
+            StepAction::Command { command, as_root } => {
+                let command = render(command, &run.variables);
+                run_command(&state, device.id, &mut session, &command, *as_root).await
+            }
+            StepAction::Upload {
+                local_path,
+                remote_path,
+                mode,
+                owner,
+            } => {
+                let spec = PushSpec {
+                    local_path: local_path.clone(),
+                    remote_path: remote_path.clone(),
+                    mode: mode.clone(),
+                    owner: owner.clone(),
+                    post_command: None,
+                };
+                upload(&state, &device, &spec).await
+            }
+        };
+        record(
+            &state,
+            run.id,
+            index,
+            &step.name,
+            "step",
+            outcome.success,
+            outcome.exit_code,
+            &outcome.output,
+        );
+        if let Some(var) = &step.capture {
+            run.variables
+                .insert(var.clone(), outcome.stdout.trim().to_string());
+        }
+        if outcome.success && !run.completed.contains(&index) {
+            run.completed.push(index);
+        }
+
+        let next = next_step(&run.steps, index, outcome.exit_code, outcome.success);
+        if !advance(&state, &mut run, next, &mut session).await {
+            break;
+        }
+        save(app, &run);
+    }
+
+    match &run.error {
+        Some(e) => error!("Runbook run {} on {} failed: {}", run.id, device.name, e),
+        None => info!("Runbook run {} on {} is {}", run.id, device.name, run.state),
+    }
+    save(app, &run);
+    run
+}
+
+/// Move a run to `running` from one of `from` and load it
+fn claim(state: &AppState, run_id: i32, from: &[RunState]) -> Result<RunbookRun, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let from: Vec<&str> = from.iter().map(RunState::as_str).collect();
+    if !db
+        .claim_runbook_run(run_id, &from, unix_timestamp())
+        .map_err(|e| e.to_string())?
+    {
+        return Err(format!("Run {} is not {}", run_id, from.join(" or ")));
+    }
+    db.get_runbook_run(run_id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Run {} not found", run_id))
+}
+
+/// Start a runbook on every device, at most `concurrency` at a time
+///
+/// Each device gets its own run with the given variables plus `device_id`,
+/// `device_name` and `device_ip`. Returns the new run ids; progress arrives
+/// through the `runbook-run` event.
+pub fn start(
+    app: &AppHandle,
+    runbook: &Runbook,
+    devices: Vec<Device>,
+    variables: BTreeMap<String, String>,
+    concurrency: usize,
+) -> Result<Vec<i32>, String> {
+    validate(&runbook.name, &runbook.steps)?;
+    if let Some(invalid) = variables
+        .keys()
+        .find(|k| !crate::scripts::valid_identifier(k))
+    {
+        return Err(format!("Invalid variable name: {}", invalid));
+    }
+
+    let run_ids = {
+        let state = app.state::<AppState>();
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        let mut ids = Vec::new();
+        for device in &devices {
+            let mut vars = variables.clone();
+            vars.entry("device_id".to_string())
+                .or_insert_with(|| device.id.to_string());
+            vars.entry("device_name".to_string())
+                .or_insert_with(|| device.name.clone());
+            vars.entry("device_ip".to_string())
+                .or_insert_with(|| device.ip.clone());
+            let id = db
+                .insert_runbook_run(
+                    runbook.id,
+                    device.id,
+                    &runbook.steps,
+                    &vars,
+                    unix_timestamp(),
+                )
+                .map_err(|e| e.to_string())?;
+            ids.push(id as i32);
+        }
+        ids
+    };
+    info!(
+        "Starting runbook {} on {} device(s)",
+        runbook.name,
+        run_ids.len()
+    );
+
+    let app = app.clone();
+    let ids = run_ids.clone();
+    tauri::async_runtime::spawn(async move {
+        let app = &app;
+        futures_util::stream::iter(ids)
+            .for_each_concurrent(concurrency, |id| async move {
+                let state = app.state::<AppState>();
+                match claim(&state, id, &[RunState::Pending]) {
+                    Ok(run) => {
+                        execute(app, run).await;
+                    }
+                    Err(e) => error!("Failed to start runbook run {}: {}", id, e),
+                }
+            })
+            .await;
+    });
+    Ok(run_ids)
+}
+
+/// Resume a run cut short by an app restart, re-running its current step
+pub fn resume(app: &AppHandle, run_id: i32) -> Result<RunbookRun, String> {
+    let run = {
+        let state = app.state::<AppState>();
+        claim(&state, run_id, &[RunState::Interrupted, RunState::Pending])?
+    };
+    info!(
+        "Resuming runbook run {} at step {}",
+        run.id, run.current_step
+    );
+    let app = app.clone();
+    let claimed = run.clone();
+    tauri::async_runtime::spawn(async move {
+        execute(&app, claimed).await;
+    });
+    Ok(run)
+}
+
+/// Approve or reject the approval step a run is waiting at
+///
+/// An approved run follows the step's success branch and keeps going in the
+/// background; a rejected one follows its failure branch, which by default
+/// rolls the run back.
+pub async fn approve(app: &AppHandle, run_id: i32, approved: bool) -> Result<RunbookRun, String> {
+    let state = app.state::<AppState>();
+    let mut run = claim(&state, run_id, &[RunState::AwaitingApproval])?;
+    let index = run.current_step as usize;
+    let step_name = run
+        .steps
+        .get(index)
+        .map(|s| s.name.clone())
+        .ok_or_else(|| format!("Run {} has no step {}", run_id, index))?;
+    info!(
+        "Runbook run {} step {} {}",
+        run_id,
+        step_name,
+        if approved { "approved" } else { "rejected" }
+    );
+    record(
+        &state,
+        run.id,
+        index,
+        &step_name,
+        "approval",
+        approved,
+        None,
+        if approved { "Approved" } else { "Rejected" },
+    );
+
+    let next = next_step(&run.steps, index, None, approved);
+    let mut session = None;
+    if !advance(&state, &mut run, next, &mut session).await {
+        save(app, &run);
+        return Ok(run);
+    }
+    save(app, &run);
+    let handle = app.clone();
+    let claimed = run.clone();
+    tauri::async_runtime::spawn(async move {
+        execute(&handle, claimed).await;
+    });
+    Ok(run)
+}
+
+/// Mark runs left running by the last exit as interrupted so they can be resumed
+pub fn mark_interrupted(app: &AppHandle) {
+    let state = app.state::<AppState>();
+    let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+        db.interrupt_running_runbook_runs()
+            .map_err(|e| e.to_string())
+    });
+    match result {
+        Ok(0) => {}
+        Ok(n) => info!("{} runbook run(s) were interrupted and can be resumed", n),
+        Err(e) => error!("Failed to mark interrupted runbook runs: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(name: &str) -> RunbookStep {
+        RunbookStep {
+            name: name.to_string(),
+            action: StepAction::Command {
+                command: "true".to_string(),
+                as_root: false,
+            },
+            capture: None,
+            branches: BTreeMap::new(),
+            on_success: None,
+            on_failure: None,
+            rollback: None,
+        }
+    }
+
+    #[test]
+    fn next_step_follows_branches() {
+        let mut check = step("check");
+        check.branches.insert(3, "install".to_string());
+        check.on_success = Some(END.to_string());
+        let steps = vec![check, step("install"), step("verify")];
+
+        assert_eq!(next_step(&steps, 0, Some(3), false), Next::Step(1));
+        assert_eq!(next_step(&steps, 0, Some(0), true), Next::End);
+        assert_eq!(next_step(&steps, 0, Some(1), false), Next::Fail);
+        assert_eq!(next_step(&steps, 1, Some(0), true), Next::Step(2));
+        assert_eq!(next_step(&steps, 2, Some(0), true), Next::End);
+    }
+
+    #[test]
+    fn validate_checks_names_and_targets() {
+        assert!(validate("deploy", &[step("a"), step("b")]).is_ok());
+        assert!(validate("deploy", &[]).is_err());
+        assert!(validate("deploy", &[step("a"), step("a")]).is_err());
+        assert!(validate("deploy", &[step(END)]).is_err());
+
+        let mut a = step("a");
+        a.on_failure = Some("missing".to_string());
+        assert!(validate("deploy", &[a]).is_err());
+    }
+
+    #[test]
+    fn steps_round_trip_through_json() {
+        let json = r#"[
+            {"name": "check", "type": "command", "command": "test -f /etc/app.conf",
+             "branches": {"1": "install"}, "on_success": "end"},
+            {"name": "install", "type": "upload", "local_path": "/tmp/app.conf",
+             "remote_path": "/etc/app.conf", "rollback": "rm -f /etc/app.conf"},
+            {"name": "confirm", "type": "approval", "message": "Restart the app?"}
+        ]"#;
+        let steps: Vec<RunbookStep> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            steps[0].branches.get(&1).map(String::as_str),
+            Some("install")
+        );
+        assert!(matches!(steps[1].action, StepAction::Upload { .. }));
+        assert!(validate("deploy", &steps).is_ok());
+    }
+
+    #[test]
+    fn render_quotes_variables() {
+        let vars = BTreeMap::from([("version".to_string(), "1.2; reboot".to_string())]);
+        assert_eq!(
+            render("install.sh {{version}}", &vars),
+            "install.sh '1.2; reboot'"
+        );
+    }
+
+    #[test]
+    fn render_keeps_placeholders_in_captured_output_literal() {
+        // `release` was captured from a device that printed a placeholder
+        let vars = BTreeMap::from([
+            ("release".to_string(), "v2 {{device_name}}".to_string()),
+            ("device_name".to_string(), "$(reboot)".to_string()),
+        ]);
+        assert_eq!(
+            render("deploy {{release}} to {{device_name}}", &vars),
+            "deploy 'v2 {{device_name}}' to '$(reboot)'"
+        );
+    }
+}
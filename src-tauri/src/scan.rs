@@ -0,0 +1,510 @@
+//! Sweep an IPv4 range for SSH-capable devices.
+//!
+//! Every host and port pair is probed with a plain TCP connect, a bounded
+//! number at a time. An SSH server sends its version line as soon as the
+//! connection opens, so that line is kept as the banner; host keys are then
+//! fetched with `ssh-keyscan` and fingerprinted the way `ssh-keygen -l` does.
+
+use crate::command::AppState;
+use crate::db::Device;
+use crate::ssh::SshConfig;
+use crate::validation;
+use futures_util::StreamExt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+pub const SCAN_PROGRESS_EVENT: &str = "scan-progress";
+
+const DEFAULT_PORTS: &[u16] = &[22];
+const DEFAULT_CONCURRENCY: usize = 256;
+const MAX_CONCURRENCY: usize = 1024;
+const DEFAULT_TIMEOUT_MS: u64 = 800;
+/// Smallest prefix accepted, a /16 of 65534 hosts
+const MIN_PREFIX: u8 = 16;
+/// Bytes read while looking for the SSH version line
+const MAX_BANNER_BYTES: usize = 1024;
+/// `ssh-keyscan` runs started at once
+const KEYSCAN_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScanOptions {
+    /// `192.168.1.0/24`, or a single address
+    pub cidr: String,
+    /// Ports to probe, port 22 when empty
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    pub concurrency: Option<usize>,
+    /// Connect and banner timeout per probe
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostKey {
+    pub key_type: String,
+    /// `SHA256:` followed by unpadded base64, as printed by `ssh-keygen -l`
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredHost {
+    pub ip: String,
+    pub open_ports: Vec<u16>,
+    /// First open port that answered with an SSH version line
+    pub ssh_port: Option<u16>,
+    pub banner: Option<String>,
+    pub host_keys: Vec<HostKey>,
+    /// Stored device with the same IP or one of the same host keys
+    pub known_device_id: Option<i32>,
+    /// `ip` or `host_key`
+    pub known_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanProgress {
+    pub probed: usize,
+    pub total: usize,
+    pub found: usize,
+}
+
+/// A scan result the user chose to add as a device
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscoveredDevice {
+    /// Defaults to the IP
+    pub name: Option<String>,
+    pub ip: String,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub host_key_fingerprint: Option<String>,
+}
+
+/// Host addresses in a CIDR range; the network and broadcast addresses are
+/// left out of ranges larger than a /31
+pub fn parse_cidr(cidr: &str) -> Result<Vec<Ipv4Addr>, String> {
+    let cidr = cidr.trim();
+    let (addr, prefix) = match cidr.split_once('/') {
+        Some((addr, prefix)) => (
+            addr,
+            prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= 32)
+                .ok_or_else(|| format!("Invalid prefix length in {}", cidr))?,
+        ),
+        None => (cidr, 32),
+    };
+    let addr: Ipv4Addr = addr
+        .parse()
+        .map_err(|_| format!("Invalid IPv4 address in {}", cidr))?;
+    if prefix < MIN_PREFIX {
+        return Err(format!(
+            "{} is too large to scan, use a /{} or smaller",
+            cidr, MIN_PREFIX
+        ));
+    }
+
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    let network = u32::from(addr) & mask;
+    let broadcast = network | !mask;
+    let (first, last) = if prefix >= 31 {
+        (network, broadcast)
+    } else {
+        (network + 1, broadcast - 1)
+    };
+    Ok((first..=last).map(Ipv4Addr::from).collect())
+}
+
+/// Connect to `addr`; `None` when closed, otherwise the SSH version line if
+/// the server sent one before the timeout
+async fn probe(addr: SocketAddr, timeout: Duration) -> Option<Option<String>> {
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .ok()?
+        .ok()?;
+    let read_banner = async {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 256];
+        while buf.len() < MAX_BANNER_BYTES {
+            let n = stream.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            // Servers may send other lines before the version line
+            let text = String::from_utf8_lossy(&buf);
+            if let Some(line) = text
+                .split_inclusive('\n')
+                .find(|l| l.starts_with("SSH-") && l.ends_with('\n'))
+            {
+                return Some(line.trim_end().to_string());
+            }
+        }
+        None
+    };
+    Some(
+        tokio::time::timeout(timeout, read_banner)
+            .await
+            .ok()
+            .flatten(),
+    )
+}
+
+/// Probe every port of every host, calling `on_progress` with the number of
+/// probes done after each one; returns the hosts with an open port, by address
+pub async fn probe_hosts(
+    hosts: &[Ipv4Addr],
+    ports: &[u16],
+    concurrency: usize,
+    timeout: Duration,
+    mut on_progress: impl FnMut(usize),
+) -> Vec<DiscoveredHost> {
+    let probes: Vec<(Ipv4Addr, u16)> = hosts
+        .iter()
+        .flat_map(|ip| ports.iter().map(move |port| (*ip, *port)))
+        .collect();
+    let mut results = futures_util::stream::iter(probes)
+        .map(|(ip, port)| async move {
+            let result = probe(SocketAddr::from((ip, port)), timeout).await;
+            (ip, port, result)
+        })
+        .buffer_unordered(concurrency);
+
+    let mut found: BTreeMap<Ipv4Addr, DiscoveredHost> = BTreeMap::new();
+    let mut probed = 0;
+    while let Some((ip, port, result)) = results.next().await {
+        probed += 1;
+        if let Some(banner) = result {
+            let host = found.entry(ip).or_insert_with(|| DiscoveredHost {
+                ip: ip.to_string(),
+                open_ports: Vec::new(),
+                ssh_port: None,
+                banner: None,
+                host_keys: Vec::new(),
+                known_device_id: None,
+                known_by: None,
+            });
+            host.open_ports.push(port);
+            if banner.is_some()
+                && host
+                    .ssh_port
+                    .is_none_or(|p| port_rank(ports, port) < port_rank(ports, p))
+            {
+                host.ssh_port = Some(port);
+                host.banner = banner;
+            }
+        }
+        on_progress(probed);
+    }
+
+    let mut hosts: Vec<DiscoveredHost> = found.into_values().collect();
+    for host in &mut hosts {
+        host.open_ports.sort_unstable();
+    }
+    hosts
+}
+
+/// Position of a port in the requested list, so the first listed SSH port wins
+fn port_rank(ports: &[u16], port: u16) -> usize {
+    ports.iter().position(|p| *p == port).unwrap_or(usize::MAX)
+}
+
+/// Host keys offered by an SSH server, fetched with `ssh-keyscan`
+async fn host_keys(ip: &str, port: u16, timeout: Duration) -> Result<Vec<HostKey>, String> {
+    let output = tokio::process::Command::new("ssh-keyscan")
+        .arg("-T")
+        .arg(timeout.as_secs().max(1).to_string())
+        .arg("-p")
+        .arg(port.to_string())
+        .arg(ip)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run ssh-keyscan: {}", e))?;
+    Ok(parse_keyscan(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse `ssh-keyscan` output lines of the form `host key-type base64-blob`
+pub fn parse_keyscan(output: &str) -> Vec<HostKey> {
+    let mut keys: Vec<HostKey> = output
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (_host, key_type, blob) = (fields.next()?, fields.next()?, fields.next()?);
+            Some(HostKey {
+                key_type: key_type.to_string(),
+                fingerprint: fingerprint(blob)?,
+            })
+        })
+        .collect();
+    keys.dedup();
+    keys
+}
+
+/// SHA-256 fingerprint of a base64 public key blob
+pub fn fingerprint(blob: &str) -> Option<String> {
+    let digest = Sha256::digest(base64_decode(blob)?);
+    Some(format!("SHA256:{}", base64_encode(&digest)))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 without padding
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut n) = (0u32, 0u32);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE64.iter().position(|b| *b == c)? as u32;
+        bits = bits << 6 | value;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+            bits &= (1 << n) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// Flag hosts that match a stored device, by IP first and then by host key
+pub fn mark_known(hosts: &mut [DiscoveredHost], devices: &[Device]) {
+    for host in hosts {
+        let by_ip = devices.iter().find(|d| d.ip == host.ip);
+        let by_key = || {
+            devices.iter().find(|d| {
+                d.host_key_fingerprint
+                    .as_ref()
+                    .is_some_and(|f| host.host_keys.iter().any(|k| &k.fingerprint == f))
+            })
+        };
+        let (device, known_by) = match by_ip {
+            Some(device) => (Some(device), "ip"),
+            None => (by_key(), "host_key"),
+        };
+        if let Some(device) = device {
+            host.known_device_id = Some(device.id);
+            host.known_by = Some(known_by.to_string());
+        }
+    }
+}
+
+/// Scan a range, emitting progress as it goes, and return the hosts found
+pub async fn scan(app: &AppHandle, options: ScanOptions) -> Result<Vec<DiscoveredHost>, String> {
+    let hosts = parse_cidr(&options.cidr)?;
+    let ports = if options.ports.is_empty() {
+        DEFAULT_PORTS.to_vec()
+    } else {
+        options.ports.clone()
+    };
+    if ports.contains(&0) {
+        return Err("Port 0 cannot be scanned".to_string());
+    }
+    let concurrency = options
+        .concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .clamp(1, MAX_CONCURRENCY);
+    let timeout = Duration::from_millis(options.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS).max(50));
+    let total = hosts.len() * ports.len();
+    info!(
+        "Scanning {} ({} hosts, ports {:?})",
+        options.cidr,
+        hosts.len(),
+        ports
+    );
+
+    let emit = |probed: usize, found: usize| {
+        let progress = ScanProgress {
+            probed,
+            total,
+            found,
+        };
+        if let Err(e) = app.emit(SCAN_PROGRESS_EVENT, &progress) {
+            error!("Failed to emit scan progress: {}", e);
+        }
+    };
+    let mut found = probe_hosts(&hosts, &ports, concurrency, timeout, |probed| {
+        // Every probe would flood the frontend on a /16
+        if probed % 64 == 0 {
+            emit(probed, 0);
+        }
+    })
+    .await;
+    emit(total, found.len());
+
+    let targets: Vec<(String, Option<u16>)> =
+        found.iter().map(|h| (h.ip.clone(), h.ssh_port)).collect();
+    let keys: Vec<Vec<HostKey>> = futures_util::stream::iter(targets)
+        .map(|(ip, port)| async move {
+            let Some(port) = port else {
+                return Vec::new();
+            };
+            host_keys(&ip, port, timeout).await.unwrap_or_else(|e| {
+                error!("Failed to fetch host keys of {}: {}", ip, e);
+                Vec::new()
+            })
+        })
+        .buffered(KEYSCAN_CONCURRENCY)
+        .collect()
+        .await;
+    for (host, keys) in found.iter_mut().zip(keys) {
+        host.host_keys = keys;
+    }
+
+    let state = app.state::<AppState>();
+    let devices = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_all_devices().map_err(|e| e.to_string())?
+    };
+    mark_known(&mut found, &devices);
+    info!(
+        "Scan of {} found {} host(s), {} with SSH",
+        options.cidr,
+        found.len(),
+        found.iter().filter(|h| h.ssh_port.is_some()).count()
+    );
+    Ok(found)
+}
+
+/// Add scan results as devices, skipping IPs that are already stored;
+/// returns the new device ids
+pub fn add_discovered(state: &AppState, devices: &[DiscoveredDevice]) -> Result<Vec<i64>, String> {
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    let mut existing = db.get_all_devices().map_err(|e| e.to_string())?;
+    let mut added = Vec::new();
+    for device in devices {
+        let ip = validation::normalize_host(&device.ip)
+            .map_err(|e| format!("Invalid address {}: {}", device.ip, e))?;
+        let port = device.port.filter(|p| *p != 22);
+        if validation::find_duplicate(&existing, &ip, port, None).is_some() {
+            info!("Skipping {}, already a device", ip);
+            continue;
+        }
+        let name = device
+            .name
+            .as_deref()
+            .and_then(|n| validation::normalize_name(n).ok())
+            .unwrap_or_else(|| ip.clone());
+        let config = SshConfig {
+            username: device.username.clone().filter(|u| !u.is_empty()),
+            port,
+            strict_host_key_checking: None,
+            connect_timeout: None,
+        };
+        let id = db
+            .insert_discovered_device(&name, &ip, &config, device.host_key_fingerprint.as_deref())
+            .map_err(|e| e.to_string())?;
+        // Later entries in the same batch are checked against this one too
+        if let Some(device) = db.get_device(id as i32).map_err(|e| e.to_string())? {
+            existing.push(device);
+        }
+        info!("Added discovered device {} ({})", name, ip);
+        added.push(id);
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_cidr_ranges() {
+        let hosts = parse_cidr("10.0.0.0/30").unwrap();
+        assert_eq!(
+            hosts,
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]
+        );
+        assert_eq!(parse_cidr("192.168.1.77/24").unwrap().len(), 254);
+        assert_eq!(
+            parse_cidr("10.1.2.3").unwrap(),
+            vec![Ipv4Addr::new(10, 1, 2, 3)]
+        );
+        assert_eq!(parse_cidr("10.0.0.0/31").unwrap().len(), 2);
+        assert!(parse_cidr("10.0.0.0/8").is_err());
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0/24").is_err());
+    }
+
+    #[test]
+    fn fingerprints_like_ssh_keygen() {
+        let output = "# 127.0.0.1:22 SSH-2.0-OpenSSH_9.6\n\
+            127.0.0.1 ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOPA4+7OzclFiUPZipBKT1tiWOZcIsVN3paYE2Z+elf8\n";
+        assert_eq!(
+            parse_keyscan(output),
+            vec![HostKey {
+                key_type: "ssh-ed25519".to_string(),
+                fingerprint: "SHA256:vBg7qToMsWF4gEEHDnnpuGmL30Ro2O6E8CxEYBgIEiU".to_string(),
+            }]
+        );
+        assert_eq!(base64_encode(b"ab"), "YWI");
+        assert_eq!(base64_decode("YWI=").unwrap(), b"ab");
+    }
+
+    /// Listen on an unused loopback port, greeting every connection with `greeting`
+    async fn listener(ip: &str, greeting: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind((ip, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.write_all(greeting).await;
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn finds_ssh_servers_on_loopback() {
+        let ssh = listener("127.0.0.2", b"SSH-2.0-OpenSSH_9.6 Ubuntu\r\n").await;
+        let http = listener("127.0.0.2", b"").await;
+        // Bound and dropped, so nothing listens there
+        let closed = TcpListener::bind("127.0.0.3:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let hosts = parse_cidr("127.0.0.0/30").unwrap();
+        let mut progress = 0;
+        let found = probe_hosts(
+            &hosts,
+            &[closed, http, ssh],
+            8,
+            Duration::from_millis(300),
+            |probed| progress = probed,
+        )
+        .await;
+
+        assert_eq!(progress, 6);
+        assert_eq!(found.len(), 1);
+        let host = &found[0];
+        assert_eq!(host.ip, "127.0.0.2");
+        let mut open = vec![http, ssh];
+        open.sort_unstable();
+        assert_eq!(host.open_ports, open);
+        assert_eq!(host.ssh_port, Some(ssh));
+        assert_eq!(host.banner.as_deref(), Some("SSH-2.0-OpenSSH_9.6 Ubuntu"));
+    }
+}
@@ -0,0 +1,310 @@
+//! Saved scripts with typed parameters, rendered safely and run on devices.
+//!
+//! The rendered script is piped to the interpreter's stdin, so it never has
+//! to be written to the device. Parameter values are also exported as
+//! environment variables on the `env` command line, so they are visible in
+//! the device's process list and stored in `command_logs`: parameters are not
+//! a place for secrets.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, ParamKind, Script, ScriptParam};
+use crate::ssh::{privileged, shell_quote};
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Supported interpreters and the command that runs a script from stdin
+pub const INTERPRETERS: &[(&str, &str)] = &[
+    ("sh", "sh -s"),
+    ("bash", "bash -s"),
+    ("python3", "python3 -"),
+    ("perl", "perl -"),
+];
+
+/// Interpreters whose scripts get `{{name}}` placeholders substituted
+const SHELLS: &[&str] = &["sh", "bash"];
+
+impl ScriptParam {
+    /// Check a value against the parameter's type
+    fn check(&self, value: &str) -> Result<(), String> {
+        let valid = match self.kind {
+            ParamKind::String => !value.contains('\0'),
+            ParamKind::Integer => value.parse::<i64>().is_ok(),
+            ParamKind::Boolean => value == "true" || value == "false",
+            ParamKind::Choice => self.choices.iter().any(|c| c == value),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("Invalid value for {}: {:?}", self.name, value))
+        }
+    }
+}
+
+/// Outcome of running a script on a device
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptRunResult {
+    pub script_id: i32,
+    pub script_version: i64,
+    pub device_id: i32,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Entry in `command_logs` recording the run
+    pub log_id: i64,
+}
+
+pub(crate) fn valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check a script definition before it is saved
+pub fn validate(name: &str, interpreter: &str, parameters: &[ScriptParam]) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("A script needs a name".to_string());
+    }
+    if !INTERPRETERS.iter().any(|(i, _)| *i == interpreter) {
+        return Err(format!("Unsupported interpreter: {}", interpreter));
+    }
+    for (i, param) in parameters.iter().enumerate() {
+        if !valid_identifier(&param.name) {
+            return Err(format!("Invalid parameter name: {}", param.name));
+        }
+        if parameters[..i].iter().any(|p| p.name == param.name) {
+            return Err(format!("Duplicate parameter: {}", param.name));
+        }
+        if param.kind == ParamKind::Choice && param.choices.is_empty() {
+            return Err(format!("Parameter {} needs choices", param.name));
+        }
+        if let Some(default) = &param.default {
+            param.check(default)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolve every parameter to a checked value, applying defaults
+pub fn resolve(
+    parameters: &[ScriptParam],
+    values: &HashMap<String, String>,
+) -> Result<Vec<(String, String)>, String> {
+    if let Some(unknown) = values
+        .keys()
+        .find(|k| !parameters.iter().any(|p| &p.name == *k))
+    {
+        return Err(format!("Unknown parameter: {}", unknown));
+    }
+    parameters
+        .iter()
+        .map(|param| {
+            let value = match values.get(&param.name).or(param.default.as_ref()) {
+                Some(value) => {
+                    param.check(value)?;
+                    value.clone()
+                }
+                None if param.required => {
+                    return Err(format!("Missing required parameter: {}", param.name))
+                }
+                None => String::new(),
+            };
+            Ok((param.name.clone(), value))
+        })
+        .collect()
+}
+
+/// Substitute `{{name}}` placeholders with shell-quoted values in shell scripts
+///
+/// Other interpreters read their parameters from the environment instead,
+/// since shell quoting means nothing to them.
+pub fn render(body: &str, interpreter: &str, values: &[(String, String)]) -> String {
+    if !SHELLS.contains(&interpreter) {
+        return body.to_string();
+    }
+    // One pass over the template: a value that itself contains `{{name}}`
+    // is emitted as is and never substituted into
+    let mut out = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let name = &after[..end];
+            values
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                out.push_str(&shell_quote(value));
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Command line that runs a script piped to stdin, with every parameter
+/// exported as an environment variable
+pub fn command_line(interpreter: &str, values: &[(String, String)], as_root: bool) -> String {
+    let run = INTERPRETERS
+        .iter()
+        .find(|(i, _)| *i == interpreter)
+        .map_or("sh -s", |(_, run)| *run);
+    let mut cmd = String::from("env");
+    for (name, value) in values {
+        cmd.push_str(&format!(" {}={}", name, shell_quote(value)));
+    }
+    cmd.push(' ');
+    cmd.push_str(run);
+    if as_root {
+        privileged(&cmd)
+    } else {
+        cmd
+    }
+}
+
+/// Run the current version of a script on a device and log it
+pub async fn run(
+    state: &AppState,
+    script: &Script,
+    device_id: i32,
+    values: &HashMap<String, String>,
+    as_root: bool,
+) -> Result<ScriptRunResult, String> {
+    let resolved = resolve(&script.parameters, values)?;
+    let body = render(&script.body, &script.interpreter, &resolved);
+    let command = command_line(&script.interpreter, &resolved, as_root);
+
+    let (device, session) = crate::ssh::connect_device(state, device_id).await?;
+    let privilege = crate::ssh::Privilege::for_device(state, &device);
+    info!(
+        "Running script {} v{} on device {}",
+        script.name, script.version, device_id
+    );
+    let output =
+        crate::ssh::exec_with_input_as(&session, &privilege, &command, body.as_bytes()).await?;
+
+    let log_id = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.insert_script_command_log(
+            device_id,
+            &format!("script {} v{}: {}", script.name, script.version, command),
+            Some(&output.log_text()),
+            script.id,
+            script.version,
+            unix_timestamp(),
+        )
+        .map_err(|e| e.to_string())?
+    };
+
+    Ok(ScriptRunResult {
+        script_id: script.id,
+        script_version: script.version,
+        device_id,
+        success: output.success(),
+        exit_code: output.exit_code,
+        stdout: output.stdout,
+        stderr: output.stderr,
+        log_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(name: &str, kind: ParamKind) -> ScriptParam {
+        ScriptParam {
+            name: name.to_string(),
+            kind,
+            description: None,
+            default: None,
+            required: false,
+            choices: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn render_quotes_values_in_shell_scripts() {
+        let values = vec![("path".to_string(), "/tmp/x'; rm -rf /".to_string())];
+        assert_eq!(
+            render("ls -l {{path}}", "sh", &values),
+            r#"ls -l '/tmp/x'\''; rm -rf /'"#
+        );
+        assert_eq!(
+            render("print('{{path}}')", "python3", &values),
+            "print('{{path}}')"
+        );
+    }
+
+    #[test]
+    fn render_does_not_substitute_into_values() {
+        let values = vec![
+            ("a".to_string(), "'{{b}}'".to_string()),
+            ("b".to_string(), "; touch /tmp/pwned".to_string()),
+        ];
+        assert_eq!(
+            render("echo {{a}} {{b}} {{c}} {{", "sh", &values),
+            r#"echo ''\''{{b}}'\''' '; touch /tmp/pwned' {{c}} {{"#
+        );
+    }
+
+    #[test]
+    fn resolve_applies_defaults_and_types() {
+        let mut count = param("count", ParamKind::Integer);
+        count.default = Some("10".to_string());
+        let mut unit = param("unit", ParamKind::Choice);
+        unit.choices = vec!["nginx".to_string(), "sshd".to_string()];
+        unit.required = true;
+        let params = [count, unit];
+
+        let values = HashMap::from([("unit".to_string(), "sshd".to_string())]);
+        assert_eq!(
+            resolve(&params, &values).unwrap(),
+            vec![
+                ("count".to_string(), "10".to_string()),
+                ("unit".to_string(), "sshd".to_string())
+            ]
+        );
+
+        assert!(resolve(&params, &HashMap::new()).is_err());
+        let bad = HashMap::from([
+            ("unit".to_string(), "sshd".to_string()),
+            ("count".to_string(), "ten".to_string()),
+        ]);
+        assert!(resolve(&params, &bad).is_err());
+        let unknown = HashMap::from([
+            ("unit".to_string(), "sshd".to_string()),
+            ("other".to_string(), "x".to_string()),
+        ]);
+        assert!(resolve(&params, &unknown).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_bad_definitions() {
+        assert!(validate("disk", "sh", &[param("path", ParamKind::String)]).is_ok());
+        assert!(validate("disk", "ruby", &[]).is_err());
+        assert!(validate("disk", "sh", &[param("1x", ParamKind::String)]).is_err());
+        assert!(validate("disk", "sh", &[param("x", ParamKind::Choice)]).is_err());
+        assert!(validate(
+            "disk",
+            "sh",
+            &[
+                param("x", ParamKind::String),
+                param("x", ParamKind::Integer)
+            ]
+        )
+        .is_err());
+    }
+}
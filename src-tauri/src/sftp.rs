@@ -0,0 +1,501 @@
+//! File browsing on devices through the SFTP subsystem.
+
+use crate::command::AppState;
+use futures_util::StreamExt;
+use log::{info, warn};
+use openssh::{Child, Session, Stdio};
+use openssh_sftp_client::error::{Error as SftpError, SftpErrorKind};
+use openssh_sftp_client::fs::Fs;
+use openssh_sftp_client::metadata::MetaData;
+use openssh_sftp_client::{Sftp, SftpOptions};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncSeekExt;
+
+/// Largest file `read_file` returns
+pub const MAX_READ_BYTES: u64 = 1024 * 1024;
+
+/// Bytes requested per SFTP read in `read_file`
+const READ_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Broad category of a file operation failure, for the frontend to act on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileErrorKind {
+    NotFound,
+    PermissionDenied,
+    InvalidPath,
+    TooLarge,
+    Unsupported,
+    Connection,
+    Other,
+}
+
+/// Error returned by the file commands
+#[derive(Debug, Clone, Serialize)]
+pub struct FileError {
+    pub kind: FileErrorKind,
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(kind: FileErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Map an SFTP failure on `path` to an error kind
+    pub fn from_sftp(err: SftpError, path: &str) -> Self {
+        match err {
+            SftpError::SftpError(code, msg) => {
+                let kind = match code {
+                    SftpErrorKind::NoSuchFile => FileErrorKind::NotFound,
+                    SftpErrorKind::PermDenied => FileErrorKind::PermissionDenied,
+                    SftpErrorKind::OpUnsupported => FileErrorKind::Unsupported,
+                    _ => FileErrorKind::Other,
+                };
+                let detail = match kind {
+                    FileErrorKind::NotFound => "No such file or directory".to_string(),
+                    FileErrorKind::PermissionDenied => "Permission denied".to_string(),
+                    _ => msg.to_string(),
+                };
+                Self::new(kind, format!("{}: {}", path, detail))
+            }
+            SftpError::IOError(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Self::new(FileErrorKind::NotFound, format!("{}: {}", path, e))
+            }
+            SftpError::IOError(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Self::new(FileErrorKind::PermissionDenied, format!("{}: {}", path, e))
+            }
+            other => Self::new(FileErrorKind::Other, format!("{}: {}", path, other)),
+        }
+    }
+}
+
+impl From<String> for FileError {
+    /// Errors from outside SFTP come from opening the SSH session
+    fn from(message: String) -> Self {
+        Self::new(FileErrorKind::Connection, message)
+    }
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Normalise a remote path into an absolute path without `.` or `..`
+///
+/// Paths must be absolute; `..` never climbs above `/`. Empty paths and
+/// paths containing NUL are rejected.
+pub fn sanitize_path(path: &str) -> Result<String, FileError> {
+    if !path.starts_with('/') || path.contains('\0') {
+        return Err(FileError::new(
+            FileErrorKind::InvalidPath,
+            format!("Invalid path '{}': paths must be absolute", path),
+        ));
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    Ok(format!("/{}", parts.join("/")))
+}
+
+/// Reject operations that would act on `/` itself
+fn require_non_root(path: &str) -> Result<(), FileError> {
+    if path == "/" {
+        return Err(FileError::new(
+            FileErrorKind::InvalidPath,
+            "Refusing to operate on /",
+        ));
+    }
+    Ok(())
+}
+
+/// Join a directory and an entry name with a single `/`
+fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub path: String,
+    /// file, dir, symlink or other
+    pub kind: String,
+    pub size: Option<u64>,
+    /// Permission bits, e.g. 0o755
+    pub mode: Option<u32>,
+    /// `ls -l` style permissions, e.g. `drwxr-xr-x`
+    pub permissions: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// UNIX time of the last modification
+    pub modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileContent {
+    pub path: String,
+    pub size: u64,
+    /// `None` when the file is not valid UTF-8
+    pub content: Option<String>,
+    pub binary: bool,
+}
+
+fn kind_of(meta: &MetaData) -> (&'static str, char) {
+    match meta.file_type() {
+        Some(t) if t.is_dir() => ("dir", 'd'),
+        Some(t) if t.is_symlink() => ("symlink", 'l'),
+        Some(t) if t.is_file() => ("file", '-'),
+        _ => ("other", '?'),
+    }
+}
+
+fn mode_bits(meta: &MetaData) -> Option<u32> {
+    let p = meta.permissions()?;
+    let bits = [
+        (p.suid(), 0o4000),
+        (p.sgid(), 0o2000),
+        (p.svtx(), 0o1000),
+        (p.read_by_owner(), 0o400),
+        (p.write_by_owner(), 0o200),
+        (p.execute_by_owner(), 0o100),
+        (p.read_by_group(), 0o040),
+        (p.write_by_group(), 0o020),
+        (p.execute_by_group(), 0o010),
+        (p.read_by_other(), 0o004),
+        (p.write_by_other(), 0o002),
+        (p.execute_by_other(), 0o001),
+    ];
+    Some(
+        bits.iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, bit)| bit)
+            .sum(),
+    )
+}
+
+/// Render mode bits the way `ls -l` does
+pub fn format_mode(type_char: char, mode: u32) -> String {
+    let mut s = String::with_capacity(10);
+    s.push(type_char);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+/// uid and gid to name maps read from the device
+#[derive(Default)]
+struct Owners {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl Owners {
+    async fn load(fs: &mut Fs) -> Self {
+        Self {
+            users: read_id_map(fs, "/etc/passwd").await,
+            groups: read_id_map(fs, "/etc/group").await,
+        }
+    }
+
+    fn entry(&self, name: String, path: String, meta: &MetaData) -> FileEntry {
+        let (kind, type_char) = kind_of(meta);
+        let mode = mode_bits(meta);
+        FileEntry {
+            name,
+            path,
+            kind: kind.to_string(),
+            size: meta.len(),
+            mode,
+            permissions: mode.map(|m| format_mode(type_char, m)),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            owner: meta.uid().and_then(|id| self.users.get(&id).cloned()),
+            group: meta.gid().and_then(|id| self.groups.get(&id).cloned()),
+            modified: meta.modified().map(|t| t.as_duration().as_secs() as i64),
+        }
+    }
+}
+
+/// Read a passwd-format file into an id to name map; unreadable files give an empty map
+async fn read_id_map(fs: &mut Fs, path: &str) -> HashMap<u32, String> {
+    fs.read(path)
+        .await
+        .map(|b| crate::process::parse_passwd(&String::from_utf8_lossy(&b)))
+        .unwrap_or_default()
+}
+
+/// An SFTP channel on a fresh session to a device
+pub struct DeviceFs {
+    sftp: Sftp,
+    /// The remote `sftp` subsystem; declared after `sftp` so it is dropped last
+    child: Child<Arc<Session>>,
+    session: Arc<Session>,
+}
+
+impl DeviceFs {
+    pub async fn open(state: &AppState, device_id: i32) -> Result<Self, FileError> {
+        let (device, session) = crate::ssh::connect_device(state, device_id).await?;
+        let connection_error = |e: String| {
+            FileError::new(
+                FileErrorKind::Connection,
+                format!("Failed to start SFTP on {}: {}", device.name, e),
+            )
+        };
+
+        let session = Arc::new(session);
+        let mut child = Session::to_subsystem(session.clone(), "sftp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .await
+            .map_err(|e| connection_error(e.to_string()))?;
+        let (Some(stdin), Some(stdout)) = (child.stdin().take(), child.stdout().take()) else {
+            return Err(connection_error("subsystem has no stdio".to_string()));
+        };
+        let sftp = Sftp::new(stdin, stdout, SftpOptions::default())
+            .await
+            .map_err(|e| connection_error(e.to_string()))?;
+        Ok(Self {
+            sftp,
+            child,
+            session,
+        })
+    }
+
+    pub fn sftp(&self) -> &Sftp {
+        &self.sftp
+    }
+
+    /// The SSH session carrying the SFTP channel, for running commands alongside it
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub async fn close(self) {
+        if let Err(e) = self.sftp.close().await {
+            warn!("Failed to close SFTP session: {}", e);
+        }
+        if let Err(e) = self.child.wait().await {
+            warn!("SFTP subsystem did not exit cleanly: {}", e);
+        }
+    }
+
+    /// Directory entries sorted with directories first, then by name
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<FileEntry>, FileError> {
+        let path = sanitize_path(path)?;
+        let mut fs = self.sftp.fs();
+        let owners = Owners::load(&mut fs).await;
+        let dir = fs
+            .open_dir(&path)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &path))?;
+
+        let mut read_dir = Box::pin(dir.read_dir());
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next().await {
+            let entry = entry.map_err(|e| FileError::from_sftp(e, &path))?;
+            let name = entry.filename().to_string_lossy().to_string();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let entry_path = join(&path, &name);
+            entries.push(owners.entry(name, entry_path, &entry.metadata()));
+        }
+        entries.sort_by(|a, b| {
+            (a.kind != "dir")
+                .cmp(&(b.kind != "dir"))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(entries)
+    }
+
+    /// Metadata of a path; symlinks are reported as links, not followed
+    pub async fn stat(&self, path: &str) -> Result<FileEntry, FileError> {
+        let path = sanitize_path(path)?;
+        let mut fs = self.sftp.fs();
+        let owners = Owners::load(&mut fs).await;
+        let meta = fs
+            .symlink_metadata(&path)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &path))?;
+        let name = Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "/".to_string());
+        Ok(owners.entry(name, path, &meta))
+    }
+
+    pub async fn mkdir(&self, path: &str) -> Result<(), FileError> {
+        let path = sanitize_path(path)?;
+        require_non_root(&path)?;
+        self.sftp
+            .fs()
+            .create_dir(&path)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &path))
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), FileError> {
+        let from = sanitize_path(from)?;
+        let to = sanitize_path(to)?;
+        require_non_root(&from)?;
+        require_non_root(&to)?;
+        self.sftp
+            .fs()
+            .rename(&from, &to)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &from))
+    }
+
+    /// Remove a file, symlink or empty directory
+    pub async fn delete(&self, path: &str) -> Result<(), FileError> {
+        let path = sanitize_path(path)?;
+        require_non_root(&path)?;
+        let mut fs = self.sftp.fs();
+        let meta = fs
+            .symlink_metadata(&path)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &path))?;
+        let result = if meta.file_type().is_some_and(|t| t.is_dir()) {
+            fs.remove_dir(&path).await
+        } else {
+            fs.remove_file(&path).await
+        };
+        result.map_err(|e| FileError::from_sftp(e, &path))
+    }
+
+    /// Read a regular file of at most [`MAX_READ_BYTES`]
+    pub async fn read_file(&self, path: &str) -> Result<FileContent, FileError> {
+        let path = sanitize_path(path)?;
+        let mut fs = self.sftp.fs();
+        let meta = fs
+            .metadata(&path)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &path))?;
+        if !meta.file_type().is_some_and(|t| t.is_file()) {
+            return Err(FileError::new(
+                FileErrorKind::InvalidPath,
+                format!("{}: not a regular file", path),
+            ));
+        }
+        let Some(size) = meta.len() else {
+            return Err(FileError::new(
+                FileErrorKind::Unsupported,
+                format!("{}: the server did not report its size", path),
+            ));
+        };
+        if size > MAX_READ_BYTES {
+            return Err(FileError::new(
+                FileErrorKind::TooLarge,
+                format!(
+                    "{}: {} bytes exceeds the {} byte limit",
+                    path, size, MAX_READ_BYTES
+                ),
+            ));
+        }
+
+        // The file may have grown since the stat, so never read past the limit
+        let mut file = self
+            .sftp
+            .open(&path)
+            .await
+            .map_err(|e| FileError::from_sftp(e, &path))?;
+        let mut bytes = Vec::new();
+        while (bytes.len() as u64) <= MAX_READ_BYTES {
+            // `File::read` advances by the requested length even on a short read
+            file.seek(SeekFrom::Start(bytes.len() as u64))
+                .await
+                .map_err(|e| FileError::new(FileErrorKind::Other, format!("{}: {}", path, e)))?;
+            let wanted = MAX_READ_BYTES + 1 - bytes.len() as u64;
+            let data = file
+                .read(
+                    wanted.min(READ_CHUNK_SIZE as u64) as u32,
+                    Default::default(),
+                )
+                .await
+                .map_err(|e| FileError::from_sftp(e, &path))?;
+            let Some(data) = data else {
+                break;
+            };
+            bytes.extend_from_slice(&data);
+        }
+        let _ = file.close().await;
+        if bytes.len() as u64 > MAX_READ_BYTES {
+            return Err(FileError::new(
+                FileErrorKind::TooLarge,
+                format!("{}: grew past the {} byte limit", path, MAX_READ_BYTES),
+            ));
+        }
+
+        let size = bytes.len() as u64;
+        info!("Read {} bytes from {}", size, path);
+        let content = String::from_utf8(bytes).ok();
+        Ok(FileContent {
+            path,
+            size,
+            binary: content.is_none(),
+            content,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_path_normalises_dots() {
+        assert_eq!(sanitize_path("/var/log/../lib/./x").unwrap(), "/var/lib/x");
+        assert_eq!(sanitize_path("//etc//hosts/").unwrap(), "/etc/hosts");
+        assert_eq!(sanitize_path("/../../etc").unwrap(), "/etc");
+        assert_eq!(sanitize_path("/").unwrap(), "/");
+    }
+
+    #[test]
+    fn sanitize_path_rejects_relative_and_nul() {
+        for path in ["", "etc/passwd", "../etc", "/etc/\0x"] {
+            let err = sanitize_path(path).unwrap_err();
+            assert_eq!(err.kind, FileErrorKind::InvalidPath, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn format_mode_matches_ls() {
+        assert_eq!(format_mode('d', 0o755), "drwxr-xr-x");
+        assert_eq!(format_mode('-', 0o644), "-rw-r--r--");
+        assert_eq!(format_mode('-', 0o4755), "-rwsr-xr-x");
+        assert_eq!(format_mode('d', 0o1777), "drwxrwxrwt");
+        assert_eq!(format_mode('-', 0o2640), "-rw-r-S---");
+    }
+}
@@ -1,8 +1,11 @@
 use crate::command::AppState;
+use crate::db::Device;
 use log::{error, info};
-use openssh::{KnownHosts, Session, SessionBuilder};
+use openssh::{KnownHosts, Session, SessionBuilder, Stdio};
 use serde::Serialize;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use tauri::State;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 
 /// SSH connection configuration options
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -24,6 +27,116 @@ impl Default for SshConfig {
     }
 }
 
+/// Who to log in as and where, built from a device's address and SSH settings.
+///
+/// The user and port are handed to ssh as `-l` and `-p` rather than folded into
+/// the destination string, so IPv6 literals need no brackets and their `%zone`
+/// scope IDs pass through unchanged. Port 22 is left unset so a `Port` in the
+/// user's ssh config still applies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    pub user: String,
+    /// Hostname or IP literal, without brackets
+    pub host: String,
+    pub port: u16,
+}
+
+impl Destination {
+    pub fn new(ip: &str, config: &SshConfig) -> Result<Self, String> {
+        let host = ip.trim();
+        // `[fe80::1]` as written in URLs and known_hosts
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err("No address to connect to".to_string());
+        }
+        // ssh would read these as options or as part of a user@host
+        if host.starts_with('-') || host.contains(|c: char| c.is_whitespace() || c == '@') {
+            return Err(format!("Invalid address to connect to: {:?}", ip));
+        }
+        Ok(Destination {
+            user: config
+                .username
+                .clone()
+                .unwrap_or_else(|| "user".to_string()),
+            host: host.to_string(),
+            port: config.port.unwrap_or(22),
+        })
+    }
+
+    /// Session builder for this destination with the timeout and host key
+    /// policy from `config`
+    pub fn session_builder(&self, config: &SshConfig) -> SessionBuilder {
+        let known_hosts = if config.strict_host_key_checking.unwrap_or(true) {
+            KnownHosts::Strict
+        } else {
+            KnownHosts::Accept
+        };
+        let mut builder = SessionBuilder::default();
+        builder.user(self.user.clone());
+        if self.port != 22 {
+            builder.port(self.port);
+        }
+        if let Some(timeout) = config.connect_timeout {
+            builder.connect_timeout(std::time::Duration::from_secs(timeout));
+        }
+        builder.known_hosts_check(known_hosts);
+        builder
+    }
+
+    pub async fn connect(&self, config: &SshConfig) -> Result<Session, openssh::Error> {
+        self.session_builder(config).connect(&self.host).await
+    }
+
+    /// Socket addresses for a plain TCP connection to the SSH port. The
+    /// `%zone` of a link-local IPv6 literal becomes the scope ID, whether it
+    /// is an interface index or an interface name.
+    pub async fn socket_addrs(&self) -> Result<Vec<SocketAddr>, String> {
+        if let Some((addr, zone)) = self.host.split_once('%') {
+            let addr: Ipv6Addr = addr
+                .parse()
+                .map_err(|_| format!("Invalid IPv6 address: {}", self.host))?;
+            let scope_id = zone.parse::<u32>().ok().or_else(|| interface_index(zone));
+            if let Some(scope_id) = scope_id {
+                return Ok(vec![SocketAddr::V6(SocketAddrV6::new(
+                    addr, self.port, 0, scope_id,
+                ))]);
+            }
+        }
+        // Hostnames, plain IP literals, and zones named in a way only the
+        // system resolver understands
+        tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map(|addrs| addrs.collect())
+            .map_err(|e| format!("Failed to resolve {}: {}", self.host, e))
+    }
+}
+
+/// Index of a network interface by name, as used for IPv6 scope IDs
+fn interface_index(name: &str) -> Option<u32> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return None;
+    }
+    std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// `user@host`, with `:port` when it is not 22 and brackets around IPv6
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.port, self.host.contains(':')) {
+            (22, _) => write!(f, "{}@{}", self.user, self.host),
+            (port, true) => write!(f, "{}@[{}]:{}", self.user, self.host, port),
+            (port, false) => write!(f, "{}@{}:{}", self.user, self.host, port),
+        }
+    }
+}
+
 /// Connect to a remote host with customizable SSH options
 pub async fn new_connection(
     state: State<'_, AppState>,
@@ -40,46 +153,32 @@ pub async fn new_connection_with_config(
     ip: String,
     config: SshConfig,
 ) -> Result<(), String> {
-    let username = config.username.unwrap_or_else(|| "user".to_string());
-    let port = config.port.unwrap_or(22);
-    let strict_checking = config.strict_host_key_checking.unwrap_or(true);
-
-    // Determine known_hosts policy
-    let known_hosts = if strict_checking {
-        KnownHosts::Strict
-    } else {
-        KnownHosts::Accept
-    };
-
-    // Build connection string with optional port
-    let connection_string = if port == 22 {
-        format!("{}@{}", username, ip)
-    } else {
-        format!("{}@{}:{}", username, ip, port)
-    };
+    let destination = Destination::new(&ip, &config)?;
 
     info!(
-        "Attempting SSH connection to {} ({}@{}:{}) with strict_checking={}",
-        hostname, username, ip, port, strict_checking
+        "Attempting SSH connection to {} ({}) with strict_checking={}",
+        hostname,
+        destination,
+        config.strict_host_key_checking.unwrap_or(true)
     );
 
-    // Create session builder with timeout if specified
-    let mut builder = SessionBuilder::default();
-    if let Some(timeout) = config.connect_timeout {
-        builder.connect_timeout(std::time::Duration::from_secs(timeout));
-    }
-    builder.known_hosts_check(known_hosts);
-
-    match builder.connect(&connection_string).await {
-        Ok(_session) => {
+    match destination.connect(&config).await {
+        Ok(session) => {
             info!("Successfully connected to {} at IP {}", hostname, ip);
 
             // Add device to database after successful connection
-            let db = state.db.lock().map_err(|e| e.to_string())?;
-            db.insert_device(&hostname, &ip, None)
-                .map_err(|e| e.to_string())?;
+            let id = {
+                let db = state.db.lock().map_err(|e| e.to_string())?;
+                db.insert_device_with_config(&hostname, &ip, &config)
+                    .map_err(|e| e.to_string())?
+            };
             info!("Device added successfully: {}", hostname);
 
+            // Facts are best effort; the device stays added if gathering fails
+            if let Err(e) = crate::facts::collect(&state, id as i32, &session).await {
+                error!("Failed to gather facts for {}: {}", hostname, e);
+            }
+
             Ok(())
         }
         Err(e) => {
@@ -95,40 +194,14 @@ pub async fn test_connection(
     ip: String,
     config: SshConfig,
 ) -> Result<String, String> {
-    let username = config.username.unwrap_or_else(|| "user".to_string());
-    let port = config.port.unwrap_or(22);
-    let strict_checking = config.strict_host_key_checking.unwrap_or(true);
-
-    let known_hosts = if strict_checking {
-        KnownHosts::Strict
-    } else {
-        KnownHosts::Accept
-    };
+    let destination = Destination::new(&ip, &config)?;
 
-    let connection_string = if port == 22 {
-        format!("{}@{}", username, ip)
-    } else {
-        format!("{}@{}:{}", username, ip, port)
-    };
+    info!("Testing SSH connection to {} ({})", hostname, destination);
 
-    info!(
-        "Testing SSH connection to {} ({})",
-        hostname, connection_string
-    );
-
-    let mut builder = SessionBuilder::default();
-    if let Some(timeout) = config.connect_timeout {
-        builder.connect_timeout(std::time::Duration::from_secs(timeout));
-    }
-    builder.known_hosts_check(known_hosts);
-
-    match builder.connect(&connection_string).await {
+    match destination.connect(&config).await {
         Ok(_session) => {
             info!("Test connection successful to {}", hostname);
-            Ok(format!(
-                "Successfully connected to {}@{}:{}",
-                username, ip, port
-            ))
+            Ok(format!("Successfully connected to {}", destination))
         }
         Err(e) => {
             error!("Test connection failed to {}: {}", hostname, e);
@@ -141,12 +214,18 @@ pub async fn test_connection(
 #[derive(Debug, Clone, Serialize)]
 pub struct SystemMetrics {
     pub cpu_usage: f64,
+    pub iowait_percent: f64,
+    pub steal_percent: f64,
+    pub cpu_cores: Vec<crate::collector::CoreUsage>,
     pub memory_used_mb: f64,
     pub memory_total_mb: f64,
     pub memory_percent: f64,
+    /// Root filesystem; every mounted filesystem is in `filesystems`
     pub disk_used_gb: f64,
     pub disk_total_gb: f64,
     pub disk_percent: f64,
+    pub filesystems: Vec<crate::collector::DiskUsage>,
+    pub network: Vec<crate::collector::InterfaceMetrics>,
     pub uptime_seconds: u64,
     pub load_average: String,
     pub timestamp: i64,
@@ -154,32 +233,380 @@ pub struct SystemMetrics {
 
 /// Establish SSH session for metrics streaming
 async fn create_session(ip: &str, config: &SshConfig) -> Result<Session, String> {
-    let username = config.username.as_deref().unwrap_or("user");
-    let port = config.port.unwrap_or(22);
-    let strict_checking = config.strict_host_key_checking.unwrap_or(true);
-
-    let known_hosts = if strict_checking {
-        KnownHosts::Strict
-    } else {
-        KnownHosts::Accept
-    };
+    Destination::new(ip, config)?
+        .connect(config)
+        .await
+        .map_err(|e| format!("Failed to connect: {}", e))
+}
 
-    let connection_string = if port == 22 {
-        format!("{}@{}", username, ip)
-    } else {
-        format!("{}@{}:{}", username, ip, port)
+/// Open a session to a stored device using the SSH settings saved with it
+pub async fn connect_device(state: &AppState, device_id: i32) -> Result<(Device, Session), String> {
+    let device = {
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.get_device(device_id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Device {} not found", device_id))?
     };
+    info!(
+        "Opening SSH session to device {} ({})",
+        device.name, device.ip
+    );
+    let session = create_session(&device.ip, &device.ssh_config()).await?;
+    Ok((device, session))
+}
+
+/// Result of a shell command run on a device
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the remote process was killed by a signal
+    pub exit_code: Option<i32>,
+}
 
-    let mut builder = SessionBuilder::default();
-    if let Some(timeout) = config.connect_timeout {
-        builder.connect_timeout(std::time::Duration::from_secs(timeout));
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
     }
-    builder.known_hosts_check(known_hosts);
 
-    builder
-        .connect(&connection_string)
+    /// Text stored in `command_logs.output`
+    pub fn log_text(&self) -> String {
+        let mut text = self.stdout.clone();
+        if !self.stderr.is_empty() {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&self.stderr);
+        }
+        match self.exit_code {
+            Some(0) => text,
+            Some(code) => format!("{}\n[exit {}]", text.trim_end(), code),
+            None => format!("{}\n[killed by signal]", text.trim_end()),
+        }
+    }
+}
+
+/// Run a POSIX `sh` script on the device; a non-zero exit is not an error here
+///
+/// Privileged commands in the script use plain `sudo -n`; see [`exec_as`].
+pub async fn exec(session: &Session, script: &str) -> Result<CommandOutput, String> {
+    exec_as(session, &Privilege::default(), script).await
+}
+
+/// Like [`exec`], running privileged commands with a device's escalation method
+pub async fn exec_as(
+    session: &Session,
+    privilege: &Privilege,
+    script: &str,
+) -> Result<CommandOutput, String> {
+    let (script, input) = privilege.prepare(script)?;
+    if !input.is_empty() {
+        return run_with_input(session, &script, &input).await;
+    }
+    let output = session
+        .command("sh")
+        .arg("-c")
+        .arg(&script)
+        .output()
         .await
-        .map_err(|e| format!("Failed to connect: {}", e))
+        .map_err(|e| format!("Failed to execute command: {}", e))?;
+
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        exit_code: output.status.code(),
+    })
+}
+
+/// Like [`exec`], but feed `input` to the script's stdin
+pub async fn exec_with_input(
+    session: &Session,
+    script: &str,
+    input: &[u8],
+) -> Result<CommandOutput, String> {
+    exec_with_input_as(session, &Privilege::default(), script, input).await
+}
+
+/// Like [`exec_with_input`], running privileged commands with a device's
+/// escalation method
+pub async fn exec_with_input_as(
+    session: &Session,
+    privilege: &Privilege,
+    script: &str,
+    input: &[u8],
+) -> Result<CommandOutput, String> {
+    let (script, mut stdin) = privilege.prepare(script)?;
+    stdin.extend_from_slice(input);
+    run_with_input(session, &script, &stdin).await
+}
+
+async fn run_with_input(
+    session: &Session,
+    script: &str,
+    input: &[u8],
+) -> Result<CommandOutput, String> {
+    let mut child = session
+        .command("sh")
+        .arg("-c")
+        .arg(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await
+        .map_err(|e| format!("Failed to execute command: {}", e))?;
+
+    let mut stdin = child.stdin().take().ok_or("Command has no stdin")?;
+    stdin
+        .write_all(input)
+        .await
+        .map_err(|e| format!("Failed to write command input: {}", e))?;
+    // Closing stdin lets the remote side see end of input
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("Failed to execute command: {}", e))?;
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        exit_code: output.status.code(),
+    })
+}
+
+/// Run a script and hand every line of its standard output to `on_line` as
+/// it arrives; returns the exit code, `None` when killed by a signal
+///
+/// Standard error is discarded, so scripts that want it should `2>&1`.
+pub async fn exec_streaming(
+    session: &Session,
+    script: &str,
+    on_line: impl FnMut(String),
+) -> Result<Option<i32>, String> {
+    exec_streaming_as(session, &Privilege::default(), script, on_line).await
+}
+
+/// Like [`exec_streaming`], running privileged commands with a device's
+/// escalation method
+pub async fn exec_streaming_as(
+    session: &Session,
+    privilege: &Privilege,
+    script: &str,
+    mut on_line: impl FnMut(String),
+) -> Result<Option<i32>, String> {
+    let (script, input) = privilege.prepare(script)?;
+    let mut child = session
+        .command("sh")
+        .arg("-c")
+        .arg(&script)
+        .stdin(if input.is_empty() {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .await
+        .map_err(|e| format!("Failed to execute command: {}", e))?;
+    if let Some(mut stdin) = child.stdin().take() {
+        stdin
+            .write_all(&input)
+            .await
+            .map_err(|e| format!("Failed to write command input: {}", e))?;
+    }
+    let stdout = child.stdout().take().ok_or("Command has no stdout")?;
+
+    let mut reader = BufReader::new(stdout);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let n = reader
+            .read_until(b'\n', &mut buf)
+            .await
+            .map_err(|e| format!("Failed to read command output: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        on_line(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| format!("Failed to wait for command: {}", e))?;
+    Ok(status.code())
+}
+
+/// Quote a string as a single `sh` word
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Shell function privileged commands go through, defined by the exec
+/// functions according to the device's escalation method
+const ROOT_FN: &str = "ssedge_root";
+
+/// Wrap a command so it runs as root: directly when already root, otherwise
+/// through the device's escalation method (`sudo -n` unless configured)
+pub fn privileged(command: &str) -> String {
+    format!("{} {}", ROOT_FN, shell_quote(command))
+}
+
+/// How a device's privileged commands gain root
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Escalation {
+    /// `sudo` with a NOPASSWD rule
+    #[default]
+    Sudo,
+    /// `sudo` with the user's password from the vault
+    SudoPassword,
+    /// `doas` with a `nopass` rule, since doas only reads passwords from a terminal
+    Doas,
+    /// `su` with the root password from the vault; it only works where `su`
+    /// accepts a password that is not typed on a terminal
+    Su,
+}
+
+impl Escalation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Escalation::Sudo => "sudo",
+            Escalation::SudoPassword => "sudo_password",
+            Escalation::Doas => "doas",
+            Escalation::Su => "su",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sudo" => Some(Escalation::Sudo),
+            "sudo_password" => Some(Escalation::SudoPassword),
+            "doas" => Some(Escalation::Doas),
+            "su" => Some(Escalation::Su),
+            _ => None,
+        }
+    }
+
+    pub fn needs_password(&self) -> bool {
+        matches!(self, Escalation::SudoPassword | Escalation::Su)
+    }
+}
+
+/// Escalation method of a device together with its password, if it needs one
+///
+/// The password only ever travels on the script's stdin: it never appears in
+/// the script text, on a command line or in `command_logs`.
+#[derive(Clone, Default)]
+pub struct Privilege {
+    method: Escalation,
+    password: Option<String>,
+}
+
+impl Privilege {
+    pub fn new(method: Escalation, password: Option<String>) -> Self {
+        Privilege { method, password }
+    }
+
+    /// Settings of a stored device, with its password taken from the vault
+    pub fn for_device(state: &AppState, device: &Device) -> Self {
+        let method = Escalation::parse(&device.escalation).unwrap_or_default();
+        let password = if method.needs_password() {
+            state.vault.get(device.id)
+        } else {
+            None
+        };
+        Privilege { method, password }
+    }
+
+    /// Whether privileged commands can run: a method that needs a password
+    /// has one
+    pub fn is_usable(&self) -> bool {
+        !self.method.needs_password() || self.password.is_some()
+    }
+
+    /// Prefix a script with the definition of [`ROOT_FN`], returning it with
+    /// the bytes to feed its stdin ahead of any input of its own
+    ///
+    /// Scripts without privileged commands are left untouched. The vault only
+    /// lives in memory, so after a restart a password method has no password
+    /// until it is set again; that is an error rather than a quiet fallback,
+    /// so unattended jobs and drift checks report why they failed.
+    fn prepare(&self, script: &str) -> Result<(String, Vec<u8>), String> {
+        if !script.contains(ROOT_FN) {
+            return Ok((script.to_string(), Vec::new()));
+        }
+        if !self.is_usable() {
+            return Err(format!(
+                "No {} password is set for this device; passwords are only kept \
+                 until the app restarts, so set it again",
+                self.method.as_str()
+            ));
+        }
+        let (setup, escalate) = match (self.method, &self.password) {
+            (Escalation::SudoPassword, Some(_)) => (
+                // sudo runs the askpass helper only when it needs a password,
+                // so a NOPASSWD rule never sees it on the command's stdin
+                r#"IFS= read -r ssedge_pw
+ssedge_askpass=$(mktemp "${HOME:-/tmp}/.ssedge-askpass.XXXXXX") || exit 1
+trap 'rm -f "$ssedge_askpass"' EXIT
+printf '#!/bin/sh\nprintf "%%s\\n" "$SSEDGE_PW"\n' >"$ssedge_askpass"
+chmod 700 "$ssedge_askpass"
+"#,
+                r#"(export SSEDGE_PW="$ssedge_pw" SUDO_ASKPASS="$ssedge_askpass"; eval "sudo -A $1")"#,
+            ),
+            (Escalation::Su, Some(_)) => (
+                // su reads the password from its stdin, so the script's own
+                // input is staged in a file each privileged command reads from
+                r#"IFS= read -r ssedge_pw
+ssedge_in=$(mktemp /tmp/.ssedge-stdin.XXXXXX) || exit 1
+trap 'rm -f "$ssedge_in"' EXIT
+cat >"$ssedge_in"
+exec <"$ssedge_in"
+"#,
+                r#"printf '%s\n' "$ssedge_pw" | su root -c "exec <$ssedge_in; $1""#,
+            ),
+            (Escalation::Doas, _) => ("", r#"eval "doas -n $1""#),
+            _ => ("", r#"eval "sudo -n $1""#),
+        };
+        let script = format!(
+            "{setup}{f}() {{ if [ \"$(id -u)\" -eq 0 ]; then eval \"$1\"; else {escalate}; fi; }}\n{script}",
+            f = ROOT_FN,
+        );
+        let input = match &self.password {
+            Some(password) if self.method.needs_password() => {
+                format!("{}\n", password).into_bytes()
+            }
+            _ => Vec::new(),
+        };
+        Ok((script, input))
+    }
+}
+
+/// Whether stderr shows that escalation failed for want of a (correct) password
+pub fn sudo_password_required(stderr: &str) -> bool {
+    stderr.contains("sudo: a password is required")
+        || stderr.contains("sudo: a terminal is required")
+        || stderr.contains("incorrect password attempt")
+        || stderr.contains("doas: Authentication failed")
+        || stderr.contains("doas: a password is required")
+        || stderr.contains("su: Authentication failure")
+}
+
+/// Record a command run against a device in `command_logs`
+pub fn log_command(state: &AppState, device_id: i32, command: &str, output: Option<&str>) {
+    let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+        db.insert_command_log(device_id, command, output, crate::db::unix_timestamp())
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        error!(
+            "Failed to record command log for device {}: {}",
+            device_id, e
+        );
+    }
 }
 
 /// Fetch real-time system metrics from remote device
@@ -191,32 +618,11 @@ pub async fn get_system_metrics(ip: String, config: SshConfig) -> Result<SystemM
     let session = create_session(&ip, &config).await?;
     info!("SSH session created successfully");
 
-    // Command to gather system metrics in a single SSH call
-    let command = r#"
-        # CPU usage (average over 1 second)
-        cpu=$(top -bn2 -d 0.5 | grep "Cpu(s)" | tail -1 | awk '{print 100 - $8}')
-
-        # Memory info
-        mem=$(free -m | awk 'NR==2{printf "%.2f %.2f %.2f", $3, $2, $3*100/$2}')
-
-        # Disk info (root partition)
-        disk=$(df -BG / | awk 'NR==2{gsub(/G/, "", $3); gsub(/G/, "", $2); printf "%.2f %.2f %.2f", $3, $2, $3*100/$2}')
-
-        # Uptime in seconds
-        uptime=$(awk '{print int($1)}' /proc/uptime)
-
-        # Load average
-        loadavg=$(cat /proc/loadavg | awk '{print $1","$2","$3}')
-
-        # Output in parseable format
-        echo "$cpu|$mem|$disk|$uptime|$loadavg"
-    "#;
-
-    info!("Executing metrics command via SSH");
+    info!("Executing metrics collector via SSH");
     let output = session
-        .command("bash")
+        .command("sh")
         .arg("-c")
-        .arg(command)
+        .arg(crate::collector::collect_script())
         .output()
         .await
         .map_err(|e| {
@@ -233,58 +639,226 @@ pub async fn get_system_metrics(ip: String, config: SshConfig) -> Result<SystemM
     }
 
     let output_str = String::from_utf8_lossy(&output.stdout);
-    info!("Command output: {}", output_str);
-    let parts: Vec<&str> = output_str.trim().split('|').collect();
-
-    if parts.len() != 5 {
-        return Err(format!("Unexpected metrics output format: {}", output_str));
-    }
-
-    // Parse CPU
-    let cpu_usage = parts[0].trim().parse::<f64>().unwrap_or(0.0);
-
-    // Parse memory
-    let mem_parts: Vec<&str> = parts[1].split_whitespace().collect();
-    let memory_used_mb = mem_parts.get(0).and_then(|s| s.parse().ok()).unwrap_or(0.0);
-    let memory_total_mb = mem_parts.get(1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
-    let memory_percent = mem_parts.get(2).and_then(|s| s.parse().ok()).unwrap_or(0.0);
-
-    // Parse disk
-    let disk_parts: Vec<&str> = parts[2].split_whitespace().collect();
-    let disk_used_gb = disk_parts
-        .get(0)
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(0.0);
-    let disk_total_gb = disk_parts
-        .get(1)
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(1.0);
-    let disk_percent = disk_parts
-        .get(2)
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(0.0);
-
-    // Parse uptime
-    let uptime_seconds = parts[3].trim().parse::<u64>().unwrap_or(0);
-
-    // Load average
-    let load_average = parts[4].trim().to_string();
-
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
-
-    Ok(SystemMetrics {
-        cpu_usage,
-        memory_used_mb,
-        memory_total_mb,
-        memory_percent,
-        disk_used_gb,
-        disk_total_gb,
-        disk_percent,
-        uptime_seconds,
-        load_average,
-        timestamp,
+    let timestamp = crate::db::unix_timestamp();
+
+    crate::collector::parse_metrics(&output_str, timestamp).map_err(|e| {
+        error!("Failed to parse metrics from {}: {}", ip, e);
+        format!("Failed to parse metrics: {}", e)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destination(ip: &str, username: Option<&str>, port: Option<u16>) -> Destination {
+        let config = SshConfig {
+            username: username.map(String::from),
+            port,
+            ..SshConfig::default()
+        };
+        Destination::new(ip, &config).unwrap()
+    }
+
+    fn builder_options(destination: &Destination) -> (Option<String>, Option<String>) {
+        let builder = destination.session_builder(&SshConfig::default());
+        (
+            builder.get_user().map(String::from),
+            builder.get_port().map(String::from),
+        )
+    }
+
+    #[test]
+    fn ipv4_destination_on_default_port() {
+        let dest = destination("192.168.1.10", Some("pi"), None);
+        assert_eq!(dest.host, "192.168.1.10");
+        assert_eq!(dest.to_string(), "pi@192.168.1.10");
+        assert_eq!(builder_options(&dest), (Some("pi".to_string()), None));
+    }
+
+    #[test]
+    fn non_default_port_is_passed_as_an_option() {
+        let dest = destination("192.168.1.10", Some("pi"), Some(2222));
+        assert_eq!(dest.host, "192.168.1.10");
+        assert_eq!(dest.to_string(), "pi@192.168.1.10:2222");
+        assert_eq!(
+            builder_options(&dest),
+            (Some("pi".to_string()), Some("2222".to_string()))
+        );
+    }
+
+    #[test]
+    fn ipv6_destination_is_never_bracketed_for_ssh() {
+        let dest = destination("2001:db8::1", None, Some(2222));
+        assert_eq!(dest.host, "2001:db8::1");
+        assert_eq!(dest.to_string(), "user@[2001:db8::1]:2222");
+        assert_eq!(builder_options(&dest).1.as_deref(), Some("2222"));
+
+        let dest = destination("[2001:db8::1]", None, None);
+        assert_eq!(dest.host, "2001:db8::1");
+        assert_eq!(dest.to_string(), "user@2001:db8::1");
+    }
+
+    #[tokio::test]
+    async fn scoped_ipv6_resolves_with_its_scope_id() {
+        let addrs = destination("fe80::1%3", None, Some(2222))
+            .socket_addrs()
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["[fe80::1%3]:2222".parse().unwrap()]);
+
+        // Interface names are looked up in /sys on Linux
+        if let Some(lo) = interface_index("lo") {
+            let addrs = destination("[fe80::1%lo]", None, None)
+                .socket_addrs()
+                .await
+                .unwrap();
+            match addrs[..] {
+                [SocketAddr::V6(addr)] => {
+                    assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+                    assert_eq!((addr.port(), addr.scope_id()), (22, lo));
+                }
+                _ => panic!("unexpected addresses {:?}", addrs),
+            }
+        }
+
+        let addrs = destination("192.168.1.10", None, None)
+            .socket_addrs()
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["192.168.1.10:22".parse().unwrap()]);
+        assert_eq!(interface_index("../lo"), None);
+    }
+
+    #[test]
+    fn link_local_scope_id_is_kept() {
+        let dest = destination("fe80::1%eth0", Some("admin"), Some(2200));
+        assert_eq!(dest.host, "fe80::1%eth0");
+        assert_eq!(dest.to_string(), "admin@[fe80::1%eth0]:2200");
+    }
+
+    #[test]
+    fn hostname_destination() {
+        let dest = destination(" nas.lan ", Some("backup"), Some(22));
+        assert_eq!(dest.host, "nas.lan");
+        assert_eq!(dest.to_string(), "backup@nas.lan");
+        let dest = destination("nas.lan", Some("backup"), Some(8022));
+        assert_eq!(dest.to_string(), "backup@nas.lan:8022");
+    }
+
+    #[test]
+    fn rejects_destinations_ssh_would_misread() {
+        for ip in ["", "[]", "-oProxyCommand=id", "root@host", "a host"] {
+            assert!(
+                Destination::new(ip, &SshConfig::default()).is_err(),
+                "{:?}",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn plain_scripts_are_left_alone() {
+        let privilege = Privilege::new(Escalation::SudoPassword, Some("s3cret".to_string()));
+        assert_eq!(
+            privilege.prepare("uptime"),
+            Ok(("uptime".to_string(), Vec::new()))
+        );
+    }
+
+    #[test]
+    fn privileged_commands_default_to_sudo_n() {
+        let (script, input) = Privilege::default()
+            .prepare(&privileged("systemctl restart 'a b'"))
+            .unwrap();
+        assert!(script.contains(r#"eval "sudo -n $1""#));
+        assert!(script.ends_with(r#"ssedge_root 'systemctl restart '\''a b'\'''"#));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn password_goes_to_stdin_only() {
+        for method in [Escalation::SudoPassword, Escalation::Su] {
+            let privilege = Privilege::new(method, Some("s3cret".to_string()));
+            let (script, input) = privilege.prepare(&privileged("cat /etc/shadow")).unwrap();
+            assert!(!script.contains("s3cret"));
+            assert!(script.starts_with("IFS= read -r ssedge_pw\n"));
+            assert_eq!(input, b"s3cret\n");
+        }
+    }
+
+    #[test]
+    fn missing_password_is_an_error_only_when_root_is_needed() {
+        for method in [Escalation::SudoPassword, Escalation::Su] {
+            let privilege = Privilege::new(method, None);
+            let error = privilege.prepare(&privileged("true")).unwrap_err();
+            assert!(error.contains("No ") && error.contains("password is set"));
+            assert!(privilege.prepare("uptime").is_ok());
+        }
+
+        let (script, _) = Privilege::new(Escalation::Doas, None)
+            .prepare(&privileged("true"))
+            .unwrap();
+        assert!(script.contains(r#"eval "doas -n $1""#));
+    }
+
+    /// Run a prepared script locally as a non-root user whose `sudo` and
+    /// `su` accept the password `s3cret`
+    #[cfg(unix)]
+    fn run_prepared(privilege: &Privilege, script: &str, input: &[u8]) -> String {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin = std::env::temp_dir().join(format!(
+            "ssedge-privilege-{}-{:?}",
+            std::process::id(),
+            privilege.method
+        ));
+        std::fs::create_dir_all(&bin).unwrap();
+        for (name, body) in [
+            ("id", "echo 1000"),
+            (
+                "sudo",
+                r#"if [ "$1" = -A ]; then [ "$("$SUDO_ASKPASS")" = s3cret ] || exit 1; fi
+shift; exec "$@""#,
+            ),
+            (
+                "su",
+                r#"IFS= read -r pw; [ "$pw" = s3cret ] || exit 1; exec sh -c "$3""#,
+            ),
+        ] {
+            let path = bin.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let (script, mut stdin) = privilege.prepare(script).unwrap();
+        stdin.extend_from_slice(input);
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .env(
+                "PATH",
+                format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()),
+            )
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&stdin).unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&bin).unwrap();
+        assert!(output.status.success(), "{:?}", privilege.method);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stdin_reaches_the_privileged_command() {
+        for method in [Escalation::Sudo, Escalation::SudoPassword, Escalation::Su] {
+            let privilege = Privilege::new(method, Some("s3cret".to_string()));
+            let output = run_prepared(&privilege, &privileged("cat"), b"new config\nsecond line\n");
+            assert_eq!(output, "new config\nsecond line\n", "{:?}", method);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! systemd unit inspection and control through `systemctl` and `journalctl`.
+
+use crate::collector::split_sections;
+use crate::ssh::{privileged, shell_quote, CommandOutput};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Properties requested from `systemctl show` for a unit status
+const STATUS_PROPERTIES: &str = "Id,Description,LoadState,ActiveState,SubState,UnitFileState,\
+MainPID,ActiveEnterTimestamp,FragmentPath,Result";
+
+/// Upper bound on journal lines returned with a unit status
+pub const MAX_JOURNAL_LINES: u32 = 1000;
+
+/// Action that changes the state of a unit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitAction {
+    Start,
+    Stop,
+    Restart,
+    Enable,
+    Disable,
+}
+
+impl UnitAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnitAction::Start => "start",
+            UnitAction::Stop => "stop",
+            UnitAction::Restart => "restart",
+            UnitAction::Enable => "enable",
+            UnitAction::Disable => "disable",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "start" => Some(UnitAction::Start),
+            "stop" => Some(UnitAction::Stop),
+            "restart" => Some(UnitAction::Restart),
+            "enable" => Some(UnitAction::Enable),
+            "disable" => Some(UnitAction::Disable),
+            _ => None,
+        }
+    }
+}
+
+/// One row of `systemctl list-units`
+#[derive(Debug, Clone, Serialize)]
+pub struct UnitInfo {
+    pub name: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    pub description: String,
+    /// enabled, disabled, static, ... from `systemctl list-unit-files`
+    pub unit_file_state: Option<String>,
+}
+
+/// Detailed state of a unit together with its most recent journal lines
+#[derive(Debug, Clone, Serialize)]
+pub struct UnitStatus {
+    pub name: String,
+    pub description: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    pub unit_file_state: Option<String>,
+    pub main_pid: Option<u32>,
+    /// Human readable time the unit last entered the active state
+    pub active_since: Option<String>,
+    pub fragment_path: Option<String>,
+    pub result: Option<String>,
+    pub journal: Vec<String>,
+}
+
+/// Outcome of a start/stop/restart/enable/disable action
+#[derive(Debug, Clone, Serialize)]
+pub struct UnitActionResult {
+    pub unit: String,
+    pub action: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    /// Set when the action failed because escalating to root wanted a password
+    pub sudo_password_required: bool,
+    pub message: String,
+    /// State of the unit after the action, when it could be read
+    pub active_state: Option<String>,
+    pub sub_state: Option<String>,
+    pub unit_file_state: Option<String>,
+}
+
+/// Check a unit name before it is put on a command line
+///
+/// Only the characters systemd allows in unit names are accepted, and a
+/// leading `-` is rejected so the name can never be read as an option.
+pub fn validate_unit_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 256
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":-_.@\\".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid unit name: {}", name))
+    }
+}
+
+/// Script listing all service units and their unit file states
+pub fn list_script() -> String {
+    r#"
+export LC_ALL=C SYSTEMD_COLORS=0
+echo "==units=="; systemctl list-units --type=service --all --no-legend --no-pager --plain
+echo "==files=="; systemctl list-unit-files --type=service --no-legend --no-pager
+"#
+    .to_string()
+}
+
+/// Script printing the properties and the last `lines` journal entries of a unit
+pub fn status_script(unit: &str, lines: u32) -> String {
+    let unit = shell_quote(unit);
+    let mut script = format!(
+        r#"
+export LC_ALL=C SYSTEMD_COLORS=0
+echo "==show=="; systemctl show --no-pager --property={props} -- {unit}
+"#,
+        props = STATUS_PROPERTIES,
+        unit = unit,
+    );
+    if lines > 0 {
+        let journal = privileged(&format!(
+            "journalctl --no-pager --output=short-iso -n {} -u {}",
+            lines.min(MAX_JOURNAL_LINES),
+            unit
+        ));
+        script.push_str(&format!("echo \"==journal==\"; {} 2>&1\n", journal));
+    }
+    script
+}
+
+/// Command performing an action on a unit as root
+pub fn action_command(unit: &str, action: UnitAction) -> String {
+    privileged(&format!(
+        "systemctl {} -- {}",
+        action.as_str(),
+        shell_quote(unit)
+    ))
+}
+
+/// Split `systemctl list-units --plain --no-legend` output into units
+fn parse_units(section: &str) -> Vec<UnitInfo> {
+    section
+        .lines()
+        .filter_map(|line| {
+            let mut rest = line.trim();
+            let mut fields = Vec::with_capacity(4);
+            for _ in 0..4 {
+                let (field, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                if field.is_empty() {
+                    return None;
+                }
+                fields.push(field);
+                rest = tail.trim_start();
+            }
+            Some(UnitInfo {
+                name: fields[0].to_string(),
+                load_state: fields[1].to_string(),
+                active_state: fields[2].to_string(),
+                sub_state: fields[3].to_string(),
+                description: rest.to_string(),
+                unit_file_state: None,
+            })
+        })
+        .collect()
+}
+
+/// Map unit name to unit file state from `systemctl list-unit-files`
+fn parse_unit_files(section: &str) -> HashMap<&str, &str> {
+    section
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .collect()
+}
+
+/// Build the unit list from the output of [`list_script`]
+pub fn parse_unit_list(output: &str) -> Vec<UnitInfo> {
+    let sections = split_sections(output);
+    let files = parse_unit_files(sections.get("files").copied().unwrap_or(""));
+    let mut units = parse_units(sections.get("units").copied().unwrap_or(""));
+    for unit in &mut units {
+        unit.unit_file_state = files.get(unit.name.as_str()).map(|s| s.to_string());
+    }
+    units.sort_by(|a, b| a.name.cmp(&b.name));
+    units
+}
+
+/// Parse `KEY=VALUE` lines of `systemctl show`
+fn parse_properties(section: &str) -> HashMap<&str, &str> {
+    section
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect()
+}
+
+/// Build a unit status from the output of [`status_script`]
+pub fn parse_status(unit: &str, output: &str) -> Result<UnitStatus, String> {
+    let sections = split_sections(output);
+    let props = parse_properties(sections.get("show").copied().unwrap_or(""));
+    let prop = |key: &str| {
+        props
+            .get(key)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+
+    let load_state = prop("LoadState").unwrap_or_default();
+    if load_state.is_empty() {
+        return Err(format!("systemctl show returned no state for {}", unit));
+    }
+
+    let journal = sections
+        .get("journal")
+        .copied()
+        .unwrap_or("")
+        .lines()
+        .filter(|l| !l.is_empty() && *l != "-- No entries --")
+        .map(str::to_string)
+        .collect();
+
+    Ok(UnitStatus {
+        name: prop("Id").unwrap_or_else(|| unit.to_string()),
+        description: prop("Description").unwrap_or_default(),
+        load_state,
+        active_state: prop("ActiveState").unwrap_or_default(),
+        sub_state: prop("SubState").unwrap_or_default(),
+        unit_file_state: prop("UnitFileState"),
+        main_pid: prop("MainPID")
+            .and_then(|p| p.parse().ok())
+            .filter(|pid| *pid != 0),
+        active_since: prop("ActiveEnterTimestamp"),
+        fragment_path: prop("FragmentPath"),
+        result: prop("Result"),
+        journal,
+    })
+}
+
+/// Turn the output of [`action_command`] and an optional follow-up status
+/// into a typed result
+pub fn action_result(
+    unit: &str,
+    action: UnitAction,
+    output: &CommandOutput,
+    status: Option<&UnitStatus>,
+) -> UnitActionResult {
+    let sudo_password_required =
+        !output.success() && crate::ssh::sudo_password_required(&output.stderr);
+    let message = if output.success() {
+        format!("{} {} succeeded", action.as_str(), unit)
+    } else if sudo_password_required {
+        "Running systemctl as root needs a password on this device".to_string()
+    } else {
+        let detail = output.stderr.trim();
+        if detail.is_empty() {
+            format!("{} {} failed", action.as_str(), unit)
+        } else {
+            detail.to_string()
+        }
+    };
+
+    UnitActionResult {
+        unit: unit.to_string(),
+        action: action.as_str().to_string(),
+        success: output.success(),
+        exit_code: output.exit_code,
+        sudo_password_required,
+        message,
+        active_state: status.map(|s| s.active_state.clone()),
+        sub_state: status.map(|s| s.sub_state.clone()),
+        unit_file_state: status.and_then(|s| s.unit_file_state.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `list_script` output captured from a Debian host
+    const UNIT_LIST: &str = "==units==
+cron.service                     loaded    active   running Regular background program processing daemon
+nginx.service                    loaded    failed   failed  A high performance web server and a reverse proxy server
+ssh.service                      loaded    active   running OpenBSD Secure Shell server
+systemd-fsck@dev-sda1.service    loaded    inactive dead    File System Check on /dev/sda1
+broken.service                   not-found
+==files==
+cron.service                           enabled         enabled
+nginx.service                          enabled         enabled
+ssh.service                            enabled         enabled
+systemd-fsck@.service                  static          -
+";
+
+    /// `status_script` output for nginx with two journal lines
+    const NGINX_STATUS: &str = "==show==
+Id=nginx.service
+Description=A high performance web server and a reverse proxy server
+LoadState=loaded
+ActiveState=active
+SubState=running
+UnitFileState=enabled
+MainPID=812
+ActiveEnterTimestamp=Mon 2024-05-06 09:14:02 UTC
+FragmentPath=/lib/systemd/system/nginx.service
+Result=success
+==journal==
+2024-05-06T09:14:02+0000 pi systemd[1]: Starting nginx.service - A high performance web server...
+2024-05-06T09:14:02+0000 pi systemd[1]: Started nginx.service - A high performance web server.
+";
+
+    fn output(exit_code: Option<i32>, stderr: &str) -> CommandOutput {
+        CommandOutput {
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            exit_code,
+        }
+    }
+
+    #[test]
+    fn parses_unit_list() {
+        let units = parse_unit_list(UNIT_LIST);
+        let names: Vec<&str> = units.iter().map(|u| u.name.as_str()).collect();
+        // The truncated not-found row is dropped
+        assert_eq!(
+            names,
+            vec![
+                "cron.service",
+                "nginx.service",
+                "ssh.service",
+                "systemd-fsck@dev-sda1.service"
+            ]
+        );
+        let nginx = &units[1];
+        assert_eq!(
+            (
+                nginx.load_state.as_str(),
+                nginx.active_state.as_str(),
+                nginx.sub_state.as_str()
+            ),
+            ("loaded", "failed", "failed")
+        );
+        assert_eq!(
+            nginx.description,
+            "A high performance web server and a reverse proxy server"
+        );
+        assert_eq!(nginx.unit_file_state.as_deref(), Some("enabled"));
+        // Template instances have no unit file row of their own
+        assert_eq!(units[3].unit_file_state, None);
+        assert_eq!(units[3].description, "File System Check on /dev/sda1");
+    }
+
+    #[test]
+    fn parses_status_and_journal() {
+        let status = parse_status("nginx", NGINX_STATUS).unwrap();
+        assert_eq!(status.name, "nginx.service");
+        assert_eq!(status.active_state, "active");
+        assert_eq!(status.sub_state, "running");
+        assert_eq!(status.unit_file_state.as_deref(), Some("enabled"));
+        assert_eq!(status.main_pid, Some(812));
+        assert_eq!(
+            status.active_since.as_deref(),
+            Some("Mon 2024-05-06 09:14:02 UTC")
+        );
+        assert_eq!(status.result.as_deref(), Some("success"));
+        assert_eq!(status.journal.len(), 2);
+        assert!(
+            status.journal[1].ends_with("Started nginx.service - A high performance web server.")
+        );
+    }
+
+    #[test]
+    fn parses_status_of_a_stopped_unit() {
+        let output = "==show==
+Id=backup.service
+Description=Nightly backup
+LoadState=loaded
+ActiveState=inactive
+SubState=dead
+UnitFileState=
+MainPID=0
+ActiveEnterTimestamp=
+FragmentPath=/etc/systemd/system/backup.service
+Result=exit-code
+==journal==
+-- No entries --
+";
+        let status = parse_status("backup.service", output).unwrap();
+        assert_eq!(status.main_pid, None);
+        assert_eq!(status.unit_file_state, None);
+        assert_eq!(status.active_since, None);
+        assert_eq!(status.result.as_deref(), Some("exit-code"));
+        assert!(status.journal.is_empty());
+
+        assert!(parse_status("gone.service", "==show==\n").is_err());
+    }
+
+    #[test]
+    fn action_results() {
+        let status = parse_status("nginx", NGINX_STATUS).unwrap();
+        let ok = action_result(
+            "nginx.service",
+            UnitAction::Restart,
+            &output(Some(0), ""),
+            Some(&status),
+        );
+        assert!(ok.success && !ok.sudo_password_required);
+        assert_eq!(ok.message, "restart nginx.service succeeded");
+        assert_eq!(ok.active_state.as_deref(), Some("active"));
+        assert_eq!(ok.unit_file_state.as_deref(), Some("enabled"));
+
+        let password = action_result(
+            "nginx.service",
+            UnitAction::Stop,
+            &output(Some(1), "sudo: a password is required\n"),
+            None,
+        );
+        assert!(!password.success && password.sudo_password_required);
+        assert_eq!(
+            password.message,
+            "Running systemctl as root needs a password on this device"
+        );
+        assert_eq!(password.active_state, None);
+
+        let failed = action_result(
+            "nginx.service",
+            UnitAction::Start,
+            &output(
+                Some(1),
+                "Job for nginx.service failed because the control process exited with error code.\n",
+            ),
+            None,
+        );
+        assert!(!failed.sudo_password_required);
+        assert_eq!(failed.exit_code, Some(1));
+        assert!(failed.message.starts_with("Job for nginx.service failed"));
+
+        let killed = action_result("nginx.service", UnitAction::Enable, &output(None, ""), None);
+        assert!(!killed.success);
+        assert_eq!(killed.message, "enable nginx.service failed");
+    }
+}
@@ -0,0 +1,693 @@
+//! Queued, resumable file uploads and downloads over SFTP.
+//!
+//! Transfers are rows in the `transfers` table; a background worker runs the
+//! queued ones. Data is written to a `.part` file next to the destination,
+//! so an interrupted transfer resumes from the size of that file. Once
+//! complete, the sha256 of the copy is compared with the source and the
+//! `.part` file is renamed into place.
+
+use crate::command::AppState;
+use crate::db::{unix_timestamp, Transfer};
+use crate::sftp::{sanitize_path, DeviceFs};
+use crate::ssh::shell_quote;
+use log::{error, info, warn};
+use openssh_sftp_client::Sftp;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Notify;
+
+/// Tauri event emitted with a [`TransferProgress`] while a transfer runs
+pub const TRANSFER_PROGRESS_EVENT: &str = "transfer-progress";
+
+const CHUNK_SIZE: u32 = 256 * 1024;
+const MAX_CONCURRENT: usize = 2;
+/// Attempts per run before a transfer is marked failed; each resumes where the last stopped
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the worker looks for queued transfers when not woken
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const PART_SUFFIX: &str = ".part";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "upload" => Some(Direction::Upload),
+            "download" => Some(Direction::Download),
+            _ => None,
+        }
+    }
+}
+
+/// Payload of the `transfer-progress` event
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub id: i32,
+    pub state: String,
+    pub transferred: u64,
+    pub size: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Cancellation flags of running transfers, managed as Tauri state
+#[derive(Default)]
+pub struct TransferQueue {
+    running: Mutex<HashMap<i32, Arc<AtomicBool>>>,
+    wake: Notify,
+    /// Notified whenever a transfer reaches a final state
+    finished: Notify,
+}
+
+impl TransferQueue {
+    /// Ask the worker to look for queued transfers now
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Flag a running transfer for cancellation; returns false if it is not running
+    pub fn cancel(&self, id: i32) -> Result<bool, String> {
+        let running = self.running.lock().map_err(|e| e.to_string())?;
+        match running.get(&id) {
+            Some(flag) => {
+                flag.store(true, Ordering::Relaxed);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn is_running(&self, id: i32) -> Result<bool, String> {
+        let running = self.running.lock().map_err(|e| e.to_string())?;
+        Ok(running.contains_key(&id))
+    }
+
+    /// Wake tasks in [`wait`] after a transfer finished or was removed
+    pub fn notify_finished(&self) {
+        self.finished.notify_waiters();
+    }
+}
+
+/// Validate paths and queue a transfer; returns its id
+pub fn enqueue(
+    app: &AppHandle,
+    device_id: i32,
+    direction: Direction,
+    local_path: &str,
+    remote_path: &str,
+) -> Result<i64, String> {
+    if !Path::new(local_path).is_absolute() {
+        return Err(format!("Local path must be absolute: {}", local_path));
+    }
+    let remote_path = sanitize_path(remote_path).map_err(|e| e.message)?;
+    if direction == Direction::Upload && !Path::new(local_path).is_file() {
+        return Err(format!("Not a file: {}", local_path));
+    }
+
+    let id = {
+        let state = app.state::<AppState>();
+        let db = state.db.lock().map_err(|e| e.to_string())?;
+        db.insert_transfer(
+            device_id,
+            direction.as_str(),
+            local_path,
+            &remote_path,
+            unix_timestamp(),
+        )
+        .map_err(|e| e.to_string())?
+    };
+    info!(
+        "Queued {} {} of {} <-> {} on device {}",
+        direction.as_str(),
+        id,
+        local_path,
+        remote_path,
+        device_id
+    );
+    app.state::<TransferQueue>().wake();
+    Ok(id)
+}
+
+/// Requeue transfers interrupted by the last exit and start the queue worker
+pub fn spawn_worker(app: AppHandle) {
+    {
+        let state = app.state::<AppState>();
+        let result = state
+            .db
+            .lock()
+            .map_err(|e| e.to_string())
+            .and_then(|db| db.requeue_running_transfers().map_err(|e| e.to_string()));
+        match result {
+            Ok(0) => {}
+            Ok(n) => info!("Resuming {} interrupted transfers", n),
+            Err(e) => error!("Failed to requeue interrupted transfers: {}", e),
+        }
+    }
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            start_queued(&app);
+            let queue = app.state::<TransferQueue>();
+            let _ = tokio::time::timeout(POLL_INTERVAL, queue.wake.notified()).await;
+        }
+    });
+}
+
+/// Start queued transfers while there are free slots
+fn start_queued(app: &AppHandle) {
+    let queue = app.state::<TransferQueue>();
+    let state = app.state::<AppState>();
+    let mut running = match queue.running.lock() {
+        Ok(running) => running,
+        Err(e) => {
+            error!("Failed to lock running transfers: {}", e);
+            return;
+        }
+    };
+    let free = MAX_CONCURRENT.saturating_sub(running.len());
+    if free == 0 {
+        return;
+    }
+
+    let queued = match state.db.lock() {
+        Ok(db) => db.get_queued_transfers(free).and_then(|transfers| {
+            for t in &transfers {
+                db.set_transfer_state(t.id, "running", None, unix_timestamp())?;
+            }
+            Ok(transfers)
+        }),
+        Err(e) => {
+            error!("Failed to lock db to start transfers: {}", e);
+            return;
+        }
+    };
+    let queued = match queued {
+        Ok(queued) => queued,
+        Err(e) => {
+            error!("Failed to load queued transfers: {}", e);
+            return;
+        }
+    };
+
+    for transfer in queued {
+        let cancel = Arc::new(AtomicBool::new(false));
+        running.insert(transfer.id, cancel.clone());
+        tauri::async_runtime::spawn(run_transfer(app.clone(), transfer, cancel));
+    }
+}
+
+/// Throttles progress events and DB updates for one transfer
+struct Progress<'a> {
+    app: &'a AppHandle,
+    id: i32,
+    size: Option<u64>,
+    last: Instant,
+}
+
+impl<'a> Progress<'a> {
+    fn new(app: &'a AppHandle, id: i32) -> Self {
+        Self {
+            app,
+            id,
+            size: None,
+            last: Instant::now() - PROGRESS_INTERVAL,
+        }
+    }
+
+    fn update(&mut self, transferred: u64) {
+        if self.last.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last = Instant::now();
+        let state = self.app.state::<AppState>();
+        if let Ok(db) = state.db.lock() {
+            if let Err(e) =
+                db.update_transfer_progress(self.id, transferred as i64, unix_timestamp())
+            {
+                error!("Failed to record progress of transfer {}: {}", self.id, e);
+            }
+        }
+        self.emit("running", transferred, None);
+    }
+
+    fn emit(&self, state: &str, transferred: u64, error: Option<String>) {
+        let progress = TransferProgress {
+            id: self.id,
+            state: state.to_string(),
+            transferred,
+            size: self.size,
+            error,
+        };
+        if let Err(e) = self.app.emit(TRANSFER_PROGRESS_EVENT, &progress) {
+            error!("Failed to emit transfer progress: {}", e);
+        }
+    }
+}
+
+/// Run one transfer with retries, then record its final state
+async fn run_transfer(app: AppHandle, transfer: Transfer, cancel: Arc<AtomicBool>) {
+    let mut progress = Progress::new(&app, transfer.id);
+    let mut result = Err("not started".to_string());
+    for attempt in 1..=MAX_ATTEMPTS {
+        result = attempt_transfer(&app, &transfer, &cancel, &mut progress).await;
+        match &result {
+            Ok(_) => break,
+            Err(_) if cancel.load(Ordering::Relaxed) => break,
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                warn!(
+                    "Transfer {} failed on attempt {}/{}, resuming in {}s: {}",
+                    transfer.id,
+                    attempt,
+                    MAX_ATTEMPTS,
+                    RETRY_DELAY.as_secs(),
+                    e
+                );
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            Err(_) => {}
+        }
+    }
+
+    let (final_state, transferred, err) = match result {
+        Ok(size) => {
+            info!("Transfer {} completed ({} bytes)", transfer.id, size);
+            ("completed", size, None)
+        }
+        Err(_) if cancel.load(Ordering::Relaxed) => {
+            info!("Transfer {} cancelled", transfer.id);
+            ("cancelled", 0, None)
+        }
+        Err(e) => {
+            error!("Transfer {} failed: {}", transfer.id, e);
+            ("failed", 0, Some(e))
+        }
+    };
+
+    {
+        let state = app.state::<AppState>();
+        let result = state.db.lock().map_err(|e| e.to_string()).and_then(|db| {
+            let now = unix_timestamp();
+            if final_state == "completed" {
+                db.update_transfer_progress(transfer.id, transferred as i64, now)
+                    .map_err(|e| e.to_string())?;
+            }
+            db.set_transfer_state(transfer.id, final_state, err.as_deref(), now)
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            error!("Failed to record state of transfer {}: {}", transfer.id, e);
+        }
+    }
+    progress.emit(final_state, transferred, err);
+
+    let queue = app.state::<TransferQueue>();
+    match queue.running.lock() {
+        Ok(mut running) => {
+            running.remove(&transfer.id);
+        }
+        Err(e) => error!("Failed to release transfer {}: {}", transfer.id, e),
+    }
+    queue.notify_finished();
+    queue.wake();
+}
+
+/// Wait for a transfer to complete, fail or be cancelled and return its
+/// final row
+pub async fn wait(app: &AppHandle, id: i32) -> Result<Transfer, String> {
+    let queue = app.state::<TransferQueue>();
+    loop {
+        // Created before the check so a transfer finishing in between still wakes it
+        let finished = queue.finished.notified();
+        let transfer = {
+            let state = app.state::<AppState>();
+            let db = state.db.lock().map_err(|e| e.to_string())?;
+            db.get_transfer(id)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("Transfer {} was deleted", id))?
+        };
+        if !matches!(transfer.state.as_str(), "queued" | "running") {
+            return Ok(transfer);
+        }
+        let _ = tokio::time::timeout(POLL_INTERVAL, finished).await;
+    }
+}
+
+/// One connection's worth of work; returns the number of bytes in the finished file
+async fn attempt_transfer(
+    app: &AppHandle,
+    transfer: &Transfer,
+    cancel: &AtomicBool,
+    progress: &mut Progress<'_>,
+) -> Result<u64, String> {
+    let state = app.state::<AppState>();
+    let fs = DeviceFs::open(&state, transfer.device_id)
+        .await
+        .map_err(|e| e.message)?;
+    let result = match Direction::parse(&transfer.direction) {
+        Some(Direction::Upload) => upload(app, &fs, transfer, cancel, progress).await,
+        Some(Direction::Download) => download(app, &fs, transfer, cancel, progress).await,
+        None => Err(format!(
+            "Unknown transfer direction: {}",
+            transfer.direction
+        )),
+    };
+    fs.close().await;
+    result
+}
+
+fn check_cancelled(cancel: &AtomicBool) -> Result<(), String> {
+    if cancel.load(Ordering::Relaxed) {
+        Err("cancelled".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// sha256 of a local file, computed off the async runtime
+pub(crate) async fn local_sha256(path: &str) -> Result<String, String> {
+    let owned = path.to_string();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&owned)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(hex(&hasher.finalize()))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| format!("Failed to hash {}: {}", path, e))
+}
+
+/// Extract the digest from `sha256sum` output
+pub fn parse_sha256sum(output: &str) -> Option<String> {
+    let digest = output.split_whitespace().next()?;
+    // GNU sha256sum marks lines whose file name needed escaping with a `\`
+    let digest = digest.strip_prefix('\\').unwrap_or(digest);
+    (digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| digest.to_lowercase())
+}
+
+async fn remote_sha256(fs: &DeviceFs, path: &str) -> Result<String, String> {
+    let output =
+        crate::ssh::exec(fs.session(), &format!("sha256sum -- {}", shell_quote(path))).await?;
+    if !output.success() {
+        return Err(format!(
+            "sha256sum of {} failed: {}",
+            path,
+            output.stderr.trim()
+        ));
+    }
+    parse_sha256sum(&output.stdout)
+        .ok_or_else(|| format!("Unexpected sha256sum output for {}", path))
+}
+
+/// Byte to resume a transfer of a `size` byte file at, given the size of a
+/// partial copy left by an earlier attempt. A partial copy larger than the
+/// file cannot belong to it, so the transfer starts over.
+fn resume_offset(partial: Option<u64>, size: u64) -> u64 {
+    match partial {
+        Some(existing) if existing <= size => existing,
+        _ => 0,
+    }
+}
+
+/// Size of a remote file, or `None` if it does not exist
+async fn remote_size(sftp: &Sftp, path: &str) -> Result<Option<u64>, String> {
+    match sftp.fs().metadata(path).await {
+        Ok(meta) => Ok(Some(meta.len().unwrap_or(0))),
+        Err(e) => {
+            let err = crate::sftp::FileError::from_sftp(e, path);
+            if err.kind == crate::sftp::FileErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(err.message)
+            }
+        }
+    }
+}
+
+fn record_source(app: &AppHandle, id: i32, size: u64, sha256: &str) -> Result<(), String> {
+    let state = app.state::<AppState>();
+    let db = state.db.lock().map_err(|e| e.to_string())?;
+    db.set_transfer_source(id, size as i64, sha256)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn upload(
+    app: &AppHandle,
+    fs: &DeviceFs,
+    transfer: &Transfer,
+    cancel: &AtomicBool,
+    progress: &mut Progress<'_>,
+) -> Result<u64, String> {
+    let size = tokio::fs::metadata(&transfer.local_path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", transfer.local_path, e))?
+        .len();
+    progress.size = Some(size);
+    let expected = local_sha256(&transfer.local_path).await?;
+    record_source(app, transfer.id, size, &expected)?;
+
+    // Resume from what already reached the device, unless it cannot belong to this file
+    let part = format!("{}{}", transfer.remote_path, PART_SUFFIX);
+    let offset = resume_offset(remote_size(fs.sftp(), &part).await?, size);
+    if offset > 0 {
+        info!("Resuming upload {} at byte {}", transfer.id, offset);
+    }
+
+    let mut remote = fs
+        .sftp()
+        .options()
+        .write(true)
+        .create(true)
+        .truncate(offset == 0)
+        .open(&part)
+        .await
+        .map_err(|e| crate::sftp::FileError::from_sftp(e, &part).message)?;
+    remote
+        .seek(SeekFrom::Start(offset))
+        .await
+        .map_err(|e| format!("Failed to seek {}: {}", part, e))?;
+
+    let mut local = tokio::fs::File::open(&transfer.local_path)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", transfer.local_path, e))?;
+    local
+        .seek(SeekFrom::Start(offset))
+        .await
+        .map_err(|e| format!("Failed to seek {}: {}", transfer.local_path, e))?;
+
+    let chunk = CHUNK_SIZE as usize;
+    let mut buf = vec![0u8; chunk];
+    let mut sent = offset;
+    loop {
+        check_cancelled(cancel)?;
+        let n = tokio::io::AsyncReadExt::read(&mut local, &mut buf)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", transfer.local_path, e))?;
+        if n == 0 {
+            break;
+        }
+        remote
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", part, e))?;
+        sent += n as u64;
+        progress.update(sent);
+    }
+    remote
+        .close()
+        .await
+        .map_err(|e| format!("Failed to close {}: {}", part, e))?;
+
+    let actual = remote_sha256(fs, &part).await?;
+    if actual != expected {
+        // Start over on the next attempt
+        let _ = fs.sftp().fs().remove_file(&part).await;
+        return Err(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            transfer.remote_path, expected, actual
+        ));
+    }
+
+    let output = crate::ssh::exec(
+        fs.session(),
+        &format!(
+            "mv -f -- {} {}",
+            shell_quote(&part),
+            shell_quote(&transfer.remote_path)
+        ),
+    )
+    .await?;
+    if !output.success() {
+        return Err(format!(
+            "Failed to move {} into place: {}",
+            part,
+            output.stderr.trim()
+        ));
+    }
+    Ok(size)
+}
+
+async fn download(
+    app: &AppHandle,
+    fs: &DeviceFs,
+    transfer: &Transfer,
+    cancel: &AtomicBool,
+    progress: &mut Progress<'_>,
+) -> Result<u64, String> {
+    let size = remote_size(fs.sftp(), &transfer.remote_path)
+        .await?
+        .ok_or_else(|| format!("{}: No such file or directory", transfer.remote_path))?;
+    progress.size = Some(size);
+    let expected = remote_sha256(fs, &transfer.remote_path).await?;
+    record_source(app, transfer.id, size, &expected)?;
+
+    let part = format!("{}{}", transfer.local_path, PART_SUFFIX);
+    let partial = tokio::fs::metadata(&part).await.ok().map(|meta| meta.len());
+    let offset = resume_offset(partial, size);
+    if offset > 0 {
+        info!("Resuming download {} at byte {}", transfer.id, offset);
+    }
+
+    let mut local = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(offset == 0)
+        .open(&part)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", part, e))?;
+    local
+        .seek(SeekFrom::Start(offset))
+        .await
+        .map_err(|e| format!("Failed to seek {}: {}", part, e))?;
+
+    let mut remote = fs
+        .sftp()
+        .open(&transfer.remote_path)
+        .await
+        .map_err(|e| crate::sftp::FileError::from_sftp(e, &transfer.remote_path).message)?;
+
+    let mut received = offset;
+    loop {
+        check_cancelled(cancel)?;
+        // `File::read` advances by the requested length even on a short read
+        remote
+            .seek(SeekFrom::Start(received))
+            .await
+            .map_err(|e| format!("Failed to seek {}: {}", transfer.remote_path, e))?;
+        let data = remote
+            .read(CHUNK_SIZE, Default::default())
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", transfer.remote_path, e))?;
+        let Some(data) = data else {
+            break;
+        };
+        local
+            .write_all(&data)
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", part, e))?;
+        received += data.len() as u64;
+        progress.update(received);
+    }
+    local
+        .flush()
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", part, e))?;
+    drop(local);
+    let _ = remote.close().await;
+
+    let actual = local_sha256(&part).await?;
+    if actual != expected {
+        let _ = tokio::fs::remove_file(&part).await;
+        return Err(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            transfer.local_path, expected, actual
+        ));
+    }
+    tokio::fs::rename(&part, &transfer.local_path)
+        .await
+        .map_err(|e| format!("Failed to move {} into place: {}", part, e))?;
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn parses_sha256sum_output() {
+        assert_eq!(
+            parse_sha256sum(&format!("{}  /srv/app.tar\n", DIGEST)).as_deref(),
+            Some(DIGEST)
+        );
+        assert_eq!(
+            parse_sha256sum(&DIGEST.to_uppercase()).as_deref(),
+            Some(DIGEST)
+        );
+        // File names with a newline or backslash are escaped by GNU sha256sum
+        assert_eq!(
+            parse_sha256sum(&format!("\\{}  /srv/a\\nb.part\n", DIGEST)).as_deref(),
+            Some(DIGEST)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_sha256sum_output() {
+        for output in [
+            String::new(),
+            "\n".to_string(),
+            "sha256sum: /srv/app.tar: No such file or directory\n".to_string(),
+            format!("{}  /srv/app.tar", &DIGEST[..63]),
+            format!("{}0  /srv/app.tar", DIGEST),
+            format!("{}g  /srv/app.tar", &DIGEST[..63]),
+            format!("\\\\{}  /srv/app.tar", DIGEST),
+        ] {
+            assert_eq!(parse_sha256sum(&output), None, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn resumes_only_from_a_partial_copy_that_fits() {
+        assert_eq!(resume_offset(None, 1000), 0);
+        assert_eq!(resume_offset(Some(0), 1000), 0);
+        assert_eq!(resume_offset(Some(400), 1000), 400);
+        assert_eq!(resume_offset(Some(1000), 1000), 1000);
+        // Larger than the source: left from another file, start over
+        assert_eq!(resume_offset(Some(1001), 1000), 0);
+        assert_eq!(resume_offset(Some(5), 0), 0);
+    }
+}
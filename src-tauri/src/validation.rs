@@ -0,0 +1,274 @@
+//! Checks applied to a device's name, address and port before it is stored.
+//!
+//! Addresses are IPv4 or IPv6 literals, optionally with a `%scope` zone for
+//! link-local IPv6, or DNS hostnames, and are stored in canonical form:
+//! IP literals as `IpAddr` prints them, hostnames lowercased without a
+//! trailing dot. Two devices may not share an address and port. The
+//! database enforces the same rules with triggers, so a caller that skips
+//! these checks still cannot store a malformed row.
+
+use crate::db::{Db, Device};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv6Addr};
+
+const DEFAULT_PORT: u16 = 22;
+const MAX_NAME_LEN: usize = 100;
+
+/// A problem with one input field, for showing next to it in a form
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    /// `name`, `ip` or `port`
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, message: impl Into<String>) -> Self {
+        FieldError {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// All field errors as one message, for commands that return a string
+pub fn describe(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{}: {}", e.field, e.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Name and address of a device that passed validation
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidDevice {
+    pub name: String,
+    pub ip: String,
+}
+
+/// Trim a name and collapse runs of whitespace to one space
+pub fn normalize_name(name: &str) -> Result<String, String> {
+    if name.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return Err("must not contain control characters".to_string());
+    }
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        return Err("is required".to_string());
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("must be at most {} characters", MAX_NAME_LEN));
+    }
+    Ok(name)
+}
+
+/// Whether `s` is a DNS hostname: dot-separated labels of letters, digits
+/// and inner hyphens, not ending in a numeric label like a mistyped IPv4
+/// address does
+pub fn valid_hostname(s: &str) -> bool {
+    let s = s.strip_suffix('.').unwrap_or(s);
+    !s.is_empty()
+        && s.len() <= 253
+        && !s
+            .rsplit('.')
+            .next()
+            .unwrap()
+            .chars()
+            .all(|c| c.is_ascii_digit())
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Parse an IP literal or hostname into the form devices store
+pub fn normalize_host(host: &str) -> Result<String, String> {
+    let host = host.trim();
+    if host.is_empty() {
+        return Err("is required".to_string());
+    }
+    // `[fe80::1]` as copied from a URL
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+
+    if let Some((addr, zone)) = host.split_once('%') {
+        let addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|_| format!("{:?} is not a valid IPv6 address", addr))?;
+        if zone.is_empty()
+            || !zone
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+        {
+            return Err(format!("{:?} is not a valid interface or zone", zone));
+        }
+        return Ok(format!("{}%{}", addr, zone));
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+    if valid_hostname(host) {
+        return Ok(host.trim_end_matches('.').to_ascii_lowercase());
+    }
+    if let Some((_, port)) = host.split_once(':').filter(|(h, _)| !h.contains(':')) {
+        if !port.contains(':') && port.parse::<u16>().is_ok() {
+            return Err("must not include a port; set the port separately".to_string());
+        }
+    }
+    if host.contains(':') {
+        Err(format!("{:?} is not a valid IPv6 address", host))
+    } else if host.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        Err(format!("{:?} is not a valid IPv4 address", host))
+    } else {
+        Err(format!("{:?} is not a valid IP address or hostname", host))
+    }
+}
+
+/// Address and port a device is reached at, compared for duplicates.
+/// Rows stored before validation existed may not parse, so those fall
+/// back to the trimmed, lowercased address.
+pub fn endpoint(ip: &str, port: Option<u16>) -> (String, u16) {
+    let ip = normalize_host(ip).unwrap_or_else(|_| ip.trim().to_ascii_lowercase());
+    (ip, port.unwrap_or(DEFAULT_PORT))
+}
+
+/// Stored device, other than `device_id`, already at this address and port
+pub fn find_duplicate<'a>(
+    existing: &'a [Device],
+    ip: &str,
+    port: Option<u16>,
+    device_id: Option<i32>,
+) -> Option<&'a Device> {
+    let key = endpoint(ip, port);
+    existing
+        .iter()
+        .filter(|d| Some(d.id) != device_id)
+        .find(|d| endpoint(&d.ip, d.port) == key)
+}
+
+/// Check a device against the rules and the stored devices, collecting
+/// every field's errors. `device_id` is the device being edited, if any.
+pub fn validate_device(
+    name: &str,
+    ip: &str,
+    port: Option<u16>,
+    existing: &[Device],
+    device_id: Option<i32>,
+) -> Result<ValidDevice, Vec<FieldError>> {
+    let mut errors = Vec::new();
+    let name = normalize_name(name).map_err(|e| errors.push(FieldError::new("name", e)));
+    let ip = normalize_host(ip).map_err(|e| errors.push(FieldError::new("ip", e)));
+    if port == Some(0) {
+        errors.push(FieldError::new("port", "must be between 1 and 65535"));
+    }
+    if let Ok(ip) = &ip {
+        if let Some(other) = find_duplicate(existing, ip, port, device_id) {
+            errors.push(FieldError::new(
+                "ip",
+                format!(
+                    "{} port {} is already used by {}",
+                    ip,
+                    port.unwrap_or(DEFAULT_PORT),
+                    other.name
+                ),
+            ));
+        }
+    }
+    match (name, ip) {
+        (Ok(name), Ok(ip)) if errors.is_empty() => Ok(ValidDevice { name, ip }),
+        _ => Err(errors),
+    }
+}
+
+/// Validate a new device against the database, as a single message
+pub fn check_new_device(
+    db: &Db,
+    name: &str,
+    ip: &str,
+    port: Option<u16>,
+) -> Result<ValidDevice, String> {
+    let existing = db.get_all_devices().map_err(|e| e.to_string())?;
+    validate_device(name, ip, port, &existing, None).map_err(|errors| describe(&errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: i32, name: &str, ip: &str, port: Option<u16>) -> Device {
+        Device {
+            port,
+            ..Device::fixture(id, name, ip)
+        }
+    }
+
+    #[test]
+    fn normalizes_names() {
+        assert_eq!(normalize_name("  Lab \t Pi  ").unwrap(), "Lab Pi");
+        assert!(normalize_name("   ").is_err());
+        assert!(normalize_name("pi\u{0}").is_err());
+        assert!(normalize_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn normalizes_addresses() {
+        assert_eq!(normalize_host(" 192.168.1.10 ").unwrap(), "192.168.1.10");
+        assert_eq!(normalize_host("2001:DB8:0:0::1").unwrap(), "2001:db8::1");
+        assert_eq!(normalize_host("[fd00::20]").unwrap(), "fd00::20");
+        assert_eq!(normalize_host("fe80::1%eth0").unwrap(), "fe80::1%eth0");
+        assert_eq!(normalize_host("NAS.Local.").unwrap(), "nas.local");
+        assert_eq!(normalize_host("raspberrypi").unwrap(), "raspberrypi");
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for (host, kind) in [
+            ("", "required"),
+            ("300.1.1.1", "IPv4"),
+            ("192.168.1", "IPv4"),
+            ("fe80::1::2", "IPv6"),
+            ("fe80::1%", "zone"),
+            ("fe80::1%eth 0", "zone"),
+            ("host_name", "hostname"),
+            ("-bad.lan", "hostname"),
+            ("10.0.0.1:22", "port"),
+            ("nas.lan:2222", "port"),
+        ] {
+            let error = normalize_host(host).unwrap_err();
+            assert!(error.contains(kind), "{:?}: {}", host, error);
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_address_and_port() {
+        let existing = vec![
+            device(1, "gateway", "192.168.1.1", None),
+            device(2, "legacy", " NAS.lan ", Some(2222)),
+        ];
+        let errors = validate_device("gw", "192.168.1.1", Some(22), &existing, None).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![FieldError::new(
+                "ip",
+                "192.168.1.1 port 22 is already used by gateway"
+            )]
+        );
+        assert!(validate_device("gw", "192.168.1.1", Some(2222), &existing, None).is_ok());
+        assert!(validate_device("nas", "nas.lan", Some(2222), &existing, None).is_err());
+        // A device does not clash with itself when edited
+        assert!(validate_device("gw", "192.168.1.1", None, &existing, Some(1)).is_ok());
+    }
+
+    #[test]
+    fn reports_every_field() {
+        let errors = validate_device(" ", "999.0.0.1", Some(0), &[], None).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field).collect();
+        assert_eq!(fields, vec!["name", "ip", "port"]);
+        assert!(describe(&errors).starts_with("name: is required; ip: "));
+    }
+}
@@ -0,0 +1,39 @@
+//! Device passwords used for privilege escalation.
+//!
+//! Passwords are held in memory only: they are never written to the database
+//! or the log, and have to be entered again after a restart.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct Vault {
+    passwords: Mutex<HashMap<i32, String>>,
+}
+
+impl Vault {
+    pub fn get(&self, device_id: i32) -> Option<String> {
+        self.passwords
+            .lock()
+            .ok()
+            .and_then(|passwords| passwords.get(&device_id).cloned())
+    }
+
+    pub fn set(&self, device_id: i32, password: String) {
+        if let Ok(mut passwords) = self.passwords.lock() {
+            passwords.insert(device_id, password);
+        }
+    }
+
+    pub fn remove(&self, device_id: i32) {
+        if let Ok(mut passwords) = self.passwords.lock() {
+            passwords.remove(&device_id);
+        }
+    }
+
+    pub fn contains(&self, device_id: i32) -> bool {
+        self.passwords
+            .lock()
+            .is_ok_and(|passwords| passwords.contains_key(&device_id))
+    }
+}
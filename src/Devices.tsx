@@ -41,6 +41,7 @@ interface Device {
   name: string;
   ip: string;
   last_seen: number | null;
+  tags: string[];
 }
 
 interface SystemMetrics {
@@ -164,7 +165,8 @@ function Devices() {
   const formatLastSeen = (timestamp: number | null) => {
     if (!timestamp) return "Never";
     const now = Date.now();
-    const diff = now - timestamp;
+    // last_seen is stored in seconds
+    const diff = now - timestamp * 1000;
     const minutes = Math.floor(diff / 60000);
     if (minutes < 1) return "Just now";
     if (minutes < 60) return `${minutes} min ago`;
@@ -188,6 +190,7 @@ function Devices() {
     try {
       const result = await invoke<SystemMetrics>("get_device_metrics", {
         ip: device.ip,
+        deviceId: device.id,
         username: "root", // TODO: Store username in DB per device
         port: null,
         strictHostKeyChecking: false, // For localhost testing